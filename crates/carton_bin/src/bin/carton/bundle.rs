@@ -0,0 +1,321 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! OCI bundles, for `carton run-bundle`: a directory with a root filesystem and a `config.json`
+//! as the OCI runtime specification describes it.
+//!
+//! Carton takes the settings that it has an equivalent for: the root filesystem, the hostname and
+//! domain name, the process (its arguments, environment, working directory, user and terminal),
+//! bind mounts and the ID mappings of a user namespace. The kernel filesystems that `runc spec` mounts, like /proc,
+//! /dev and /sys, are carton's default mounts. Other settings, like capabilities, rlimits, seccomp
+//! and cgroup resources, don't restrict the container; each of them is logged as a warning.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use libcarton::{ContainerBuilder, IdMapping, NeedsRootfs};
+
+use log::warn;
+
+use serde::Deserialize;
+
+use crate::image::{self, DEFAULT_PATH};
+use crate::settings::Settings;
+
+/// Destinations of the mounts of `runc spec` that carton's default mounts cover.
+const DEFAULT_MOUNTS: [&str; 8] = [
+    "/proc",
+    "/dev",
+    "/dev/pts",
+    "/dev/shm",
+    "/dev/mqueue",
+    "/sys",
+    "/sys/fs/cgroup",
+    "/tmp",
+];
+
+/// The parts of a bundle's `config.json` that carton uses.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Spec {
+    oci_version: String,
+    root: Root,
+    process: Process,
+    hostname: Option<String>,
+    domainname: Option<String>,
+    #[serde(default)]
+    mounts: Vec<SpecMount>,
+    #[serde(default)]
+    linux: Linux,
+    #[serde(flatten)]
+    other: BTreeMap<String, serde_json::Value>,
+    /// Where the `config.json` was read from.
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+#[derive(Deserialize, Debug)]
+struct Root {
+    /// The root filesystem, relative to the bundle unless it's absolute.
+    path: PathBuf,
+    #[serde(default)]
+    readonly: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct Process {
+    #[serde(default)]
+    terminal: bool,
+    #[serde(default)]
+    user: User,
+    args: Vec<String>,
+    /// Variables as `NAME=value`.
+    #[serde(default)]
+    env: Vec<String>,
+    cwd: PathBuf,
+    #[serde(flatten)]
+    other: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct User {
+    uid: u32,
+    gid: u32,
+    #[serde(default)]
+    additional_gids: Vec<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SpecMount {
+    destination: PathBuf,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    /// The source of a bind mount, relative to the bundle unless it's absolute.
+    source: Option<PathBuf>,
+    #[serde(default)]
+    options: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct Linux {
+    #[serde(default)]
+    namespaces: Vec<Namespace>,
+    #[serde(default)]
+    uid_mappings: Vec<SpecIdMapping>,
+    #[serde(default)]
+    gid_mappings: Vec<SpecIdMapping>,
+    #[serde(flatten)]
+    other: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Namespace {
+    #[serde(rename = "type")]
+    kind: String,
+    path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SpecIdMapping {
+    container_id: u32,
+    host_id: u32,
+    size: u32,
+}
+
+impl Spec {
+    /// Reads the `config.json` of the bundle in `bundle`.
+    pub(crate) fn load(bundle: &Path) -> Result<Self> {
+        let path = bundle.join("config.json");
+        let contents =
+            fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+        let mut spec: Spec = serde_json::from_str(&contents)
+            .with_context(|| format!("parsing {}", path.display()))?;
+
+        if !spec.oci_version.starts_with("1.") {
+            bail!(
+                "{} is for version {} of the OCI runtime specification, carton supports 1.x",
+                path.display(),
+                spec.oci_version
+            );
+        }
+        spec.path = path;
+
+        Ok(spec)
+    }
+
+    /// Whether the process wants a terminal, which it gets through `--console-socket`.
+    pub(crate) fn terminal(&self) -> bool {
+        self.process.terminal
+    }
+
+    /// Whether the container gets a network namespace of its own, rather than the host's.
+    pub(crate) fn has_network_namespace(&self) -> bool {
+        self.linux
+            .namespaces
+            .iter()
+            .any(|namespace| namespace.kind == "network")
+    }
+
+    /// Configures `builder` with the settings of the bundle in `bundle`, and records where they
+    /// came from in `settings`.
+    pub(crate) fn configure(
+        self,
+        bundle: &Path,
+        builder: ContainerBuilder<NeedsRootfs>,
+        settings: &mut Settings,
+    ) -> Result<ContainerBuilder> {
+        let rootfs = bundle.join(&self.root.path);
+        if self.root.readonly {
+            warn!("ignoring root.readonly, the root filesystem is writable");
+        }
+
+        let environment = self
+            .process
+            .env
+            .iter()
+            .map(|variable| match variable.split_once('=') {
+                Some((name, value)) => Ok((name.to_string(), value.to_string())),
+                None => bail!(
+                    "invalid environment variable `{}`, expected NAME=value",
+                    variable
+                ),
+            })
+            .collect::<Result<Vec<(String, String)>>>()?;
+        let Some((command, arguments)) = self.process.args.split_first() else {
+            bail!("process.args is empty, there's no command to run");
+        };
+        let path = environment
+            .iter()
+            .find(|(name, _)| name == "PATH")
+            .map_or(DEFAULT_PATH, |(_, value)| value.as_str());
+        let mut builder = builder
+            .rootfs(rootfs.clone())
+            .command(
+                image::find_command(&rootfs, command, path)?,
+                Some(arguments.to_vec()),
+            )
+            .environment(environment)
+            .working_dir(self.process.cwd);
+
+        let user = self.process.user;
+        if (user.uid, user.gid) != (0, 0) {
+            builder = builder.user(user.uid, user.gid);
+        }
+        if !user.additional_gids.is_empty() {
+            warn!("ignoring process.user.additionalGids, the process has no supplementary groups");
+        }
+
+        let hostname = settings
+            .setting("hostname")
+            .bundle(&self.path, self.hostname)
+            .value();
+        if let Some(hostname) = hostname {
+            builder = builder.hostname(hostname);
+        }
+        let domainname = settings
+            .setting("domainname")
+            .bundle(&self.path, self.domainname)
+            .value();
+        if let Some(domainname) = domainname {
+            builder = builder.domainname(domainname);
+        }
+
+        let mut default_mounts = false;
+        for mount in self.mounts {
+            let is_bind = mount.kind.as_deref() == Some("bind")
+                || mount
+                    .options
+                    .iter()
+                    .any(|option| option == "bind" || option == "rbind");
+            if !is_bind
+                && DEFAULT_MOUNTS
+                    .iter()
+                    .any(|path| mount.destination == Path::new(path))
+            {
+                default_mounts = true;
+                continue;
+            }
+            let (true, Some(source)) = (is_bind, mount.source) else {
+                bail!(
+                    "the {} mount at {} isn't supported, only bind mounts are",
+                    mount.kind.as_deref().unwrap_or("(untyped)"),
+                    mount.destination.display()
+                );
+            };
+            if mount.options.iter().any(|option| option == "ro") {
+                warn!(
+                    "ignoring the ro option of the mount at {}, it's writable",
+                    mount.destination.display()
+                );
+            }
+            let target = mount
+                .destination
+                .strip_prefix("/")
+                .unwrap_or(&mount.destination);
+            builder = builder.add_mount(bundle.join(source), target.into());
+        }
+        if default_mounts {
+            builder = builder.add_default_mounts().add_default_devices();
+        }
+
+        for namespace in &self.linux.namespaces {
+            if let Some(path) = &namespace.path {
+                bail!(
+                    "joining the {} namespace at {} isn't supported",
+                    namespace.kind,
+                    path.display()
+                );
+            }
+        }
+        if self
+            .linux
+            .namespaces
+            .iter()
+            .any(|namespace| namespace.kind == "user")
+        {
+            let (uid_mappings, gid_mappings) = if self.linux.uid_mappings.is_empty() {
+                IdMapping::current_user()?
+            } else {
+                (
+                    id_mappings(&self.linux.uid_mappings),
+                    id_mappings(&self.linux.gid_mappings),
+                )
+            };
+            builder = builder.user_namespace(uid_mappings, gid_mappings);
+        }
+
+        let ignored = self
+            .other
+            .keys()
+            .filter(|key| key.as_str() != "annotations")
+            .map(String::from)
+            .chain(
+                self.process
+                    .other
+                    .keys()
+                    .map(|key| format!("process.{}", key)),
+            )
+            .chain(self.linux.other.keys().map(|key| format!("linux.{}", key)));
+        for setting in ignored {
+            warn!(
+                "ignoring the `{}` setting, which carton doesn't support",
+                setting
+            );
+        }
+
+        Ok(builder)
+    }
+}
+
+fn id_mappings(mappings: &[SpecIdMapping]) -> Vec<IdMapping> {
+    mappings
+        .iter()
+        .map(|mapping| IdMapping::new(mapping.container_id, mapping.host_id, mapping.size))
+        .collect()
+}
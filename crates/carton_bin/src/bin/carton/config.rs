@@ -0,0 +1,316 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+use std::env;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use clap::ValueEnum;
+
+use ipnetwork::{Ipv4Network, Ipv6Network};
+
+use log::info;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+const SYSTEM_CONFIG_PATH: &str = "/etc/carton/carton.toml";
+/// The version of the layout of carton's TOML files (config files and project manifests) that
+/// this carton reads, see `parse_versioned()`.
+const SCHEMA_VERSION: i64 = 1;
+
+/// Host-level defaults, read from `/etc/carton/carton.toml` and the user's
+/// `$XDG_CONFIG_HOME/carton/carton.toml`. Settings in the user's file override the system-wide
+/// ones, and command line flags override both (see `Settings`).
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Directory in which the state of containers is kept.
+    pub(crate) state_dir: Option<PathBuf>,
+    /// Directory in which data that outlives a reboot is kept.
+    pub(crate) data_dir: Option<PathBuf>,
+    /// Whether to mount /proc, /sys, /tmp, /dev, etc. inside containers (defaults to true).
+    pub(crate) default_mounts: Option<bool>,
+    /// Directories from the host to bind mount inside every container.
+    pub(crate) mounts: Option<Vec<MountConfig>>,
+    /// How containers are connected to the network (defaults to `host`).
+    pub(crate) network: Option<NetworkMode>,
+    /// Settings of the bridge that containers are connected to in the `bridge` network mode.
+    pub(crate) bridge: Option<BridgeConfig>,
+    /// Host interface on top of which interfaces are created in the `macvlan` and `ipvlan`
+    /// network modes.
+    pub(crate) parent: Option<String>,
+    /// Settings for the `cni` network mode.
+    pub(crate) cni: Option<CniConfig>,
+    /// DNS servers for the containers' /etc/resolv.conf (defaults to the host's).
+    pub(crate) dns: Option<Vec<IpAddr>>,
+    /// DNS search domains for the containers' /etc/resolv.conf (defaults to the host's).
+    pub(crate) dns_search: Option<Vec<String>>,
+    /// Init binary that `--init` runs the command with (defaults to `carton-init` next to the
+    /// carton executable).
+    pub(crate) init_path: Option<PathBuf>,
+    /// Names of the host's environment variables, or patterns like `LC_*`, that the commands of
+    /// containers get (defaults to all of carton's environment).
+    pub(crate) env_host: Option<Vec<String>>,
+    /// Seccomp profile that limits the system calls of containers that aren't given one with
+    /// `--seccomp-profile` (defaults to none).
+    pub(crate) seccomp_profile: Option<PathBuf>,
+    /// The config files that were read, lowest first, each with its own settings.
+    #[serde(skip)]
+    pub(crate) files: Vec<(PathBuf, Config)>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MountConfig {
+    /// Path on the host.
+    pub(crate) source: PathBuf,
+    /// Path inside the container.
+    pub(crate) target: PathBuf,
+    /// Skip the mount, with a warning, when the source doesn't exist on this host.
+    #[serde(default)]
+    pub(crate) optional: bool,
+    /// User and group of the container, as `uid:gid`, that the files of the source appear to be
+    /// owned by.
+    #[serde(default, deserialize_with = "deserialize_owner")]
+    pub(crate) owner: Option<(u32, u32)>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum NetworkMode {
+    /// Share the network of the host
+    Host,
+    /// Connect the container to a bridge, with its own address
+    Bridge,
+    /// Put the container directly on the network of a host interface, with its own MAC address
+    Macvlan,
+    /// Put the container directly on the network of a host interface, sharing its MAC address
+    Ipvlan,
+    /// Let CNI plugins connect the container to a network
+    Cni,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BridgeConfig {
+    /// Name of the bridge interface (defaults to `carton0`).
+    pub(crate) name: Option<String>,
+    /// Subnet from which containers get their addresses (defaults to `10.88.0.0/16`).
+    #[serde(default, deserialize_with = "deserialize_subnet")]
+    pub(crate) subnet: Option<Ipv4Network>,
+    /// Subnet from which containers get their IPv6 addresses (defaults to `fd88::/64`).
+    #[serde(default, deserialize_with = "deserialize_subnet")]
+    pub(crate) ipv6_subnet: Option<Ipv6Network>,
+    /// Whether containers get an IPv4 address (defaults to true).
+    pub(crate) ipv4: Option<bool>,
+    /// Whether containers get an IPv6 address (defaults to false).
+    pub(crate) ipv6: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CniConfig {
+    /// Name of the CNI network to connect containers to.
+    pub(crate) network: Option<String>,
+    /// Directory with the network configurations (defaults to `/etc/cni/net.d`).
+    pub(crate) conf_dir: Option<PathBuf>,
+    /// Directories with the plugin executables (defaults to `/opt/cni/bin`).
+    pub(crate) plugin_dirs: Option<Vec<PathBuf>>,
+}
+
+impl Config {
+    pub(crate) fn load() -> Result<Self> {
+        let mut config = Config::default();
+
+        for path in [Some(PathBuf::from(SYSTEM_CONFIG_PATH)), user_config_path()]
+            .into_iter()
+            .flatten()
+        {
+            if path.exists() {
+                let file = Config::from_file(&path)?;
+                config = config.merge(file.clone());
+                config.files.push((path, file));
+            }
+        }
+
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        info!("Reading configuration from {}", path.display());
+
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+
+        parse_versioned(path, &contents)
+    }
+
+    /// Returns a configuration where every setting that is present in `other` replaces the one in
+    /// `self`.
+    fn merge(self, other: Config) -> Self {
+        Config {
+            state_dir: other.state_dir.or(self.state_dir),
+            data_dir: other.data_dir.or(self.data_dir),
+            default_mounts: other.default_mounts.or(self.default_mounts),
+            mounts: other.mounts.or(self.mounts),
+            network: other.network.or(self.network),
+            bridge: other.bridge.or(self.bridge),
+            parent: other.parent.or(self.parent),
+            cni: other.cni.or(self.cni),
+            dns: other.dns.or(self.dns),
+            dns_search: other.dns_search.or(self.dns_search),
+            init_path: other.init_path.or(self.init_path),
+            env_host: other.env_host.or(self.env_host),
+            seccomp_profile: other.seccomp_profile.or(self.seccomp_profile),
+            files: self.files,
+        }
+    }
+}
+
+fn user_config_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|config_dir| config_dir.join("carton").join("carton.toml"))
+}
+
+fn deserialize_owner<'de, D>(deserializer: D) -> Result<Option<(u32, u32)>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let owner = String::deserialize(deserializer)?;
+    owner
+        .split_once(':')
+        .and_then(|(uid, gid)| Some((uid.parse().ok()?, gid.parse().ok()?)))
+        .map(Some)
+        .ok_or_else(|| {
+            serde::de::Error::custom(format!(
+                "expected `uid:gid` like 1000:1000, got `{}`",
+                owner
+            ))
+        })
+}
+
+fn deserialize_subnet<'de, D, N>(deserializer: D) -> Result<Option<N>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    N: std::str::FromStr,
+    N::Err: std::fmt::Display,
+{
+    let subnet = String::deserialize(deserializer)?;
+    subnet.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+/// Parses `contents`, the TOML file at `path`, which may say which version of its layout it's
+/// written for with a top-level `schema_version` (1 when it doesn't). The version is checked
+/// before anything else, so that a file for a newer carton fails with an error that says so,
+/// instead of one about a setting that this carton doesn't know. Once the layout changes, files
+/// of older versions are rewritten into the current one here, before they're deserialized.
+pub(crate) fn parse_versioned<T: DeserializeOwned>(path: &Path, contents: &str) -> Result<T> {
+    let mut table: toml::Table =
+        toml::from_str(contents).with_context(|| format!("parsing {}", path.display()))?;
+    match table.remove("schema_version") {
+        None => {}
+        Some(toml::Value::Integer(version)) if (1..=SCHEMA_VERSION).contains(&version) => {}
+        Some(toml::Value::Integer(version)) if version > SCHEMA_VERSION => bail!(
+            "{} is meant for a newer carton, it has schema version {} and this carton only \
+             knows up to version {}",
+            path.display(),
+            version,
+            SCHEMA_VERSION
+        ),
+        Some(version) => bail!(
+            "{} has an invalid schema_version {}",
+            path.display(),
+            version
+        ),
+    }
+
+    T::deserialize(toml::Value::Table(table)).with_context(|| format!("parsing {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    #[serde(deny_unknown_fields)]
+    struct Example {
+        name: String,
+    }
+
+    fn parse(contents: &str) -> Result<Example> {
+        parse_versioned(Path::new("carton.toml"), contents)
+    }
+
+    #[test]
+    fn parses_files_with_and_without_a_version() {
+        let expected = Example { name: "web".into() };
+
+        assert_eq!(parse("name = \"web\"").unwrap(), expected);
+        assert_eq!(
+            parse("schema_version = 1\nname = \"web\"").unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn refuses_newer_versions_before_unknown_settings() {
+        let e = parse("schema_version = 2\nname = \"web\"\nnew_setting = true").unwrap_err();
+
+        assert_eq!(
+            e.to_string(),
+            "carton.toml is meant for a newer carton, it has schema version 2 and this carton \
+             only knows up to version 1"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_versions() {
+        for version in ["0", "-1", "\"1\"", "1.0"] {
+            let contents = format!("schema_version = {}\nname = \"web\"", version);
+            let e = parse(&contents).unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                format!("carton.toml has an invalid schema_version {}", version),
+            );
+        }
+    }
+
+    #[test]
+    fn merges_settings_of_later_files_over_earlier_ones() {
+        let system: Config = parse_versioned(
+            Path::new("/etc/carton/carton.toml"),
+            "seccomp_profile = \"/etc/carton/default.json\"\ninit_path = \"/usr/libexec/carton-init\"",
+        )
+        .unwrap();
+        let user: Config = parse_versioned(
+            Path::new("carton.toml"),
+            "seccomp_profile = \"/home/alice/strict.json\"",
+        )
+        .unwrap();
+
+        let config = Config::default().merge(system.clone());
+        assert_eq!(
+            config.seccomp_profile,
+            Some(PathBuf::from("/etc/carton/default.json"))
+        );
+
+        let config = config.merge(user).merge(Config::default());
+        assert_eq!(
+            config.seccomp_profile,
+            Some(PathBuf::from("/home/alice/strict.json"))
+        );
+        assert_eq!(config.init_path, system.init_path);
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        assert!(parse("name = ").is_err());
+        assert!(parse("name = \"web\"\nother = 1").is_err());
+    }
+}
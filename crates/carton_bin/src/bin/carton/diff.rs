@@ -0,0 +1,169 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! What an ephemeral container changed in its root filesystem, for `carton diff`.
+//!
+//! The container's writes end up in the upper layer of its overlay, but that's on a tmpfs that
+//! only exists in the container's mount namespace. So instead of listing the upper layer, the
+//! root filesystem as the container sees it (through `/proc/<pid>/root`) is compared with the root
+//! filesystem underneath, which is the overlay's lower layer. Overlayfs shows the files of the
+//! lower layer as they are, so only files that the container wrote, or whose owner or mode it
+//! changed, differ.
+
+use std::collections::{BTreeSet, HashSet};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, Metadata};
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// How a path in the container differs from the root filesystem underneath.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Change {
+    Added,
+    Changed,
+    Deleted,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Change::Added => "A",
+            Change::Changed => "C",
+            Change::Deleted => "D",
+        })
+    }
+}
+
+struct Trees<'a> {
+    lower: &'a Path,
+    merged: &'a Path,
+    mount_points: &'a HashSet<PathBuf>,
+}
+
+/// Returns the changes of the container's root filesystem at `merged` compared to `lower`, by
+/// their path in the container, in the order of a walk through the tree. The mount points of the
+/// container (see `mount_points()`) are left out, because what's mounted on them isn't part of the
+/// root filesystem. The contents of a deleted directory aren't listed separately.
+pub(crate) fn changes(
+    lower: &Path,
+    merged: &Path,
+    mount_points: &HashSet<PathBuf>,
+) -> Result<Vec<(Change, PathBuf)>> {
+    let trees = Trees {
+        lower,
+        merged,
+        mount_points,
+    };
+    let mut changes = Vec::new();
+    compare_dir(&trees, Path::new("/"), true, &mut changes)?;
+
+    Ok(changes)
+}
+
+/// Compares the directory `dir` of the container with the same directory of the lower tree, where
+/// it may not be a directory at all.
+fn compare_dir(
+    trees: &Trees,
+    dir: &Path,
+    in_lower: bool,
+    changes: &mut Vec<(Change, PathBuf)>,
+) -> Result<()> {
+    let mut names = entry_names(&in_tree(trees.merged, dir))?;
+    if in_lower {
+        names.extend(entry_names(&in_tree(trees.lower, dir))?);
+    }
+
+    for name in names {
+        let path = dir.join(name);
+        if trees.mount_points.contains(&path) {
+            continue;
+        }
+
+        let lower = if in_lower {
+            metadata(&in_tree(trees.lower, &path))?
+        } else {
+            None
+        };
+        let Some(merged) = metadata(&in_tree(trees.merged, &path))? else {
+            if lower.is_some() {
+                changes.push((Change::Deleted, path));
+            }
+            continue;
+        };
+
+        match &lower {
+            None => changes.push((Change::Added, path.clone())),
+            Some(lower) if differ(lower, &merged) => changes.push((Change::Changed, path.clone())),
+            Some(_) => {}
+        }
+        if merged.is_dir() {
+            let lower_is_dir = lower.as_ref().map_or(false, Metadata::is_dir);
+            compare_dir(trees, &path, lower_is_dir, changes)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns whether a file was changed, which includes being replaced by another kind of file.
+fn differ(lower: &Metadata, merged: &Metadata) -> bool {
+    let attributes = |metadata: &Metadata| {
+        (
+            metadata.mode(),
+            metadata.uid(),
+            metadata.gid(),
+            metadata.size(),
+            (metadata.mtime(), metadata.mtime_nsec()),
+            (metadata.ctime(), metadata.ctime_nsec()),
+        )
+    };
+
+    attributes(lower) != attributes(merged)
+}
+
+/// Returns `path` of the container in the tree at `root`.
+fn in_tree(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Returns the names in the directory `dir`, which are none when it no longer exists.
+fn entry_names(dir: &Path) -> Result<BTreeSet<OsString>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", dir.display())),
+    };
+
+    entries
+        .map(|entry| Ok(entry?.file_name()))
+        .collect::<Result<_, std::io::Error>>()
+        .with_context(|| format!("reading {}", dir.display()))
+}
+
+/// Returns the metadata of the file at `path` itself, or `None` when there's no such file.
+fn metadata(path: &Path) -> Result<Option<Metadata>> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+    }
+}
+
+/// Returns the mount points of the container whose process is `pid`, other than its root, as
+/// paths in the container.
+pub(crate) fn mount_points(pid: i32) -> Result<HashSet<PathBuf>> {
+    let path = format!("/proc/{}/mountinfo", pid);
+    let mountinfo = fs::read_to_string(&path).with_context(|| format!("reading {}", path))?;
+
+    // The mount point is the fifth field, relative to the root of the process
+    Ok(mountinfo
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(4))
+        .filter(|mount_point| *mount_point != "/")
+        .map(PathBuf::from)
+        .collect())
+}
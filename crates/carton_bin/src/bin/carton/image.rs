@@ -0,0 +1,99 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! The command line of a container, composed like Docker composes it from an image.
+//!
+//! An image's config has an `Entrypoint`, the program that always runs, and a `Cmd`, its default
+//! arguments (or the whole command, without an entrypoint). The command line is the entrypoint
+//! followed by the command. Arguments after the root filesystem replace the image's `Cmd`, and
+//! `--entrypoint` replaces its `Entrypoint` and drops its `Cmd`, because those were arguments for
+//! another program.
+//!
+//! Carton only reads the config of an OCI image (the `config` blob that `skopeo` or `umoci` leave
+//! next to the unpacked root filesystem), not the image itself.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use serde::Deserialize;
+
+/// The `PATH` in which a command is looked up when it's just a name.
+pub(crate) const DEFAULT_PATH: &str =
+    "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// The parts of an OCI image config that carton uses.
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct ImageConfig {
+    #[serde(default, alias = "Config")]
+    config: ContainerConfig,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerConfig {
+    #[serde(default)]
+    entrypoint: Option<Vec<String>>,
+    #[serde(default)]
+    cmd: Option<Vec<String>>,
+}
+
+impl ImageConfig {
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+
+        serde_json::from_str(&contents).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    /// Returns the command line of a container of the image: `entrypoint`, or the image's
+    /// entrypoint, followed by `command`, or the image's command without an `entrypoint`.
+    pub(crate) fn command_line(
+        &self,
+        entrypoint: Option<String>,
+        command: Vec<String>,
+    ) -> Result<Vec<String>> {
+        let image_command = if entrypoint.is_some() {
+            Vec::new()
+        } else {
+            self.config.cmd.clone().unwrap_or_default()
+        };
+        let entrypoint = match entrypoint {
+            Some(entrypoint) => vec![entrypoint],
+            None => self.config.entrypoint.clone().unwrap_or_default(),
+        };
+        let command = if command.is_empty() {
+            image_command
+        } else {
+            command
+        };
+
+        let command_line = [entrypoint, command].concat();
+        if command_line.is_empty() {
+            bail!(
+                "there's no command to run, give one after the root filesystem, or an image \
+                 config with an Entrypoint or Cmd"
+            );
+        }
+
+        Ok(command_line)
+    }
+}
+
+/// Returns the path of `command` in the root filesystem at `rootfs`, looking it up in the
+/// directories of `path` when it's just a name, like `sh`.
+pub(crate) fn find_command(rootfs: &Path, command: &str, path: &str) -> Result<PathBuf> {
+    if command.contains('/') {
+        return Ok(command.into());
+    }
+
+    path.split(':')
+        .map(|dir| Path::new(dir).join(command))
+        .find(|candidate| {
+            rootfs
+                .join(candidate.strip_prefix("/").unwrap_or(candidate))
+                .is_file()
+        })
+        .with_context(|| format!("{} not found in the PATH of the root filesystem", command))
+}
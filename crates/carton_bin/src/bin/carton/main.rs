@@ -0,0 +1,2904 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fs::File;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+
+use clap::{Args, CommandFactory, Parser, Subcommand};
+
+use clap_complete::Shell;
+
+use ipnetwork::{Ipv4Network, Ipv6Network};
+
+use log::{info, warn};
+
+use libcarton::{
+    checkpoint_container, copy_from_container, copy_to_container, debug_container, drop_privileges,
+    parse_signal, pause_container, resume_container, signal_container, syscall_number,
+    update_limits, Architecture, BandwidthLimit, BridgeNetwork, CgroupVersion, CheckpointOptions,
+    CniNetwork, Container, ContainerBuilder, ContainerExit, ContainerInfo, ContainerManager,
+    ContainerState, ContainerStats, CoreDumps, DbusProxy, DebugOptions, Diagnostic, EnvFilter,
+    FuseRootfs, Gpus, HidePid, HostFeatures, IdMapping, ListenFds, LocalVolumes, MacAddress,
+    MacvlanKind, MacvlanNetwork, Network, Paths, ProcfsOptions, ResourceLimits, Route,
+    SeccompProfile, SeccompRecorder, SelinuxMode, Sha256Digest, SharedNetwork, Signal, StateStore,
+    SyscallRequest, VolumeDriver,
+};
+
+use serde::Serialize;
+
+use crate::bundle::Spec;
+use crate::config::{Config, NetworkMode};
+use crate::image::{ImageConfig, DEFAULT_PATH};
+use crate::otlp::OtlpExporter;
+use crate::output::{render_template, OutputFormat};
+use crate::project::{Condition, Manifest, DEFAULT_MANIFEST};
+use crate::secrets::SecretSource;
+use crate::settings::Settings;
+use crate::syscall_log::SyscallLog;
+use crate::systemd::RestartPolicy;
+
+mod bundle;
+mod config;
+mod diff;
+mod image;
+mod otlp;
+mod output;
+mod project;
+mod secrets;
+mod settings;
+mod syscall_log;
+mod systemd;
+mod top;
+
+/// The user that carton switches to right after it starts as root, when set, keeping only a small
+/// privileged helper (see `drop_privileges()`). It's an environment variable rather than an
+/// option because it has to take effect before the arguments are parsed.
+const PRIVSEP_USER_VAR: &str = "CARTON_PRIVSEP_USER";
+
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Cli {
+    /// Directory in which the state of containers is kept [default: /run/carton, or
+    /// $XDG_RUNTIME_DIR/carton when not running as root]
+    #[arg(long, global = true, env = "CARTON_STATE_DIR")]
+    state_dir: Option<PathBuf>,
+    /// Directory in which data that outlives a reboot is kept [default: /var/lib/carton, or
+    /// $XDG_DATA_HOME/carton when not running as root]
+    #[arg(long, global = true, env = "CARTON_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a command inside a new container
+    Run(Box<RunArgs>),
+    /// Run the container of an OCI bundle: a directory with a root filesystem and a config.json
+    RunBundle(RunBundleArgs),
+    /// Run a command of the host in new namespaces, on the host's filesystem instead of a root
+    /// filesystem of its own
+    Sandbox(Box<SandboxArgs>),
+    /// Start the containers of a project, and wait until they exit
+    Up(UpArgs),
+    /// Stop the containers of a project and remove them
+    Down(DownArgs),
+    /// Stop the containers of a project, and start them again from its manifest
+    Restart(RestartArgs),
+    /// List containers
+    Ps(PsArgs),
+    /// Show detailed information about one or more containers
+    Inspect(InspectArgs),
+    /// Remove one or more exited containers
+    Rm(RmArgs),
+    /// Print a systemd service unit that runs a container like an existing one
+    GenerateSystemd(GenerateSystemdArgs),
+    /// Write the root filesystem of a container to a tar archive
+    Export(ExportArgs),
+    /// List the files that a running ephemeral container added, changed or deleted
+    Diff(DiffArgs),
+    /// Copy files or directories between the host and a container
+    Cp(CpArgs),
+    /// List the processes of a running container
+    Top(TopArgs),
+    /// Show what running containers use, refreshed every second
+    Stats(StatsArgs),
+    /// Change the resource limits of a running container
+    Update(UpdateArgs),
+    /// Give a container another name
+    Rename(RenameArgs),
+    /// Wait until containers exit, and print their exit codes
+    Wait(WaitArgs),
+    /// Send a signal to running containers
+    Kill(KillArgs),
+    /// Pause all processes of running containers
+    Pause(PauseArgs),
+    /// Resume the processes of paused containers
+    Unpause(PauseArgs),
+    /// Pause a running container and run a shell in its namespaces, to look at it as it is, and
+    /// resume it when the shell exits
+    Debug(DebugArgs),
+    /// Save the state of a running container to disk with CRIU, and stop it
+    Checkpoint(CheckpointArgs),
+    /// Start a checkpointed container again from where it was
+    Restore(RestoreArgs),
+    /// Create, list and remove volumes, which keep data that outlives containers
+    Volume(VolumeArgs),
+    /// Remove exited containers and unused volumes
+    Prune(PruneArgs),
+    /// Show what the host supports that containers can use
+    Info(InfoArgs),
+    /// Print the SHA-256 digest of files or directories, to check them against with `run
+    /// --rootfs-digest` or `run --verify-file`
+    Digest(DigestArgs),
+    /// Print a shell completion script to stdout
+    Completions(CompletionsArgs),
+    /// Write man pages for carton and all of its subcommands to a directory
+    #[command(hide = true)]
+    Manpages(ManpagesArgs),
+}
+
+#[derive(Args, Debug)]
+struct RunArgs {
+    #[command(flatten)]
+    network_args: NetworkArgs,
+    #[command(flatten)]
+    proc_args: ProcArgs,
+    #[command(flatten)]
+    dbus_args: DbusArgs,
+    /// Name of the container, which other commands accept instead of its ID
+    #[arg(long)]
+    name: Option<String>,
+    /// Hostname of the container [default: the first 12 characters of its ID]
+    #[arg(long)]
+    hostname: Option<String>,
+    /// NIS domain name of the container [default: the host's]
+    #[arg(long)]
+    domainname: Option<String>,
+    /// Pass only the variables of carton's environment whose names match PATTERN on to the
+    /// command, like `LANG` or `LC_*` (can be repeated) [default: all of them]
+    #[arg(long = "env-host", value_name = "PATTERN")]
+    env_host: Vec<String>,
+    /// DNS server for the container's /etc/resolv.conf (can be repeated) [default: the host's]
+    #[arg(long = "dns", value_name = "ADDRESS")]
+    dns_servers: Vec<IpAddr>,
+    /// DNS search domain for the container's /etc/resolv.conf (can be repeated)
+    #[arg(long = "dns-search", value_name = "DOMAIN")]
+    dns_search: Vec<String>,
+    /// Give the container the secret NAME in /run/secrets/NAME, from `file:PATH`, `env:VARIABLE`,
+    /// the output of `cmd:COMMAND` or the systemd credential `credential:NAME` (can be repeated)
+    #[arg(long = "secret", value_name = "NAME=SOURCE", value_parser = parse_secret)]
+    secrets: Vec<(String, SecretSource)>,
+    /// Give the container a file at PATH with CONTENTS, like a small config file (can be
+    /// repeated)
+    #[arg(long = "file", value_name = "PATH=CONTENTS", value_parser = parse_file)]
+    files: Vec<(PathBuf, String)>,
+    /// Mount the volume NAME at PATH in the container, and create the volume if it doesn't exist
+    /// (can be repeated)
+    #[arg(long = "volume", value_name = "NAME:PATH", value_parser = parse_volume)]
+    volumes: Vec<(String, PathBuf)>,
+    /// Extra entry for the container's /etc/hosts, as `name:address` (can be repeated)
+    #[arg(long = "add-host", value_name = "NAME:ADDRESS", value_parser = parse_extra_host)]
+    extra_hosts: Vec<(String, IpAddr)>,
+    /// GPUs to pass into the container: `all`, or the indices of NVIDIA GPUs like `0,1`
+    #[arg(long, value_name = "GPUS", value_parser = parse_gpus)]
+    gpus: Option<Gpus>,
+    /// Let the container run desktop programs on carton's Wayland or X11 display (of
+    /// WAYLAND_DISPLAY and DISPLAY)
+    #[arg(long)]
+    display: bool,
+    /// Architecture of the root filesystem, like `arm64` or `linux/arm64`, whose binaries are run
+    /// with QEMU's user mode emulator when the host has another architecture (needs
+    /// qemu-user-static)
+    #[arg(long, value_name = "ARCH", value_parser = Architecture::from_str)]
+    platform: Option<Architecture>,
+    /// Give the container a cgroup subtree of its own, so that systemd or a container runtime can
+    /// run inside it (needs cgroup v2)
+    #[arg(long)]
+    delegate_cgroup: bool,
+    /// Run the container in a transient scope of the user's systemd, carton-<ID>.scope, which
+    /// shows up in `systemctl --user` and is stopped when the session ends
+    #[arg(long, conflicts_with = "delegate_cgroup")]
+    systemd_scope: bool,
+    /// Run the command under a tiny init process that passes on signals and reaps zombie processes
+    #[arg(long)]
+    init: bool,
+    /// Stop the container with SIGTERM when its command has been running for this many seconds,
+    /// and exit with 124 like timeout(1)
+    #[arg(long, value_name = "SECONDS")]
+    timeout: Option<u64>,
+    /// Seconds that a container that ran out of time gets to exit after SIGTERM, before it gets
+    /// SIGKILL [default: 10]
+    #[arg(long, value_name = "SECONDS", requires = "timeout")]
+    timeout_grace_period: Option<u64>,
+    /// Keep whatever the container writes to its root filesystem in memory, so nothing persists
+    /// after it exits
+    #[arg(long)]
+    ephemeral: bool,
+    /// Size of the container's /dev/shm, on which browsers and databases keep their shared memory,
+    /// like 256m or 1g [default: 64m]
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    shm_size: Option<u64>,
+    /// Limit how much space the root filesystem can take up, like 512m or 1g (the writes of an
+    /// --ephemeral container, or else the whole root filesystem with a project quota)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    storage_limit: Option<u64>,
+    /// Only allow the container the system calls in the seccomp profile at PATH, which
+    /// --seccomp-learn writes; others fail with EPERM
+    #[arg(long, value_name = "PATH", conflicts_with = "seccomp_learn")]
+    seccomp_profile: Option<PathBuf>,
+    /// Record which system calls the container makes, and write a seccomp profile that allows
+    /// only those to PATH when it exits
+    #[arg(long, value_name = "PATH")]
+    seccomp_learn: Option<PathBuf>,
+    /// Write the system calls that the container makes, with their arguments, to the log file at
+    /// PATH (slows down the container a lot)
+    #[arg(long, value_name = "PATH", conflicts_with = "seccomp_learn")]
+    trace_syscalls: Option<PathBuf>,
+    /// Only write these system calls to the log of --trace-syscalls, like `openat` (can be
+    /// repeated)
+    #[arg(long, value_name = "NAME", requires = "trace_syscalls", value_parser = parse_syscall)]
+    trace_syscalls_filter: Vec<i64>,
+    /// Refuse to start the container unless the root filesystem has this SHA-256 digest (see
+    /// `carton digest`)
+    #[arg(long, value_name = "DIGEST")]
+    rootfs_digest: Option<Sha256Digest>,
+    /// Refuse to start the container unless the file or directory at PATH, like the image of a
+    /// FUSE root filesystem, has this SHA-256 digest (can be repeated)
+    #[arg(long, value_name = "PATH=DIGEST", value_parser = parse_verified_file)]
+    verify_file: Vec<(PathBuf, Sha256Digest)>,
+    /// Disable core dumps of processes in the container
+    #[arg(long, conflicts_with = "core_dump_dir")]
+    no_core_dumps: bool,
+    /// Write core dumps of processes in the container into this directory on the host, instead of
+    /// handing them to the host's core dump handler
+    #[arg(long, value_name = "PATH")]
+    core_dump_dir: Option<PathBuf>,
+    /// Give the container a terminal and send its master end to this Unix socket (like the
+    /// `--console-socket` of OCI runtimes)
+    #[arg(long, value_name = "PATH")]
+    console_socket: Option<PathBuf>,
+    /// FUSE daemon that mounts the root filesystem at ROOTFS_PATH, like `squashfuse -f app.sqfs`.
+    /// The mountpoint is added as its last argument.
+    #[arg(long, value_name = "COMMAND")]
+    fuse: Option<String>,
+    /// Let users other than the one running carton access the FUSE root filesystem
+    #[arg(long, requires = "fuse")]
+    fuse_allow_other: bool,
+    /// Mount the root filesystem ID-mapped, so that files owned by user CONTAINER_ID (and up) on
+    /// disk appear to be owned by HOST_ID (and up) (can be repeated)
+    #[arg(long, value_name = "CONTAINER_ID:HOST_ID:SIZE", value_parser = parse_id_mapping)]
+    rootfs_uidmap: Vec<IdMapping>,
+    /// Like --rootfs-uidmap, for groups [default: the same as --rootfs-uidmap]
+    #[arg(long, value_name = "CONTAINER_ID:HOST_ID:SIZE", value_parser = parse_id_mapping)]
+    rootfs_gidmap: Vec<IdMapping>,
+    /// Run the container in a user namespace in which root is the user running carton, and the
+    /// IDs from 1 up are the user's subordinate IDs from /etc/subuid and /etc/subgid
+    #[arg(long, conflicts_with_all = ["uidmap", "gidmap"])]
+    userns: bool,
+    /// Run the container in a user namespace in which user CONTAINER_ID (and up) is user HOST_ID
+    /// (and up) on the host (can be repeated)
+    #[arg(long, value_name = "CONTAINER_ID:HOST_ID:SIZE", value_parser = parse_id_mapping)]
+    uidmap: Vec<IdMapping>,
+    /// Like --uidmap, for groups [default: the same as --uidmap]
+    #[arg(long, value_name = "CONTAINER_ID:HOST_ID:SIZE", value_parser = parse_id_mapping)]
+    gidmap: Vec<IdMapping>,
+    /// Print what setting up the container would do, like its namespaces, mounts and devices,
+    /// without doing any of it
+    #[arg(long)]
+    dry_run: bool,
+    /// Print every privileged operation of the container's setup, like its mounts and device
+    /// nodes, with its arguments and result to stderr
+    #[arg(long, conflicts_with = "dry_run")]
+    debug_setup: bool,
+    /// Config of the OCI image that the root filesystem was unpacked from, whose Entrypoint and
+    /// Cmd make up the command line
+    #[arg(long, value_name = "PATH")]
+    image_config: Option<PathBuf>,
+    /// Run this program instead of the image's Entrypoint, without the image's Cmd
+    #[arg(long, value_name = "COMMAND")]
+    entrypoint: Option<String>,
+    /// Run this shell command, which can have pipes and redirections, with the root filesystem's
+    /// /bin/sh -c, instead of a command with arguments
+    #[arg(long, value_name = "COMMAND", conflicts_with_all = ["entrypoint", "image_config", "command"])]
+    sh: Option<String>,
+    /// The root filesystem of the container
+    rootfs_path: PathBuf,
+    /// The command in the root filesystem to run inside the container, with its arguments; after
+    /// an entrypoint these are its arguments [default: the image's Cmd]
+    command: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct SandboxArgs {
+    #[command(flatten)]
+    network_args: NetworkArgs,
+    #[command(flatten)]
+    proc_args: ProcArgs,
+    /// Name of the sandbox, which other commands accept instead of its ID
+    #[arg(long)]
+    name: Option<String>,
+    /// Hostname of the sandbox [default: the first 12 characters of its ID]
+    #[arg(long)]
+    hostname: Option<String>,
+    /// NIS domain name of the sandbox [default: the host's]
+    #[arg(long)]
+    domainname: Option<String>,
+    /// Give the sandbox the secret NAME in /run/secrets/NAME, from `file:PATH`, `env:VARIABLE`,
+    /// the output of `cmd:COMMAND` or the systemd credential `credential:NAME` (can be repeated)
+    #[arg(long = "secret", value_name = "NAME=SOURCE", value_parser = parse_secret)]
+    secrets: Vec<(String, SecretSource)>,
+    /// Give the sandbox a file at PATH with CONTENTS, like a small config file (can be repeated)
+    #[arg(long = "file", value_name = "PATH=CONTENTS", value_parser = parse_file)]
+    files: Vec<(PathBuf, String)>,
+    /// Give the sandbox a cgroup subtree of its own (needs cgroup v2)
+    #[arg(long)]
+    delegate_cgroup: bool,
+    /// Run the sandbox in a transient scope of the user's systemd, carton-<ID>.scope
+    #[arg(long, conflicts_with = "delegate_cgroup")]
+    systemd_scope: bool,
+    /// Run the command under a tiny init process that passes on signals and reaps zombie processes
+    #[arg(long)]
+    init: bool,
+    /// Stop the sandbox with SIGTERM when its command has been running for this many seconds, and
+    /// exit with 124 like timeout(1)
+    #[arg(long, value_name = "SECONDS")]
+    timeout: Option<u64>,
+    /// Seconds that a sandbox that ran out of time gets to exit after SIGTERM, before it gets
+    /// SIGKILL [default: 10]
+    #[arg(long, value_name = "SECONDS", requires = "timeout")]
+    timeout_grace_period: Option<u64>,
+    /// Run the sandbox in a user namespace in which root is the user running carton, and the IDs
+    /// from 1 up are the user's subordinate IDs from /etc/subuid and /etc/subgid
+    #[arg(long)]
+    userns: bool,
+    /// Only allow the sandbox the system calls in the seccomp profile at PATH
+    #[arg(long, value_name = "PATH", conflicts_with = "seccomp_learn")]
+    seccomp_profile: Option<PathBuf>,
+    /// Record which system calls the sandbox makes, and write a seccomp profile that allows only
+    /// those to PATH when it exits
+    #[arg(long, value_name = "PATH")]
+    seccomp_learn: Option<PathBuf>,
+    /// Write the system calls that the sandbox makes, with their arguments, to the log file at
+    /// PATH (slows down the sandbox a lot)
+    #[arg(long, value_name = "PATH", conflicts_with = "seccomp_learn")]
+    trace_syscalls: Option<PathBuf>,
+    /// Only write these system calls to the log of --trace-syscalls (can be repeated)
+    #[arg(long, value_name = "NAME", requires = "trace_syscalls", value_parser = parse_syscall)]
+    trace_syscalls_filter: Vec<i64>,
+    /// Print what setting up the sandbox would do without doing any of it
+    #[arg(long)]
+    dry_run: bool,
+    /// Print every privileged operation of the sandbox's setup with its arguments and result to
+    /// stderr
+    #[arg(long, conflicts_with = "dry_run")]
+    debug_setup: bool,
+    /// The command to run, from the host's filesystem
+    command: PathBuf,
+    /// Arguments to the command
+    arguments: Option<Vec<String>>,
+}
+
+#[derive(Args, Debug)]
+struct RunBundleArgs {
+    #[command(flatten)]
+    network_args: NetworkArgs,
+    /// Name of the container, which other commands accept instead of its ID
+    #[arg(long)]
+    name: Option<String>,
+    /// Send the master end of the container's terminal to this Unix socket, when the bundle's
+    /// process has a terminal [default: the container uses carton's terminal]
+    #[arg(long, value_name = "PATH")]
+    console_socket: Option<PathBuf>,
+    /// Pass the variables of carton's environment whose names match PATTERN on to the command,
+    /// like `LANG` or `LC_*`, on top of the bundle's (can be repeated)
+    #[arg(long = "env-host", value_name = "PATTERN")]
+    env_host: Vec<String>,
+    /// Print what setting up the container would do without doing any of it
+    #[arg(long)]
+    dry_run: bool,
+    /// Print every privileged operation of the container's setup with its arguments and result
+    /// to stderr
+    #[arg(long, conflicts_with = "dry_run")]
+    debug_setup: bool,
+    /// Directory of the bundle
+    #[arg(default_value = ".")]
+    bundle: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct ProcArgs {
+    /// Hide the processes of other users in the container's /proc: `off`, `noaccess` (their
+    /// details only), `invisible` or `ptraceable` (all but the ones one may trace)
+    #[arg(long, value_name = "VALUE")]
+    proc_hidepid: Option<HidePid>,
+    /// Leave everything but the process directories out of the container's /proc, like
+    /// /proc/sys and /proc/cpuinfo (needs Linux 5.8)
+    #[arg(long)]
+    proc_subset_pid: bool,
+}
+
+impl ProcArgs {
+    fn procfs_options(&self) -> Option<ProcfsOptions> {
+        let mut options = ProcfsOptions::new();
+        if let Some(hidepid) = self.proc_hidepid {
+            options = options.hidepid(hidepid);
+        }
+        if self.proc_subset_pid {
+            options = options.subset_pid();
+        }
+
+        (options != ProcfsOptions::new()).then_some(options)
+    }
+}
+
+#[derive(Args, Debug)]
+struct DbusArgs {
+    /// Give the container the session bus through xdg-dbus-proxy, on which it can only talk to the
+    /// bus itself and the names of the other --dbus options
+    #[arg(long)]
+    dbus: bool,
+    /// Let the container talk to NAME on the session bus, like `org.freedesktop.Notifications`
+    /// (can be repeated, implies --dbus)
+    #[arg(long, value_name = "NAME")]
+    dbus_talk: Vec<String>,
+    /// Let the container own NAME on the session bus (can be repeated, implies --dbus)
+    #[arg(long, value_name = "NAME")]
+    dbus_own: Vec<String>,
+    /// Let the container see NAME on the session bus, without talking to it (can be repeated,
+    /// implies --dbus)
+    #[arg(long, value_name = "NAME")]
+    dbus_see: Vec<String>,
+    /// Let the container call the methods of NAME that match RULE, like
+    /// `org.freedesktop.portal.Desktop=*` (can be repeated, implies --dbus)
+    #[arg(long, value_name = "NAME=RULE", value_parser = parse_dbus_call)]
+    dbus_call: Vec<(String, String)>,
+    /// Log the messages between the container and the session bus to stderr
+    #[arg(long)]
+    dbus_log: bool,
+}
+
+impl DbusArgs {
+    /// Returns the proxy of the session bus that the options ask for, if any. When carton runs
+    /// with sudo, the proxy runs as the user that ran sudo, whose session bus it is.
+    fn dbus_proxy(self) -> Result<Option<DbusProxy>> {
+        if !self.dbus
+            && self.dbus_talk.is_empty()
+            && self.dbus_own.is_empty()
+            && self.dbus_see.is_empty()
+            && self.dbus_call.is_empty()
+        {
+            if self.dbus_log {
+                bail!("--dbus-log needs --dbus");
+            }
+            return Ok(None);
+        }
+
+        let mut proxy = DbusProxy::session();
+        for name in &self.dbus_talk {
+            proxy = proxy.talk(name);
+        }
+        for name in &self.dbus_own {
+            proxy = proxy.own(name);
+        }
+        for name in &self.dbus_see {
+            proxy = proxy.see(name);
+        }
+        for (name, rule) in &self.dbus_call {
+            proxy = proxy.call(name, rule);
+        }
+        if self.dbus_log {
+            proxy = proxy.log();
+        }
+        if let (Ok(uid), Ok(gid)) = (env::var("SUDO_UID"), env::var("SUDO_GID")) {
+            let uid = uid.parse().context("SUDO_UID")?;
+            let gid = gid.parse().context("SUDO_GID")?;
+            proxy = proxy.user(uid, gid);
+        }
+
+        Ok(Some(proxy))
+    }
+}
+
+#[derive(Args, Debug, Default)]
+struct NetworkArgs {
+    /// How to connect the container to the network [default: host]
+    #[arg(long, value_enum)]
+    network: Option<NetworkMode>,
+    /// Subnet from which the container gets its address on the bridge network
+    #[arg(long)]
+    subnet: Option<Ipv4Network>,
+    /// Give the container an IPv6 address on the bridge network as well
+    #[arg(long, conflicts_with = "no_ipv6")]
+    ipv6: bool,
+    /// Subnet from which the container gets its IPv6 address on the bridge network
+    #[arg(long, value_name = "SUBNET")]
+    ipv6_subnet: Option<Ipv6Network>,
+    /// Don't give the container an IPv4 address
+    #[arg(long)]
+    no_ipv4: bool,
+    /// Don't let the container configure IPv6 addresses
+    #[arg(long)]
+    no_ipv6: bool,
+    /// Host interface on top of which the macvlan or ipvlan interface is created
+    #[arg(long, value_name = "INTERFACE")]
+    parent: Option<String>,
+    /// Static address of the container, like 192.168.1.20/24 for a macvlan or ipvlan interface or
+    /// 10.88.0.20 on the bridge network [default: DHCP or a free address on the bridge network]
+    #[arg(long = "ip", value_name = "ADDRESS[/PREFIX]")]
+    address: Option<Ipv4Network>,
+    /// Default gateway to go with the static address given with --ip
+    #[arg(long, value_name = "ADDRESS")]
+    gateway: Option<Ipv4Addr>,
+    /// MAC address of the container's interface, like 02:42:0a:58:00:14
+    #[arg(long, value_name = "ADDRESS")]
+    mac_address: Option<MacAddress>,
+    /// Additional route, like 10.1.0.0/16,192.168.1.1 (can be repeated)
+    #[arg(long = "route", value_name = "DESTINATION,GATEWAY", value_parser = parse_route)]
+    routes: Vec<Route>,
+    /// Name of the CNI network to connect the container to
+    #[arg(long, value_name = "NAME")]
+    cni_network: Option<String>,
+    /// Maximum rate at which the container receives data on the bridge network, like 10mbit
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    network_bw_ingress: Option<u64>,
+    /// Maximum rate at which the container sends data on the bridge network, like 10mbit
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    network_bw_egress: Option<u64>,
+    /// Join the network of this running ("infra") container, like the containers of a pod
+    #[arg(
+        long,
+        value_name = "CONTAINER",
+        conflicts_with_all = [
+            "network", "subnet", "ipv6", "ipv6_subnet", "no_ipv4", "no_ipv6", "parent", "address",
+            "gateway", "mac_address", "routes", "cni_network", "network_bw_ingress",
+            "network_bw_egress"
+        ]
+    )]
+    pod: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct PsArgs {
+    /// Also show containers that are no longer running
+    #[arg(short, long)]
+    all: bool,
+    /// Output format: `table`, `json` or a template like `{{.id}}\t{{.state}}`
+    #[arg(long, default_value = "table")]
+    format: OutputFormat,
+    /// Only show the containers of this project
+    #[arg(short, long, value_name = "NAME")]
+    project: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct UpArgs {
+    /// Name of the project [default: the name of the manifest's directory]
+    #[arg(short, long, value_name = "NAME")]
+    project: Option<String>,
+    /// The manifest that describes the containers of the project
+    #[arg(short = 'f', long, value_name = "PATH", default_value = DEFAULT_MANIFEST)]
+    file: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct DownArgs {
+    /// Name of the project
+    project: String,
+    /// Seconds to wait for the containers to exit after SIGTERM before they're killed
+    #[arg(short, long, value_name = "SECONDS", default_value = "10")]
+    timeout: u64,
+}
+
+#[derive(Args, Debug)]
+struct RestartArgs {
+    /// Name of the project
+    project: String,
+    /// Seconds to wait for the containers to exit after SIGTERM before they're killed
+    #[arg(short, long, value_name = "SECONDS", default_value = "10")]
+    timeout: u64,
+}
+
+#[derive(Args, Debug)]
+struct InspectArgs {
+    /// IDs, names or unique ID prefixes of the containers to inspect
+    #[arg(required = true)]
+    ids: Vec<String>,
+    /// Output format: `json` or a template like `{{.pid}}`
+    #[arg(long, default_value = "json")]
+    format: OutputFormat,
+}
+
+#[derive(Args, Debug)]
+struct RmArgs {
+    /// IDs, names or unique ID prefixes of the containers to remove
+    #[arg(required = true)]
+    ids: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct GenerateSystemdArgs {
+    /// ID, name or unique ID prefix of the container to generate a unit for
+    id: String,
+    /// When systemd should restart the container
+    #[arg(long, value_enum, default_value = "on-failure")]
+    restart: RestartPolicy,
+}
+
+#[derive(Args, Debug)]
+struct ExportArgs {
+    /// ID, name or unique ID prefix of the container to export
+    id: String,
+    /// Write the archive to this file instead of stdout
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct DiffArgs {
+    /// ID, name or unique ID prefix of the container
+    id: String,
+}
+
+#[derive(Args, Debug)]
+struct StatsArgs {
+    /// IDs, names or unique ID prefixes of the containers to show [default: all running
+    /// containers]
+    ids: Vec<String>,
+    /// Show the numbers once instead of refreshing them
+    #[arg(long)]
+    no_stream: bool,
+    /// Output format: `table`, `json` or a template like `{{.id}}\t{{.memory}}`
+    #[arg(long, default_value = "table")]
+    format: OutputFormat,
+}
+
+#[derive(Args, Debug)]
+struct UpdateArgs {
+    /// ID, name or unique ID prefix of the container, which needs a cgroup of its own
+    /// (--delegate-cgroup)
+    id: String,
+    /// The most memory that the container can use, like 512m or 1g
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    memory: Option<u64>,
+    /// How many CPUs' worth of time the container can use, like 1.5
+    #[arg(long)]
+    cpus: Option<f64>,
+}
+
+#[derive(Args, Debug)]
+struct RenameArgs {
+    /// ID, name or unique ID prefix of the container
+    id: String,
+    /// The new name, which no other container may have
+    name: String,
+}
+
+#[derive(Args, Debug)]
+struct WaitArgs {
+    /// IDs, names or unique ID prefixes of the containers
+    #[arg(required = true)]
+    ids: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct KillArgs {
+    /// IDs, names or unique ID prefixes of the containers
+    #[arg(required = true)]
+    ids: Vec<String>,
+    /// The signal to send, by name (SIGTERM or TERM) or number (15)
+    #[arg(short, long, default_value = "SIGKILL", value_parser = parse_signal)]
+    signal: Signal,
+    /// Send the signal to all processes of the container, instead of only its first process
+    #[arg(long)]
+    all: bool,
+}
+
+#[derive(Args, Debug)]
+struct PauseArgs {
+    /// IDs, names or unique ID prefixes of the containers, which need a cgroup of their own
+    /// (--delegate-cgroup)
+    #[arg(required = true)]
+    ids: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct DebugArgs {
+    /// ID, name or unique ID prefix of the container
+    id: String,
+    /// Root filesystem on the host with the shell and tools to debug with, like an unpacked
+    /// busybox image; the container's files are at /proc/1/root then [default: the container's]
+    #[arg(long, value_name = "PATH")]
+    rootfs: Option<PathBuf>,
+    /// Leave the container running, like one without a cgroup of its own (--delegate-cgroup),
+    /// which can't be paused
+    #[arg(long)]
+    no_pause: bool,
+    /// The command to run instead of /bin/sh, with its arguments
+    #[arg(last = true)]
+    command: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct CheckpointArgs {
+    /// ID, name or unique ID prefix of the container, which has to be on the host's network
+    id: String,
+    /// Directory to save the checkpoint to [default: a directory in the container's state
+    /// directory]
+    #[arg(long, value_name = "DIR")]
+    image_dir: Option<PathBuf>,
+    /// Keep the container running after the checkpoint
+    #[arg(long)]
+    leave_running: bool,
+    /// Include established TCP connections in the checkpoint
+    #[arg(long)]
+    tcp_established: bool,
+}
+
+#[derive(Args, Debug)]
+struct RestoreArgs {
+    /// ID, name or unique ID prefix of the container
+    id: String,
+    /// Directory that the checkpoint was saved to [default: a directory in the container's state
+    /// directory]
+    #[arg(long, value_name = "DIR")]
+    image_dir: Option<PathBuf>,
+    /// Restore the established TCP connections of a checkpoint that has them
+    #[arg(long)]
+    tcp_established: bool,
+}
+
+#[derive(Args, Debug)]
+struct TopArgs {
+    /// ID, name or unique ID prefix of the container
+    id: String,
+}
+
+#[derive(Args, Debug)]
+struct CpArgs {
+    /// What to copy: a path on the host, or CONTAINER:PATH for a path in a container (with the ID,
+    /// name or unique ID prefix of the container)
+    #[arg(value_parser = parse_copy_path)]
+    source: CopyPath,
+    /// Where to copy it to, like SOURCE. When it's a directory, the copy ends up in it.
+    #[arg(value_parser = parse_copy_path)]
+    target: CopyPath,
+}
+
+/// A path on the host or in a container, for `carton cp`.
+#[derive(Clone, Debug)]
+enum CopyPath {
+    Host(PathBuf),
+    Container(String, PathBuf),
+}
+
+#[derive(Args, Debug)]
+struct VolumeArgs {
+    #[command(subcommand)]
+    command: VolumeCommand,
+}
+
+#[derive(Args, Debug)]
+struct PruneArgs {
+    #[command(subcommand)]
+    command: PruneCommand,
+}
+
+#[derive(Subcommand, Debug, Clone, Copy, PartialEq, Eq)]
+enum PruneCommand {
+    /// Remove all exited containers
+    Containers,
+    /// Remove the volumes that no container uses, including exited ones
+    Volumes,
+    /// Remove all exited containers, and then the volumes that no container uses
+    All,
+}
+
+#[derive(Subcommand, Debug)]
+enum VolumeCommand {
+    /// Create a volume
+    Create(VolumeCreateArgs),
+    /// List volumes
+    Ls,
+    /// Remove one or more volumes and everything in them
+    Rm(VolumeRmArgs),
+}
+
+#[derive(Args, Debug)]
+struct VolumeCreateArgs {
+    /// Name of the volume
+    name: String,
+}
+
+#[derive(Args, Debug)]
+struct VolumeRmArgs {
+    /// Names of the volumes to remove
+    #[arg(required = true)]
+    names: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct InfoArgs {
+    /// Output format: `table`, `json` or a template like `{{.cgroup_version}}`
+    #[arg(long, default_value = "table")]
+    format: OutputFormat,
+}
+
+#[derive(Args, Debug)]
+struct DigestArgs {
+    /// Files or directories (like root filesystems) to compute the digest of
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct CompletionsArgs {
+    /// The shell to generate the completion script for
+    shell: Shell,
+}
+
+#[derive(Args, Debug)]
+struct ManpagesArgs {
+    /// Directory to write the man pages to
+    out_dir: PathBuf,
+}
+
+fn main() -> Result<ExitCode> {
+    if let Some(user) = env::var_os(PRIVSEP_USER_VAR) {
+        let user = user.to_string_lossy();
+        drop_privileges(&user)
+            .with_context(|| format!("dropping privileges to {} ({})", user, PRIVSEP_USER_VAR))?;
+    }
+    env_logger::init();
+
+    let cli = Cli::parse();
+    let config = Config::load()?;
+    let mut paths = Paths::new();
+    if let Some(state_dir) = cli.state_dir.or_else(|| config.state_dir.clone()) {
+        paths = paths.with_state_dir(state_dir);
+    }
+    if let Some(data_dir) = cli.data_dir.or_else(|| config.data_dir.clone()) {
+        paths = paths.with_data_dir(data_dir);
+    }
+    let store = StateStore::new(paths.state_dir().into());
+
+    match cli.command {
+        Command::Run(args) => run(&store, &paths, &config, *args),
+        Command::Sandbox(args) => sandbox(&store, &paths, &config, *args),
+        Command::RunBundle(args) => run_bundle(&store, &paths, &config, args),
+        Command::Up(args) => up(&store, &paths, &config, args),
+        Command::Down(args) => down(&store, &args.project, Duration::from_secs(args.timeout))
+            .map(|_| ExitCode::SUCCESS),
+        Command::Restart(args) => restart(&store, &paths, &config, args),
+        Command::Ps(args) => ps(&store, args).map(|_| ExitCode::SUCCESS),
+        Command::Inspect(args) => inspect(&store, args).map(|_| ExitCode::SUCCESS),
+        Command::Rm(args) => rm(&store, args).map(|_| ExitCode::SUCCESS),
+        Command::GenerateSystemd(args) => generate_systemd(&store, args).map(|_| ExitCode::SUCCESS),
+        Command::Export(args) => export(&store, args).map(|_| ExitCode::SUCCESS),
+        Command::Diff(args) => diff(&store, args).map(|_| ExitCode::SUCCESS),
+        Command::Cp(args) => cp(&store, args).map(|_| ExitCode::SUCCESS),
+        Command::Top(args) => top(&store, args).map(|_| ExitCode::SUCCESS),
+        Command::Stats(args) => stats(&store, args).map(|_| ExitCode::SUCCESS),
+        Command::Update(args) => update(&store, args).map(|_| ExitCode::SUCCESS),
+        Command::Rename(args) => rename(&store, args).map(|_| ExitCode::SUCCESS),
+        Command::Wait(args) => wait(&store, args).map(|_| ExitCode::SUCCESS),
+        Command::Kill(args) => kill(&store, args).map(|_| ExitCode::SUCCESS),
+        Command::Pause(args) => pause(&store, args, true).map(|_| ExitCode::SUCCESS),
+        Command::Unpause(args) => pause(&store, args, false).map(|_| ExitCode::SUCCESS),
+        Command::Debug(args) => debug(&store, args),
+        Command::Checkpoint(args) => checkpoint(&store, args).map(|_| ExitCode::SUCCESS),
+        Command::Restore(args) => restore(&store, args),
+        Command::Volume(args) => volume(&paths, args).map(|_| ExitCode::SUCCESS),
+        Command::Prune(args) => prune(&store, &paths, args).map(|_| ExitCode::SUCCESS),
+        Command::Info(args) => host_info(args).map(|_| ExitCode::SUCCESS),
+        Command::Digest(args) => digest(args).map(|_| ExitCode::SUCCESS),
+        Command::Completions(args) => completions(args).map(|_| ExitCode::SUCCESS),
+        Command::Manpages(args) => manpages(args).map(|_| ExitCode::SUCCESS),
+    }
+}
+
+/// Runs the container and returns the exit code of the command that ran inside it, so that it can
+/// be passed on as carton's own exit code.
+fn run(store: &StateStore, paths: &Paths, config: &Config, args: RunArgs) -> Result<ExitCode> {
+    let mut settings = Settings::new(config);
+    let builder = ContainerBuilder::new().state_dir(store.root().into());
+
+    // The command is looked up in the root filesystem below
+    let builder = match &args.fuse {
+        Some(fuse) => {
+            let mut words = fuse.split_whitespace().map(String::from);
+            let Some(daemon) = words.next() else {
+                bail!("--fuse needs the command of a FUSE daemon");
+            };
+            let mut fuse_rootfs =
+                FuseRootfs::new(daemon.into(), words.collect(), args.rootfs_path.clone());
+            if args.fuse_allow_other {
+                fuse_rootfs = fuse_rootfs.allow_other();
+            }
+            builder.fuse_rootfs(fuse_rootfs)
+        }
+        None => builder.rootfs(args.rootfs_path.clone()),
+    };
+    let mut builder = match args.sh {
+        Some(shell_command) => builder.shell_command(shell_command),
+        None => {
+            let image = match &args.image_config {
+                Some(path) => ImageConfig::load(path)?,
+                None => ImageConfig::default(),
+            };
+            let command_line = image.command_line(args.entrypoint, args.command)?;
+            // A FUSE root filesystem is only there once the container is set up
+            let command = match &args.fuse {
+                Some(_) => PathBuf::from(&command_line[0]),
+                None => image::find_command(&args.rootfs_path, &command_line[0], DEFAULT_PATH)?,
+            };
+            builder.command(command, Some(command_line[1..].to_vec()))
+        }
+    };
+
+    let default_mounts = settings
+        .setting("default_mounts")
+        .default(true)
+        .config(|config| config.default_mounts)
+        .value();
+    if default_mounts == Some(true) {
+        builder = builder
+            .add_default_mounts()
+            .add_default_devices()
+            .add_network_files();
+    }
+    if let Some(size) = args.shm_size {
+        if default_mounts != Some(true) {
+            bail!("--shm-size needs the default mounts, which the config file disables");
+        }
+        builder = builder.shm_size(size);
+    }
+
+    if !args.rootfs_uidmap.is_empty() || !args.rootfs_gidmap.is_empty() {
+        let gid_mappings = if args.rootfs_gidmap.is_empty() {
+            args.rootfs_uidmap.clone()
+        } else {
+            args.rootfs_gidmap
+        };
+        builder = builder.idmap_rootfs(args.rootfs_uidmap, gid_mappings);
+    }
+
+    if args.userns {
+        let (uid_mappings, gid_mappings) = IdMapping::current_user()?;
+        builder = builder.user_namespace(uid_mappings, gid_mappings);
+    } else if !args.uidmap.is_empty() || !args.gidmap.is_empty() {
+        let gid_mappings = if args.gidmap.is_empty() {
+            args.uidmap.clone()
+        } else {
+            args.gidmap
+        };
+        builder = builder.user_namespace(args.uidmap, gid_mappings);
+    }
+
+    if let Some(name) = args.name {
+        builder = builder.name(name);
+    }
+
+    if let Some(hostname) = settings.setting("hostname").flag(args.hostname).value() {
+        builder = builder.hostname(hostname);
+    }
+    if let Some(domainname) = settings.setting("domainname").flag(args.domainname).value() {
+        builder = builder.domainname(domainname);
+    }
+
+    if let Some(filter) = env_filter(&mut settings, args.env_host) {
+        builder = builder.inherit_env(filter);
+    }
+
+    let dns_servers = settings
+        .setting("dns")
+        .config(|config| config.dns.clone().filter(|dns| !dns.is_empty()))
+        .flag((!args.dns_servers.is_empty()).then_some(args.dns_servers))
+        .value();
+    if let Some(dns_servers) = dns_servers {
+        builder = builder.dns_servers(dns_servers);
+    }
+
+    let dns_search = settings
+        .setting("dns_search")
+        .config(|config| {
+            config
+                .dns_search
+                .clone()
+                .filter(|dns_search| !dns_search.is_empty())
+        })
+        .flag((!args.dns_search.is_empty()).then_some(args.dns_search))
+        .value();
+    if let Some(dns_search) = dns_search {
+        builder = builder.dns_search(dns_search);
+    }
+
+    if !args.volumes.is_empty() {
+        let volumes = Arc::new(LocalVolumes::new(paths.volume_dir()));
+        let existing = volumes.list()?;
+        for (name, target) in args.volumes {
+            if !existing.contains(&name) && !args.dry_run {
+                volumes.create(&name, &BTreeMap::new())?;
+            }
+            // The target is always relative to the container's root filesystem
+            let target = target.strip_prefix("/").unwrap_or(&target).into();
+            builder = builder.add_volume(volumes.clone(), name, target);
+        }
+    }
+
+    for (name, address) in args.extra_hosts {
+        builder = builder.add_host(name, address);
+    }
+
+    builder = with_secrets(builder, args.secrets)?;
+    builder = with_files(builder, args.files);
+
+    if let Some(gpus) = args.gpus {
+        builder = builder.add_gpu(gpus);
+    }
+    if args.display {
+        builder = builder.add_display();
+    }
+    if let Some(proxy) = args.dbus_args.dbus_proxy()? {
+        builder = builder.dbus_proxy(proxy);
+    }
+
+    if let Some(architecture) = args.platform {
+        builder = builder.emulate(architecture);
+    }
+
+    if args.delegate_cgroup {
+        builder = builder.delegate_cgroup();
+    }
+    if args.systemd_scope {
+        builder = builder.systemd_scope();
+    }
+
+    if let Some(options) = args.proc_args.procfs_options() {
+        builder = builder.procfs_options(options);
+    }
+
+    if args.init {
+        builder = builder.init(init_path(&mut settings)?);
+    }
+
+    builder = with_timeout(builder, args.timeout, args.timeout_grace_period);
+
+    if args.ephemeral {
+        builder = builder.ephemeral();
+    }
+
+    if let Some(limit) = args.storage_limit {
+        builder = builder.storage_limit(limit);
+    }
+
+    let learn = args.seccomp_learn.is_some();
+    let profile = seccomp_profile(&mut settings, args.seccomp_profile, learn);
+    let (mut builder, recorder) = with_seccomp(builder, profile, learn)?;
+    builder = with_syscall_log(builder, args.trace_syscalls, args.trace_syscalls_filter)?;
+
+    if let Some(digest) = args.rootfs_digest {
+        builder = builder.verify_rootfs(digest);
+    }
+    for (path, digest) in args.verify_file {
+        builder = builder.verify_file(path, digest);
+    }
+
+    if args.no_core_dumps {
+        builder = builder.core_dumps(CoreDumps::Disabled);
+    } else if let Some(dir) = args.core_dump_dir {
+        builder = builder.core_dumps(CoreDumps::Directory(dir));
+    }
+
+    if let Some(console_socket) = args.console_socket {
+        builder = builder.console_socket(console_socket);
+    } else if stdin_is_piped() {
+        builder = builder.stdin(io::stdin());
+    }
+
+    // With socket activation the container's process is the one that serves the connections
+    if let Some(listen_fds) = ListenFds::from_env() {
+        builder = builder.listen_fds(listen_fds);
+    }
+
+    // When carton runs as a systemd service with Type=notify, the container's process is the one
+    // that knows when the service is ready
+    if let Some(notify_socket) = env::var_os("NOTIFY_SOCKET") {
+        builder = builder.notify_socket(notify_socket.into());
+    }
+
+    let mounts = settings
+        .setting("mounts")
+        .config(|config| config.mounts.clone())
+        .value();
+    for mount in mounts.iter().flatten() {
+        // The target is always relative to the container's root filesystem
+        let target = mount.target.strip_prefix("/").unwrap_or(&mount.target);
+        builder = match (mount.optional, mount.owner) {
+            (false, None) => builder.add_mount(mount.source.clone(), target.into()),
+            (true, None) => builder.add_optional_mount(mount.source.clone(), target.into()),
+            (false, Some((uid, gid))) => {
+                builder.add_owned_mount(mount.source.clone(), target.into(), uid, gid)
+            }
+            (true, Some(_)) => bail!(
+                "the mount of {} can't be both optional and owned",
+                mount.source.display()
+            ),
+        };
+    }
+
+    let network = network_from_args(store, paths, &mut settings, &args.network_args)?;
+    builder = with_network(builder, network, &args.network_args).settings(settings.into_map());
+
+    let exit_code = run_container(store, builder, args.debug_setup, args.dry_run)?;
+    if let (Some(recorder), Some(path)) = (recorder, args.seccomp_learn) {
+        save_seccomp_profile(&recorder, &path)?;
+    }
+
+    Ok(exit_code)
+}
+
+/// Runs a sandbox and returns the exit code of its command, like `run()`.
+fn sandbox(
+    store: &StateStore,
+    paths: &Paths,
+    config: &Config,
+    args: SandboxArgs,
+) -> Result<ExitCode> {
+    let mut settings = Settings::new(config);
+    let mut builder = ContainerBuilder::new()
+        .state_dir(store.root().into())
+        .sandbox()
+        .command(args.command, args.arguments);
+
+    if let Some(name) = args.name {
+        builder = builder.name(name);
+    }
+    if let Some(hostname) = settings.setting("hostname").flag(args.hostname).value() {
+        builder = builder.hostname(hostname);
+    }
+    if let Some(domainname) = settings.setting("domainname").flag(args.domainname).value() {
+        builder = builder.domainname(domainname);
+    }
+    builder = with_secrets(builder, args.secrets)?;
+    builder = with_files(builder, args.files);
+    if args.delegate_cgroup {
+        builder = builder.delegate_cgroup();
+    }
+    if args.systemd_scope {
+        builder = builder.systemd_scope();
+    }
+    if let Some(options) = args.proc_args.procfs_options() {
+        builder = builder.procfs_options(options);
+    }
+    if args.init {
+        builder = builder.init(init_path(&mut settings)?);
+    }
+    builder = with_timeout(builder, args.timeout, args.timeout_grace_period);
+    if stdin_is_piped() {
+        builder = builder.stdin(io::stdin());
+    }
+    if args.userns {
+        let (uid_mappings, gid_mappings) = IdMapping::current_user()?;
+        builder = builder.user_namespace(uid_mappings, gid_mappings);
+    }
+    let learn = args.seccomp_learn.is_some();
+    let profile = seccomp_profile(&mut settings, args.seccomp_profile, learn);
+    let (mut builder, recorder) = with_seccomp(builder, profile, learn)?;
+    builder = with_syscall_log(builder, args.trace_syscalls, args.trace_syscalls_filter)?;
+
+    let network = network_from_args(store, paths, &mut settings, &args.network_args)?;
+    // The host's own files are right for the host's network
+    if !matches!(network, Network::Host) {
+        builder = builder.add_network_files();
+    }
+    builder = with_network(builder, network, &args.network_args).settings(settings.into_map());
+
+    let exit_code = run_container(store, builder, args.debug_setup, args.dry_run)?;
+    if let (Some(recorder), Some(path)) = (recorder, args.seccomp_learn) {
+        save_seccomp_profile(&recorder, &path)?;
+    }
+
+    Ok(exit_code)
+}
+
+/// Runs the container of an OCI bundle and returns the exit code of its command, like `run()`.
+fn run_bundle(
+    store: &StateStore,
+    paths: &Paths,
+    config: &Config,
+    args: RunBundleArgs,
+) -> Result<ExitCode> {
+    let spec = Spec::load(&args.bundle)?;
+    let mut settings = Settings::new(config);
+    let mut builder = ContainerBuilder::new().state_dir(store.root().into());
+
+    if let Some(name) = args.name {
+        builder = builder.name(name);
+    }
+    if let (true, Some(console_socket)) = (spec.terminal(), args.console_socket) {
+        builder = builder.console_socket(console_socket);
+    } else if stdin_is_piped() {
+        builder = builder.stdin(io::stdin());
+    }
+    if spec.has_network_namespace() && args.network_args.network.is_none() {
+        warn!("the bundle's container shares the host's network, unless --network says otherwise");
+    }
+
+    let mut builder = spec.configure(&args.bundle, builder, &mut settings)?;
+    if let Some(filter) = env_filter(&mut settings, args.env_host) {
+        builder = builder.inherit_env(filter);
+    }
+    if let Some(profile) = seccomp_profile(&mut settings, None, false) {
+        builder = builder.seccomp_profile(SeccompProfile::load(&profile)?);
+    }
+    let network = network_from_args(store, paths, &mut settings, &args.network_args)?;
+    builder = with_network(builder, network, &args.network_args).settings(settings.into_map());
+
+    run_container(store, builder, args.debug_setup, args.dry_run)
+}
+
+/// Returns the filter of the host's environment variables that the command gets, from `--env-host`
+/// or the config file, if it only gets some.
+fn env_filter(settings: &mut Settings, patterns: Vec<String>) -> Option<EnvFilter> {
+    let patterns = settings
+        .setting("env_host")
+        .config(|config| config.env_host.clone())
+        .flag((!patterns.is_empty()).then_some(patterns))
+        .value()?;
+
+    Some(
+        patterns
+            .into_iter()
+            .fold(EnvFilter::new(), EnvFilter::allow),
+    )
+}
+
+/// Gives the container the secrets of `--secret`, read from their sources, and the systemd
+/// credentials of the service that carton runs as, unless `--secret` gives a secret of that name.
+fn with_secrets(
+    mut builder: ContainerBuilder,
+    secrets: Vec<(String, SecretSource)>,
+) -> Result<ContainerBuilder> {
+    let credentials = secrets::credentials().context("importing systemd credentials")?;
+    let has_credentials = !credentials.is_empty();
+    for (name, value) in credentials {
+        if !secrets.iter().any(|(secret, _)| *secret == name) {
+            builder = builder.add_secret(name, value);
+        }
+    }
+    for (name, source) in secrets {
+        let value = source
+            .read()
+            .with_context(|| format!("reading secret {}", name))?;
+        builder = builder.add_secret(name, value);
+    }
+
+    // The directory is carton's, so the command is pointed at its copies instead
+    if has_credentials {
+        builder = builder.credentials_directory();
+    }
+
+    Ok(builder)
+}
+
+/// Gives the container the files of `--file`.
+fn with_files(mut builder: ContainerBuilder, files: Vec<(PathBuf, String)>) -> ContainerBuilder {
+    for (path, contents) in files {
+        let target = path.strip_prefix("/").unwrap_or(&path).into();
+        builder = builder.add_file(target, contents);
+    }
+
+    builder
+}
+
+/// Stops the container after the seconds of `--timeout`.
+fn with_timeout(
+    builder: ContainerBuilder,
+    timeout: Option<u64>,
+    grace_period: Option<u64>,
+) -> ContainerBuilder {
+    let Some(timeout) = timeout else {
+        return builder;
+    };
+    let builder = builder.timeout(Duration::from_secs(timeout));
+
+    match grace_period {
+        Some(grace_period) => builder.timeout_grace_period(Duration::from_secs(grace_period)),
+        None => builder,
+    }
+}
+
+/// Returns the seccomp profile of `--seccomp-profile`, or else the one of the config file. A
+/// container that records its system calls for `--seccomp-learn` isn't limited by the config
+/// file's profile, which would keep it from making the calls that it should learn.
+fn seccomp_profile(settings: &mut Settings, flag: Option<PathBuf>, learn: bool) -> Option<PathBuf> {
+    if learn {
+        return None;
+    }
+
+    settings
+        .setting("seccomp_profile")
+        .config(|config| config.seccomp_profile.clone())
+        .flag(flag)
+        .value()
+}
+
+/// Limits the container to the seccomp profile of `--seccomp-profile` or the config file, or
+/// records one for `--seccomp-learn`, with the recorder to save it from once the container exited.
+fn with_seccomp(
+    builder: ContainerBuilder,
+    profile: Option<PathBuf>,
+    learn: bool,
+) -> Result<(ContainerBuilder, Option<SeccompRecorder>)> {
+    if let Some(path) = profile {
+        return Ok((builder.seccomp_profile(SeccompProfile::load(&path)?), None));
+    }
+    if learn {
+        let recorder = SeccompRecorder::new();
+        return Ok((builder.seccomp_record(recorder.clone()), Some(recorder)));
+    }
+
+    Ok((builder, None))
+}
+
+/// Writes the system calls of the container to the log of `--trace-syscalls`, or only those of
+/// `--trace-syscalls-filter`.
+fn with_syscall_log(
+    builder: ContainerBuilder,
+    path: Option<PathBuf>,
+    filter: Vec<i64>,
+) -> Result<ContainerBuilder> {
+    let Some(path) = path else {
+        return Ok(builder);
+    };
+    let log = SyscallLog::create(&path)?;
+    let handler = move |request: &SyscallRequest| log.log(request);
+
+    Ok(if filter.is_empty() {
+        builder.seccomp_notify_all(handler)
+    } else {
+        builder.seccomp_notify(filter, handler)
+    })
+}
+
+/// Writes the seccomp profile that `recorder` recorded to `path`.
+fn save_seccomp_profile(recorder: &SeccompRecorder, path: &Path) -> Result<()> {
+    let profile = recorder.profile();
+    profile.save(path)?;
+    eprintln!(
+        "carton: wrote a seccomp profile with {} system calls to {}",
+        profile.syscalls().count(),
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Connects the container to `network`, with the bandwidth limit in `args` if there is one.
+fn with_network(
+    builder: ContainerBuilder,
+    network: Network,
+    args: &NetworkArgs,
+) -> ContainerBuilder {
+    let mut builder = builder.network(network);
+
+    if args.network_bw_ingress.is_some() || args.network_bw_egress.is_some() {
+        let mut limit = BandwidthLimit::new();
+        if let Some(rate) = args.network_bw_ingress {
+            limit = limit.ingress(rate);
+        }
+        if let Some(rate) = args.network_bw_egress {
+            limit = limit.egress(rate);
+        }
+        builder = builder.bandwidth_limit(limit);
+    }
+
+    builder
+}
+
+/// Exports the spans of the container's lifecycle with OTLP, when the environment says where to.
+fn with_tracer(builder: ContainerBuilder) -> Result<ContainerBuilder> {
+    Ok(match OtlpExporter::from_env()? {
+        Some(exporter) => builder.tracer(exporter),
+        None => builder,
+    })
+}
+
+/// Runs the container that `builder` builds (or prints its plan, with `dry_run`), and returns the
+/// exit code of its command, so that it can be passed on as carton's own exit code.
+fn run_container(
+    store: &StateStore,
+    mut builder: ContainerBuilder,
+    debug_setup: bool,
+    dry_run: bool,
+) -> Result<ExitCode> {
+    if debug_setup {
+        builder = builder
+            .debug_setup()
+            .diagnostics(|diagnostic: &Diagnostic| eprintln!("carton: {}", diagnostic));
+    }
+
+    if dry_run {
+        print!("{}", builder.plan().context("planning container")?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let mut container = with_tracer(builder)?
+        .build()
+        .context("building container")?;
+
+    info!("Starting container {}", container.id());
+    start(store, &mut container)?;
+
+    info!("Waiting for container to exit");
+    let exit = container.wait_for_exit()?;
+    store
+        .save(&container.info())
+        .context("saving container state")?;
+
+    if exit == ContainerExit::TimedOut {
+        eprintln!(
+            "carton: container {} ran out of time and was stopped",
+            &container.id()[..container.id().len().min(12)]
+        );
+    }
+
+    // Exit codes are limited to 0-255 by the kernel, so this does not truncate anything
+    Ok(ExitCode::from(exit.code() as u8))
+}
+
+/// Returns whether carton's stdin is a pipe, a file or a socket, rather than a terminal (or
+/// /dev/null). The container then gets a pipe that carton copies its stdin into, instead of the
+/// host's file or socket itself.
+fn stdin_is_piped() -> bool {
+    std::fs::metadata("/proc/self/fd/0")
+        .map_or(false, |metadata| !metadata.file_type().is_char_device())
+}
+
+/// Returns the init binary for `--init`, which is installed next to carton unless configured
+/// otherwise.
+fn init_path(settings: &mut Settings) -> Result<PathBuf> {
+    let carton = env::current_exe().context("finding the carton executable")?;
+    let installed = carton.with_file_name("carton-init");
+    let init_path = settings
+        .setting("init_path")
+        .default(installed.clone())
+        .config(|config| config.init_path.clone())
+        .value()
+        .unwrap_or_default();
+    if init_path == installed && !init_path.is_file() {
+        bail!(
+            "{} not found, set `init_path` in the config file",
+            init_path.display()
+        );
+    }
+
+    Ok(init_path)
+}
+
+/// Starts the containers of a project and waits until all of them exited.
+fn up(store: &StateStore, paths: &Paths, config: &Config, args: UpArgs) -> Result<ExitCode> {
+    let manifest = Manifest::load(&args.file)?;
+    let project = match args.project {
+        Some(project) => project,
+        None => manifest.default_name()?,
+    };
+
+    up_project(store, paths, config, &manifest, &project)
+}
+
+fn up_project(
+    store: &StateStore,
+    paths: &Paths,
+    config: &Config,
+    manifest: &Manifest,
+    project: &str,
+) -> Result<ExitCode> {
+    for info in store.list()? {
+        if !project::is_member(&info, project) {
+            continue;
+        }
+        if info.state == ContainerState::Running {
+            bail!(
+                "project {} is already up, its container {} is running",
+                project,
+                info.name.as_deref().unwrap_or(&info.id)
+            );
+        }
+        // The containers of the last time that the project was up make way for the new ones
+        store.remove(&info.id)?;
+    }
+
+    let mut manager = ContainerManager::new();
+    let mut healthy = HashSet::new();
+    for name in manifest.containers() {
+        let member = project::member_name(project, name);
+        let mut settings = Settings::new(config);
+        let builder = ContainerBuilder::new().state_dir(store.root().into());
+        let mut builder = manifest.configure(project, name, builder)?;
+
+        let default_mounts = settings
+            .setting("default_mounts")
+            .default(true)
+            .config(|config| config.default_mounts)
+            .value();
+        if default_mounts == Some(true) {
+            builder = builder
+                .add_default_mounts()
+                .add_default_devices()
+                .add_network_files();
+        }
+        if let Some(profile) = seccomp_profile(&mut settings, None, false) {
+            builder = builder.seccomp_profile(SeccompProfile::load(&profile)?);
+        }
+        let network = network_from_args(store, paths, &mut settings, &NetworkArgs::default())?;
+        builder = builder.network(network).settings(settings.into_map());
+
+        let started = wait_for_dependencies(&mut manager, manifest, project, name, &mut healthy)
+            .and_then(|_| start_member(store, &mut manager, builder));
+        if let Err(e) = started {
+            // A project is up with all of its containers or not at all
+            manager.stop_all(Duration::from_secs(10))?;
+            for container in manager.iter() {
+                store.save(&container.info())?;
+            }
+            return Err(e.context(format!("starting container {}", member)));
+        }
+        println!("{}", member);
+    }
+
+    loop {
+        let exited = manager.wait_any(None)?;
+        if exited.is_empty() {
+            break;
+        }
+        for (id, exit) in exited {
+            let container = manager.get(&id).expect("exited container is managed");
+            store
+                .save(&container.info())
+                .context("saving container state")?;
+            println!("{} {}", container.name().unwrap_or(&id), exit);
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Waits until the containers that the container `name` of a project depends on being healthy are,
+/// unless they were already. `healthy` has the names of those that are.
+fn wait_for_dependencies(
+    manager: &mut ContainerManager,
+    manifest: &Manifest,
+    project: &str,
+    name: &str,
+    healthy: &mut HashSet<String>,
+) -> Result<()> {
+    for (dependency, condition) in manifest.dependencies(name) {
+        if condition != Condition::Healthy || healthy.contains(dependency) {
+            continue;
+        }
+
+        let member = project::member_name(project, dependency);
+        let container = manager
+            .get_mut(&member)
+            .expect("dependencies are started first");
+        let check = manifest
+            .health_check(dependency)
+            .expect("dependencies that have to be healthy have a health check");
+        info!("Waiting for container {} to become healthy", member);
+        check
+            .wait_until_healthy(container)
+            .with_context(|| format!("waiting for container {}", member))?;
+        healthy.insert(dependency.to_string());
+    }
+
+    Ok(())
+}
+
+/// Builds and runs a container of a project.
+fn start_member(
+    store: &StateStore,
+    manager: &mut ContainerManager,
+    builder: ContainerBuilder,
+) -> Result<()> {
+    let container = manager.add(
+        with_tracer(builder)?
+            .build()
+            .context("building container")?,
+    )?;
+
+    info!("Starting container {}", container.id());
+    start(store, container)
+}
+
+/// Runs the new `container`, after claiming its name in `store` (see `StateStore::create()`), and
+/// records that it's running. The container is removed from the store again when it fails to
+/// start.
+fn start(store: &StateStore, container: &mut Container) -> Result<()> {
+    store
+        .create(&container.info())
+        .context("creating container")?;
+    if let Err(e) = container.run() {
+        let _ = store.remove(container.id());
+        return Err(e.into());
+    }
+    store
+        .save(&container.info())
+        .context("saving container state")?;
+
+    Ok(())
+}
+
+/// Stops the running containers of a project and removes all of its containers. They get SIGTERM,
+/// and SIGKILL when they haven't exited after `timeout`.
+fn down(store: &StateStore, project: &str, timeout: Duration) -> Result<Vec<ContainerInfo>> {
+    let members = store
+        .list()?
+        .into_iter()
+        .filter(|info| project::is_member(info, project))
+        .collect::<Vec<ContainerInfo>>();
+    if members.is_empty() {
+        bail!("project {} has no containers", project);
+    }
+
+    for info in &members {
+        if info.state == ContainerState::Running {
+            signal_container(info, Signal::SIGTERM, false)
+                .with_context(|| format!("stopping container {}", info.id))?;
+        }
+    }
+
+    let deadline = Instant::now() + timeout;
+    for info in &members {
+        while store.load(&info.id)?.state == ContainerState::Running {
+            if Instant::now() >= deadline {
+                info!(
+                    "Container {} did not stop within {:?}, killing it",
+                    info.id, timeout
+                );
+                signal_container(info, Signal::SIGKILL, false)
+                    .with_context(|| format!("killing container {}", info.id))?;
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        // The carton process that started the container saves its exit, which mustn't bring
+        // back the state of a container that was removed already
+        store.wait(&info.id)?;
+    }
+
+    for info in &members {
+        store.remove(&info.id)?;
+        println!("{}", info.name.as_deref().unwrap_or(&info.id));
+    }
+
+    Ok(members)
+}
+
+/// Takes a project down, and brings it up again from the manifest that it was started from.
+fn restart(
+    store: &StateStore,
+    paths: &Paths,
+    config: &Config,
+    args: RestartArgs,
+) -> Result<ExitCode> {
+    let members = down(store, &args.project, Duration::from_secs(args.timeout))?;
+    let manifest = members
+        .into_iter()
+        .find_map(|info| info.project)
+        .map(|project| project.manifest)
+        .expect("members of a project have a project");
+    let manifest = Manifest::load(&manifest)?;
+
+    up_project(store, paths, config, &manifest, &args.project)
+}
+
+fn ps(store: &StateStore, args: PsArgs) -> Result<()> {
+    let containers = store
+        .list()?
+        .into_iter()
+        .filter(|info| args.all || info.state == ContainerState::Running)
+        .filter(|info| {
+            args.project
+                .as_ref()
+                .map_or(true, |project| project::is_member(info, project))
+        })
+        .collect::<Vec<ContainerInfo>>();
+
+    match args.format {
+        OutputFormat::Table => {
+            println!(
+                "{:<12}  {:<16}  {:<8}  {:<12}  {:<24}  ROOTFS",
+                "CONTAINER ID", "NAME", "PID", "STATE", "COMMAND"
+            );
+            for info in containers {
+                println!(
+                    "{:<12}  {:<16}  {:<8}  {:<12}  {:<24}  {}",
+                    &info.id[..info.id.len().min(12)],
+                    info.name.as_deref().unwrap_or_default(),
+                    info.pid.map(|pid| pid.to_string()).unwrap_or_default(),
+                    format_state(&info),
+                    format_command(&info),
+                    format_rootfs(&info)
+                );
+            }
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&containers)?),
+        OutputFormat::Template(template) => {
+            for info in containers {
+                println!(
+                    "{}",
+                    render_template(&template, &serde_json::to_value(info)?)?
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn inspect(store: &StateStore, args: InspectArgs) -> Result<()> {
+    let containers = args
+        .ids
+        .iter()
+        .map(|id| store.resolve(id))
+        .collect::<Result<Vec<ContainerInfo>, _>>()?;
+
+    match args.format {
+        OutputFormat::Table => bail!("the table format is not supported by inspect"),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&containers)?),
+        OutputFormat::Template(template) => {
+            for info in containers {
+                println!(
+                    "{}",
+                    render_template(&template, &serde_json::to_value(info)?)?
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn rm(store: &StateStore, args: RmArgs) -> Result<()> {
+    for id in args.ids {
+        let info = store.resolve(&id)?;
+        if info.state == ContainerState::Running {
+            bail!("container {} is still running", id);
+        }
+
+        store.remove(&info.id)?;
+        println!("{}", id);
+    }
+
+    Ok(())
+}
+
+fn generate_systemd(store: &StateStore, args: GenerateSystemdArgs) -> Result<()> {
+    let info = store.resolve(&args.id)?;
+    let carton = env::current_exe().context("finding the carton executable")?;
+    // A relative state directory would be relative to wherever systemd starts carton
+    let state_dir = std::fs::canonicalize(store.root()).unwrap_or_else(|_| store.root().into());
+
+    print!(
+        "{}",
+        systemd::unit_file(&info, &carton, &state_dir, args.restart)
+    );
+
+    Ok(())
+}
+
+/// Writes the root filesystem of a container to a tar archive with `tar`. The root filesystem of a
+/// running container is read through its process, so that the archive has the changes of an
+/// ephemeral container too.
+fn export(store: &StateStore, args: ExportArgs) -> Result<()> {
+    let info = store.resolve(&args.id)?;
+    if info.sandbox {
+        bail!(
+            "container {} is a sandbox, which has no root filesystem of its own",
+            args.id
+        );
+    }
+
+    let rootfs = match (info.state, info.pid) {
+        (ContainerState::Running, Some(pid)) => PathBuf::from(format!("/proc/{}/root", pid)),
+        _ => info.rootfs,
+    };
+    // Only the root filesystem itself, without the container's /proc, /dev, etc. The owners are
+    // the container's, whose names the host may not know.
+    let status = std::process::Command::new("tar")
+        .args([
+            "--create",
+            "--numeric-owner",
+            "--xattrs",
+            "--one-file-system",
+        ])
+        .arg("--directory")
+        .arg(&rootfs)
+        .arg("--file")
+        .arg(args.output.as_deref().unwrap_or_else(|| Path::new("-")))
+        .arg(".")
+        .status()
+        .context("running tar")?;
+    // tar exits with 1 when files changed while it read them, which running containers do
+    if !matches!(status.code(), Some(0 | 1)) {
+        bail!("tar failed to archive {} ({})", rootfs.display(), status);
+    }
+
+    Ok(())
+}
+
+/// Prints the changes of an ephemeral container to its root filesystem, like `C /etc` or
+/// `A /etc/motd`.
+fn diff(store: &StateStore, args: DiffArgs) -> Result<()> {
+    let info = store.resolve(&args.id)?;
+    if !info.ephemeral {
+        bail!(
+            "container {} writes to its root filesystem itself, only the changes of --ephemeral \
+             containers are kept apart",
+            args.id
+        );
+    }
+    let (ContainerState::Running, Some(pid)) = (info.state, info.pid) else {
+        bail!(
+            "container {} isn't running, and its changes were gone when it exited",
+            args.id
+        );
+    };
+
+    let merged = PathBuf::from(format!("/proc/{}/root", pid));
+    let mount_points = diff::mount_points(pid)?;
+    for (change, path) in diff::changes(&info.rootfs, &merged, &mount_points)? {
+        println!("{} {}", change, path.display());
+    }
+
+    Ok(())
+}
+
+/// Shows what running containers use, until interrupted unless `--no-stream` is given. The CPU
+/// usage is measured over the second between two refreshes, so the first numbers appear after a
+/// second.
+fn stats(store: &StateStore, args: StatsArgs) -> Result<()> {
+    for id in &args.ids {
+        if store.resolve(id)?.state != ContainerState::Running {
+            bail!("container {} isn't running", id);
+        }
+    }
+
+    let mut previous_cpu_times = HashMap::new();
+    let mut first = true;
+    loop {
+        let containers = if args.ids.is_empty() {
+            store
+                .list()?
+                .into_iter()
+                .filter(|info| info.state == ContainerState::Running)
+                .collect()
+        } else {
+            args.ids
+                .iter()
+                .map(|id| store.resolve(id))
+                .collect::<Result<Vec<ContainerInfo>, _>>()?
+        };
+
+        let mut rows = Vec::new();
+        for info in containers {
+            // Containers that exited in the meantime are left out
+            let Ok(stats) = ContainerStats::collect(&info) else {
+                continue;
+            };
+            let now = Instant::now();
+            let cpu_percent = previous_cpu_times
+                .insert(info.id.clone(), (now, stats.cpu_time))
+                .map(|(then, cpu_time): (Instant, Duration)| {
+                    let used = stats.cpu_time.saturating_sub(cpu_time).as_secs_f64();
+                    100.0 * used / now.duration_since(then).as_secs_f64()
+                });
+            rows.push(StatsRow::new(info, stats, cpu_percent));
+        }
+
+        if !first {
+            match &args.format {
+                OutputFormat::Table => {
+                    if !args.no_stream {
+                        // Clears the terminal, like watch does
+                        print!("\x1b[2J\x1b[H");
+                    }
+                    println!(
+                        "{:<12}  {:<16}  {:>7}  {:<21}  {:>5}  NET I/O",
+                        "CONTAINER ID", "NAME", "CPU %", "MEM USAGE / LIMIT", "PIDS"
+                    );
+                    for row in &rows {
+                        println!(
+                            "{:<12}  {:<16}  {:>7}  {:<21}  {:>5}  {}",
+                            &row.id[..row.id.len().min(12)],
+                            row.name.as_deref().unwrap_or_default(),
+                            row.cpu_percent
+                                .map_or("-".into(), |percent| format!("{:.2}%", percent)),
+                            format!(
+                                "{} / {}",
+                                format_bytes(row.memory),
+                                row.memory_limit.map_or("-".into(), format_bytes)
+                            ),
+                            row.pids,
+                            match (row.network_received, row.network_sent) {
+                                (Some(received), Some(sent)) =>
+                                    format!("{} / {}", format_bytes(received), format_bytes(sent)),
+                                _ => "-".into(),
+                            },
+                        );
+                    }
+                }
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&rows)?),
+                OutputFormat::Template(template) => {
+                    for row in &rows {
+                        println!(
+                            "{}",
+                            render_template(template, &serde_json::to_value(row)?)?
+                        );
+                    }
+                }
+            }
+            if args.no_stream {
+                return Ok(());
+            }
+        }
+        first = false;
+
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// One container in the output of `stats`, which is also what the JSON and template formats see.
+#[derive(Serialize, Debug)]
+struct StatsRow {
+    id: String,
+    name: Option<String>,
+    /// Unknown until the second snapshot of the container.
+    cpu_percent: Option<f64>,
+    memory: u64,
+    memory_limit: Option<u64>,
+    pids: u64,
+    network_received: Option<u64>,
+    network_sent: Option<u64>,
+}
+
+impl StatsRow {
+    fn new(info: ContainerInfo, stats: ContainerStats, cpu_percent: Option<f64>) -> Self {
+        StatsRow {
+            id: info.id,
+            name: info.name,
+            cpu_percent,
+            memory: stats.memory,
+            memory_limit: stats.memory_limit,
+            pids: stats.pids,
+            network_received: stats.network.map(|(received, _)| received),
+            network_sent: stats.network.map(|(_, sent)| sent),
+        }
+    }
+}
+
+fn update(store: &StateStore, args: UpdateArgs) -> Result<()> {
+    if args.memory.is_none() && args.cpus.is_none() {
+        bail!("nothing to update, expected --memory or --cpus");
+    }
+
+    let info = store.resolve(&args.id)?;
+    let limits = ResourceLimits {
+        memory: args.memory,
+        cpus: args.cpus,
+    };
+    update_limits(&info, &limits)
+        .with_context(|| format!("updating the limits of container {}", args.id))?;
+
+    Ok(())
+}
+
+fn rename(store: &StateStore, args: RenameArgs) -> Result<()> {
+    store
+        .rename(&args.id, &args.name)
+        .with_context(|| format!("renaming container {}", args.id))?;
+
+    Ok(())
+}
+
+/// Waits for the containers one after the other, and prints the exit code of each as soon as it
+/// has exited.
+fn wait(store: &StateStore, args: WaitArgs) -> Result<()> {
+    // Refer to the containers by their IDs, which don't change while waiting like names can
+    let containers = args
+        .ids
+        .iter()
+        .map(|id| Ok((id, store.resolve(id)?.id)))
+        .collect::<Result<Vec<(&String, String)>>>()?;
+
+    for (reference, id) in containers {
+        let info = store
+            .wait(&id)
+            .with_context(|| format!("waiting for container {}", reference))?;
+        let Some(exit) = info.exit else {
+            bail!(
+                "container {} is {}, but its exit code is unknown",
+                reference,
+                info.state
+            );
+        };
+        println!("{}", exit.code());
+    }
+
+    Ok(())
+}
+
+fn kill(store: &StateStore, args: KillArgs) -> Result<()> {
+    for id in args.ids {
+        let info = store.resolve(&id)?;
+        signal_container(&info, args.signal, args.all)
+            .with_context(|| format!("sending {} to container {}", args.signal, id))?;
+        println!("{}", id);
+    }
+
+    Ok(())
+}
+
+/// Pauses the containers, or with `paused` false resumes them.
+fn pause(store: &StateStore, args: PauseArgs, paused: bool) -> Result<()> {
+    for id in args.ids {
+        let info = store.resolve(&id)?;
+        if info.paused == paused {
+            bail!(
+                "container {} is {}",
+                id,
+                if paused {
+                    "paused already"
+                } else {
+                    "not paused"
+                }
+            );
+        }
+
+        if paused {
+            pause_container(&info).with_context(|| format!("pausing container {}", id))?;
+        } else {
+            resume_container(&info).with_context(|| format!("resuming container {}", id))?;
+        }
+        println!("{}", id);
+    }
+
+    Ok(())
+}
+
+/// Debugs a running container in place, and returns the exit code of the debug command.
+fn debug(store: &StateStore, args: DebugArgs) -> Result<ExitCode> {
+    let info = store.resolve(&args.id)?;
+    let mut options = DebugOptions::new();
+    if !args.command.is_empty() {
+        options = options.command(args.command);
+    }
+    if let Some(rootfs) = args.rootfs {
+        options = options.rootfs(rootfs);
+    }
+    if args.no_pause {
+        options = options.no_pause();
+    }
+
+    let exit_code = debug_container(&info, &options)
+        .with_context(|| format!("debugging container {}", args.id))?;
+
+    Ok(ExitCode::from(exit_code as u8))
+}
+
+/// Checkpoints a container that another carton process started. That process sees the container
+/// exit, after which its state becomes checkpointed.
+fn checkpoint(store: &StateStore, args: CheckpointArgs) -> Result<()> {
+    let info = store.resolve(&args.id)?;
+    let images_dir = args
+        .image_dir
+        .unwrap_or_else(|| default_checkpoint_dir(store, &info));
+    let mut options = CheckpointOptions::new();
+    if args.leave_running {
+        options = options.leave_running();
+    }
+    if args.tcp_established {
+        options = options.tcp_established();
+    }
+
+    checkpoint_container(&info, &images_dir, &options)
+        .with_context(|| format!("checkpointing container {}", args.id))?;
+    if !args.leave_running {
+        let info = store.wait(&info.id)?;
+        store.save(&ContainerInfo {
+            state: ContainerState::Checkpointed,
+            exit: None,
+            ..info
+        })?;
+    }
+    println!("{}", images_dir.display());
+
+    Ok(())
+}
+
+/// Restores a checkpointed container with the configuration it had, in the foreground like `carton
+/// run`, and returns its exit code.
+fn restore(store: &StateStore, args: RestoreArgs) -> Result<ExitCode> {
+    let info = store.resolve(&args.id)?;
+    if info.state != ContainerState::Checkpointed {
+        bail!("container {} isn't checkpointed", args.id);
+    }
+    let images_dir = args
+        .image_dir
+        .unwrap_or_else(|| default_checkpoint_dir(store, &info));
+    let mut options = CheckpointOptions::new();
+    if args.tcp_established {
+        options = options.tcp_established();
+    }
+
+    let mut builder = ContainerBuilder::new()
+        .id(info.id.clone())
+        .state_dir(store.root().into())
+        .rootfs(info.rootfs.clone())
+        .command(info.command.clone(), Some(info.arguments.clone()))
+        .settings(info.settings.clone());
+    if let Some(name) = info.name.clone() {
+        builder = builder.name(name);
+    }
+    if let Some(hostname) = info.hostname.clone() {
+        builder = builder.hostname(hostname);
+    }
+    if let Some(domainname) = info.domainname.clone() {
+        builder = builder.domainname(domainname);
+    }
+    if let Some(project) = info.project.clone() {
+        builder = builder.project(project.name, project.manifest);
+    }
+    for (target, source) in &info.bind_mounts {
+        builder = builder.add_mount(
+            source.clone(),
+            target.strip_prefix("/").unwrap_or(target).into(),
+        );
+    }
+    let mut container = builder.build().context("building container")?;
+
+    info!("Restoring container {}", container.id());
+    container
+        .restore_with(&images_dir, &options)
+        .with_context(|| format!("restoring container {}", args.id))?;
+    store
+        .save(&container.info())
+        .context("saving container state")?;
+
+    info!("Waiting for container to exit");
+    let exit = container.wait_for_exit()?;
+    store
+        .save(&container.info())
+        .context("saving container state")?;
+
+    Ok(ExitCode::from(exit.code() as u8))
+}
+
+/// Returns where a checkpoint of the container of `info` goes by default, which is removed with
+/// the container.
+fn default_checkpoint_dir(store: &StateStore, info: &ContainerInfo) -> PathBuf {
+    store.root().join(&info.id).join("checkpoint")
+}
+
+fn top(store: &StateStore, args: TopArgs) -> Result<()> {
+    let info = store.resolve(&args.id)?;
+    let (ContainerState::Running, Some(pid)) = (info.state, info.pid) else {
+        bail!("container {} isn't running", args.id);
+    };
+
+    println!(
+        "{:<8}  {:<8}  {:<12}  {:<8}  {:>10}  COMMAND",
+        "PID", "HOST PID", "USER", "TIME", "RSS (KiB)"
+    );
+    for process in top::processes(pid)? {
+        println!(
+            "{:<8}  {:<8}  {:<12}  {:<8}  {:>10}  {}",
+            process.pid,
+            process.host_pid,
+            process.user,
+            top::format_cpu_time(process.cpu_time),
+            process.rss_kib,
+            process.command
+        );
+    }
+
+    Ok(())
+}
+
+fn cp(store: &StateStore, args: CpArgs) -> Result<()> {
+    match (args.source, args.target) {
+        (CopyPath::Host(source), CopyPath::Container(id, target)) => {
+            copy_to_container(&store.resolve(&id)?, &source, &target)
+                .with_context(|| format!("copying {} to {}", source.display(), id))?;
+        }
+        (CopyPath::Container(id, source), CopyPath::Host(target)) => {
+            copy_from_container(&store.resolve(&id)?, &source, &target)
+                .with_context(|| format!("copying {} from {}", source.display(), id))?;
+        }
+        (CopyPath::Host(_), CopyPath::Host(_)) => {
+            bail!("one of the paths has to be in a container, like CONTAINER:PATH")
+        }
+        (CopyPath::Container(..), CopyPath::Container(..)) => {
+            bail!("copying from one container to another isn't supported, copy through the host")
+        }
+    }
+
+    Ok(())
+}
+
+fn volume(paths: &Paths, args: VolumeArgs) -> Result<()> {
+    let volumes = LocalVolumes::new(paths.volume_dir());
+
+    match args.command {
+        VolumeCommand::Create(args) => {
+            volumes.create(&args.name, &BTreeMap::new())?;
+            println!("{}", args.name);
+        }
+        VolumeCommand::Ls => {
+            for name in volumes.list()? {
+                println!("{}", name);
+            }
+        }
+        VolumeCommand::Rm(args) => {
+            for name in args.names {
+                volumes.remove(&name)?;
+                println!("{}", name);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes exited containers and/or unused volumes, printing what it removes and how much space
+/// that frees up.
+fn prune(store: &StateStore, paths: &Paths, args: PruneArgs) -> Result<()> {
+    let mut reclaimed = 0;
+
+    if matches!(args.command, PruneCommand::Containers | PruneCommand::All) {
+        for info in store.list()? {
+            if info.state != ContainerState::Exited {
+                continue;
+            }
+            reclaimed += disk_usage(&store.root().join(&info.id));
+            store.remove(&info.id)?;
+            println!("{}", info.id);
+        }
+    }
+
+    if matches!(args.command, PruneCommand::Volumes | PruneCommand::All) {
+        // Containers that start in the meantime wait for the lock before they're recorded, so
+        // that none of them starts using a volume that's being removed
+        let _lock = store.lock()?;
+        let mut used = HashSet::new();
+        for (id, result) in store.load_all()? {
+            let info = result.with_context(|| {
+                format!(
+                    "reading the state of container {}, which may use volumes, no volumes were \
+                     removed",
+                    id
+                )
+            })?;
+            used.extend(info.volumes);
+        }
+        let volumes = LocalVolumes::new(paths.volume_dir());
+        for name in volumes.list()? {
+            if used.contains(&name) {
+                continue;
+            }
+            reclaimed += disk_usage(&paths.volume_dir().join(&name));
+            volumes.remove(&name)?;
+            println!("{}", name);
+        }
+    }
+
+    println!("Total reclaimed space: {}", format_bytes(reclaimed));
+
+    Ok(())
+}
+
+fn host_info(args: InfoArgs) -> Result<()> {
+    let features = HostFeatures::detect();
+
+    match args.format {
+        OutputFormat::Table => {
+            let yes_no = |supported: bool| if supported { "yes" } else { "no" };
+            let cgroup_version = match features.cgroup_version {
+                CgroupVersion::V1 => "v1",
+                CgroupVersion::V2 => "v2",
+                CgroupVersion::Hybrid => "hybrid (v1 and v2)",
+                CgroupVersion::None => "none",
+            };
+            let selinux = match features.selinux {
+                Some(SelinuxMode::Enforcing) => "enforcing",
+                Some(SelinuxMode::Permissive) => "permissive",
+                None => "no",
+            };
+
+            for (feature, value) in [
+                ("Kernel", features.kernel.clone()),
+                ("cgroups", cgroup_version.into()),
+                (
+                    "cgroup v2 controllers",
+                    features.cgroup_controllers.join(" "),
+                ),
+                ("User namespaces", yes_no(features.user_namespaces).into()),
+                (
+                    "Unprivileged user namespaces",
+                    yes_no(features.user_namespaces && features.unprivileged_user_namespaces)
+                        .into(),
+                ),
+                ("seccomp", yes_no(features.seccomp).into()),
+                (
+                    "seccomp user notification",
+                    yes_no(features.seccomp_user_notification).into(),
+                ),
+                ("AppArmor", yes_no(features.apparmor).into()),
+                ("SELinux", selinux.into()),
+                ("overlayfs", yes_no(features.overlayfs).into()),
+                ("ID-mapped mounts", yes_no(features.idmapped_mounts).into()),
+            ] {
+                println!("{:<30}  {}", format!("{}:", feature), value);
+            }
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&features)?),
+        OutputFormat::Template(template) => println!(
+            "{}",
+            render_template(&template, &serde_json::to_value(features)?)?
+        ),
+    }
+
+    Ok(())
+}
+
+fn digest(args: DigestArgs) -> Result<()> {
+    for path in args.paths {
+        let digest = Sha256Digest::of_path(&path)
+            .with_context(|| format!("computing the digest of {}", path.display()))?;
+        println!("{}  {}", digest, path.display());
+    }
+
+    Ok(())
+}
+
+fn completions(args: CompletionsArgs) -> Result<()> {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(args.shell, &mut command, name, &mut io::stdout());
+
+    Ok(())
+}
+
+fn manpages(args: ManpagesArgs) -> Result<()> {
+    std::fs::create_dir_all(&args.out_dir)
+        .with_context(|| format!("creating {}", args.out_dir.display()))?;
+
+    let command = Cli::command();
+    write_manpage(&args.out_dir, command.get_name(), command.clone())?;
+
+    for subcommand in command.get_subcommands().filter(|sub| !sub.is_hide_set()) {
+        let name = format!("{}-{}", command.get_name(), subcommand.get_name());
+        let subcommand = subcommand.clone().display_name(&name).bin_name(format!(
+            "{} {}",
+            command.get_name(),
+            subcommand.get_name()
+        ));
+        write_manpage(&args.out_dir, &name, subcommand)?;
+    }
+
+    Ok(())
+}
+
+fn write_manpage(out_dir: &Path, name: &str, command: clap::Command) -> Result<()> {
+    let path = out_dir.join(format!("{}.1", name));
+    let mut file = File::create(&path).with_context(|| format!("creating {}", path.display()))?;
+    clap_mangen::Man::new(command)
+        .title(name.to_uppercase())
+        .render(&mut file)?;
+
+    Ok(())
+}
+
+fn network_from_args(
+    store: &StateStore,
+    paths: &Paths,
+    settings: &mut Settings,
+    args: &NetworkArgs,
+) -> Result<Network> {
+    if let Some(pod) = &args.pod {
+        let infra = store.resolve(pod)?;
+        if infra.state != ContainerState::Running {
+            bail!("container {} is not running", pod);
+        }
+
+        return Ok(Network::Shared(SharedNetwork::from_info(&infra)));
+    }
+
+    let mode = settings
+        .setting("network")
+        .default(NetworkMode::Host)
+        .config(|config| config.network)
+        .flag(args.network)
+        .value()
+        .unwrap_or(NetworkMode::Host);
+
+    if (args.subnet.is_some() || args.ipv6 || args.ipv6_subnet.is_some())
+        && mode != NetworkMode::Bridge
+    {
+        bail!("--subnet, --ipv6 and --ipv6-subnet can only be used with --network bridge");
+    }
+    if (args.no_ipv4 || args.no_ipv6) && matches!(mode, NetworkMode::Host | NetworkMode::Cni) {
+        bail!("--no-ipv4 and --no-ipv6 can only be used with --network bridge, macvlan or ipvlan");
+    }
+    if (args.parent.is_some() || args.gateway.is_some())
+        && !matches!(mode, NetworkMode::Macvlan | NetworkMode::Ipvlan)
+    {
+        bail!("--parent and --gateway can only be used with --network macvlan or ipvlan");
+    }
+    if (args.address.is_some() || args.mac_address.is_some() || !args.routes.is_empty())
+        && matches!(mode, NetworkMode::Host | NetworkMode::Cni)
+    {
+        bail!("--ip, --mac-address and --route can only be used with --network bridge, macvlan or ipvlan");
+    }
+    if args.cni_network.is_some() && mode != NetworkMode::Cni {
+        bail!("--cni-network can only be used with --network cni");
+    }
+
+    let network = match mode {
+        NetworkMode::Host => Network::Host,
+        NetworkMode::Bridge => {
+            let mut bridge = BridgeNetwork::new().lease_dir(paths.lease_dir());
+            let name = settings
+                .setting("bridge.name")
+                .config(|config| config.bridge.as_ref()?.name.clone())
+                .value();
+            if let Some(name) = name {
+                bridge = bridge.name(name);
+            }
+            let subnet = settings
+                .setting("bridge.subnet")
+                .config(|config| config.bridge.as_ref()?.subnet)
+                .flag(args.subnet)
+                .value();
+            if let Some(subnet) = subnet {
+                bridge = bridge.subnet(subnet);
+            }
+            let ipv6_subnet = settings
+                .setting("bridge.ipv6_subnet")
+                .config(|config| config.bridge.as_ref()?.ipv6_subnet)
+                .flag(args.ipv6_subnet)
+                .value();
+            if let Some(ipv6_subnet) = ipv6_subnet {
+                bridge = bridge.ipv6_subnet(ipv6_subnet);
+            }
+            let ipv4 = settings
+                .setting("bridge.ipv4")
+                .default(true)
+                .config(|config| config.bridge.as_ref()?.ipv4)
+                .flag(args.no_ipv4.then_some(false))
+                .value();
+            if ipv4 == Some(false) {
+                bridge = bridge.ipv4(false);
+            }
+            let ipv6 = settings
+                .setting("bridge.ipv6")
+                .default(false)
+                .config(|config| config.bridge.as_ref()?.ipv6)
+                .flag(match (args.ipv6, args.no_ipv6) {
+                    (_, true) => Some(false),
+                    (true, false) => Some(true),
+                    (false, false) => None,
+                })
+                .value();
+            if ipv6 == Some(true) {
+                bridge = bridge.ipv6(true);
+            }
+            // The prefix comes from the bridge's subnet
+            if let Some(address) = args.address {
+                bridge = bridge.address(address.ip());
+            }
+            if let Some(mac_address) = args.mac_address {
+                bridge = bridge.mac_address(mac_address);
+            }
+            for route in &args.routes {
+                bridge = bridge.add_route(*route);
+            }
+
+            Network::Bridge(bridge)
+        }
+        NetworkMode::Macvlan | NetworkMode::Ipvlan => {
+            let kind = if mode == NetworkMode::Macvlan {
+                MacvlanKind::Macvlan
+            } else {
+                MacvlanKind::Ipvlan
+            };
+            let parent = settings
+                .setting("parent")
+                .config(|config| config.parent.clone())
+                .flag(args.parent.clone())
+                .value();
+            let Some(parent) = parent else {
+                bail!("the macvlan and ipvlan network modes need a parent interface (--parent)");
+            };
+
+            let mut macvlan = MacvlanNetwork::new(parent, kind);
+            if let Some(address) = args.address {
+                macvlan = macvlan.address(address);
+            }
+            if let Some(gateway) = args.gateway {
+                macvlan = macvlan.gateway(gateway);
+            }
+            if args.no_ipv4 {
+                macvlan = macvlan.ipv4(false);
+            }
+            if args.no_ipv6 {
+                macvlan = macvlan.ipv6(false);
+            }
+            if let Some(mac_address) = args.mac_address {
+                macvlan = macvlan.mac_address(mac_address);
+            }
+            for route in &args.routes {
+                macvlan = macvlan.add_route(*route);
+            }
+
+            Network::Macvlan(macvlan)
+        }
+        NetworkMode::Cni => {
+            let name = settings
+                .setting("cni.network")
+                .config(|config| config.cni.as_ref()?.network.clone())
+                .flag(args.cni_network.clone())
+                .value();
+            let Some(name) = name else {
+                bail!("the cni network mode needs the name of a network (--cni-network)");
+            };
+
+            let mut cni = CniNetwork::new(name);
+            let conf_dir = settings
+                .setting("cni.conf_dir")
+                .config(|config| config.cni.as_ref()?.conf_dir.clone())
+                .value();
+            if let Some(conf_dir) = conf_dir {
+                cni = cni.conf_dir(conf_dir);
+            }
+            let plugin_dirs = settings
+                .setting("cni.plugin_dirs")
+                .config(|config| config.cni.as_ref()?.plugin_dirs.clone())
+                .value();
+            if let Some(plugin_dirs) = plugin_dirs {
+                cni = cni.plugin_dirs(plugin_dirs);
+            }
+
+            Network::Cni(cni)
+        }
+    };
+
+    Ok(network)
+}
+
+/// Parses the `name:address` value of `--add-host`. IPv6 addresses contain colons as well, so only
+/// the first colon separates the name from the address.
+fn parse_extra_host(value: &str) -> Result<(String, IpAddr), String> {
+    let (name, address) = value
+        .split_once(':')
+        .ok_or_else(|| format!("expected `name:address`, got `{}`", value))?;
+    let address = address
+        .parse()
+        .map_err(|e| format!("invalid address `{}`: {}", address, e))?;
+
+    Ok((name.into(), address))
+}
+
+/// Parses the `name=source` value of `--secret`.
+fn parse_secret(value: &str) -> Result<(String, SecretSource), String> {
+    match value.split_once('=') {
+        Some((name, source)) if !name.is_empty() => Ok((name.into(), SecretSource::parse(source)?)),
+        _ => Err(format!("expected `name=source`, got `{}`", value)),
+    }
+}
+
+/// Parses the `path=contents` value of `--file`. The contents can contain `=`, the path can't.
+fn parse_file(value: &str) -> Result<(PathBuf, String), String> {
+    match value.split_once('=') {
+        Some((path, contents)) if path.starts_with('/') => Ok((path.into(), contents.into())),
+        _ => Err(format!(
+            "expected `path=contents` with an absolute path, got `{}`",
+            value
+        )),
+    }
+}
+
+/// Parses the `name=rule` value of `--dbus-call`.
+fn parse_dbus_call(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, rule)) if !name.is_empty() && !rule.is_empty() => {
+            Ok((name.into(), rule.into()))
+        }
+        _ => Err(format!("expected `name=rule`, got `{}`", value)),
+    }
+}
+
+/// Parses the name of a system call, like `openat`, into its number on this architecture.
+fn parse_syscall(name: &str) -> Result<i64, String> {
+    syscall_number(name).ok_or_else(|| format!("unknown system call `{}`", name))
+}
+
+/// Parses the `name:path` value of `--volume`.
+fn parse_volume(value: &str) -> Result<(String, PathBuf), String> {
+    match value.split_once(':') {
+        Some((name, path)) if !name.is_empty() && path.starts_with('/') => {
+            Ok((name.into(), path.into()))
+        }
+        _ => Err(format!(
+            "expected `name:path` with an absolute path, got `{}`",
+            value
+        )),
+    }
+}
+
+/// Parses a path of `carton cp`: `CONTAINER:PATH` is a path in a container, anything else a path
+/// on the host. Host paths with a `:` can be written like `./a:b`.
+fn parse_copy_path(value: &str) -> Result<CopyPath, String> {
+    match value.split_once(':') {
+        Some((container, path)) if !container.is_empty() && !container.contains('/') => {
+            Ok(CopyPath::Container(container.into(), path.into()))
+        }
+        _ => Ok(CopyPath::Host(value.into())),
+    }
+}
+
+/// Parses a `PATH=DIGEST` pair.
+fn parse_verified_file(value: &str) -> Result<(PathBuf, Sha256Digest), String> {
+    let (path, digest) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected `path=digest`, got `{}`", value))?;
+    let digest = digest.parse::<Sha256Digest>().map_err(|e| e.to_string())?;
+
+    Ok((path.into(), digest))
+}
+
+/// Parses the `container_id:host_id:size` value of `--rootfs-uidmap` and `--rootfs-gidmap`.
+fn parse_id_mapping(value: &str) -> Result<IdMapping, String> {
+    let ids = value
+        .split(':')
+        .map(|id| id.parse::<u32>())
+        .collect::<Result<Vec<u32>, _>>();
+    match ids.as_deref() {
+        Ok([container_id, host_id, size]) => Ok(IdMapping::new(*container_id, *host_id, *size)),
+        _ => Err(format!(
+            "expected `container_id:host_id:size` like 0:100000:65536, got `{}`",
+            value
+        )),
+    }
+}
+
+/// Parses the value of `--gpus`: `all` or a list of GPU indices.
+fn parse_gpus(value: &str) -> Result<Gpus, String> {
+    if value == "all" {
+        return Ok(Gpus::All);
+    }
+
+    value
+        .split(',')
+        .map(|index| index.trim().parse::<u32>())
+        .collect::<Result<Vec<u32>, _>>()
+        .map(Gpus::Nvidia)
+        .map_err(|_| format!("expected `all` or GPU indices like `0,1`, got `{}`", value))
+}
+
+/// Parses the `destination,gateway` value of `--route`.
+fn parse_route(value: &str) -> Result<Route, String> {
+    let (destination, gateway) = value
+        .split_once(',')
+        .ok_or_else(|| format!("expected `destination,gateway`, got `{}`", value))?;
+    let destination = destination
+        .parse()
+        .map_err(|e| format!("invalid destination `{}`: {}", destination, e))?;
+    let gateway = gateway
+        .parse()
+        .map_err(|e| format!("invalid gateway `{}`: {}", gateway, e))?;
+
+    Ok(Route {
+        destination,
+        gateway,
+    })
+}
+
+/// Parses a rate in bits per second, with an optional `kbit`, `mbit` or `gbit` unit like `tc` uses.
+fn parse_rate(value: &str) -> Result<u64, String> {
+    let lowercase = value.to_lowercase();
+    let (number, multiplier) = [
+        ("gbit", 1_000_000_000),
+        ("mbit", 1_000_000),
+        ("kbit", 1_000),
+    ]
+    .into_iter()
+    .find_map(|(unit, multiplier)| {
+        lowercase
+            .strip_suffix(unit)
+            .map(|number| (number, multiplier))
+    })
+    .unwrap_or((lowercase.strip_suffix("bit").unwrap_or(&lowercase), 1));
+
+    match number.trim().parse::<u64>() {
+        Ok(rate) if rate > 0 => rate
+            .checked_mul(multiplier)
+            .ok_or_else(|| format!("rate is too high: {}", value)),
+        _ => Err(format!(
+            "expected a rate like 500kbit, 10mbit or 1gbit, got `{}`",
+            value
+        )),
+    }
+}
+
+/// Parses a size in bytes, with an optional `k`, `m`, `g` or `t` unit (powers of 1024).
+fn parse_size(value: &str) -> Result<u64, String> {
+    let lowercase = value.to_lowercase();
+    let lowercase = lowercase.strip_suffix('b').unwrap_or(&lowercase);
+    let (number, multiplier) = [
+        ('t', 1 << 40),
+        ('g', 1 << 30),
+        ('m', 1 << 20),
+        ('k', 1 << 10),
+    ]
+    .into_iter()
+    .find_map(|(unit, multiplier)| {
+        lowercase
+            .strip_suffix(unit)
+            .map(|number| (number, multiplier))
+    })
+    .unwrap_or((lowercase, 1));
+
+    match number.trim().parse::<u64>() {
+        Ok(size) if size > 0 => size
+            .checked_mul(multiplier)
+            .ok_or_else(|| format!("size is too large: {}", value)),
+        _ => Err(format!("expected a size like 512m or 1g, got `{}`", value)),
+    }
+}
+
+/// Formats the container's state for display in a table column, including the exit code for
+/// containers that have exited.
+fn format_state(info: &ContainerInfo) -> String {
+    match (info.state, &info.exit) {
+        (ContainerState::Running, _) if info.paused => "paused".into(),
+        (ContainerState::Exited, Some(ContainerExit::TimedOut)) => {
+            format!("{} (timed out)", info.state)
+        }
+        (ContainerState::Exited, Some(ContainerExit::SetupFailed(_))) => {
+            format!("{} (setup failed)", info.state)
+        }
+        (ContainerState::Exited, Some(exit)) => format!("{} ({})", info.state, exit.code()),
+        (state, _) => state.to_string(),
+    }
+}
+
+/// Formats the container's root filesystem for display in a table column.
+fn format_rootfs(info: &ContainerInfo) -> String {
+    if info.sandbox {
+        "(host)".into()
+    } else {
+        info.rootfs.display().to_string()
+    }
+}
+
+/// Formats the container's command line for display in a table column.
+fn format_command(info: &ContainerInfo) -> String {
+    let mut command = info.command.display().to_string();
+    for arg in &info.arguments {
+        command.push(' ');
+        command.push_str(arg);
+    }
+
+    if command.chars().count() > 24 {
+        command = command.chars().take(21).collect::<String>() + "...";
+    }
+
+    command
+}
+
+/// Formats a number of bytes like 12.3MiB.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+}
+
+/// Returns how much space the file or directory at `path` takes up on disk. Files with several
+/// hard links in it are counted once, and what can't be read counts as nothing.
+fn disk_usage(path: &Path) -> u64 {
+    fn walk(path: &Path, seen: &mut HashSet<(u64, u64)>) -> u64 {
+        let Ok(metadata) = std::fs::symlink_metadata(path) else {
+            return 0;
+        };
+        if !seen.insert((metadata.dev(), metadata.ino())) {
+            return 0;
+        }
+
+        // st_blocks is in units of 512 bytes, whatever the block size of the filesystem
+        let mut usage = metadata.blocks() * 512;
+        if metadata.is_dir() {
+            for entry in std::fs::read_dir(path).into_iter().flatten().flatten() {
+                usage += walk(&entry.path(), seen);
+            }
+        }
+
+        usage
+    }
+
+    walk(path, &mut HashSet::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("500kbit"), Ok(500_000));
+        assert_eq!(parse_rate("10Mbit"), Ok(10_000_000));
+        assert_eq!(parse_rate("1gbit"), Ok(1_000_000_000));
+        assert_eq!(parse_rate("8000bit"), Ok(8000));
+        assert_eq!(parse_rate("8000"), Ok(8000));
+    }
+
+    #[test]
+    fn rejects_invalid_rates() {
+        for rate in ["", "0mbit", "-1mbit", "10mb", "fast", "20000000000gbit"] {
+            assert!(parse_rate(rate).is_err(), "{}", rate);
+        }
+    }
+
+    #[test]
+    fn formats_stats_as_json_or_template() {
+        let cli =
+            Cli::try_parse_from(["carton", "stats", "--format", "{{.id}} {{.pids}}"]).unwrap();
+        let Command::Stats(args) = cli.command else {
+            panic!("expected the stats command");
+        };
+        let OutputFormat::Template(template) = args.format else {
+            panic!("expected a template");
+        };
+
+        let row = StatsRow {
+            id: "abc".into(),
+            name: None,
+            cpu_percent: None,
+            memory: 1024,
+            memory_limit: Some(2048),
+            pids: 3,
+            network_received: None,
+            network_sent: None,
+        };
+        let value = serde_json::to_value(&row).unwrap();
+        assert_eq!(render_template(&template, &value).unwrap(), "abc 3");
+        assert_eq!(value["memory_limit"], 2048);
+        assert!(value["cpu_percent"].is_null());
+    }
+}
@@ -0,0 +1,243 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Exports the spans of a container's lifecycle (see `Tracer`) to an OpenTelemetry collector or
+//! tracing backend, with OTLP over HTTP in its JSON encoding.
+//!
+//! Exporting is configured like it is for other OpenTelemetry programs, with
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` (to which `/v1/traces` is added) or
+//! `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`, and `OTEL_SERVICE_NAME`. Only plain `http://` endpoints are
+//! supported, like a collector on the same host.
+//!
+//! Every container gets a trace of its own. Its spans are collected while carton runs and sent
+//! in one request when the container is dropped, so that exporting doesn't add to the time that
+//! it takes to start the container. Exporting is best effort: a collector that isn't there only
+//! gets a warning in the log.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+
+use log::warn;
+
+use serde_json::{json, Value};
+
+use libcarton::{Span, Tracer};
+
+/// How long connecting to the endpoint, and sending to and reading from it, may take.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where the spans go: an `http://` URL.
+struct Endpoint {
+    /// The host and port, like `localhost:4318`.
+    authority: String,
+    /// The path of the request, like `/v1/traces`.
+    path: String,
+}
+
+impl Endpoint {
+    fn parse(url: &str) -> Result<Self> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            bail!("only http:// endpoints are supported, got `{}`", url);
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            bail!("the endpoint `{}` has no host", url);
+        }
+
+        Ok(Endpoint {
+            authority: authority.into(),
+            path: path.into(),
+        })
+    }
+
+    /// Returns the address to connect to, with the default port of HTTP when there's none.
+    fn address(&self) -> String {
+        let has_port = match self.authority.rfind(']') {
+            // An IPv6 address, like `[::1]:4318`
+            Some(index) => self.authority[index..].contains(':'),
+            None => self.authority.contains(':'),
+        };
+        if has_port {
+            self.authority.clone()
+        } else {
+            format!("{}:80", self.authority)
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}{}", self.authority, self.path)
+    }
+}
+
+/// A `Tracer` that exports spans with OTLP, see the module documentation.
+pub(crate) struct OtlpExporter {
+    endpoint: Endpoint,
+    service_name: String,
+    /// The spans to export by container ID, in the order in which they ended.
+    spans: Mutex<Vec<(String, Span)>>,
+}
+
+impl OtlpExporter {
+    /// Returns the exporter that the environment configures, if any.
+    pub(crate) fn from_env() -> Result<Option<Self>> {
+        let url = match env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT") {
+            Ok(url) if !url.is_empty() => url,
+            _ => match env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+                Ok(url) if !url.is_empty() => format!("{}/v1/traces", url.trim_end_matches('/')),
+                _ => return Ok(None),
+            },
+        };
+        let endpoint = Endpoint::parse(&url).context("OTLP endpoint")?;
+        let service_name = env::var("OTEL_SERVICE_NAME")
+            .ok()
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "carton".into());
+
+        Ok(Some(OtlpExporter {
+            endpoint,
+            service_name,
+            spans: Mutex::new(Vec::new()),
+        }))
+    }
+
+    /// Sends the spans that were collected so far.
+    fn export(&self) -> Result<()> {
+        let spans = std::mem::take(&mut *self.spans.lock().unwrap_or_else(|e| e.into_inner()));
+        if spans.is_empty() {
+            return Ok(());
+        }
+
+        let mut trace_ids = HashMap::new();
+        let mut otlp_spans = Vec::new();
+        for (container_id, span) in &spans {
+            if !trace_ids.contains_key(container_id) {
+                trace_ids.insert(container_id.clone(), random_id(16)?);
+            }
+            let trace_id = &trace_ids[container_id];
+            add_span(&mut otlp_spans, trace_id, None, container_id, span)?;
+        }
+        let request = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [attribute("service.name", &self.service_name)],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "carton", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": otlp_spans,
+                }],
+            }],
+        });
+
+        self.post(&serde_json::to_vec(&request)?)
+    }
+
+    /// Sends `body` to the endpoint in a POST request.
+    fn post(&self, body: &[u8]) -> Result<()> {
+        let address = self
+            .endpoint
+            .address()
+            .to_socket_addrs()?
+            .next()
+            .with_context(|| format!("{} has no address", self.endpoint.authority))?;
+        let mut stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.endpoint.path,
+            self.endpoint.authority,
+            body.len()
+        )?;
+        stream.write_all(body)?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            _ => bail!("the endpoint responded with `{}`", status_line),
+        }
+    }
+}
+
+impl Tracer for OtlpExporter {
+    fn span(&self, container_id: &str, span: &Span) {
+        self.spans
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((container_id.into(), span.clone()));
+    }
+}
+
+impl Drop for OtlpExporter {
+    fn drop(&mut self) {
+        if let Err(e) = self.export() {
+            warn!("Could not export spans to {}: {:#}", self.endpoint, e);
+        }
+    }
+}
+
+/// Adds `span` and its children to `otlp_spans`, in the OTLP JSON encoding.
+fn add_span(
+    otlp_spans: &mut Vec<Value>,
+    trace_id: &str,
+    parent_id: Option<&str>,
+    container_id: &str,
+    span: &Span,
+) -> Result<()> {
+    let span_id = random_id(8)?;
+    let mut otlp_span = json!({
+        "traceId": trace_id,
+        "spanId": span_id,
+        "name": span.name,
+        // Internal
+        "kind": 1,
+        "startTimeUnixNano": unix_nanos(span.start).to_string(),
+        "endTimeUnixNano": unix_nanos(span.end).to_string(),
+    });
+    match parent_id {
+        Some(parent_id) => otlp_span["parentSpanId"] = parent_id.into(),
+        None => otlp_span["attributes"] = json!([attribute("container.id", container_id)]),
+    }
+    otlp_spans.push(otlp_span);
+
+    for child in &span.children {
+        add_span(otlp_spans, trace_id, Some(&span_id), container_id, child)?;
+    }
+
+    Ok(())
+}
+
+/// Returns an attribute with a string value.
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_nanos())
+}
+
+/// Returns a random trace or span ID of `size` bytes, in hexadecimal.
+fn random_id(size: usize) -> Result<String> {
+    let mut bytes = vec![0; size];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
@@ -0,0 +1,135 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+
+use serde_json::Value;
+
+/// How the output of commands like `ps` and `inspect` should be presented.
+#[derive(Clone, Debug)]
+pub(crate) enum OutputFormat {
+    /// Human readable columns.
+    Table,
+    /// Pretty-printed JSON.
+    Json,
+    /// A template in which fields are referenced like `{{.id}}`, rendered once per item.
+    Template(String),
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            template if template.contains("{{") => Ok(OutputFormat::Template(template.into())),
+            _ => bail!(
+                "unknown format `{}`, expected `table`, `json` or a template like `{{{{.id}}}}`",
+                format
+            ),
+        }
+    }
+}
+
+/// Renders `template` by replacing every `{{.field}}` with the value of that field in `value`.
+/// Nested fields can be referenced as `{{.outer.inner}}`.
+pub(crate) fn render_template(template: &str, value: &Value) -> Result<String> {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&unescape(&rest[..start]));
+
+        let end = rest[start..]
+            .find("}}")
+            .map(|end| start + end)
+            .ok_or_else(|| anyhow!("unclosed `{{{{` in template"))?;
+        let field = rest[start + 2..end].trim();
+        let Some(path) = field.strip_prefix('.') else {
+            bail!("template field `{}` should start with a `.`", field);
+        };
+
+        let mut field_value = value;
+        for key in path.split('.').filter(|key| !key.is_empty()) {
+            field_value = field_value
+                .get(key)
+                .ok_or_else(|| anyhow!("unknown template field `{}`", field))?;
+        }
+
+        match field_value {
+            Value::String(s) => output.push_str(s),
+            Value::Null => {}
+            other => output.push_str(&other.to_string()),
+        }
+
+        rest = &rest[end + 2..];
+    }
+    output.push_str(&unescape(rest));
+
+    Ok(output)
+}
+
+/// Allows templates given on the command line to contain escaped tabs and newlines. Only the
+/// template itself is unescaped, not the values of its fields.
+fn unescape(literal: &str) -> String {
+    literal.replace("\\t", "\t").replace("\\n", "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn renders_fields_and_nested_fields() {
+        let value = json!({"id": "abc", "exit": {"type": "exited", "code": 3}, "pid": null});
+
+        assert_eq!(
+            render_template("{{.id}} {{ .exit.type }}/{{.exit.code}}{{.pid}}", &value).unwrap(),
+            "abc exited/3"
+        );
+    }
+
+    #[test]
+    fn unescapes_tabs_and_newlines() {
+        assert_eq!(
+            render_template("{{.a}}\\t{{.b}}\\n", &json!({"a": 1, "b": true})).unwrap(),
+            "1\ttrue\n"
+        );
+    }
+
+    #[test]
+    fn leaves_backslashes_in_field_values_alone() {
+        let value = json!({"args": ["printf", "a\\tb\\n"]});
+
+        assert_eq!(
+            render_template("{{.args}}\\n", &value).unwrap(),
+            "[\"printf\",\"a\\\\tb\\\\n\"]\n"
+        );
+        assert_eq!(
+            render_template("{{.path}}", &json!({"path": "C:\\new"})).unwrap(),
+            "C:\\new"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        let value = json!({"id": "abc"});
+
+        assert!(render_template("{{.id", &value).is_err());
+        assert!(render_template("{{id}}", &value).is_err());
+        assert!(render_template("{{.name}}", &value).is_err());
+    }
+
+    #[test]
+    fn parses_output_formats() {
+        assert!(matches!("table".parse(), Ok(OutputFormat::Table)));
+        assert!(matches!("json".parse(), Ok(OutputFormat::Json)));
+        assert!(matches!("{{.id}}".parse(), Ok(OutputFormat::Template(t)) if t == "{{.id}}"));
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+}
@@ -0,0 +1,419 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Projects, groups of containers that `carton up` starts together from a manifest, like the
+//! services of a compose file:
+//!
+//! ```toml
+//! [containers.web]
+//! rootfs = "rootfs/web"
+//! command = ["httpd", "-f", "-p", "8080"]
+//!
+//! [containers.worker]
+//! rootfs = "rootfs/worker"
+//! command = ["worker", "--queue", "jobs"]
+//! environment = { QUEUE_URL = "redis://localhost" }
+//! files = { "/etc/worker.conf" = "concurrency = 4\n" }
+//! depends_on = { queue = "healthy" }
+//!
+//! [containers.queue]
+//! rootfs = "rootfs/redis"
+//! command = ["redis-server"]
+//! healthcheck = { command = ["redis-cli", "ping"], interval = 1 }
+//! ```
+//!
+//! Containers start after the containers that they depend on: after those were started, or with
+//! the `healthy` condition after their health checks passed (see `HealthCheck`). Containers
+//! without dependencies between them start in the order of their names.
+//!
+//! The containers are called `<project>-<container>`, and each of them records the project and
+//! the path of its manifest (see `ContainerInfo::project`). That's all that makes a group: `carton
+//! down`, `carton restart` and `carton ps --project` find the members of a project in the state
+//! directory, so they work from any other process than the one that started them.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+use libcarton::{ContainerBuilder, ContainerInfo, HealthCheck, NeedsRootfs};
+
+use serde::Deserialize;
+
+use crate::config;
+use crate::image::{self, DEFAULT_PATH};
+
+/// The manifest that `carton up` reads when it isn't given one.
+pub(crate) const DEFAULT_MANIFEST: &str = "carton-project.toml";
+
+/// A project's manifest.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Manifest {
+    /// The containers of the project, by their name within the project.
+    containers: BTreeMap<String, Service>,
+    /// Where the manifest was read from.
+    #[serde(skip)]
+    path: PathBuf,
+    /// The names of the containers in the order in which they're started.
+    #[serde(skip)]
+    order: Vec<String>,
+}
+
+/// A container of a project.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Service {
+    /// The root filesystem, relative to the manifest's directory unless it's absolute.
+    rootfs: PathBuf,
+    /// The command and its arguments. A command that's just a name is looked up in the `PATH` of
+    /// the root filesystem.
+    command: Vec<String>,
+    hostname: Option<String>,
+    #[serde(default)]
+    environment: BTreeMap<String, String>,
+    #[serde(default)]
+    ephemeral: bool,
+    /// Files that the container gets, by their absolute paths in the container, with their
+    /// contents.
+    #[serde(default)]
+    files: BTreeMap<PathBuf, String>,
+    /// The containers that have to be up before this one starts, and what up means for them.
+    #[serde(default)]
+    depends_on: BTreeMap<String, Condition>,
+    healthcheck: Option<HealthCheckConfig>,
+}
+
+/// When a container that others depend on is up.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Condition {
+    /// Once it was started.
+    Started,
+    /// Once its health check passed.
+    Healthy,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct HealthCheckConfig {
+    command: Vec<String>,
+    /// Seconds between checks.
+    interval: Option<u64>,
+    /// Seconds that a check may take.
+    timeout: Option<u64>,
+    retries: Option<u32>,
+}
+
+impl Manifest {
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        let mut manifest: Manifest = config::parse_versioned(path, &contents)?;
+        if manifest.containers.is_empty() {
+            bail!("{} has no containers", path.display());
+        }
+        manifest.order = manifest
+            .start_order()
+            .with_context(|| format!("in {}", path.display()))?;
+        // Recorded absolute, so that `carton restart` finds it from any directory
+        manifest.path = path
+            .canonicalize()
+            .with_context(|| format!("resolving {}", path.display()))?;
+
+        Ok(manifest)
+    }
+
+    /// Returns the name of the project when it isn't given one: the name of the directory of the
+    /// manifest.
+    pub(crate) fn default_name(&self) -> Result<String> {
+        self.path
+            .parent()
+            .and_then(Path::file_name)
+            .and_then(|name| name.to_str())
+            .map(String::from)
+            .with_context(|| {
+                format!(
+                    "{} has no directory to name the project after, give it a name with --project",
+                    self.path.display()
+                )
+            })
+    }
+
+    /// Returns the names of the containers within the project, in the order in which they're
+    /// started.
+    pub(crate) fn containers(&self) -> impl Iterator<Item = &str> {
+        self.order.iter().map(String::as_str)
+    }
+
+    /// Returns the containers that the container `name` depends on, with their conditions.
+    pub(crate) fn dependencies(&self, name: &str) -> impl Iterator<Item = (&str, Condition)> {
+        self.containers[name]
+            .depends_on
+            .iter()
+            .map(|(dependency, condition)| (dependency.as_str(), *condition))
+    }
+
+    /// Returns the health check of the container `name`, if it has one.
+    pub(crate) fn health_check(&self, name: &str) -> Option<HealthCheck> {
+        let config = self.containers[name].healthcheck.as_ref()?;
+        let mut check = HealthCheck::new(config.command.clone());
+        if let Some(interval) = config.interval {
+            check = check.interval(Duration::from_secs(interval));
+        }
+        if let Some(timeout) = config.timeout {
+            check = check.timeout(Duration::from_secs(timeout));
+        }
+        if let Some(retries) = config.retries {
+            check = check.retries(retries);
+        }
+
+        Some(check)
+    }
+
+    /// Returns the names of the containers in an order in which every container comes after the
+    /// ones it depends on, and the names come in order otherwise.
+    fn start_order(&self) -> Result<Vec<String>> {
+        for (name, service) in &self.containers {
+            for (dependency, condition) in &service.depends_on {
+                let Some(other) = self.containers.get(dependency) else {
+                    bail!(
+                        "container {} depends on {}, which doesn't exist",
+                        name,
+                        dependency
+                    );
+                };
+                if *condition == Condition::Healthy && other.healthcheck.is_none() {
+                    bail!(
+                        "container {} depends on {} being healthy, which has no healthcheck",
+                        name,
+                        dependency
+                    );
+                }
+            }
+        }
+
+        let mut order = Vec::new();
+        let mut waiting = self.containers.keys().collect::<BTreeSet<&String>>();
+        while !waiting.is_empty() {
+            let ready = waiting
+                .iter()
+                .copied()
+                .find(|name| {
+                    self.containers[*name]
+                        .depends_on
+                        .keys()
+                        .all(|dependency| !waiting.contains(dependency))
+                })
+                .cloned();
+            let Some(ready) = ready else {
+                bail!(
+                    "the dependencies of containers {} form a cycle",
+                    waiting
+                        .iter()
+                        .map(|name| name.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                );
+            };
+            waiting.remove(&ready);
+            order.push(ready);
+        }
+
+        Ok(order)
+    }
+
+    /// Configures `builder` with the settings of the container `name` of the project `project`.
+    pub(crate) fn configure(
+        &self,
+        project: &str,
+        name: &str,
+        builder: ContainerBuilder<NeedsRootfs>,
+    ) -> Result<ContainerBuilder> {
+        let service = &self.containers[name];
+        let dir = self.path.parent().unwrap_or(Path::new("/"));
+        let rootfs = dir.join(&service.rootfs);
+
+        let Some((command, arguments)) = service.command.split_first() else {
+            bail!("the command of container {} is empty", name);
+        };
+        let path = service
+            .environment
+            .get("PATH")
+            .map_or(DEFAULT_PATH, String::as_str);
+        let command = image::find_command(&rootfs, command, path)
+            .with_context(|| format!("container {}", name))?;
+        let mut builder = builder
+            .name(member_name(project, name))
+            .project(project.into(), self.path.clone())
+            .rootfs(rootfs)
+            .command(command, Some(arguments.to_vec()));
+
+        if let Some(hostname) = &service.hostname {
+            builder = builder.hostname(hostname.clone());
+        }
+        if !service.environment.is_empty() {
+            builder = builder.environment(service.environment.clone().into_iter().collect());
+        }
+        if service.ephemeral {
+            builder = builder.ephemeral();
+        }
+        for (path, contents) in &service.files {
+            let Ok(target) = path.strip_prefix("/") else {
+                bail!(
+                    "the file {} of container {} needs an absolute path",
+                    path.display(),
+                    name
+                );
+            };
+            builder = builder.add_file(target.into(), contents.clone());
+        }
+
+        Ok(builder)
+    }
+}
+
+/// Returns the name of the container `name` of the project `project`.
+pub(crate) fn member_name(project: &str, name: &str) -> String {
+    format!("{}-{}", project, name)
+}
+
+/// Returns whether the container of `info` is a member of the project `project`.
+pub(crate) fn is_member(info: &ContainerInfo, project: &str) -> bool {
+    info.project
+        .as_ref()
+        .map_or(false, |info| info.name == project)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start_order(contents: &str) -> Result<Vec<String>> {
+        let manifest: Manifest =
+            config::parse_versioned(Path::new("carton-project.toml"), contents)?;
+
+        manifest.start_order()
+    }
+
+    #[test]
+    fn starts_dependencies_first() {
+        let order = start_order(
+            r#"
+            [containers.web]
+            rootfs = "web"
+            command = ["httpd"]
+            depends_on = { queue = "healthy", db = "started" }
+
+            [containers.queue]
+            rootfs = "redis"
+            command = ["redis-server"]
+            healthcheck = { command = ["redis-cli", "ping"] }
+
+            [containers.db]
+            rootfs = "postgres"
+            command = ["postgres"]
+            depends_on = { queue = "started" }
+
+            [containers.cache]
+            rootfs = "memcached"
+            command = ["memcached"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(order, ["cache", "queue", "db", "web"]);
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let e = start_order(
+            r#"
+            [containers.a]
+            rootfs = "a"
+            command = ["a"]
+            depends_on = { b = "started" }
+
+            [containers.b]
+            rootfs = "b"
+            command = ["b"]
+            depends_on = { c = "started" }
+
+            [containers.c]
+            rootfs = "c"
+            command = ["c"]
+            depends_on = { a = "started" }
+
+            [containers.d]
+            rootfs = "d"
+            command = ["d"]
+            "#,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            e.to_string(),
+            "the dependencies of containers a, b, c form a cycle"
+        );
+    }
+
+    #[test]
+    fn rejects_containers_that_depend_on_themselves() {
+        let e = start_order(
+            r#"
+            [containers.a]
+            rootfs = "a"
+            command = ["a"]
+            depends_on = { a = "started" }
+            "#,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            e.to_string(),
+            "the dependencies of containers a form a cycle"
+        );
+    }
+
+    #[test]
+    fn rejects_missing_dependencies() {
+        let e = start_order(
+            r#"
+            [containers.a]
+            rootfs = "a"
+            command = ["a"]
+            depends_on = { b = "started" }
+            "#,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            e.to_string(),
+            "container a depends on b, which doesn't exist"
+        );
+    }
+
+    #[test]
+    fn rejects_healthy_dependencies_without_a_health_check() {
+        let e = start_order(
+            r#"
+            [containers.a]
+            rootfs = "a"
+            command = ["a"]
+            depends_on = { b = "healthy" }
+
+            [containers.b]
+            rootfs = "b"
+            command = ["b"]
+            "#,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            e.to_string(),
+            "container a depends on b being healthy, which has no healthcheck"
+        );
+    }
+}
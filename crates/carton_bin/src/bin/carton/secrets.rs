@@ -0,0 +1,128 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Where the secrets of `--secret NAME=SOURCE` come from: a file, a variable of carton's
+//! environment or the output of a command, like `pass show db` or `vault kv get -field=password
+//! db`. Carton reads them when it starts the container, and they only end up in the
+//! container's /run/secrets (see `ContainerBuilder::add_secret()`), not in its recorded settings.
+//!
+//! When carton runs as a systemd service with credentials (`LoadCredential=`, `SetCredential=` and
+//! the like), systemd puts them in a directory of their own that only the service can read, and
+//! tells it where with `CREDENTIALS_DIRECTORY`. `credential:NAME` is one of those, and
+//! `credentials()` returns all of them, which carton passes on as secrets of the same names.
+
+use std::env;
+use std::fs;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+
+/// The environment variable in which systemd passes the directory with a service's credentials.
+pub(crate) const CREDENTIALS_DIRECTORY: &str = "CREDENTIALS_DIRECTORY";
+
+/// Where the value of a secret comes from.
+#[derive(Debug, Clone)]
+pub(crate) enum SecretSource {
+    /// `file:PATH`, the whole contents of the file.
+    File(PathBuf),
+    /// `env:VARIABLE`, a variable of carton's environment.
+    Env(String),
+    /// `cmd:COMMAND`, what the shell command prints to stdout, without its last newline.
+    Command(String),
+    /// `credential:NAME`, a systemd credential of the service that carton runs as.
+    Credential(String),
+}
+
+impl SecretSource {
+    /// Parses a source like `file:/etc/app/db_password`.
+    pub(crate) fn parse(value: &str) -> Result<Self, String> {
+        match value.split_once(':') {
+            Some(("file", path)) if !path.is_empty() => Ok(SecretSource::File(path.into())),
+            Some(("env", name)) if !name.is_empty() => Ok(SecretSource::Env(name.into())),
+            Some(("cmd", command)) if !command.is_empty() => {
+                Ok(SecretSource::Command(command.into()))
+            }
+            Some(("credential", name)) if !name.is_empty() && !name.contains('/') => {
+                Ok(SecretSource::Credential(name.into()))
+            }
+            _ => Err(format!(
+                "expected `file:PATH`, `env:VARIABLE`, `cmd:COMMAND` or `credential:NAME`, got `{}`",
+                value
+            )),
+        }
+    }
+
+    pub(crate) fn read(&self) -> Result<Vec<u8>> {
+        match self {
+            SecretSource::File(path) => {
+                fs::read(path).with_context(|| format!("reading {}", path.display()))
+            }
+            SecretSource::Env(name) => match env::var_os(name) {
+                Some(value) => Ok(value.into_vec()),
+                None => bail!("the environment variable {} isn't set", name),
+            },
+            SecretSource::Command(command) => {
+                let output = Command::new("/bin/sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(Stdio::null())
+                    .stderr(Stdio::inherit())
+                    .output()
+                    .with_context(|| format!("running `{}`", command))?;
+                if !output.status.success() {
+                    bail!("`{}` failed: {}", command, output.status);
+                }
+
+                let mut value = output.stdout;
+                if value.last() == Some(&b'\n') {
+                    value.pop();
+                }
+                Ok(value)
+            }
+            SecretSource::Credential(name) => {
+                let Some(dir) = credentials_directory() else {
+                    bail!(
+                        "there are no systemd credentials, {} isn't set",
+                        CREDENTIALS_DIRECTORY
+                    );
+                };
+                let path = dir.join(name);
+                fs::read(&path).with_context(|| format!("reading {}", path.display()))
+            }
+        }
+    }
+}
+
+/// Returns the systemd credentials of the service that carton runs as, by their names, or
+/// nothing when it doesn't run as one or the service has none.
+pub(crate) fn credentials() -> Result<Vec<(String, Vec<u8>)>> {
+    let Some(dir) = credentials_directory() else {
+        return Ok(Vec::new());
+    };
+
+    let mut credentials = Vec::new();
+    for entry in fs::read_dir(&dir).with_context(|| format!("reading {}", dir.display()))? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            bail!(
+                "the credential {:?} doesn't have a UTF-8 name",
+                entry.file_name()
+            );
+        };
+        let value =
+            fs::read(entry.path()).with_context(|| format!("reading credential {}", name))?;
+        credentials.push((name, value));
+    }
+    credentials.sort();
+
+    Ok(credentials)
+}
+
+/// Returns the directory with the credentials, from `CREDENTIALS_DIRECTORY`.
+fn credentials_directory() -> Option<PathBuf> {
+    env::var_os(CREDENTIALS_DIRECTORY)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
@@ -0,0 +1,133 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Where the settings of a container come from. A setting can have a value from several sources,
+//! which are merged in a fixed order: carton's default, the config files (the system-wide one, then
+//! the user's), the `config.json` of a bundle for `carton run-bundle` and the command line flags.
+//! The last source with a value wins.
+//!
+//! The values of all sources are recorded with the container (see `ContainerInfo::settings`), so
+//! that `carton inspect` shows where each setting came from and what it overrode. A source that
+//! overrides a different value of another source than the default is a conflict, which is logged
+//! as a warning instead of being settled silently.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use libcarton::{Setting, SettingSource};
+
+use log::warn;
+
+use serde::Serialize;
+
+use crate::config::Config;
+
+/// The merged settings of a container.
+pub(crate) struct Settings<'a> {
+    config: &'a Config,
+    settings: BTreeMap<String, Setting>,
+}
+
+impl<'a> Settings<'a> {
+    pub(crate) fn new(config: &'a Config) -> Self {
+        Settings {
+            config,
+            settings: BTreeMap::new(),
+        }
+    }
+
+    /// Starts merging the sources of the setting `name`, in the order in which they're added.
+    pub(crate) fn setting<T: Serialize>(&mut self, name: &'static str) -> Sources<'_, 'a, T> {
+        Sources {
+            settings: self,
+            name,
+            values: Vec::new(),
+        }
+    }
+
+    /// Returns the recorded settings, for `ContainerBuilder::settings()`.
+    pub(crate) fn into_map(self) -> BTreeMap<String, Setting> {
+        self.settings
+    }
+}
+
+/// The values of the sources of a setting, see `Settings::setting()`.
+pub(crate) struct Sources<'s, 'a, T> {
+    settings: &'s mut Settings<'a>,
+    name: &'static str,
+    values: Vec<(SettingSource, T)>,
+}
+
+impl<T: Serialize> Sources<'_, '_, T> {
+    pub(crate) fn default(mut self, value: T) -> Self {
+        self.values.push((SettingSource::Default, value));
+        self
+    }
+
+    /// Adds the value that `setting` picks from each config file that has one.
+    pub(crate) fn config(mut self, setting: impl Fn(&Config) -> Option<T>) -> Self {
+        for (path, file) in &self.settings.config.files {
+            if let Some(value) = setting(file) {
+                self.values
+                    .push((SettingSource::ConfigFile(path.clone()), value));
+            }
+        }
+        self
+    }
+
+    /// Adds the value of the bundle whose `config.json` is at `path`.
+    pub(crate) fn bundle(mut self, path: &Path, value: Option<T>) -> Self {
+        if let Some(value) = value {
+            self.values
+                .push((SettingSource::Bundle(path.into()), value));
+        }
+        self
+    }
+
+    pub(crate) fn flag(mut self, value: Option<T>) -> Self {
+        if let Some(value) = value {
+            self.values.push((SettingSource::Flag, value));
+        }
+        self
+    }
+
+    /// Returns the value of the last source that has one, and records it with the values that it
+    /// overrides.
+    pub(crate) fn value(self) -> Option<T> {
+        let mut values = self.values;
+        let (source, value) = values.pop()?;
+        let json = to_json(&value);
+
+        let overridden = values
+            .into_iter()
+            .map(|(source, value)| Setting {
+                value: to_json(&value),
+                source,
+                overridden: Vec::new(),
+            })
+            .collect::<Vec<Setting>>();
+        for other in &overridden {
+            if other.source != SettingSource::Default && other.value != json {
+                warn!(
+                    "{} is {} from {}, which overrides {} from {}",
+                    self.name, json, source, other.value, other.source
+                );
+            }
+        }
+
+        self.settings.settings.insert(
+            self.name.into(),
+            Setting {
+                value: json,
+                source,
+                overridden,
+            },
+        );
+
+        Some(value)
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> serde_json::Value {
+    serde_json::to_value(value).expect("settings should be representable as JSON")
+}
@@ -0,0 +1,100 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! The log of `--trace-syscalls`, for finding out why a program that works on the host fails in
+//! a container, like which files it looks for.
+//!
+//! The container's system calls are passed to carton with seccomp user notifications (see
+//! `ContainerBuilder::seccomp_notify_all()`), which writes each of them to the log and lets it
+//! go ahead. That doesn't need ptrace, so it works for programs that use ptrace themselves, but
+//! carton only sees a system call before the kernel executes it, not what it returns. Each line
+//! has the time since the container started, the host PID of the calling process, the name of
+//! the system call and its arguments, with the paths of system calls that take paths read from
+//! the process' memory:
+//!
+//! ```text
+//! 0.004317 [81234] openat(0xffffff9c, "/etc/ld.so.cache", 0x80000, 0x0, 0x0, 0x0)
+//! ```
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+
+use libcarton::{SyscallRequest, SyscallResponse};
+
+/// Writes the system calls of a container to a file.
+pub(crate) struct SyscallLog {
+    file: Mutex<File>,
+    start: Instant,
+}
+
+impl SyscallLog {
+    pub(crate) fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("creating system call log {}", path.display()))?;
+
+        Ok(SyscallLog {
+            file: Mutex::new(file),
+            start: Instant::now(),
+        })
+    }
+
+    /// Writes `request` to the log, and lets the kernel execute it.
+    pub(crate) fn log(&self, request: &SyscallRequest) -> SyscallResponse {
+        let name = request.name();
+        let paths = name.map_or(&[][..], path_arguments);
+        let arguments: Vec<String> = request
+            .args
+            .iter()
+            .enumerate()
+            .map(|(index, argument)| {
+                match paths
+                    .contains(&index)
+                    .then(|| request.read_string(*argument))
+                {
+                    Some(Ok(path)) => format!("{:?}", String::from_utf8_lossy(&path)),
+                    _ => format!("{:#x}", argument),
+                }
+            })
+            .collect();
+        let line = format!(
+            "{:.6} [{}] {}({})\n",
+            self.start.elapsed().as_secs_f64(),
+            request.pid,
+            name.map_or_else(|| format!("syscall_{}", request.syscall), String::from),
+            arguments.join(", ")
+        );
+        // Writing the whole line at once keeps the lines of concurrent system calls apart. A
+        // system call that can't be logged still goes ahead.
+        let _ = self
+            .file
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .write_all(line.as_bytes());
+
+        SyscallResponse::Continue
+    }
+}
+
+/// Returns the indices of the arguments of the system call `name` that are paths.
+fn path_arguments(name: &str) -> &'static [usize] {
+    match name {
+        "access" | "acct" | "chdir" | "chmod" | "chown" | "chroot" | "creat" | "execve"
+        | "getxattr" | "lchown" | "lgetxattr" | "listxattr" | "llistxattr" | "lremovexattr"
+        | "lsetxattr" | "lstat" | "mkdir" | "mknod" | "open" | "readlink" | "removexattr"
+        | "rmdir" | "setxattr" | "stat" | "statfs" | "swapoff" | "swapon" | "truncate"
+        | "umount2" | "unlink" | "uselib" | "utime" | "utimes" => &[0],
+        "execveat" | "faccessat" | "faccessat2" | "fchmodat" | "fchownat" | "futimesat"
+        | "inotify_add_watch" | "mkdirat" | "mknodat" | "name_to_handle_at" | "newfstatat"
+        | "open_tree" | "openat" | "openat2" | "readlinkat" | "statx" | "unlinkat"
+        | "utimensat" => &[1],
+        "link" | "mount" | "pivot_root" | "rename" | "symlink" => &[0, 1],
+        "symlinkat" => &[0, 2],
+        "linkat" | "renameat" | "renameat2" => &[1, 3],
+        _ => &[],
+    }
+}
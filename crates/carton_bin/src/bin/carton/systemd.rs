@@ -0,0 +1,151 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::Write;
+use std::path::Path;
+
+use clap::ValueEnum;
+
+use libcarton::ContainerInfo;
+
+/// When systemd should start the container again after it exited.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub(crate) enum RestartPolicy {
+    No,
+    OnFailure,
+    Always,
+}
+
+impl RestartPolicy {
+    /// Returns the value for `Restart=`.
+    fn as_systemd(self) -> &'static str {
+        match self {
+            RestartPolicy::No => "no",
+            RestartPolicy::OnFailure => "on-failure",
+            RestartPolicy::Always => "always",
+        }
+    }
+}
+
+/// How long the container gets to exit after it was sent SIGTERM, before systemd kills it.
+const STOP_TIMEOUT_SECONDS: u32 = 10;
+
+/// Generates a service unit that runs a container like `info` with `carton` (the path of the
+/// carton executable). Every start of the service creates a new container, with a new ID but the
+/// same name (`systemd-` and the name or short ID of `info`), so that the container of the
+/// previous start can be removed first and `ExecStop=` can find the container to stop.
+///
+/// Only what is recorded in the container's state ends up in the unit. How the container was
+/// connected to the network isn't recorded, so network flags have to be added by hand.
+pub(crate) fn unit_file(
+    info: &ContainerInfo,
+    carton: &Path,
+    state_dir: &Path,
+    restart: RestartPolicy,
+) -> String {
+    let name = info
+        .name
+        .as_deref()
+        .unwrap_or(&info.id[..info.id.len().min(12)]);
+    let service_name = format!("systemd-{}", name);
+    let carton_command = |subcommand: &str| {
+        vec![
+            carton.display().to_string(),
+            "--state-dir".into(),
+            state_dir.display().to_string(),
+            subcommand.into(),
+        ]
+    };
+
+    let mut exec_start = carton_command(if info.sandbox { "sandbox" } else { "run" });
+    exec_start.extend(["--name".into(), service_name.clone()]);
+    // Containers that didn't get a hostname of their own are named after their ID, which will be
+    // a different one
+    if let Some(hostname) = info
+        .hostname
+        .as_ref()
+        .filter(|hostname| !info.id.starts_with(hostname.as_str()))
+    {
+        exec_start.extend(["--hostname".into(), hostname.clone()]);
+    }
+    if !info.sandbox {
+        exec_start.push(info.rootfs.display().to_string());
+    }
+    exec_start.push(info.command.display().to_string());
+    if !info.arguments.is_empty() {
+        // The arguments may start with a hyphen, like `-c`
+        exec_start.push("--".into());
+        exec_start.extend(info.arguments.iter().cloned());
+    }
+
+    let mut exec_start_pre = carton_command("rm");
+    exec_start_pre.push(service_name.clone());
+    let mut exec_stop = carton_command("kill");
+    exec_stop.extend(["--signal".into(), "SIGTERM".into(), service_name.clone()]);
+    let mut exec_stop_wait = carton_command("wait");
+    exec_stop_wait.push(service_name);
+
+    let mut unit = String::new();
+    let _ = writeln!(
+        unit,
+        "# Generated by carton generate-systemd from container {}",
+        info.id
+    );
+    let _ = writeln!(unit, "[Unit]");
+    let _ = writeln!(
+        unit,
+        "Description=carton container {} ({})",
+        name,
+        info.command.display()
+    );
+    let _ = writeln!(unit, "Wants=network-online.target");
+    let _ = writeln!(unit, "After=network-online.target");
+    let _ = writeln!(unit);
+    let _ = writeln!(unit, "[Service]");
+    // The container of the previous start, if there is one. The `-` makes systemd carry on when
+    // there isn't.
+    let _ = writeln!(unit, "ExecStartPre=-{}", command_line(&exec_start_pre));
+    let _ = writeln!(unit, "ExecStart={}", command_line(&exec_start));
+    // systemd runs these after the container exited by itself as well, when they fail
+    let _ = writeln!(unit, "ExecStop=-{}", command_line(&exec_stop));
+    // Until the container has exited and `carton run` has recorded how, so that systemd doesn't
+    // signal carton itself while the container is still stopping
+    let _ = writeln!(unit, "ExecStop=-{}", command_line(&exec_stop_wait));
+    // Whatever is left when the timeout is up is killed, the container included
+    let _ = writeln!(unit, "KillMode=mixed");
+    let _ = writeln!(unit, "TimeoutStopSec={}", STOP_TIMEOUT_SECONDS);
+    let _ = writeln!(unit, "Restart={}", restart.as_systemd());
+    let _ = writeln!(unit);
+    let _ = writeln!(unit, "[Install]");
+    let _ = writeln!(unit, "WantedBy=multi-user.target");
+
+    unit
+}
+
+fn command_line(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| quote(arg))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Quotes an argument for a systemd command line. systemd also expands `$VARIABLE` and
+/// specifiers like `%n` in command lines, which have to be escaped by doubling them.
+fn quote(arg: &str) -> String {
+    let escaped = arg.replace('$', "$$").replace('%', "%%");
+    if !escaped.is_empty()
+        && !escaped
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';'))
+    {
+        return escaped;
+    }
+
+    format!(
+        "\"{}\"",
+        escaped
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
@@ -0,0 +1,156 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! The processes of a running container, for `carton top`.
+//!
+//! The processes of a container are the ones in its PID namespace, which works for containers
+//! without a cgroup of their own too. Everything about them comes from /proc on the host; users
+//! are shown the way the container sees them, with the names of its own /etc/passwd.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+/// The unit of the CPU times in /proc/<pid>/stat, which is 100 per second on the architectures
+/// that carton runs on.
+const CLOCK_TICKS_PER_SECOND: u64 = 100;
+
+/// A process of a container.
+#[derive(Debug)]
+pub(crate) struct Process {
+    /// PID of the process on the host.
+    pub(crate) host_pid: i32,
+    /// PID of the process in the container.
+    pub(crate) pid: i32,
+    /// User that the process runs as, by its name in the container if it has one.
+    pub(crate) user: String,
+    /// CPU time that the process used, in user and kernel mode.
+    pub(crate) cpu_time: Duration,
+    /// Resident memory of the process in KiB.
+    pub(crate) rss_kib: u64,
+    pub(crate) command: String,
+}
+
+/// Returns the processes of the container whose first process is `pid`, ordered by PID.
+pub(crate) fn processes(pid: i32) -> Result<Vec<Process>> {
+    let namespace = fs::read_link(format!("/proc/{}/ns/pid", pid))
+        .with_context(|| format!("finding the PID namespace of process {}", pid))?;
+    let uid_map = fs::read_to_string(format!("/proc/{}/uid_map", pid)).unwrap_or_default();
+    let users = users(Path::new(&format!("/proc/{}/root/etc/passwd", pid)));
+
+    let mut processes = Vec::new();
+    for entry in fs::read_dir("/proc").context("reading /proc")? {
+        let Some(host_pid) = entry?
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        else {
+            continue;
+        };
+        // Processes that exit in the meantime are left out
+        if fs::read_link(format!("/proc/{}/ns/pid", host_pid)).ok() != Some(namespace.clone()) {
+            continue;
+        }
+        if let Some(process) = process(host_pid, &uid_map, &users) {
+            processes.push(process);
+        }
+    }
+    processes.sort_by_key(|process| process.pid);
+
+    Ok(processes)
+}
+
+/// Returns the process with `host_pid` on the host, or `None` when it's gone.
+fn process(host_pid: i32, uid_map: &str, users: &HashMap<u32, String>) -> Option<Process> {
+    let status = fs::read_to_string(format!("/proc/{}/status", host_pid)).ok()?;
+    let stat = fs::read_to_string(format!("/proc/{}/stat", host_pid)).ok()?;
+    let cmdline = fs::read(format!("/proc/{}/cmdline", host_pid)).ok()?;
+
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .map(str::split_whitespace)
+    };
+    // The PIDs from the host's namespace down to the process' own, which is the container's
+    let pid = field("NSpid")?.last()?.parse().ok()?;
+    // The effective user, as ps shows it
+    let host_uid = field("Uid")?.nth(1)?.parse().ok()?;
+    let rss_kib = field("VmRSS")
+        .and_then(|mut values| values.next()?.parse().ok())
+        .unwrap_or(0);
+
+    // The name of the command is in parentheses and can contain anything, so the other fields are
+    // the ones after the last parenthesis. utime and stime are the 14th and 15th field.
+    let mut times = stat[stat.rfind(')')? + 1..].split_whitespace().skip(11);
+    let ticks = times.next()?.parse::<u64>().ok()? + times.next()?.parse::<u64>().ok()?;
+
+    let uid = container_id(uid_map, host_uid);
+    let user = uid
+        .and_then(|uid| users.get(&uid).cloned())
+        .or_else(|| uid.map(|uid| uid.to_string()))
+        .unwrap_or_else(|| "?".into());
+
+    // Kernel threads and zombies have no command line, ps shows their name in brackets instead
+    let command = if cmdline.is_empty() {
+        format!("[{}]", field("Name")?.next()?)
+    } else {
+        String::from_utf8_lossy(&cmdline)
+            .trim_end_matches('\0')
+            .replace('\0', " ")
+    };
+
+    Some(Process {
+        host_pid,
+        pid,
+        user,
+        cpu_time: Duration::from_millis(ticks * 1000 / CLOCK_TICKS_PER_SECOND),
+        rss_kib,
+        command,
+    })
+}
+
+/// Returns the ID in a user namespace with `map` (the contents of its uid_map) that `host_id` is,
+/// if it's mapped there.
+fn container_id(map: &str, host_id: u32) -> Option<u32> {
+    map.lines().find_map(|line| {
+        let mut fields = line
+            .split_whitespace()
+            .map(|field| field.parse::<u32>().ok());
+        let (inside, outside, count) = (fields.next()??, fields.next()??, fields.next()??);
+        if host_id >= outside && host_id - outside < count {
+            Some(inside + (host_id - outside))
+        } else {
+            None
+        }
+    })
+}
+
+/// Returns the user names of the passwd file at `path` by their IDs.
+fn users(path: &Path) -> HashMap<u32, String> {
+    let passwd = fs::read_to_string(path).unwrap_or_default();
+
+    passwd
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let uid = fields.nth(1)?.parse().ok()?;
+            Some((uid, name.into()))
+        })
+        .collect()
+}
+
+/// Formats a CPU time like ps does, as `hh:mm:ss`.
+pub(crate) fn format_cpu_time(time: Duration) -> String {
+    let seconds = time.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
@@ -0,0 +1,384 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! End-to-end tests of `carton run`, see the `carton-test` crate for how to run them.
+
+use std::env;
+use std::fs;
+use std::process;
+
+use carton_test::{busybox_rootfs, require_root, Carton};
+
+fn carton() -> Carton {
+    Carton::new(env!("CARGO_BIN_EXE_carton"))
+}
+
+#[test]
+#[ignore = "needs root"]
+fn passes_on_the_exit_code_of_the_command() {
+    require_root!();
+
+    carton()
+        .run::<&str>(&[], &busybox_rootfs(), "/bin/sh", &["-c", "exit 3"])
+        .assert_exit_code(3);
+}
+
+#[test]
+#[ignore = "needs root"]
+fn sets_the_hostname() {
+    require_root!();
+
+    carton()
+        .run(
+            &["--hostname", "box"],
+            &busybox_rootfs(),
+            "/bin/hostname",
+            &[],
+        )
+        .assert_success()
+        .assert_stdout("box\n");
+}
+
+#[test]
+#[ignore = "needs root"]
+fn mounts_proc_of_its_own_pid_namespace() {
+    require_root!();
+
+    // The command is PID 1 in the container
+    carton()
+        .run::<&str>(&[], &busybox_rootfs(), "/bin/sh", &["-c", "echo $$"])
+        .assert_success()
+        .assert_stdout("1\n");
+}
+
+#[test]
+#[ignore = "needs root"]
+fn ephemeral_containers_leave_the_rootfs_alone() {
+    require_root!();
+    let rootfs = busybox_rootfs();
+
+    carton()
+        .run(
+            &["--ephemeral"],
+            &rootfs,
+            "/bin/sh",
+            &["-c", "echo hi > /written && cat /written"],
+        )
+        .assert_success()
+        .assert_stdout("hi\n");
+    assert!(!rootfs.join("written").exists());
+}
+
+#[test]
+#[ignore = "needs root"]
+fn fails_for_a_missing_command() {
+    require_root!();
+
+    carton()
+        .run::<&str>(&[], &busybox_rootfs(), "/bin/does-not-exist", &[])
+        .assert_exit_code(125)
+        .assert_stderr_contains("executing /bin/does-not-exist");
+}
+
+#[test]
+#[ignore = "needs root"]
+fn passes_stdin_to_the_command() {
+    require_root!();
+
+    carton()
+        .run_with_input::<&str>(&[], &busybox_rootfs(), "/bin/cat", &[], b"one\ntwo\n")
+        .assert_success()
+        .assert_stdout("one\ntwo\n");
+}
+
+#[test]
+#[ignore = "needs root"]
+fn records_where_settings_came_from() {
+    require_root!();
+    let carton = carton();
+
+    carton
+        .run(
+            &["--name", "box", "--hostname", "box"],
+            &busybox_rootfs(),
+            "/bin/true",
+            &[],
+        )
+        .assert_success();
+    carton
+        .command(&[
+            "inspect",
+            "--format",
+            "{{.settings.hostname.source}} {{.settings.network.source}}",
+            "box",
+        ])
+        .assert_success()
+        .assert_stdout("flag default\n");
+}
+
+#[test]
+#[ignore = "needs root"]
+fn passes_the_command_to_the_entrypoint() {
+    require_root!();
+
+    carton()
+        .run(&["--entrypoint", "/bin/echo"], &busybox_rootfs(), "hi", &[])
+        .assert_success()
+        .assert_stdout("hi\n");
+}
+
+#[test]
+#[ignore = "needs root"]
+fn takes_the_containers_of_a_project_down_together() {
+    require_root!();
+    let carton = carton();
+    let dir = std::env::temp_dir().join(format!("carton-test-project-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let manifest = dir.join("carton-project.toml");
+    std::fs::write(
+        &manifest,
+        format!(
+            "[containers.one]\nrootfs = {:?}\ncommand = [\"echo\", \"one\"]\n\n\
+             [containers.two]\nrootfs = {0:?}\ncommand = [\"echo\", \"two\"]\n",
+            busybox_rootfs()
+        ),
+    )
+    .unwrap();
+
+    carton
+        .command(&["up", "-p", "app", "-f", manifest.to_str().unwrap()])
+        .assert_success();
+    carton
+        .command(&["ps", "--all", "--project", "app", "--format", "{{.name}}"])
+        .assert_success()
+        .assert_stdout_contains("app-one\n")
+        .assert_stdout_contains("app-two\n");
+    carton.command(&["down", "app"]).assert_success();
+    carton
+        .command(&["ps", "--all", "--format", "{{.name}}"])
+        .assert_success()
+        .assert_stdout("");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+#[ignore = "needs root"]
+fn gives_the_container_its_secrets() {
+    require_root!();
+
+    carton()
+        .run(
+            &["--secret", "password=cmd:echo hunter2"],
+            &busybox_rootfs(),
+            "/bin/cat",
+            &["/run/secrets/password"],
+        )
+        .assert_success()
+        .assert_stdout("hunter2");
+}
+
+#[test]
+#[ignore = "needs root"]
+fn points_the_command_at_its_credentials() {
+    require_root!();
+
+    let credentials = env::temp_dir().join(format!("carton-test-credentials-{}", process::id()));
+    fs::create_dir_all(&credentials).unwrap();
+    fs::write(credentials.join("password"), "hunter2").unwrap();
+
+    // Only PATH of carton's environment gets through, so the variable has to be set in the
+    // container itself
+    let output = carton().env("CREDENTIALS_DIRECTORY", &credentials).run(
+        &["--env-host", "PATH"],
+        &busybox_rootfs(),
+        "/bin/sh",
+        &[
+            "-c",
+            "echo $CREDENTIALS_DIRECTORY; cat $CREDENTIALS_DIRECTORY/password",
+        ],
+    );
+    fs::remove_dir_all(&credentials).unwrap();
+
+    output
+        .assert_success()
+        .assert_stdout("/run/secrets\nhunter2");
+}
+
+#[test]
+#[ignore = "needs root"]
+fn gives_the_container_its_files() {
+    require_root!();
+
+    carton()
+        .run(
+            &["--file", "/etc/app/app.conf=port = 8080"],
+            &busybox_rootfs(),
+            "/bin/cat",
+            &["/etc/app/app.conf"],
+        )
+        .assert_success()
+        .assert_stdout("port = 8080");
+}
+
+#[test]
+#[ignore = "needs root"]
+fn limits_the_container_to_the_system_calls_it_learned() {
+    require_root!();
+
+    let carton = carton();
+    let profile = carton.state_dir().join("profile.json");
+    let profile = profile.to_str().unwrap();
+    carton
+        .run(
+            &["--seccomp-learn", profile],
+            &busybox_rootfs(),
+            "/bin/echo",
+            &["learned"],
+        )
+        .assert_success()
+        .assert_stderr_contains("wrote a seccomp profile");
+
+    carton
+        .run(
+            &["--seccomp-profile", profile],
+            &busybox_rootfs(),
+            "/bin/echo",
+            &["allowed"],
+        )
+        .assert_success()
+        .assert_stdout("allowed\n");
+    // Sleeping needs system calls that echo doesn't make
+    carton
+        .run(
+            &["--seccomp-profile", profile],
+            &busybox_rootfs(),
+            "/bin/sleep",
+            &["1"],
+        )
+        .assert_failure();
+}
+
+#[test]
+#[ignore = "needs root"]
+fn traces_the_system_calls_of_the_container() {
+    require_root!();
+
+    let carton = carton();
+    std::fs::create_dir_all(carton.state_dir()).unwrap();
+    let log = carton.state_dir().join("syscalls.log");
+    carton
+        .run(
+            &[
+                "--trace-syscalls",
+                log.to_str().unwrap(),
+                "--trace-syscalls-filter",
+                "openat",
+            ],
+            &busybox_rootfs(),
+            "/bin/cat",
+            &["/etc/missing"],
+        )
+        .assert_failure();
+
+    let log = std::fs::read_to_string(log).unwrap();
+    assert!(
+        log.contains("openat(0xffffff9c, \"/etc/missing\""),
+        "{}",
+        log
+    );
+    assert!(!log.contains("execve("), "{}", log);
+}
+
+#[test]
+#[ignore = "needs root"]
+fn stops_a_container_that_runs_out_of_time() {
+    require_root!();
+
+    // As the container's PID 1, sleep ignores SIGTERM and gets SIGKILL after the grace period
+    carton()
+        .run(
+            &["--timeout", "1", "--timeout-grace-period", "1"],
+            &busybox_rootfs(),
+            "/bin/sleep",
+            &["30"],
+        )
+        .assert_exit_code(124)
+        .assert_stderr_contains("ran out of time");
+}
+
+#[test]
+#[ignore = "needs root"]
+fn limits_the_size_of_dev_shm() {
+    require_root!();
+
+    carton()
+        .run(
+            &["--shm-size", "256m"],
+            &busybox_rootfs(),
+            "/bin/cat",
+            &["/proc/mounts"],
+        )
+        .assert_success()
+        .assert_stdout_contains("/dev/shm tmpfs rw,nosuid,nodev,noexec,relatime,size=262144k");
+}
+
+#[test]
+#[ignore = "needs root"]
+fn passes_the_wayland_socket_into_the_container() {
+    require_root!();
+    let runtime_dir =
+        std::env::temp_dir().join(format!("carton-test-display-{}", std::process::id()));
+    std::fs::create_dir_all(&runtime_dir).unwrap();
+    let _listener =
+        std::os::unix::net::UnixListener::bind(runtime_dir.join("wayland-test")).unwrap();
+
+    let output = carton()
+        .env("WAYLAND_DISPLAY", "wayland-test")
+        .env("XDG_RUNTIME_DIR", &runtime_dir)
+        .env("DISPLAY", "")
+        .run(
+            &["--display"],
+            &busybox_rootfs(),
+            "/bin/sh",
+            &[
+                "-c",
+                "echo $WAYLAND_DISPLAY $XDG_RUNTIME_DIR && ls /run/user/0",
+            ],
+        );
+    let _ = std::fs::remove_dir_all(&runtime_dir);
+    output
+        .assert_success()
+        .assert_stdout("wayland-test /run/user/0\nwayland-test\n");
+}
+
+#[test]
+#[ignore = "needs root"]
+fn reads_the_state_files_of_older_versions() {
+    require_root!();
+    let carton = carton();
+    carton
+        .run::<&str>(&[], &busybox_rootfs(), "/bin/sh", &["-c", "exit 3"])
+        .assert_exit_code(3);
+
+    // Rewritten into the layout from before state files had a schema version
+    let container_dir = std::fs::read_dir(carton.state_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.is_dir())
+        .unwrap();
+    let state_file = container_dir.join("state.json");
+    let mut state: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&state_file).unwrap()).unwrap();
+    let fields = state.as_object_mut().unwrap();
+    fields.remove("schema_version");
+    fields.remove("exit");
+    fields.insert("exit_code".into(), 3.into());
+    fields.insert("timed_out".into(), true.into());
+    std::fs::write(&state_file, serde_json::to_vec(&state).unwrap()).unwrap();
+
+    carton
+        .command(&["ps", "-a", "--format", "{{.exit.type}}"])
+        .assert_success()
+        .assert_stdout("timed_out\n");
+}
@@ -0,0 +1,118 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! A minimal init process for containers, like tini or dumb-init.
+//!
+//! The first process in a PID namespace (PID 1) has two jobs that other programs don't expect to
+//! have. The kernel doesn't apply the default action of a signal to it, so a program that doesn't
+//! install signal handlers can't be stopped with SIGTERM or Ctrl-C. And processes whose parent
+//! exits are handed to it, so it has to wait for them, or they stay around as zombies.
+//!
+//! carton-init runs as PID 1 instead of the command. It starts the command as its child, passes
+//! the signals it gets on to the command and reaps every process that ends up with it. When the
+//! command exits, carton-init exits with the same exit status.
+//!
+//! It has to run in any root filesystem, so it should be linked statically, for instance with
+//! `cargo build --release -p carton-init --target x86_64-unknown-linux-musl`.
+
+use std::env;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStringExt;
+use std::process::{self, ExitCode};
+
+use nix::errno::Errno;
+use nix::sys::signal::{self, SigSet, SigmaskHow, Signal};
+use nix::sys::wait::{self, WaitPidFlag, WaitStatus};
+use nix::unistd::{self, ForkResult, Pid};
+
+fn main() -> ExitCode {
+    let mut args = env::args_os().skip(1).peekable();
+    if args.peek().map_or(false, |arg| arg == "--") {
+        args.next();
+    }
+    let Ok(args) = args
+        .map(|arg| CString::new(arg.into_vec()))
+        .collect::<Result<Vec<CString>, _>>()
+    else {
+        eprintln!("carton-init: arguments can't contain NUL bytes");
+        return ExitCode::from(126);
+    };
+    if args.is_empty() {
+        eprintln!("usage: carton-init [--] COMMAND [ARGUMENT...]");
+        return ExitCode::from(2);
+    }
+
+    // Signals are picked up with sigwait() instead of with handlers, so none of them can get lost
+    // between starting the command and waiting for it
+    let signals = SigSet::all();
+    if let Err(e) = signal::sigprocmask(SigmaskHow::SIG_BLOCK, Some(&signals), None) {
+        eprintln!("carton-init: could not block signals: {}", e);
+        return ExitCode::from(126);
+    }
+
+    let child = match unsafe { unistd::fork() } {
+        Ok(ForkResult::Child) => execute_command(&args),
+        Ok(ForkResult::Parent { child }) => child,
+        Err(e) => {
+            eprintln!("carton-init: could not start the command: {}", e);
+            return ExitCode::from(126);
+        }
+    };
+
+    loop {
+        match signals.wait() {
+            Ok(Signal::SIGCHLD) => {
+                if let Some(exit_code) = reap(child) {
+                    return ExitCode::from(exit_code);
+                }
+            }
+            Ok(signal) => {
+                let _ = signal::kill(child, signal);
+            }
+            Err(e) => {
+                eprintln!("carton-init: could not wait for signals: {}", e);
+                return ExitCode::from(1);
+            }
+        }
+    }
+}
+
+/// Executes the command in the child process.
+fn execute_command(args: &[CString]) -> ! {
+    // The command gets a process group of its own, which becomes the terminal's foreground group.
+    // Otherwise a Ctrl-C would reach it twice: once from the terminal and once from carton-init.
+    // Signals are still blocked here, so tcsetpgrp() doesn't stop the process with SIGTTOU.
+    let _ = unistd::setpgid(Pid::from_raw(0), Pid::from_raw(0));
+    let _ = unistd::tcsetpgrp(0, unistd::getpid());
+    let _ = signal::sigprocmask(SigmaskHow::SIG_UNBLOCK, Some(&SigSet::all()), None);
+
+    // Sockets passed with socket activation are meant for the command, not for carton-init
+    if env::var("LISTEN_PID").ok() == Some(unistd::getppid().to_string()) {
+        env::set_var("LISTEN_PID", unistd::getpid().to_string());
+    }
+
+    let e = unistd::execvp(&args[0], args).unwrap_err();
+    eprintln!(
+        "carton-init: could not execute {}: {}",
+        args[0].to_string_lossy(),
+        e
+    );
+    process::exit(if e == Errno::ENOENT { 127 } else { 126 })
+}
+
+/// Waits for all processes that have exited. Returns the exit code for carton-init once the
+/// command is one of them, which is 128 + the signal number if the command was killed by a signal.
+fn reap(child: Pid) -> Option<u8> {
+    let mut exit_code = None;
+    loop {
+        match wait::waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::Exited(pid, code)) if pid == child => exit_code = Some(code as u8),
+            Ok(WaitStatus::Signaled(pid, signal, _)) if pid == child => {
+                exit_code = Some(128 + signal as i32 as u8)
+            }
+            Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => return exit_code,
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(_) => return exit_code,
+        }
+    }
+}
@@ -0,0 +1,388 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers for end-to-end tests that run real containers with the `carton` binary.
+//!
+//! Containers need root (or at least a user namespace with subordinate IDs), so these tests are
+//! marked `#[ignore]` and start with `require_root!()`. Run them in a virtual machine with:
+//!
+//! ```text
+//! sudo -E cargo test -p carton -p libcarton -- --ignored
+//! ```
+//!
+//! The root filesystem of the containers is a tiny busybox one, see `busybox_rootfs()`. Busybox
+//! is downloaded the first time (and checked against its SHA-256 digest), unless
+//! `CARTON_TEST_BUSYBOX` points to a statically linked busybox binary, or `CARTON_TEST_ROOTFS` to
+//! a root filesystem to use instead. The download is an x86_64 binary, so on other architectures
+//! one of those has to be set.
+//!
+//! See `crates/carton_bin/tests/` and `crates/libcarton/tests/` for examples.
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// Statically linked busybox that `busybox_rootfs()` downloads.
+const BUSYBOX_URL: &str = "https://busybox.net/downloads/binaries/1.35.0-x86_64-linux-musl/busybox";
+/// SHA-256 digest of the download at `BUSYBOX_URL`.
+const BUSYBOX_SHA256: &str = "6e123e7f3202a8c1e9b1f94d8941580a25135382b99e8d3e34fb858bba311348";
+/// Directories that carton mounts things on, or that programs expect to exist.
+const ROOTFS_DIRS: [&str; 9] = [
+    "bin", "dev", "etc", "proc", "root", "run", "sys", "tmp", "var",
+];
+
+static ROOTFS: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Returns early from a test that needs root, with a note on stderr, when it doesn't run as root.
+#[macro_export]
+macro_rules! require_root {
+    () => {
+        if !$crate::is_root() {
+            eprintln!("skipping {}: needs root", module_path!());
+            return;
+        }
+    };
+}
+
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Returns the path of a busybox root filesystem, which is built once and then shared by all tests
+/// (and test runs). Tests shouldn't write to it; `carton run --ephemeral` keeps it as it is.
+///
+/// Panics when there's no busybox to build it from.
+pub fn busybox_rootfs() -> PathBuf {
+    if let Some(rootfs) = env::var_os("CARTON_TEST_ROOTFS") {
+        return rootfs.into();
+    }
+
+    let mut rootfs = ROOTFS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(rootfs) = &*rootfs {
+        return rootfs.clone();
+    }
+
+    let path = env::temp_dir().join("carton-test-busybox-rootfs");
+    if !path.join("bin/busybox").exists() {
+        build_busybox_rootfs(&path);
+    }
+    *rootfs = Some(path.clone());
+
+    path
+}
+
+/// Builds the root filesystem in a directory next to `path`, which is renamed to `path` when it's
+/// done, so that other test runs never see half of it.
+fn build_busybox_rootfs(path: &Path) {
+    let building = path.with_extension(format!("building-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&building);
+    for dir in ROOTFS_DIRS {
+        fs::create_dir_all(building.join(dir)).expect("creating root filesystem directory");
+    }
+
+    let busybox = building.join("bin/busybox");
+    match env::var_os("CARTON_TEST_BUSYBOX") {
+        Some(binary) => {
+            fs::copy(&binary, &busybox).expect("copying CARTON_TEST_BUSYBOX");
+        }
+        None => {
+            if cfg!(not(target_arch = "x86_64")) {
+                panic!(
+                    "the busybox that the tests download is an x86_64 binary, set \
+                     CARTON_TEST_BUSYBOX to a statically linked busybox binary for this \
+                     architecture instead"
+                );
+            }
+            let status = Command::new("curl")
+                .args([
+                    "--silent",
+                    "--show-error",
+                    "--fail",
+                    "--location",
+                    "--output",
+                ])
+                .arg(&busybox)
+                .arg(BUSYBOX_URL)
+                .status()
+                .expect("running curl to download busybox");
+            assert!(
+                status.success(),
+                "could not download busybox from {}, set CARTON_TEST_BUSYBOX to a statically \
+                 linked busybox binary instead",
+                BUSYBOX_URL
+            );
+            verify_download(&busybox, &building);
+        }
+    }
+    fs::set_permissions(&busybox, fs::Permissions::from_mode(0o755))
+        .expect("making busybox executable");
+
+    let applets = Command::new(&busybox)
+        .arg("--list")
+        .output()
+        .expect("listing the busybox applets");
+    for applet in String::from_utf8_lossy(&applets.stdout).lines() {
+        if applet != "busybox" {
+            symlink("busybox", building.join("bin").join(applet)).expect("linking busybox applet");
+        }
+    }
+
+    fs::write(
+        building.join("etc/passwd"),
+        "root:x:0:0:root:/root:/bin/sh\nnobody:x:65534:65534:nobody:/:/bin/false\n",
+    )
+    .expect("writing /etc/passwd");
+    fs::write(building.join("etc/group"), "root:x:0:\nnogroup:x:65534:\n")
+        .expect("writing /etc/group");
+    // Carton bind mounts these on top of the files when it manages the container's network
+    for file in ["etc/hosts", "etc/resolv.conf"] {
+        fs::write(building.join(file), "").expect("creating network file");
+    }
+
+    // Another test run may have been quicker, in which case its root filesystem is just as good
+    if fs::rename(&building, path).is_err() {
+        let _ = fs::remove_dir_all(&building);
+    }
+}
+
+/// Checks the digest of the busybox that was downloaded to `busybox`, and removes the root
+/// filesystem that's being built in `building` when it's not what it should be.
+fn verify_download(busybox: &Path, building: &Path) {
+    let output = Command::new("sha256sum")
+        .arg(busybox)
+        .output()
+        .expect("running sha256sum to verify busybox");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let digest = stdout.split_whitespace().next().unwrap_or_default();
+    if !output.status.success() || digest != BUSYBOX_SHA256 {
+        let _ = fs::remove_dir_all(building);
+        panic!(
+            "the busybox downloaded from {} has SHA-256 digest {:?} instead of {}, set \
+             CARTON_TEST_BUSYBOX to a statically linked busybox binary instead",
+            BUSYBOX_URL, digest, BUSYBOX_SHA256
+        );
+    }
+}
+
+/// Runs the `carton` binary, with a state directory of its own that is removed when it's dropped.
+#[derive(Debug)]
+pub struct Carton {
+    binary: PathBuf,
+    state_dir: PathBuf,
+    envs: Vec<(OsString, OsString)>,
+}
+
+impl Carton {
+    /// `binary` is the `carton` executable, which is `env!("CARGO_BIN_EXE_carton")` in the tests
+    /// of the `carton` package.
+    pub fn new<P: AsRef<Path>>(binary: P) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let state_dir = env::temp_dir().join(format!(
+            "carton-test-state-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        Carton {
+            binary: binary.as_ref().into(),
+            state_dir,
+            envs: Vec::new(),
+        }
+    }
+
+    /// Sets the environment variable `name` of the `carton` processes to `value`.
+    pub fn env<N: AsRef<OsStr>, V: AsRef<OsStr>>(mut self, name: N, value: V) -> Self {
+        self.envs
+            .push((name.as_ref().into(), value.as_ref().into()));
+        self
+    }
+
+    pub fn state_dir(&self) -> &Path {
+        &self.state_dir
+    }
+
+    /// Runs `carton run <options> <rootfs> <command> <arguments>` and waits for it to exit.
+    pub fn run<S: AsRef<OsStr>>(
+        &self,
+        options: &[S],
+        rootfs: &Path,
+        command: &str,
+        arguments: &[&str],
+    ) -> RunOutput {
+        self.output(&run_args(options, rootfs, command, arguments), None)
+    }
+
+    /// Like `run()`, with `input` piped into carton's stdin.
+    pub fn run_with_input<S: AsRef<OsStr>>(
+        &self,
+        options: &[S],
+        rootfs: &Path,
+        command: &str,
+        arguments: &[&str],
+        input: &[u8],
+    ) -> RunOutput {
+        self.output(&run_args(options, rootfs, command, arguments), Some(input))
+    }
+
+    /// Runs `carton <args>`, like `carton ps --all`, and waits for it to exit.
+    pub fn command<S: AsRef<OsStr>>(&self, args: &[S]) -> RunOutput {
+        self.output(args, None)
+    }
+
+    fn output<S: AsRef<OsStr>>(&self, args: &[S], input: Option<&[u8]>) -> RunOutput {
+        let mut child = Command::new(&self.binary)
+            .arg("--state-dir")
+            .arg(&self.state_dir)
+            .args(args)
+            .envs(self.envs.iter().cloned())
+            .stdin(if input.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap_or_else(|e| panic!("running {}: {}", self.binary.display(), e));
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            // Dropping stdin closes it, which carton passes on as the end of the input. It's
+            // written from a thread of its own, because carton doesn't read all of it when the
+            // command exits early.
+            let input = input.to_vec();
+            thread::spawn(move || stdin.write_all(&input));
+        }
+        let output = child
+            .wait_with_output()
+            .unwrap_or_else(|e| panic!("running {}: {}", self.binary.display(), e));
+
+        RunOutput {
+            description: format!(
+                "carton {}",
+                args.iter()
+                    .map(|arg| arg.as_ref().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+            output,
+        }
+    }
+}
+
+fn run_args<'a, S: AsRef<OsStr>>(
+    options: &'a [S],
+    rootfs: &'a Path,
+    command: &'a str,
+    arguments: &[&'a str],
+) -> Vec<&'a OsStr> {
+    let mut args = vec![OsStr::new("run")];
+    args.extend(options.iter().map(AsRef::as_ref));
+    // Otherwise carton takes the command's options, like `sh -c`, for its own
+    args.push(OsStr::new("--"));
+    args.push(rootfs.as_os_str());
+    args.push(OsStr::new(command));
+    args.extend(arguments.iter().map(|argument| OsStr::new(*argument)));
+
+    args
+}
+
+impl Drop for Carton {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.state_dir);
+    }
+}
+
+/// What a `carton` command printed and how it exited, with assertions that show both when they
+/// fail.
+#[derive(Debug)]
+pub struct RunOutput {
+    description: String,
+    output: Output,
+}
+
+impl RunOutput {
+    pub fn exit_code(&self) -> Option<i32> {
+        self.output.status.code()
+    }
+
+    pub fn stdout(&self) -> String {
+        String::from_utf8_lossy(&self.output.stdout).into_owned()
+    }
+
+    pub fn stderr(&self) -> String {
+        String::from_utf8_lossy(&self.output.stderr).into_owned()
+    }
+
+    pub fn assert_success(&self) -> &Self {
+        self.assert_exit_code(0)
+    }
+
+    /// Asserts that carton exited with `exit_code`, which is the exit code of the container's
+    /// command when the container ran.
+    pub fn assert_exit_code(&self, exit_code: i32) -> &Self {
+        assert_eq!(
+            self.exit_code(),
+            Some(exit_code),
+            "unexpected exit code of `{}`\n{}",
+            self.description,
+            self
+        );
+        self
+    }
+
+    pub fn assert_failure(&self) -> &Self {
+        assert!(
+            !self.output.status.success(),
+            "`{}` should have failed\n{}",
+            self.description,
+            self
+        );
+        self
+    }
+
+    pub fn assert_stdout(&self, expected: &str) -> &Self {
+        assert_eq!(
+            self.stdout(),
+            expected,
+            "unexpected stdout of `{}`\n{}",
+            self.description,
+            self
+        );
+        self
+    }
+
+    pub fn assert_stdout_contains(&self, expected: &str) -> &Self {
+        assert!(
+            self.stdout().contains(expected),
+            "stdout of `{}` doesn't contain {:?}\n{}",
+            self.description,
+            expected,
+            self
+        );
+        self
+    }
+
+    pub fn assert_stderr_contains(&self, expected: &str) -> &Self {
+        assert!(
+            self.stderr().contains(expected),
+            "stderr of `{}` doesn't contain {:?}\n{}",
+            self.description,
+            expected,
+            self
+        );
+        self
+    }
+}
+
+impl std::fmt::Display for RunOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "--- stdout\n{}", self.stdout())?;
+        write!(f, "--- stderr\n{}", self.stderr())
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Passes listening sockets into a container, the way systemd's [socket activation][1] passes them
+//! to a service.
+//!
+//! With socket activation systemd creates the listening sockets of a service and starts the
+//! service when the first connection comes in. The sockets are file descriptors 3 and up of the
+//! service's process, and the environment variables `LISTEN_FDS` (the number of sockets),
+//! `LISTEN_PID` (the PID they are meant for) and optionally `LISTEN_FDNAMES` describe them. When
+//! the service is carton, it has to hand the sockets on to the container's process in the same
+//! way.
+//!
+//! [1]: https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html
+
+use std::env;
+use std::os::fd::RawFd;
+
+use nix::fcntl::{self, FcntlArg, FdFlag};
+use nix::unistd;
+
+/// The first file descriptor that is passed, after stdin, stdout and stderr.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// File descriptors of listening sockets to pass into a container.
+#[derive(Debug, Clone, Default)]
+pub struct ListenFds {
+    fds: Vec<RawFd>,
+    /// Names of the sockets, for `LISTEN_FDNAMES`.
+    names: Vec<String>,
+}
+
+impl ListenFds {
+    /// The file descriptors have to stay open until the container is prepared. They can be any
+    /// file descriptors, inside the container they are renumbered to 3 and up, in this order.
+    pub fn new(fds: Vec<RawFd>) -> Self {
+        ListenFds {
+            fds,
+            names: Vec::new(),
+        }
+    }
+
+    /// Sets the names of the sockets, which are usually the names of the systemd socket units
+    /// that the service was started for.
+    pub fn names(mut self, names: Vec<String>) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Takes the sockets that systemd passed to the calling process, if any, like
+    /// `sd_listen_fds()` does. The environment variables are removed, and the sockets are marked
+    /// as close-on-exec so that other programs that the calling process runs don't get them.
+    pub fn from_env() -> Option<Self> {
+        let pid = env::var("LISTEN_PID").ok()?.parse::<i32>().ok()?;
+        let count = env::var("LISTEN_FDS").ok()?.parse::<RawFd>().ok()?;
+        let names = env::var("LISTEN_FDNAMES")
+            .map(|names| names.split(':').map(String::from).collect())
+            .unwrap_or_default();
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(name);
+        }
+
+        // The variables were meant for another process, which started this one
+        if pid != unistd::getpid().as_raw() || count <= 0 {
+            return None;
+        }
+
+        let fds = (LISTEN_FDS_START..LISTEN_FDS_START + count).collect::<Vec<RawFd>>();
+        for fd in &fds {
+            let _ = fcntl::fcntl(*fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC));
+        }
+
+        Some(ListenFds::new(fds).names(names))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fds.is_empty()
+    }
+
+    /// Moves the file descriptors to 3 and up and sets the environment variables. Called by the
+    /// container process right before it executes the command, when none of its own file
+    /// descriptors are needed anymore.
+    pub(crate) fn install(&self) -> nix::Result<()> {
+        // First move every file descriptor out of the way of the range they're renumbered to, so
+        // that none of them gets overwritten before it's been moved itself
+        let end = LISTEN_FDS_START + self.fds.len() as RawFd;
+        let moved = self
+            .fds
+            .iter()
+            .map(|fd| fcntl::fcntl(*fd, FcntlArg::F_DUPFD_CLOEXEC(end)))
+            .collect::<nix::Result<Vec<RawFd>>>()?;
+
+        // The copies that dup2() makes are not close-on-exec, so they survive execv()
+        for (target, fd) in (LISTEN_FDS_START..).zip(moved) {
+            unistd::dup2(fd, target)?;
+            unistd::close(fd)?;
+        }
+
+        env::set_var("LISTEN_FDS", self.fds.len().to_string());
+        env::set_var("LISTEN_PID", unistd::getpid().to_string());
+        if !self.names.is_empty() {
+            env::set_var("LISTEN_FDNAMES", self.names.join(":"));
+        }
+
+        Ok(())
+    }
+}
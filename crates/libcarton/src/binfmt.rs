@@ -0,0 +1,216 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Running root filesystems of another architecture, like arm64 images on an x86_64 host, with
+//! the user mode emulators of QEMU, see `ContainerBuilder::emulate()`.
+//!
+//! The kernel's binfmt_misc hands executables whose first bytes match a pattern to an interpreter.
+//! Carton registers the static emulator (`qemu-aarch64-static` and the like) for the ELF binaries
+//! of the architecture, with the `F` flag: the kernel opens the emulator right away, so that it
+//! also works in mount namespaces in which its path doesn't exist. The emulator is bind mounted
+//! into the container at the same path anyway, for registrations that were made without that flag.
+//!
+//! The registration is for the whole host and stays when the container exits, like the ones that
+//! distributions' qemu-user-static packages make. An existing registration for the architecture is
+//! left alone.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use nix::mount::{self, MsFlags};
+use nix::sys::statfs::{self, FsType};
+
+use crate::diagnostics::Reporter;
+use crate::error::CartonError;
+
+const BINFMT_MISC_DIR: &str = "/proc/sys/fs/binfmt_misc";
+/// The magic number of the binfmt_misc filesystem, "BINM".
+const BINFMT_MISC_MAGIC: FsType = FsType(0x4249_4e4d);
+/// Where distributions install the emulators.
+const EMULATOR_DIRS: [&str; 2] = ["/usr/bin", "/usr/local/bin"];
+
+/// An architecture whose binaries can be emulated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture {
+    Amd64,
+    Arm64,
+    Arm,
+    Riscv64,
+    Ppc64le,
+    S390x,
+}
+
+impl Architecture {
+    /// Returns QEMU's name of the architecture, which its emulators are named after.
+    fn qemu_name(self) -> &'static str {
+        match self {
+            Architecture::Amd64 => "x86_64",
+            Architecture::Arm64 => "aarch64",
+            Architecture::Arm => "arm",
+            Architecture::Riscv64 => "riscv64",
+            Architecture::Ppc64le => "ppc64le",
+            Architecture::S390x => "s390x",
+        }
+    }
+
+    /// Returns the first bytes of the architecture's ELF executables, and the mask of the bytes
+    /// that matter, as binfmt_misc takes them. These are the ones of QEMU's
+    /// `qemu-binfmt-conf.sh`: the ELF class and byte order, the type (executable or shared object)
+    /// and the machine.
+    fn elf_magic(self) -> (&'static str, &'static str) {
+        const MASK: &str =
+            r"\xff\xff\xff\xff\xff\xff\xff\x00\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\xff";
+
+        match self {
+            Architecture::Amd64 => (
+                r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x3e\x00",
+                MASK,
+            ),
+            Architecture::Arm64 => (
+                r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\xb7\x00",
+                MASK,
+            ),
+            Architecture::Arm => (
+                r"\x7fELF\x01\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x28\x00",
+                MASK,
+            ),
+            Architecture::Riscv64 => (
+                r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\xf3\x00",
+                MASK,
+            ),
+            Architecture::Ppc64le => (
+                r"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x15\x00",
+                r"\xff\xff\xff\xff\xff\xff\xff\xfc\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff\x00",
+            ),
+            Architecture::S390x => (
+                r"\x7fELF\x02\x02\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x16",
+                r"\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xfe\xff\xff",
+            ),
+        }
+    }
+
+    /// Whether this is the architecture that carton was built for, which needs no emulator.
+    fn is_native(self) -> bool {
+        self.qemu_name() == std::env::consts::ARCH
+            || (self == Architecture::Ppc64le && std::env::consts::ARCH == "powerpc64")
+    }
+}
+
+/// Parses an architecture like `arm64` or `aarch64`, optionally as a platform like `linux/arm64`.
+impl FromStr for Architecture {
+    type Err = CartonError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.strip_prefix("linux/").unwrap_or(value) {
+            "amd64" | "x86_64" => Ok(Architecture::Amd64),
+            "arm64" | "aarch64" => Ok(Architecture::Arm64),
+            "arm" | "armhf" | "arm/v7" => Ok(Architecture::Arm),
+            "riscv64" => Ok(Architecture::Riscv64),
+            "ppc64le" => Ok(Architecture::Ppc64le),
+            "s390x" => Ok(Architecture::S390x),
+            _ => Err(CartonError::InvalidConfiguration(format!(
+                "unknown architecture `{}`, carton can emulate amd64, arm64, arm, riscv64, \
+                 ppc64le and s390x",
+                value
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Architecture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Architecture::Amd64 => "amd64",
+            Architecture::Arm64 => "arm64",
+            Architecture::Arm => "arm",
+            Architecture::Riscv64 => "riscv64",
+            Architecture::Ppc64le => "ppc64le",
+            Architecture::S390x => "s390x",
+        };
+
+        f.pad(name)
+    }
+}
+
+/// The emulator of a container, see `ContainerBuilder::emulate()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Emulator {
+    pub(crate) architecture: Architecture,
+    /// Path of the static QEMU user mode emulator on the host.
+    pub(crate) path: PathBuf,
+}
+
+impl Emulator {
+    /// Finds the emulator for `architecture` on the host. Returns `None` when the host runs the
+    /// architecture's binaries itself.
+    pub(crate) fn find(architecture: Architecture) -> Result<Option<Self>, CartonError> {
+        if architecture.is_native() {
+            return Ok(None);
+        }
+
+        let name = format!("qemu-{}-static", architecture.qemu_name());
+        let path = EMULATOR_DIRS
+            .iter()
+            .map(|dir| Path::new(dir).join(&name))
+            .find(|path| path.is_file())
+            .ok_or_else(|| {
+                CartonError::InvalidConfiguration(format!(
+                    "running {} binaries needs {}, which isn't installed (it's in the \
+                     qemu-user-static package of most distributions)",
+                    architecture, name
+                ))
+            })?;
+
+        Ok(Some(Emulator { architecture, path }))
+    }
+
+    /// Registers the emulator with binfmt_misc, unless the architecture has a registration
+    /// already.
+    pub(crate) fn register(&self, reporter: &Reporter) -> Result<(), CartonError> {
+        let binfmt_misc = Path::new(BINFMT_MISC_DIR);
+        let name = format!("qemu-{}", self.architecture.qemu_name());
+        if !is_mounted(binfmt_misc) {
+            reporter.operation(
+                format_args!(
+                    "mount(\"binfmt_misc\", {:?}, \"binfmt_misc\", 0, NULL)",
+                    binfmt_misc
+                ),
+                mount::mount(
+                    Some("binfmt_misc"),
+                    binfmt_misc,
+                    Some("binfmt_misc"),
+                    MsFlags::empty(),
+                    None::<&str>,
+                ),
+            )?;
+        }
+        if binfmt_misc.join(&name).exists() {
+            reporter.info(format_args!(
+                "{} binaries are run by the existing binfmt_misc registration {}",
+                self.architecture, name
+            ));
+            return Ok(());
+        }
+
+        let (magic, mask) = self.architecture.elf_magic();
+        let registration = format!(":{}:M::{}:{}:{}:F", name, magic, mask, self.path.display());
+        reporter.info(format_args!(
+            "registering {} for {} binaries with binfmt_misc",
+            self.path.display(),
+            self.architecture
+        ));
+        let register = binfmt_misc.join("register");
+        reporter.operation(
+            format_args!("write({:?}, {:?})", register, registration),
+            fs::write(&register, &registration),
+        )?;
+
+        Ok(())
+    }
+}
+
+fn is_mounted(path: &Path) -> bool {
+    statfs::statfs(path).map_or(false, |fs| fs.filesystem_type() == BINFMT_MISC_MAGIC)
+}
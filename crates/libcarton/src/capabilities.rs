@@ -0,0 +1,90 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Hints about why a system call of the setup was denied, for `CartonError::PermissionDenied`.
+//!
+//! Most of the setup needs a capability, like `CAP_SYS_ADMIN` for mounts. A process in a user
+//! namespace has all capabilities, but only over that namespace and what it owns: device nodes
+//! and some filesystems still need the capability in the host's user namespace. So what a denial
+//! means depends on whether the process has the capability at all (its effective set in
+//! /proc/self/status) and on the user namespace it's in. When it has the capability outside of a
+//! user namespace, something other than capabilities denied the call, like AppArmor, SELinux or a
+//! seccomp filter of whatever started carton.
+
+use std::fs;
+
+use nix::unistd;
+
+use crate::namespace;
+
+/// A capability that a system call of the setup needs, see capabilities(7).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Capability {
+    SysChroot,
+    SysAdmin,
+    Mknod,
+}
+
+impl Capability {
+    fn name(self) -> &'static str {
+        match self {
+            Capability::SysChroot => "CAP_SYS_CHROOT",
+            Capability::SysAdmin => "CAP_SYS_ADMIN",
+            Capability::Mknod => "CAP_MKNOD",
+        }
+    }
+
+    /// The number of the capability, which is its bit in the capability sets.
+    fn number(self) -> u32 {
+        match self {
+            Capability::SysChroot => 18,
+            Capability::SysAdmin => 21,
+            Capability::Mknod => 27,
+        }
+    }
+}
+
+/// Returns a hint about why a system call that needs `capability` was denied to this process.
+pub(crate) fn hint(capability: Option<Capability>) -> String {
+    let in_user_namespace = namespace::in_user_namespace();
+
+    let Some(capability) = capability else {
+        return format!(
+            "user {} isn't allowed to by the permissions of the files involved",
+            unistd::geteuid()
+        );
+    };
+
+    match (has_capability(capability), in_user_namespace) {
+        (Some(false), true) => format!("missing {} in this user namespace", capability.name()),
+        (Some(false), false) if unistd::geteuid().is_root() => format!(
+            "missing {}, which whatever started carton took away",
+            capability.name()
+        ),
+        (Some(false), false) => {
+            format!("missing {}, carton needs to run as root", capability.name())
+        }
+        (Some(true), true) => format!(
+            "{} in this user namespace isn't enough, this needs it in the host's user namespace",
+            capability.name()
+        ),
+        (Some(true), false) => format!(
+            "the process has {}, so a security module (AppArmor, SELinux) or a seccomp filter \
+             probably denied it",
+            capability.name()
+        ),
+        (None, _) => format!("this needs {}", capability.name()),
+    }
+}
+
+/// Returns whether `capability` is in the effective set of this process, if that can be read.
+fn has_capability(capability: Capability) -> Option<bool> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let effective = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?
+        .trim();
+    let effective = u64::from_str_radix(effective, 16).ok()?;
+
+    Some(effective & (1 << capability.number()) != 0)
+}
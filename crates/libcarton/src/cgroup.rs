@@ -0,0 +1,379 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! A cgroup (v2) that is handed over to the container, so that it can manage cgroups of its own.
+//!
+//! Programs like systemd and container runtimes organize the processes they start in cgroups. For
+//! that to work inside a container, the container needs a cgroup subtree it can write to, and a
+//! cgroup namespace in which that subtree looks like the root of the cgroup hierarchy. Carton
+//! creates a cgroup per container under `<cgroup2 mount>/carton/`, moves the container process
+//! into it before the container unshares its cgroup namespace, and mounts a cgroup2 filesystem at
+//! /sys/fs/cgroup inside the container.
+//!
+//! Controllers (cpu, memory, etc.) can only be handed down the tree when the parent cgroup enables
+//! them in its `cgroup.subtree_control`, so carton enables whatever is available on the way down
+//! to the container's cgroup. The container then enables them for its own children, after moving
+//! its processes out of its root cgroup, because a cgroup with processes in it can't hand out
+//! controllers ("no internal processes" rule).
+//!
+//! Root in a container with a user namespace isn't root on the host, so the cgroup is handed to
+//! the host user that root in the container maps to, like systemd delegates cgroups to users.
+//!
+//! The limits of the cgroup itself (`memory.max`, `cpu.max`) aren't handed over, so the container
+//! can't raise them. Carton sets them from the outside, also while the container runs, see
+//! `Container::update_limits()`. The same goes for the freezer (`cgroup.freeze`), which pauses all
+//! of the container's processes, see `Container::pause()`.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use nix::sys::statfs::{self, CGROUP2_SUPER_MAGIC};
+use nix::unistd::{self, Gid, Pid, Uid};
+
+use crate::container::{ContainerState, Mount};
+use crate::diagnostics::Reporter;
+use crate::error::CartonError;
+use crate::plan::{PlanStep, PID_PLACEHOLDER};
+use crate::rollback::{Rollback, Step};
+use crate::state::ContainerInfo;
+
+/// Where cgroup v2 is mounted, on systems with only cgroup v2 and on "hybrid" systems.
+const CGROUP2_MOUNTS: [&str; 2] = ["/sys/fs/cgroup", "/sys/fs/cgroup/unified"];
+/// Parent of the cgroups of all containers.
+const CARTON_CGROUP: &str = "carton";
+/// The period of `cpu.max`, in microseconds, which is the kernel's default.
+const CPU_PERIOD_USEC: u64 = 100_000;
+/// The smallest quota that `cpu.max` accepts, in microseconds.
+const MIN_CPU_QUOTA_USEC: u64 = 1_000;
+
+/// Limits on what a container with a cgroup of its own can use. Limits that are `None` stay as
+/// they are.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResourceLimits {
+    /// The most memory that the container can use, in bytes. When it uses more, the kernel
+    /// reclaims memory from it, and kills one of its processes if that's not enough.
+    pub memory: Option<u64>,
+    /// How many CPUs' worth of time the container can use, like 1.5.
+    pub cpus: Option<f64>,
+}
+
+/// Returns the bind mount of a fresh cgroup2 filesystem for the container. Mounted from inside the
+/// container's cgroup namespace, it shows the container's cgroup as the root.
+pub(crate) fn mount() -> Mount {
+    Mount::cgroup2("sys/fs/cgroup".into())
+}
+
+pub(crate) fn validate() -> Result<(), CartonError> {
+    cgroup2_root().map(drop)
+}
+
+/// Creates the cgroup of the container `id` and moves its process `pid` into it, giving it to the
+/// host user and group `owner` if there is one. Returns the path of the cgroup.
+pub(crate) fn create(
+    reporter: &Reporter,
+    id: &str,
+    pid: Pid,
+    owner: Option<(Uid, Gid)>,
+    rollback: &Rollback,
+) -> Result<PathBuf, CartonError> {
+    let root = cgroup2_root()?;
+    let parent = containers_cgroup(&root);
+    fs::create_dir_all(&parent)?;
+
+    enable_controllers(&root);
+    enable_controllers(&parent);
+
+    let path = parent.join(id);
+    fs::create_dir_all(&path)?;
+    rollback.record(Step::Cgroup(path.clone()));
+    if let Some((uid, gid)) = owner {
+        // The files that a delegated cgroup's owner needs to write, see "Delegation Containment"
+        // in the kernel's cgroup-v2 documentation
+        for file in [
+            "",
+            "cgroup.procs",
+            "cgroup.threads",
+            "cgroup.subtree_control",
+        ] {
+            let file = path.join(file);
+            reporter.operation(
+                format_args!("chown({:?}, {}, {})", file, uid, gid),
+                unistd::chown(&file, Some(uid), Some(gid)),
+            )?;
+        }
+    }
+    info!(
+        "moving the container process into cgroup {}",
+        path.display()
+    );
+    let procs = path.join("cgroup.procs");
+    reporter.operation(
+        format_args!("write({:?}, \"{}\")", procs, pid),
+        fs::write(&procs, pid.to_string()),
+    )?;
+
+    Ok(path)
+}
+
+/// Returns the path of the cgroup of the container `id`, if it has one.
+pub(crate) fn path(id: &str) -> Option<PathBuf> {
+    let path = containers_cgroup(&cgroup2_root().ok()?).join(id);
+
+    path.is_dir().then_some(path)
+}
+
+/// Returns the cgroup v2 of process `pid` (or `self`), relative to the root of the hierarchy, or
+/// `None` without cgroup v2. That's on the line of `/proc/<pid>/cgroup` that starts with `0::`.
+pub(crate) fn of_process(pid: &str) -> Result<Option<PathBuf>, CartonError> {
+    let cgroups = fs::read_to_string(format!("/proc/{}/cgroup", pid))?;
+
+    Ok(cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| PathBuf::from(path.trim_start_matches('/'))))
+}
+
+/// Returns the PIDs of the processes in the cgroup at `path` and in the cgroups below it, which a
+/// container can create in its own. Processes that exit in the meantime may be among them.
+pub(crate) fn pids(path: &Path) -> Result<Vec<i32>, CartonError> {
+    let mut pids = fs::read_to_string(path.join("cgroup.procs"))?
+        .lines()
+        .filter_map(|line| line.parse().ok())
+        .collect::<Vec<i32>>();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            pids.extend(self::pids(&entry.path())?);
+        }
+    }
+
+    Ok(pids)
+}
+
+/// Kills all processes in the cgroup at `path` and below it with SIGKILL, including the ones
+/// that they fork meanwhile. Returns false when the kernel can't, which it can since Linux 5.14.
+pub(crate) fn kill(path: &Path) -> Result<bool, CartonError> {
+    let file = path.join("cgroup.kill");
+    if !file.exists() {
+        return Ok(false);
+    }
+    info!("killing the processes of cgroup {}", path.display());
+    fs::write(&file, "1")?;
+
+    Ok(true)
+}
+
+/// Changes the limits of the cgroup of the running container of `info`, like
+/// `Container::update_limits()` does for a container of this process.
+pub fn update_limits(info: &ContainerInfo, limits: &ResourceLimits) -> Result<(), CartonError> {
+    if info.state != ContainerState::Running {
+        return Err(CartonError::NotRunning);
+    }
+    let Some(path) = path(&info.id) else {
+        return Err(no_cgroup(&info.id));
+    };
+
+    set_limits(&path, limits)
+}
+
+/// Pauses the running container of `info`, like `Container::pause()` does for a container of this
+/// process.
+pub fn pause_container(info: &ContainerInfo) -> Result<(), CartonError> {
+    freeze_container(info, true)
+}
+
+/// Resumes the paused container of `info`, like `Container::resume()` does for a container of this
+/// process.
+pub fn resume_container(info: &ContainerInfo) -> Result<(), CartonError> {
+    freeze_container(info, false)
+}
+
+fn freeze_container(info: &ContainerInfo, frozen: bool) -> Result<(), CartonError> {
+    if info.state != ContainerState::Running {
+        return Err(CartonError::NotRunning);
+    }
+    let Some(path) = path(&info.id) else {
+        return Err(no_cgroup(&info.id));
+    };
+
+    freeze(&path, frozen)
+}
+
+/// Returns the error for a container that has no cgroup to set limits on or to freeze.
+pub(crate) fn no_cgroup(id: &str) -> CartonError {
+    CartonError::InvalidConfiguration(format!(
+        "container {} has no cgroup of its own, see ContainerBuilder::delegate_cgroup()",
+        id
+    ))
+}
+
+/// Freezes the cgroup at `path`, which stops all of its processes until it's thawed again, or
+/// thaws it.
+pub(crate) fn freeze(path: &Path, frozen: bool) -> Result<(), CartonError> {
+    let file = path.join("cgroup.freeze");
+    // The freezer of cgroup v2 is there since Linux 5.2
+    if !file.exists() {
+        return Err(CartonError::InvalidConfiguration(format!(
+            "cgroup {} has no freezer",
+            path.display()
+        )));
+    }
+    info!(
+        "{} cgroup {}",
+        if frozen { "freezing" } else { "thawing" },
+        path.display()
+    );
+    fs::write(&file, if frozen { "1" } else { "0" })?;
+
+    Ok(())
+}
+
+/// Returns whether the cgroup of the container `id` is frozen.
+pub(crate) fn is_frozen(id: &str) -> bool {
+    path(id)
+        .and_then(|path| fs::read_to_string(path.join("cgroup.freeze")).ok())
+        .map_or(false, |value| value.trim() == "1")
+}
+
+/// Writes `limits` to the cgroup at `path`.
+pub(crate) fn set_limits(path: &Path, limits: &ResourceLimits) -> Result<(), CartonError> {
+    let mut values = Vec::new();
+    if let Some(memory) = limits.memory {
+        values.push(("memory", "memory.max", memory.to_string()));
+    }
+    if let Some(cpus) = limits.cpus {
+        if !(cpus > 0.0 && cpus.is_finite()) {
+            return Err(CartonError::InvalidConfiguration(format!(
+                "expected a number of CPUs above 0, got {}",
+                cpus
+            )));
+        }
+        let quota = ((cpus * CPU_PERIOD_USEC as f64).round() as u64).max(MIN_CPU_QUOTA_USEC);
+        values.push(("cpu", "cpu.max", format!("{} {}", quota, CPU_PERIOD_USEC)));
+    }
+
+    for (controller, file, value) in values {
+        let file = path.join(file);
+        // The files of a controller only exist when the parent cgroup hands the controller down
+        if !file.exists() {
+            return Err(CartonError::InvalidConfiguration(format!(
+                "the {} controller isn't available to cgroup {}",
+                controller,
+                path.display()
+            )));
+        }
+        info!("writing \"{}\" to {}", value, file.display());
+        fs::write(&file, value)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the steps of a `Plan` that `create()` takes.
+pub(crate) fn plan(id: &str) -> Result<Vec<PlanStep>, CartonError> {
+    let root = cgroup2_root()?;
+    let parent = containers_cgroup(&root);
+    let path = parent.join(id);
+
+    let mut steps = Vec::new();
+    if !parent.is_dir() {
+        steps.push(PlanStep::CreateDirectory(parent.clone()));
+    }
+    for cgroup in [&root, &parent] {
+        steps.extend(
+            missing_controllers(cgroup)
+                .into_iter()
+                .map(|controller| PlanStep::Write {
+                    path: cgroup.join("cgroup.subtree_control"),
+                    value: format!("+{}", controller),
+                }),
+        );
+    }
+    steps.push(PlanStep::CreateDirectory(path.clone()));
+    steps.push(PlanStep::Write {
+        path: path.join("cgroup.procs"),
+        value: PID_PLACEHOLDER.into(),
+    });
+
+    Ok(steps)
+}
+
+/// Removes the cgroup of a container whose processes are gone, including the cgroups that were
+/// created inside of it.
+pub(crate) fn remove(path: &Path) {
+    if let Ok(entries) = fs::read_dir(path) {
+        for entry in entries.flatten() {
+            if entry
+                .file_type()
+                .map_or(false, |file_type| file_type.is_dir())
+            {
+                remove(&entry.path());
+            }
+        }
+    }
+
+    // The files in a cgroup can't (and don't have to) be removed
+    match fs::remove_dir(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => warn!("Could not remove cgroup {}: {}", path.display(), e),
+    }
+}
+
+/// Returns the parent of the cgroups of all containers, in the cgroup v2 hierarchy at `root`.
+pub(crate) fn containers_cgroup(root: &Path) -> PathBuf {
+    root.join(CARTON_CGROUP)
+}
+
+/// Returns where cgroup v2 is mounted.
+pub(crate) fn cgroup2_root() -> Result<PathBuf, CartonError> {
+    CGROUP2_MOUNTS
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| {
+            statfs::statfs(path).map_or(false, |fs| fs.filesystem_type() == CGROUP2_SUPER_MAGIC)
+        })
+        .ok_or_else(|| {
+            CartonError::InvalidConfiguration(
+                "cgroup delegation needs cgroup v2, which is not mounted".into(),
+            )
+        })
+}
+
+/// Hands all controllers that `cgroup` has on to its children. Controllers that can't be enabled,
+/// for instance because they're in use by cgroup v1, are skipped.
+///
+/// When carton runs in a container that has a cgroup delegated to it, the root cgroup that carton
+/// sees is that delegated cgroup. Carton's own processes are in it, so no controllers can be
+/// enabled in it, but the ones that the outer container's runtime enabled already are still
+/// handed down.
+fn enable_controllers(cgroup: &Path) {
+    for controller in missing_controllers(cgroup) {
+        if let Err(e) = fs::write(
+            cgroup.join("cgroup.subtree_control"),
+            format!("+{}", controller),
+        ) {
+            warn!(
+                "Could not enable the {} controller in {}: {}",
+                controller,
+                cgroup.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Returns the controllers that `cgroup` has, but doesn't hand on to its children yet.
+fn missing_controllers(cgroup: &Path) -> Vec<String> {
+    let controllers = fs::read_to_string(cgroup.join("cgroup.controllers")).unwrap_or_default();
+    let enabled = fs::read_to_string(cgroup.join("cgroup.subtree_control")).unwrap_or_default();
+
+    controllers
+        .split_whitespace()
+        .filter(|controller| !enabled.split_whitespace().any(|e| e == *controller))
+        .map(String::from)
+        .collect()
+}
@@ -0,0 +1,263 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Checkpoint and restore of containers with [CRIU][1].
+//!
+//! CRIU ("Checkpoint/Restore In Userspace") freezes a process tree, writes everything about it
+//! (memory, open files, namespaces, mounts, etc.) to a directory of "images" and kills it. Later
+//! the images can be used to recreate the processes exactly as they were, even on another
+//! machine. Containers don't have a cgroup (yet), so CRIU freezes the processes by attaching to
+//! them with ptrace instead of with the cgroup freezer.
+//!
+//! Not everything inside the container belongs to the container: bind mounts point at files of
+//! the host and a shared network namespace belongs to another container. These are marked as
+//! "external" when dumping, with a key that is used to hand them to CRIU again when restoring.
+//!
+//! Another process than the one that started the container, like `carton checkpoint`, finds the
+//! bind mounts in the container's `ContainerInfo`. It can only checkpoint containers on the host's
+//! network, because the `ContainerInfo` doesn't say whose network namespace a container shares.
+//!
+//! [1]: https://criu.org/
+
+use std::fs::{self, File};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::info;
+
+use nix::fcntl::{self, FcntlArg, FdFlag};
+use nix::sys::prctl;
+use nix::unistd::Pid;
+
+use crate::container::ContainerState;
+use crate::error::CartonError;
+use crate::state::ContainerInfo;
+
+const DUMP_LOG: &str = "dump.log";
+const RESTORE_LOG: &str = "restore.log";
+const RESTORE_PID_FILE: &str = "restore.pid";
+/// Key of a shared network namespace.
+const NETWORK_NAMESPACE_KEY: &str = "netns";
+
+/// A bind mount from the host, as `(path inside the container, path on the host)`.
+pub(crate) type ExternalMount = (PathBuf, PathBuf);
+
+/// How a container is checkpointed and restored, see `Container::checkpoint_with()`.
+#[derive(Debug, Clone, Default)]
+pub struct CheckpointOptions {
+    pub(crate) leave_running: bool,
+    pub(crate) tcp_established: bool,
+}
+
+impl CheckpointOptions {
+    pub fn new() -> Self {
+        CheckpointOptions::default()
+    }
+
+    /// Keeps the container running after the checkpoint, instead of stopping it.
+    pub fn leave_running(mut self) -> Self {
+        self.leave_running = true;
+        self
+    }
+
+    /// Includes the container's established TCP connections in the checkpoint, and restores them.
+    /// Their peers have to reach the container at the same address afterwards, and the original
+    /// connections have to be gone by then.
+    pub fn tcp_established(mut self) -> Self {
+        self.tcp_established = true;
+        self
+    }
+}
+
+/// Checkpoints the running container of `info`, like `Container::checkpoint_with()` does for a
+/// container of this process. Unless the container is left running, its processes are gone
+/// afterwards, and the process that started it finds that it exited.
+pub fn checkpoint_container(
+    info: &ContainerInfo,
+    images_dir: &Path,
+    options: &CheckpointOptions,
+) -> Result<(), CartonError> {
+    let (ContainerState::Running, Some(pid)) = (info.state, info.pid) else {
+        return Err(CartonError::NotRunning);
+    };
+    if fs::read_link(format!("/proc/{}/ns/net", pid))? != fs::read_link("/proc/self/ns/net")? {
+        return Err(CartonError::CheckpointError(
+            "only containers on the host's network can be checkpointed by another process than \
+             the one that started them"
+                .into(),
+        ));
+    }
+
+    dump(
+        Pid::from_raw(pid),
+        images_dir,
+        &info.bind_mounts,
+        None,
+        options,
+    )
+}
+
+/// Dumps the container process `pid` and its children to `images_dir`, which kills them.
+/// `shared_network_namespace` is the path of the network namespace of another container that the
+/// container joined, if any.
+pub(crate) fn dump(
+    pid: Pid,
+    images_dir: &Path,
+    external_mounts: &[ExternalMount],
+    shared_network_namespace: Option<&Path>,
+    options: &CheckpointOptions,
+) -> Result<(), CartonError> {
+    fs::create_dir_all(images_dir)?;
+
+    let mut command = Command::new("criu");
+    command
+        .arg("dump")
+        .arg("--tree")
+        .arg(pid.to_string())
+        .arg("--images-dir")
+        .arg(images_dir)
+        .arg("--log-file")
+        .arg(DUMP_LOG)
+        // The container uses the terminal of the carton process that started it
+        .arg("--shell-job");
+    if options.leave_running {
+        command.arg("--leave-running");
+    }
+    if options.tcp_established {
+        command.arg("--tcp-established");
+    }
+
+    for (target, _) in external_mounts {
+        command
+            .arg("--external")
+            .arg(format!("mnt[{}]:{}", target.display(), mount_key(target)));
+    }
+
+    if let Some(namespace) = shared_network_namespace {
+        let inode = fs::metadata(namespace)?.ino();
+        command
+            .arg("--external")
+            .arg(format!("net[{}]:{}", inode, NETWORK_NAMESPACE_KEY));
+    }
+
+    run_criu(&mut command, &images_dir.join(DUMP_LOG))
+}
+
+/// Recreates the processes from the images in `images_dir` inside `rootfs` and returns the PID of
+/// the container process. The process becomes a child of the calling process, so it can be waited
+/// for like a container that was started normally.
+pub(crate) fn restore(
+    images_dir: &Path,
+    rootfs: &Path,
+    external_mounts: &[ExternalMount],
+    shared_network_namespace: Option<&Path>,
+    options: &CheckpointOptions,
+) -> Result<Pid, CartonError> {
+    // CRIU itself is the parent of the restored processes. With --restore-detached it exits once
+    // they're running, after which they would be adopted by init, unless an ancestor is a
+    // "subreaper".
+    prctl::set_child_subreaper(true)?;
+
+    let pid_file = images_dir.join(RESTORE_PID_FILE);
+    let _ = fs::remove_file(&pid_file);
+
+    let mut command = Command::new("criu");
+    command
+        .arg("restore")
+        .arg("--images-dir")
+        .arg(images_dir)
+        .arg("--log-file")
+        .arg(RESTORE_LOG)
+        .arg("--root")
+        .arg(rootfs)
+        .arg("--pidfile")
+        .arg(&pid_file)
+        .arg("--restore-detached")
+        .arg("--shell-job");
+    if options.tcp_established {
+        command.arg("--tcp-established");
+    }
+
+    for (target, source) in external_mounts {
+        command
+            .arg("--external")
+            .arg(format!("mnt[{}]:{}", mount_key(target), source.display()));
+    }
+
+    // The network namespace is handed to CRIU as an open file descriptor
+    let namespace = shared_network_namespace.map(File::open).transpose()?;
+    if let Some(namespace) = &namespace {
+        let fd = namespace.as_raw_fd();
+        command
+            .arg("--inherit-fd")
+            .arg(format!("fd[{}]:{}", fd, NETWORK_NAMESPACE_KEY));
+
+        unsafe {
+            command.pre_exec(move || {
+                fcntl::fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty()))
+                    .map(drop)
+                    .map_err(std::io::Error::from)
+            });
+        }
+    }
+
+    run_criu(&mut command, &images_dir.join(RESTORE_LOG))?;
+
+    let pid = fs::read_to_string(&pid_file)?
+        .trim()
+        .parse::<i32>()
+        .map_err(|e| {
+            CartonError::CheckpointError(format!("invalid PID in {}: {}", pid_file.display(), e))
+        })?;
+
+    Ok(Pid::from_raw(pid))
+}
+
+/// CRIU keys can't contain slashes, so every byte other than a letter, a digit, `.` or `-` is
+/// written as `_` and its hexadecimal value: `/etc/hosts` becomes `etc_2fhosts`. Escaping `_`
+/// itself too keeps `/a_b` and `/a/b` apart.
+fn mount_key(target: &Path) -> String {
+    let path = target.as_os_str().as_bytes();
+    let mut key = String::new();
+    for byte in path.strip_prefix(b"/").unwrap_or(path) {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'-' => key.push(*byte as char),
+            _ => key.push_str(&format!("_{:02x}", byte)),
+        }
+    }
+
+    key
+}
+
+fn run_criu(command: &mut Command, log_path: &Path) -> Result<(), CartonError> {
+    info!("running {:?}", command);
+
+    let status = command
+        .status()
+        .map_err(|e| CartonError::CheckpointError(format!("could not run criu: {}", e)))?;
+
+    if !status.success() {
+        return Err(CartonError::CheckpointError(format!(
+            "criu failed ({}), see {} for details",
+            status,
+            log_path.display()
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_mount_keys() {
+        assert_eq!(mount_key(Path::new("/etc/hosts")), "etc_2fhosts");
+        assert_ne!(mount_key(Path::new("/a_b")), mount_key(Path::new("/a/b")));
+    }
+}
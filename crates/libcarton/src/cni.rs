@@ -0,0 +1,339 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Networking through [CNI][1] plugins, the standard that Kubernetes and other container runtimes
+//! use to connect containers to a network.
+//!
+//! A CNI network is described by a JSON file (usually in /etc/cni/net.d) that lists one or more
+//! plugins. Each plugin is an executable (usually in /opt/cni/bin) that gets its configuration on
+//! stdin and the details of the container, like the path of its network namespace, in environment
+//! variables. To connect a container, the plugins are called with `ADD` one after the other, each
+//! getting the result of the previous one. To disconnect it, they are called with `DEL` in reverse
+//! order.
+//!
+//! [1]: https://github.com/containernetworking/cni/blob/main/SPEC.md
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use log::{info, warn};
+
+use nix::mount::{self, MntFlags, MsFlags};
+use nix::unistd::Pid;
+
+use serde_json::Value;
+
+use crate::consts::{DEFAULT_CNI_CONF_DIR, DEFAULT_CNI_PLUGIN_DIR};
+use crate::error::CartonError;
+
+/// Name of the container's interface, as passed to the plugins.
+const INTERFACE_NAME: &str = "eth0";
+
+/// A network that is set up by CNI plugins, as described by the network configuration called
+/// `name` in `conf_dir`.
+#[derive(Debug, Clone)]
+pub struct CniNetwork {
+    pub(crate) name: String,
+    pub(crate) conf_dir: PathBuf,
+    /// Directories in which to look for the plugin executables.
+    pub(crate) plugin_dirs: Vec<PathBuf>,
+}
+
+impl CniNetwork {
+    pub fn new(name: String) -> Self {
+        CniNetwork {
+            name,
+            conf_dir: DEFAULT_CNI_CONF_DIR.into(),
+            plugin_dirs: vec![DEFAULT_CNI_PLUGIN_DIR.into()],
+        }
+    }
+
+    pub fn conf_dir(mut self, conf_dir: PathBuf) -> Self {
+        self.conf_dir = conf_dir;
+        self
+    }
+
+    /// Replaces the directories in which to look for plugins.
+    pub fn plugin_dirs(mut self, plugin_dirs: Vec<PathBuf>) -> Self {
+        self.plugin_dirs = plugin_dirs;
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), CartonError> {
+        let config = self.load_config()?;
+        for plugin in &config.plugins {
+            find_plugin(plugin_type(plugin)?, &self.plugin_dirs)?;
+        }
+
+        Ok(())
+    }
+
+    /// Finds the network configuration with the right name in `conf_dir`. Like other runtimes,
+    /// both configuration lists (`.conflist`) and single plugin configurations (`.conf` and
+    /// `.json`) are supported.
+    fn load_config(&self) -> Result<NetworkConfig, CartonError> {
+        let mut paths = fs::read_dir(&self.conf_dir)
+            .map_err(|e| {
+                CartonError::InvalidConfiguration(format!(
+                    "could not read CNI configuration directory {}: {}",
+                    self.conf_dir.display(),
+                    e
+                ))
+            })?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect::<Vec<PathBuf>>();
+        paths.sort();
+
+        for path in paths {
+            let is_list = match path.extension().and_then(|extension| extension.to_str()) {
+                Some("conflist") => true,
+                Some("conf" | "json") => false,
+                _ => continue,
+            };
+
+            let config = match fs::read(&path)
+                .map_err(CartonError::from)
+                .and_then(|contents| Ok(serde_json::from_slice::<Value>(&contents)?))
+            {
+                Ok(config) => config,
+                Err(e) => {
+                    warn!("Skipping CNI configuration {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+
+            if config["name"].as_str() != Some(self.name.as_str()) {
+                continue;
+            }
+
+            let cni_version = config["cniVersion"].clone();
+            let plugins = if is_list {
+                config["plugins"].as_array().cloned().unwrap_or_default()
+            } else {
+                vec![config]
+            };
+            if plugins.is_empty() {
+                return Err(CartonError::InvalidConfiguration(format!(
+                    "CNI network {} in {} has no plugins",
+                    self.name,
+                    path.display()
+                )));
+            }
+
+            return Ok(NetworkConfig {
+                cni_version,
+                plugins,
+            });
+        }
+
+        Err(CartonError::InvalidConfiguration(format!(
+            "no CNI network called {} in {}",
+            self.name,
+            self.conf_dir.display()
+        )))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct NetworkConfig {
+    cni_version: Value,
+    plugins: Vec<Value>,
+}
+
+/// What is needed to call the plugins with `DEL` when the container goes away.
+#[derive(Debug)]
+pub(crate) struct CniAttachment {
+    network: CniNetwork,
+    config: NetworkConfig,
+    container_id: String,
+    /// A bind mount of the container's network namespace, which keeps the namespace around until
+    /// the plugins had the chance to clean up what they created inside of it.
+    namespace_path: PathBuf,
+    /// The result of the last `ADD`.
+    result: Option<Value>,
+}
+
+/// Calls the plugins of the network with `ADD` to connect the network namespace of the container
+/// process `pid`. Returns the IPv4 and IPv6 addresses that the plugins gave the container, if any.
+pub(crate) fn add(
+    network: &CniNetwork,
+    container_id: &str,
+    pid: Pid,
+    container_dir: &Path,
+) -> Result<(Option<Ipv4Addr>, Option<Ipv6Addr>, CniAttachment), CartonError> {
+    let config = network.load_config()?;
+
+    fs::create_dir_all(container_dir)?;
+    let namespace_path = container_dir.join("netns");
+    File::create(&namespace_path)?;
+    mount::mount(
+        Some(&PathBuf::from(format!("/proc/{}/ns/net", pid))),
+        &namespace_path,
+        None::<&str>,
+        MsFlags::MS_BIND,
+        None::<&str>,
+    )?;
+
+    let mut attachment = CniAttachment {
+        network: network.clone(),
+        config,
+        container_id: container_id.into(),
+        namespace_path,
+        result: None,
+    };
+
+    for plugin in &attachment.config.plugins {
+        match invoke(&attachment, "ADD", plugin) {
+            Ok(result) => attachment.result = Some(result),
+            Err(e) => {
+                // Plugins that already did their thing have to clean up again, and plugins are
+                // supposed to handle a DEL for something they never added
+                del(&attachment);
+                return Err(e);
+            }
+        }
+    }
+
+    let addresses = attachment
+        .result
+        .as_ref()
+        .map(result_addresses)
+        .unwrap_or_default();
+    let address = addresses.iter().find_map(|address| match address {
+        IpAddr::V4(address) => Some(*address),
+        IpAddr::V6(_) => None,
+    });
+    let ipv6_address = addresses.iter().find_map(|address| match address {
+        IpAddr::V4(_) => None,
+        IpAddr::V6(address) => Some(*address),
+    });
+
+    Ok((address, ipv6_address, attachment))
+}
+
+/// Calls the plugins of the network with `DEL`, in reverse order. Errors are only logged, because
+/// the container is gone anyway.
+pub(crate) fn del(attachment: &CniAttachment) {
+    for plugin in attachment.config.plugins.iter().rev() {
+        if let Err(e) = invoke(attachment, "DEL", plugin) {
+            warn!("CNI plugin {} failed to clean up: {}", plugin["type"], e);
+        }
+    }
+
+    if let Err(e) = mount::umount2(&attachment.namespace_path, MntFlags::MNT_DETACH) {
+        warn!(
+            "Could not unmount {}: {}",
+            attachment.namespace_path.display(),
+            e
+        );
+    }
+    let _ = fs::remove_file(&attachment.namespace_path);
+}
+
+/// Executes one plugin and returns the result that it printed.
+fn invoke(attachment: &CniAttachment, command: &str, plugin: &Value) -> Result<Value, CartonError> {
+    let plugin_path = find_plugin(plugin_type(plugin)?, &attachment.network.plugin_dirs)?;
+
+    // Every plugin gets the name and version of the whole list, and the result of the plugin
+    // before it
+    let mut plugin_config = plugin.clone();
+    plugin_config["name"] = Value::String(attachment.network.name.clone());
+    plugin_config["cniVersion"] = attachment.config.cni_version.clone();
+    if let Some(result) = &attachment.result {
+        plugin_config["prevResult"] = result.clone();
+    }
+
+    let plugin_path_var = env::join_paths(&attachment.network.plugin_dirs)
+        .map_err(|e| CartonError::InvalidConfiguration(e.to_string()))?;
+
+    info!("running CNI plugin {} {}", plugin_path.display(), command);
+
+    let mut child = Command::new(&plugin_path)
+        .env("CNI_COMMAND", command)
+        .env("CNI_CONTAINERID", &attachment.container_id)
+        .env("CNI_NETNS", &attachment.namespace_path)
+        .env("CNI_IFNAME", INTERFACE_NAME)
+        .env("CNI_PATH", plugin_path_var)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            CartonError::NetworkError(format!("could not run {}: {}", plugin_path.display(), e))
+        })?;
+
+    child
+        .stdin
+        .take()
+        .expect("stdin should be piped")
+        .write_all(&serde_json::to_vec(&plugin_config)?)?;
+    let output = child.wait_with_output()?;
+
+    if !output.status.success() {
+        // Plugins report errors as JSON on stdout, but not all of them stick to that
+        let message = serde_json::from_slice::<Value>(&output.stdout)
+            .ok()
+            .and_then(|error| {
+                let msg = error["msg"].as_str()?.to_string();
+                Some(match error["details"].as_str() {
+                    Some(details) if !details.is_empty() => format!("{}: {}", msg, details),
+                    _ => msg,
+                })
+            })
+            .unwrap_or_else(|| String::from_utf8_lossy(&output.stderr).trim().to_string());
+
+        return Err(CartonError::NetworkError(format!(
+            "CNI plugin {} {} failed: {}",
+            plugin_path.display(),
+            command,
+            message
+        )));
+    }
+
+    if output.stdout.iter().all(u8::is_ascii_whitespace) {
+        return Ok(Value::Null);
+    }
+
+    serde_json::from_slice(&output.stdout).map_err(|e| {
+        CartonError::NetworkError(format!(
+            "CNI plugin {} {} returned an invalid result: {}",
+            plugin_path.display(),
+            command,
+            e
+        ))
+    })
+}
+
+fn plugin_type(plugin: &Value) -> Result<&str, CartonError> {
+    plugin["type"].as_str().ok_or_else(|| {
+        CartonError::InvalidConfiguration("CNI plugin configuration without a type".into())
+    })
+}
+
+fn find_plugin(plugin_type: &str, plugin_dirs: &[PathBuf]) -> Result<PathBuf, CartonError> {
+    plugin_dirs
+        .iter()
+        .map(|dir| dir.join(plugin_type))
+        .find(|path| path.is_file())
+        .ok_or_else(|| {
+            CartonError::InvalidConfiguration(format!(
+                "CNI plugin {} not found in {:?}",
+                plugin_type, plugin_dirs
+            ))
+        })
+}
+
+/// Returns the addresses in a CNI result, like `{"ips": [{"address": "10.22.0.5/16"}]}`.
+fn result_addresses(result: &Value) -> Vec<IpAddr> {
+    result["ips"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|ip| ip["address"].as_str())
+        .filter_map(|address| address.split('/').next()?.parse().ok())
+        .collect()
+}
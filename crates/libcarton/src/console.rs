@@ -0,0 +1,136 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! A pseudo terminal for the container, handed over through a "console socket" as described by
+//! the [OCI runtime spec][1].
+//!
+//! A pseudo terminal ("pty", see [pty(7)][2]) is a pair of file descriptors. The container's
+//! process gets the "slave" end as its stdin, stdout and stderr and as its controlling terminal, so
+//! it behaves like it was started from a terminal. Whoever holds the "master" end reads what the
+//! process writes and types on its behalf. Supervisors like containerd and conmon listen on a Unix socket and
+//! expect the runtime to send them the master end over it, using an `SCM_RIGHTS` message.
+//!
+//! Many init systems and images also expect the terminal at `/dev/console`, so the slave end is
+//! bind mounted there as well.
+//!
+//! [1]: https://github.com/opencontainers/runtime-spec/blob/main/config.md#console
+//! [2]: https://man7.org/linux/man-pages/man7/pty.7.html
+
+use std::io::IoSlice;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use nix::fcntl::{self, OFlag};
+use nix::libc;
+use nix::pty;
+use nix::sys::socket::{self, ControlMessage, MsgFlags, UnixAddr};
+use nix::sys::stat::Mode;
+use nix::unistd;
+
+use crate::container::Mount;
+use crate::diagnostics::Reporter;
+use crate::error::CartonError;
+use crate::rollback::Rollback;
+use crate::syscalls::Syscalls;
+
+pub(crate) const CONSOLE_PATH: &str = "dev/console";
+
+/// Both ends of a pseudo terminal.
+#[derive(Debug)]
+pub(crate) struct Pty {
+    master: OwnedFd,
+    slave: OwnedFd,
+    /// Path of the slave end, like `/dev/pts/3`.
+    name: String,
+}
+
+impl Pty {
+    /// Opens a new pseudo terminal. It's opened by carton, before the container process exists,
+    /// so the slave end is one of the host's `/dev/pts` devices.
+    pub(crate) fn open() -> Result<Self, CartonError> {
+        let master = pty::posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC)?;
+        pty::grantpt(&master)?;
+        pty::unlockpt(&master)?;
+        let name = pty::ptsname_r(&master)?;
+
+        let slave = fcntl::open(
+            name.as_str(),
+            OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+
+        Ok(Pty {
+            master: unsafe { OwnedFd::from_raw_fd(master.into_raw_fd()) },
+            slave: unsafe { OwnedFd::from_raw_fd(slave) },
+            name,
+        })
+    }
+
+    pub(crate) fn slave(&self) -> RawFd {
+        self.slave.as_raw_fd()
+    }
+
+    /// Path of the slave end on the host, like `/dev/pts/3`.
+    pub(crate) fn name(&self) -> &Path {
+        Path::new(&self.name)
+    }
+
+    /// Sends the master end to the console socket at `socket_path`. Like runc does, the name of
+    /// the terminal is sent along as the message's data.
+    pub(crate) fn send_master(&self, socket_path: &Path) -> Result<(), CartonError> {
+        let socket = UnixStream::connect(socket_path).map_err(|e| {
+            CartonError::IOError(format!(
+                "could not connect to console socket {}: {}",
+                socket_path.display(),
+                e
+            ))
+        })?;
+
+        let fds = [self.master.as_raw_fd()];
+        socket::sendmsg::<UnixAddr>(
+            socket.as_raw_fd(),
+            &[IoSlice::new(self.name.as_bytes())],
+            &[ControlMessage::ScmRights(&fds)],
+            MsgFlags::empty(),
+            None,
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Bind mounts the terminal at `slave_path` (see `Pty::name()`) at `/dev/console` in the root
+/// filesystem at `rootfs`. The container's own /dev/pts doesn't have it, because the terminal was
+/// opened outside of the container.
+pub(crate) fn mount_console(
+    syscalls: &dyn Syscalls,
+    reporter: &Reporter,
+    rollback: &Rollback,
+    slave_path: &Path,
+    rootfs: &Path,
+) -> Result<(), CartonError> {
+    // A device can only be bind mounted on top of a file, which Mount::mount() only creates for
+    // regular files
+    let console = Mount::bind(slave_path.into(), CONSOLE_PATH.into(), None, None);
+    rollback.create_file(&rootfs.join(CONSOLE_PATH))?;
+    console.mount(rootfs, syscalls, reporter, rollback)?;
+
+    Ok(())
+}
+
+/// Makes `slave` the controlling terminal of the calling process and its stdin, stdout and
+/// stderr. Called by the container process right before it executes the command.
+pub(crate) fn attach_terminal(slave: RawFd) -> nix::Result<()> {
+    // Only the leader of a session without a terminal can take one as its controlling terminal
+    unistd::setsid()?;
+    if unsafe { libc::ioctl(slave, libc::TIOCSCTTY, 0) } < 0 {
+        return Err(nix::errno::Errno::last());
+    }
+
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        unistd::dup2(slave, fd)?;
+    }
+
+    Ok(())
+}
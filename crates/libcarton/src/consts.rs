@@ -1 +1,8 @@
 pub const DEFAULT_CONTAINER_STACK_SIZE: usize = 1024 * 1024; // 1 megabyte
+pub const DEFAULT_STATE_DIR: &str = "/run/carton";
+pub const DEFAULT_DATA_DIR: &str = "/var/lib/carton";
+pub const DEFAULT_BRIDGE_NAME: &str = "carton0";
+pub const DEFAULT_BRIDGE_SUBNET: &str = "10.88.0.0/16";
+pub const DEFAULT_BRIDGE_IPV6_SUBNET: &str = "fd88::/64";
+pub const DEFAULT_CNI_CONF_DIR: &str = "/etc/cni/net.d";
+pub const DEFAULT_CNI_PLUGIN_DIR: &str = "/opt/cni/bin";
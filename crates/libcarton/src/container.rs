@@ -1,39 +1,372 @@
 // Copyright 2023 Arjen Verstoep
 // SPDX-License-Identifier: Apache-2.0
 
-use std::ffi::CString;
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::{CString, OsStr};
+use std::fmt;
+use std::fs::{self, File};
+use std::num::NonZeroUsize;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+use std::slice;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
 
 use log::{error, info, warn};
 
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::libc::{self, c_void};
 use nix::mount;
+use nix::poll::{self, PollFd, PollFlags};
 use nix::sched::{self, CloneFlags};
-use nix::sys::signal::Signal::SIGCHLD;
-use nix::sys::wait;
+use nix::sys::mman::{self, MapFlags, ProtFlags};
+use nix::sys::signal::{self, Signal, Signal::SIGCHLD, Signal::SIGKILL};
+use nix::sys::wait::{self, WaitPidFlag};
 use nix::unistd;
 
+use serde::{Deserialize, Serialize};
+
+use crate::activation::ListenFds;
+use crate::binfmt::Emulator;
+use crate::cgroup::{self, ResourceLimits};
+use crate::checkpoint::{self, CheckpointOptions, ExternalMount};
+use crate::console::{self, Pty};
+use crate::core_dump::CoreDumps;
+use crate::dbus::{DbusProxy, DbusProxyProcess};
+use crate::diagnostics::{self, Reporter};
+use crate::environment::{self, EnvFilter};
 use crate::error::CartonError;
+use crate::exit::{self, ContainerExit};
+use crate::features::{self, HostFeatures};
+use crate::files::{self, InjectedFile};
+use crate::fuse::{FuseDaemon, FuseRootfs};
+use crate::hooks::Hooks;
+use crate::idmap::{self, IdMappings};
+use crate::integrity::Sha256Digest;
+use crate::mount_provider::MountProvider;
+use crate::mux::{self, MultiplexedOutput};
 use crate::namespace::setup_namespaces;
+use crate::network::{self, BandwidthLimit, Network, NetworkAttachment};
+use crate::network_files::NetworkFiles;
+use crate::notify::{self, NotifyProxy};
+use crate::ownership;
+use crate::plan::{self, Plan, PlanStep};
+use crate::quota;
+use crate::rollback::{Rollback, Step};
+use crate::scope;
+use crate::seccomp::{SeccompNotify, SeccompProfile};
+use crate::secrets::{self, Secret};
+use crate::state::{ContainerInfo, ProjectInfo, Setting};
+use crate::stdin::{self, StdinStream};
+use crate::syscalls::{Host, Syscalls};
+use crate::trace::{self, Span};
+use crate::volume::{self, Volume};
+
+/// How often `wait_timeout()` checks on the container's process when there is no pidfd to poll.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Where the init binary is mounted inside the container, see `ContainerBuilder::init()`. That's
+/// on the container's own /dev, a tmpfs, so that nothing is created in the root filesystem.
+pub(crate) const INIT_PATH: &str = "dev/.carton-init";
+/// Directory in the container's directory on which the tmpfs of an ephemeral container is mounted
+/// (only in the container's mount namespace), see `ContainerBuilder::ephemeral()`.
+pub(crate) const EPHEMERAL_DIR: &str = "ephemeral";
+/// The shell that runs shell commands, see `ContainerBuilder::shell_command()`.
+pub(crate) const SHELL: &str = "/bin/sh";
+/// Where the container's shared memory (POSIX shared memory and semaphores) is.
+pub(crate) const SHM_DIR: &str = "dev/shm";
+/// Size of the tmpfs on /dev/shm, see `ContainerBuilder::shm_size()`.
+pub(crate) const DEFAULT_SHM_SIZE: u64 = 64 * 1024 * 1024;
+/// How long a container that ran out of time gets to exit after SIGTERM, before it gets SIGKILL,
+/// see `ContainerBuilder::timeout()`.
+pub(crate) const DEFAULT_TIMEOUT_GRACE_PERIOD: Duration = Duration::from_secs(10);
 
 #[derive(Default, Debug)]
 pub struct Container {
+    /// Randomly generated identifier of the container.
+    pub(crate) id: String,
+    /// Unix timestamp (in seconds) of when the container was created.
+    pub(crate) created: u64,
     /// The current state of the container.
     pub(crate) state: ContainerState,
     /// PID of process that essentially is the container.
     pub pid: Option<unistd::Pid>,
+    /// A file descriptor that refers to the container's process. Unlike the PID, it can't end up
+    /// referring to another process once the container's process is gone.
+    pub(crate) pidfd: Option<OwnedFd>,
+    /// How the container's process ended, once it has exited.
+    pub(crate) exit: Option<ContainerExit>,
+    /// When the container gets its next signal for running out of time, see
+    /// `ContainerBuilder::timeout()`.
+    pub(crate) deadline: Option<Instant>,
+    /// Whether the container was stopped because it ran out of time.
+    pub(crate) timed_out: bool,
+    /// How the container is connected to its network, when it has one of its own.
+    pub(crate) network: Option<NetworkAttachment>,
+    /// The pipe through which a prepared container gets the command to execute.
+    pub(crate) start_pipe: Option<OwnedFd>,
+    /// The pipe through which a prepared container's process reports on its setup, when there's a
+    /// `Diagnostics` or `Tracer` to pass the reports on to.
+    pub(crate) diagnostics_pipe: Option<OwnedFd>,
+    /// The pipe through which the container process reports it when its setup fails, see `exit`.
+    pub(crate) setup_error_pipe: Option<OwnedFd>,
+    /// The `setup` span of a prepared container, which ends when the container process is done
+    /// with its setup, see `trace`.
+    pub(crate) setup_span: Option<Span>,
+    /// Passes on the container's sd_notify messages while it runs.
+    pub(crate) notify_proxy: Option<NotifyProxy>,
+    /// The xdg-dbus-proxy between the container and a bus, while it runs.
+    pub(crate) dbus_proxy: Option<DbusProxyProcess>,
+    /// The cgroup that was delegated to the container, if any.
+    pub(crate) cgroup: Option<PathBuf>,
+    /// The daemon that provides the container's root filesystem, if it's a FUSE filesystem.
+    pub(crate) fuse_daemon: Option<FuseDaemon>,
+    /// The threads that multiplex the container's stdout and stderr, while it runs.
+    pub(crate) output_threads: Vec<JoinHandle<()>>,
 
     pub(crate) config: ContainerConfiguration,
     pub(crate) buffer: ContainerBuffer,
 }
 
 impl Container {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.config.name.as_deref()
+    }
+
+    pub fn state(&self) -> ContainerState {
+        self.state
+    }
+
+    /// Returns how the container's process ended, once it has exited.
+    pub fn exit(&self) -> Option<&ContainerExit> {
+        self.exit.as_ref()
+    }
+
+    /// Returns a snapshot of the container's configuration and status, which can be persisted with
+    /// a `StateStore`.
+    pub fn info(&self) -> ContainerInfo {
+        ContainerInfo {
+            id: self.id.clone(),
+            name: self.config.name.clone(),
+            state: self.state,
+            paused: self.is_paused(),
+            pid: self.pid.map(|pid| pid.as_raw()),
+            exit: self.exit.clone(),
+            ip_address: self
+                .network
+                .as_ref()
+                .and_then(|attachment| attachment.address),
+            ipv6_address: self
+                .network
+                .as_ref()
+                .and_then(|attachment| attachment.ipv6_address),
+            hostname: self.config.hostname.clone(),
+            domainname: self.config.domainname.clone(),
+            rootfs: self
+                .config
+                .rootfs
+                .as_ref()
+                .and_then(|rootfs| rootfs.source.clone())
+                .unwrap_or_default(),
+            sandbox: self.config.sandbox,
+            ephemeral: self.config.ephemeral,
+            bind_mounts: self.config.external_mounts(),
+            volumes: self
+                .config
+                .mounts
+                .iter()
+                .filter_map(|mount| Some(mount.mounted_volume()?.name.clone()))
+                .collect(),
+            command: self.config.command.clone().unwrap_or_default(),
+            arguments: self.config.arguments.clone(),
+            created: self.created,
+            settings: self.config.settings.clone(),
+            project: self.config.project.clone(),
+            secrets: self
+                .config
+                .secrets
+                .iter()
+                .map(|secret| secret.name.clone())
+                .collect(),
+            scope: self
+                .config
+                .systemd_scope
+                .then(|| scope::unit_name(&self.id)),
+            user: self.config.user,
+            seccomp_profile: self.config.seccomp_profile.clone(),
+        }
+    }
+
+    /// Starts the container's command. This is `prepare()` and `start()` in one go.
     pub fn run(&mut self) -> Result<(), CartonError> {
-        if let ContainerState::Running = self.state {
+        if self.config.command.is_none() {
+            return Err(CartonError::MissingRequiredConfiguration("command".into()));
+        }
+
+        self.prepare()?;
+        self.start()
+    }
+
+    /// Returns what `prepare()` and `start()` would do, without doing any of it, see `Plan`.
+    pub fn plan(&self) -> Result<Plan, CartonError> {
+        self.config.validate()?;
+
+        plan::plan(&self.id, &self.config)
+    }
+
+    /// Does all of the work of starting the container, like creating its namespaces, connecting
+    /// its network and setting up its mounts, except for executing the command. The container
+    /// process waits until `start()` or `start_command()` is called. Preparing containers ahead of
+    /// time takes the setup out of the time it takes to start a command, see `ContainerPool`.
+    ///
+    /// When the setup fails, what it created on the host so far is removed again.
+    pub fn prepare(&mut self) -> Result<(), CartonError> {
+        if matches!(
+            self.state,
+            ContainerState::Prepared | ContainerState::Running
+        ) {
             return Err(CartonError::AlreadyRunning);
         }
 
+        let rollback = Rollback::default();
+        let setup_start = SystemTime::now();
+        let mut spans = Vec::new();
+        let result = self.set_up(&rollback, &mut spans);
+        if result.is_ok() {
+            self.setup_span = Some(Span::since("setup", setup_start, spans));
+        } else {
+            // When the container process existed, release() undid some of the steps already,
+            // which makes undoing them again a no-op
+            rollback.undo(
+                &Host::new(&self.config.diagnostics),
+                &self.config.diagnostics,
+            );
+            volume::unmount_volumes(&mut self.config.mounts, &self.id, &self.config.diagnostics);
+            self.notify_proxy = None;
+            self.dbus_proxy = None;
+        }
+
+        result
+    }
+
+    /// Does the work of `prepare()`, recording the steps that have to be undone when it fails in
+    /// `rollback`, and the spans of its parts in `spans`.
+    fn set_up(&mut self, rollback: &Rollback, spans: &mut Vec<Span>) -> Result<(), CartonError> {
+        let reporter = self.config.diagnostics.clone();
+        features::adapt(&mut self.config, &HostFeatures::detect(), &reporter)?;
         self.config.validate()?;
+        self.config.verify_files()?;
+        if let Some(emulator) = &self.config.emulator {
+            emulator.register(&self.config.diagnostics)?;
+        }
+
+        let mut clone_flags =
+            CloneFlags::CLONE_NEWUTS | CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWPID;
+        if matches!(
+            self.config.network,
+            Network::Bridge(_) | Network::Macvlan(_) | Network::Cni(_)
+        ) {
+            clone_flags |= CloneFlags::CLONE_NEWNET;
+        }
+        // The other namespaces are owned by the new user namespace, which the kernel creates
+        // first
+        if self.config.user_namespace.is_some() {
+            clone_flags |= CloneFlags::CLONE_NEWUSER;
+        }
+
+        // A namespace is joined through a file descriptor that refers to it. It's opened here
+        // because the container process won't be able to see the other container's process once
+        // it's in its own PID namespace.
+        let shared_network_namespace = match &self.config.network {
+            Network::Shared(shared) => Some(File::open(
+                shared
+                    .namespace_path()
+                    .expect("shared network should have been validated"),
+            )?),
+            _ => None,
+        };
+
+        // Some parts of the setup, like connecting the container's network namespace, can only be
+        // done from outside of the container once its process exists. This pipe is used to hold
+        // the container process back until the parent is done with that. Afterwards the command
+        // to execute is sent through it.
+        let (ready_read, ready_write) = unistd::pipe2(OFlag::O_CLOEXEC)?;
+        let (setup_error_read, setup_error_write) = exit::pipe()?;
+        let diagnostics_pipe = if self.config.diagnostics.has_receiver() {
+            Some(diagnostics::pipe()?)
+        } else {
+            None
+        };
+
+        let stdin_pipe = match &self.config.stdin {
+            Some(_) => Some(stdin::pipe()?),
+            None => None,
+        };
+        let output_pipes = match &self.config.multiplexed_output {
+            Some(_) => Some(mux::pipes()?),
+            None => None,
+        };
+
+        let pty = match &self.config.console_socket {
+            Some(_) => Some(Pty::open()?),
+            None => None,
+        };
+
+        if let Some(host_socket) = &self.config.notify_socket {
+            self.notify_proxy = Some(NotifyProxy::start(&self.config.dir, host_socket)?);
+        }
+        if let Some(dbus_proxy) = &self.config.dbus_proxy {
+            self.dbus_proxy = Some(dbus_proxy.start(&self.config.dir, rollback)?);
+        }
+
+        let seccomp_sockets = match &self.config.seccomp_notify {
+            Some(_) => Some(SeccompNotify::socket_pair()?),
+            None => None,
+        };
+
+        // The container process bind mounts the root filesystem, so it has to be mounted first.
+        // It's unmounted again when anything fails before the container process exists.
+        let fuse_daemon = match &self.config.fuse_rootfs {
+            Some(fuse_rootfs) => Some(fuse_rootfs.start()?),
+            None => None,
+        };
+        self.config.verify_rootfs()?;
+
+        if self.config.ephemeral {
+            rollback.create_dir_all(&self.config.dir.join(EPHEMERAL_DIR))?;
+        }
+        files::write(&self.config.files, &self.config.dir, rollback)?;
+        self.limit_storage(rollback)?;
+        trace::measure(spans, "volumes", || {
+            volume::mount_volumes(&mut self.config.mounts, &self.id, &self.config.diagnostics)
+        })?;
+
+        // The ID-mapped copy of the root filesystem is made here, because the container process
+        // may not be privileged enough to make it (in a user namespace of its own), and it
+        // doesn't see its own processes in /proc yet
+        let idmapped_rootfs = match (&self.config.rootfs_id_mappings, &self.config.rootfs) {
+            (Some(id_mappings), Some(rootfs)) => {
+                let source = rootfs
+                    .source
+                    .as_ref()
+                    .expect("rootfs source path should not be None");
+                Some(self.config.diagnostics.operation(
+                    format_args!("creating an ID-mapped copy of {:?}", source),
+                    idmap::idmapped_tree(source, &id_mappings.user_namespace()?),
+                )?)
+            }
+            _ => None,
+        };
+        let owned_sources = ownership::prepare(&self.config, rollback)?;
 
+        let mut stdin_writers = stdin::Writers::lock();
         let pid = unsafe {
             // There are some issues with nix's clone() regarding ownership of the stack memory and
             // whatever is passed into the `cb` callback function. The solution is to call libc's
@@ -44,63 +377,1066 @@ impl Container {
             // * https://github.com/nix-rust/nix/pull/920
             sched::clone(
                 Box::new(|| {
-                    // TODO create cgroup, set limits
-
-                    setup_namespaces(&self.config).expect("container namespaces setup");
-                    unistd::chdir("/").unwrap();
-                    execute_command(
-                        self.config
-                            .command
-                            .as_ref()
-                            .expect("command should not be None at this point"),
-                        &self.config.arguments,
-                    )
+                    let _ = unistd::close(ready_write);
+                    stdin_writers.close_inherited();
+                    let rollback = Rollback::default();
+                    let reporter = match &diagnostics_pipe {
+                        Some((_, write)) => {
+                            self.config.diagnostics.container_process(write.as_raw_fd())
+                        }
+                        None => self.config.diagnostics.clone(),
+                    };
+                    if !wait_for_parent(ready_read) {
+                        return 1;
+                    }
+                    // The parent learns why from the pipe, and reports a
+                    // `ContainerExit::SetupFailed` rather than the exit code
+                    let fail = |what: &str, e: &dyn fmt::Display| {
+                        let e = CartonError::SysCallFailed(format!("{}: {}", what, e));
+                        reporter.error(format_args!("{}", e));
+                        exit::report_setup_failure(setup_error_write.as_raw_fd(), &e);
+                    };
+
+                    // The parent wrote the ID mappings by now. Until the process switches to
+                    // root in the namespace, it acts as an unmapped user and can't create files.
+                    if self.config.user_namespace.is_some() {
+                        let root = (unistd::Uid::from_raw(0), unistd::Gid::from_raw(0));
+                        let switched = reporter
+                            .operation(
+                                format_args!("setresgid(0, 0, 0)"),
+                                unistd::setresgid(root.1, root.1, root.1),
+                            )
+                            .and_then(|_| {
+                                reporter.operation(
+                                    format_args!("setresuid(0, 0, 0)"),
+                                    unistd::setresuid(root.0, root.0, root.0),
+                                )
+                            });
+                        if let Err(e) = switched {
+                            fail("switching to root in the user namespace", &e);
+                            return 1;
+                        }
+                    }
+
+                    if let Err(e) = self.config.core_dumps.apply() {
+                        fail("applying the core dump policy", &e);
+                        return 1;
+                    }
+
+                    // The parent moved this process into its cgroup by now, which becomes the root
+                    // of the new cgroup namespace
+                    if self.config.delegate_cgroup {
+                        if let Err(e) = reporter.operation(
+                            format_args!("unshare(CLONE_NEWCGROUP)"),
+                            sched::unshare(CloneFlags::CLONE_NEWCGROUP),
+                        ) {
+                            fail("creating cgroup namespace", &e);
+                            return 1;
+                        }
+                    }
+
+                    if let Some(namespace) = &shared_network_namespace {
+                        if let Err(e) = reporter.operation(
+                            format_args!("setns({}, CLONE_NEWNET)", namespace.as_raw_fd()),
+                            sched::setns(namespace, CloneFlags::CLONE_NEWNET),
+                        ) {
+                            fail("joining shared network namespace", &e);
+                            return 1;
+                        }
+                    }
+
+                    let mounts_start = SystemTime::now();
+                    let syscalls = Host::new(&reporter);
+                    if let Err(e) = setup_namespaces(
+                        &self.config,
+                        &syscalls,
+                        &reporter,
+                        &rollback,
+                        idmapped_rootfs.as_ref().map(AsRawFd::as_raw_fd),
+                        &owned_sources,
+                        pty.as_ref().map(Pty::name),
+                    ) {
+                        reporter.error(format_args!("container namespaces setup: {}", e));
+                        exit::report_setup_failure(setup_error_write.as_raw_fd(), &e);
+                        rollback.undo(&syscalls, &reporter);
+                        return 1;
+                    }
+                    let working_dir = self
+                        .config
+                        .working_dir
+                        .as_deref()
+                        .unwrap_or_else(|| Path::new("/"));
+                    if let Err(e) = unistd::chdir(working_dir) {
+                        let e = CartonError::SysCallFailed(format!(
+                            "changing to the working directory {}: {}",
+                            working_dir.display(),
+                            e
+                        ));
+                        reporter.error(format_args!("{}", e));
+                        exit::report_setup_failure(setup_error_write.as_raw_fd(), &e);
+                        rollback.undo(&syscalls, &reporter);
+                        return 1;
+                    }
+                    reporter.span(&self.id, &Span::since("mounts", mounts_start, Vec::new()));
+                    reporter.end_of_setup();
+
+                    let Some((command, arguments)) = wait_for_command(ready_read) else {
+                        return 1;
+                    };
+                    if let Some(pty) = &pty {
+                        if let Err(e) = console::attach_terminal(pty.slave()) {
+                            fail("attaching terminal", &e);
+                            return 126;
+                        }
+                    }
+                    if let Some((read, _)) = &stdin_pipe {
+                        if let Err(e) = stdin::attach(read) {
+                            fail("attaching stdin", &e);
+                            return 126;
+                        }
+                    }
+                    if let Some(pipes) = &output_pipes {
+                        if let Err(e) = mux::attach(pipes) {
+                            fail("attaching stdout and stderr", &e);
+                            return 126;
+                        }
+                    }
+                    // Only this process' copy of the environment changes, because the memory of
+                    // the parent isn't shared
+                    if let Some(environment) = environment::command_environment(
+                        self.config.inherit_env.as_ref(),
+                        self.config.environment.as_deref(),
+                    ) {
+                        for (name, _) in env::vars_os() {
+                            env::remove_var(name);
+                        }
+                        for (name, value) in environment {
+                            env::set_var(name, value);
+                        }
+                    }
+                    for (name, value) in &self.config.display_environment {
+                        env::set_var(name, value);
+                    }
+                    match &self.config.notify_socket {
+                        Some(_) => env::set_var("NOTIFY_SOCKET", notify::container_socket_path()),
+                        None => env::remove_var("NOTIFY_SOCKET"),
+                    }
+                    if let Some(dbus_proxy) = &self.config.dbus_proxy {
+                        let (name, value) = dbus_proxy.container_variable();
+                        env::set_var(name, value);
+                    }
+                    if self.config.credentials_directory {
+                        env::set_var(
+                            secrets::CREDENTIALS_DIRECTORY,
+                            Path::new("/").join(secrets::SECRETS_DIR),
+                        );
+                    }
+                    // The command was never executed, so the parent reports the setup failure
+                    // rather than this exit code, like for the other steps before execv()
+                    if let Err(e) = self.config.hooks.run_pre_exec() {
+                        let e = CartonError::IOError(format!("pre-exec hook failed: {}", e));
+                        error!("{}", e);
+                        exit::report_setup_failure(setup_error_write.as_raw_fd(), &e);
+                        return 126;
+                    }
+                    if let (Some(seccomp), Some((_, socket))) =
+                        (&self.config.seccomp_notify, &seccomp_sockets)
+                    {
+                        if let Err(e) = seccomp.install(socket.as_raw_fd()) {
+                            fail("installing seccomp filter", &e);
+                            return 126;
+                        }
+                    }
+                    if let Some(profile) = &self.config.seccomp_profile {
+                        if let Err(e) = profile.install() {
+                            fail("installing seccomp profile", &e);
+                            return 126;
+                        }
+                    }
+                    if let Some(listen_fds) = &self.config.listen_fds {
+                        if let Err(e) = listen_fds.install() {
+                            fail("passing listening sockets", &e);
+                            return 126;
+                        }
+                    }
+                    // Last, because the user may not be allowed to do the rest
+                    if let Some((uid, gid)) = self.config.user {
+                        if let Err(e) = switch_user(uid, gid) {
+                            let e = CartonError::SysCallFailed(format!(
+                                "switching to user {} and group {}: {}",
+                                uid, gid, e
+                            ));
+                            error!("{}", e);
+                            exit::report_setup_failure(setup_error_write.as_raw_fd(), &e);
+                            return 126;
+                        }
+                    }
+                    let e = match self.config.init_path() {
+                        Some(init) => {
+                            let mut init_arguments =
+                                vec!["--".into(), command.display().to_string()];
+                            init_arguments.extend(arguments);
+                            execute_command(&init, &init_arguments)
+                        }
+                        None => execute_command(&command, &arguments),
+                    };
+                    error!("{}", e);
+                    exit::report_setup_failure(setup_error_write.as_raw_fd(), &e);
+                    // Like a shell does when a command can't be executed
+                    127
                 }),
-                &mut self.buffer.stack,
-                CloneFlags::CLONE_NEWUTS | CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWPID,
+                self.buffer.stack(),
+                clone_flags,
                 Some(SIGCHLD as i32),
             )?
         };
+        unistd::close(ready_read)?;
+        drop(setup_error_write);
+        self.setup_error_pipe = Some(setup_error_read);
+        // Only the container process keeps the writing end, so that reading ends when it's gone
+        let diagnostics_pipe = diagnostics_pipe.map(|(read, _)| read);
         self.pid = Some(pid);
-        self.state = ContainerState::Running;
+        self.fuse_daemon = fuse_daemon;
+        // Only the container process keeps the reading end, so that writing fails when it's gone
+        if let (Some(stdin), Some((_, write))) = (&self.config.stdin, stdin_pipe) {
+            stdin.start(write, &mut stdin_writers);
+        }
+        drop(stdin_writers);
+        // Likewise for the writing ends, so that reading ends when the container's processes are
+        // gone
+        if let (Some(output), Some([(stdout, _), (stderr, _)])) =
+            (&self.config.multiplexed_output, output_pipes)
+        {
+            self.output_threads = output.start(stdout, stderr);
+        }
+
+        if let (Some(seccomp), Some((supervisor_socket, _))) =
+            (&self.config.seccomp_notify, seccomp_sockets)
+        {
+            // Dropping the other end makes the supervisor notice when the container process
+            // exits without sending the listener
+            seccomp.supervise(supervisor_socket);
+        }
+        self.pidfd = pidfd_open(pid)?;
+
+        let setup = self.setup_from_outside(pid, rollback, spans).and_then(|_| {
+            match (&pty, &self.config.console_socket) {
+                (Some(pty), Some(socket_path)) => pty.send_master(socket_path),
+                _ => Ok(()),
+            }
+        });
+        if let Err(e) = setup {
+            // Closing the pipe without writing to it tells the container process to give up, but
+            // it's simpler to make sure it's gone
+            let _ = unistd::close(ready_write);
+            let _ = self.kill(SIGKILL);
+            let _ = self.wait_for_status(WaitPidFlag::empty());
+            self.release();
+            return Err(e);
+        }
+
+        let ready_write = unsafe { OwnedFd::from_raw_fd(ready_write) };
+        unistd::write(ready_write.as_raw_fd(), &[1])?;
+
+        self.start_pipe = Some(ready_write);
+        self.diagnostics_pipe = diagnostics_pipe;
+        self.state = ContainerState::Prepared;
 
         Ok(())
     }
 
-    pub fn wait_for_exit(&mut self) {
-        match wait::waitpid(self.pid, None) {
-            Ok(wait::WaitStatus::Exited(_, exit_code)) => {
-                info!("Process exited with exit code {}", exit_code)
+    /// Executes the configured command inside a prepared container.
+    pub fn start(&mut self) -> Result<(), CartonError> {
+        let Some(start_pipe) = self.start_pipe.take() else {
+            return Err(CartonError::NotRunning);
+        };
+        let Some(command) = &self.config.command else {
+            return Err(CartonError::MissingRequiredConfiguration("command".into()));
+        };
+
+        // The container process has finished its setup by the time it's done reporting on it, so
+        // the command can't get ahead of the reports
+        if let Some(diagnostics_pipe) = self.diagnostics_pipe.take() {
+            let spans = self.config.diagnostics.receive(diagnostics_pipe)?;
+            if let Some(setup_span) = &mut self.setup_span {
+                setup_span.children.extend(spans);
+                setup_span.end = SystemTime::now();
             }
-            Ok(status) => warn!("Process reported this instead of exiting: {:?}", status),
-            Err(e) => error!(
-                "Error while waiting for child (did it already exit?) {:#?}",
-                e
-            ),
+        }
+        if let Some(setup_span) = self.setup_span.take() {
+            self.config.diagnostics.span(&self.id, &setup_span);
+        }
+        let start = SystemTime::now();
+
+        // The command and its arguments are sent as NUL-terminated strings, just like they're
+        // passed to execv(), preceded by their total length. Closing the pipe can't mark the end,
+        // because containers that were prepared later inherited a copy of it.
+        let mut message = vec![0; 4];
+        for part in [command.as_os_str().as_bytes()]
+            .into_iter()
+            .chain(self.config.arguments.iter().map(|arg| arg.as_bytes()))
+        {
+            message.extend_from_slice(part);
+            message.push(0);
+        }
+        let length = (message.len() - 4) as u32;
+        message[..4].copy_from_slice(&length.to_ne_bytes());
+
+        let mut remaining = &message[..];
+        while !remaining.is_empty() {
+            let written = match unistd::write(start_pipe.as_raw_fd(), remaining) {
+                Ok(written) => written,
+                // Before it executes the command, the container process is only gone when its
+                // setup failed
+                Err(Errno::EPIPE) => return Err(self.reap_failed_setup()),
+                Err(e) => return Err(e.into()),
+            };
+            remaining = &remaining[written..];
+        }
+        drop(start_pipe);
+
+        self.state = ContainerState::Running;
+        self.deadline = self.config.timeout.map(|timeout| Instant::now() + timeout);
+
+        self.config.hooks.run_post_setup(self)?;
+        self.config
+            .diagnostics
+            .span(&self.id, &Span::since("start", start, Vec::new()));
+
+        Ok(())
+    }
+
+    /// Executes `command` inside a prepared container, instead of the one it was configured with.
+    pub fn start_command(
+        &mut self,
+        command: PathBuf,
+        arguments: Vec<String>,
+    ) -> Result<(), CartonError> {
+        self.config.command = Some(command);
+        self.config.arguments = arguments;
+        self.start()
+    }
+
+    /// Reaps the container process that exited before it executed the command, and returns the
+    /// error of its setup.
+    fn reap_failed_setup(&mut self) -> CartonError {
+        match self.wait(WaitPidFlag::empty()) {
+            Ok(Some(ContainerExit::SetupFailed(error))) => error,
+            Ok(_) => CartonError::NotRunning,
+            Err(e) => e,
+        }
+    }
+
+    /// Stops a prepared container that will not be started after all.
+    pub(crate) fn discard(&mut self) {
+        if self.start_pipe.take().is_none() {
+            return;
+        }
+
+        let _ = self.kill(SIGKILL);
+        let _ = self.wait_for_status(WaitPidFlag::empty());
+        self.release();
+        self.state = ContainerState::Exited;
+    }
+
+    /// Sets the project quota of a container with a storage limit. Ephemeral containers are
+    /// limited by the size of their tmpfs instead.
+    fn limit_storage(&self, rollback: &Rollback) -> Result<(), CartonError> {
+        let (Some(limit), Some(rootfs)) = (self.config.storage_limit, self.config.rootfs_source())
+        else {
+            return Ok(());
         };
+        if self.config.ephemeral {
+            return Ok(());
+        }
+
+        let project_id = quota::project_id(&self.id);
+        // Files may have been assigned to the project when setting the limit fails
+        rollback.record(Step::StorageLimit {
+            dir: rootfs.into(),
+            project_id,
+        });
+
+        self.config.diagnostics.operation(
+            format_args!(
+                "limiting {:?} to {} bytes with project quota {}",
+                rootfs, limit, project_id
+            ),
+            quota::limit(rootfs, project_id, limit),
+        )
+    }
+
+    /// Cleans up what the container used while its process was around, once that process is
+    /// gone: its network, cgroup, etc. Returns the spans of those parts.
+    fn release(&mut self) -> Vec<Span> {
+        let mut spans = Vec::new();
+        if let Some(attachment) = self.network.take() {
+            trace::measure(&mut spans, "network", || network::disconnect(&attachment));
+        }
+        if let Some(cgroup) = self.cgroup.take() {
+            trace::measure(&mut spans, "cgroup", || cgroup::remove(&cgroup));
+        }
+        if let (Some(_), Some(rootfs), false) = (
+            self.config.storage_limit,
+            self.config.rootfs_source(),
+            self.config.ephemeral,
+        ) {
+            if let Err(e) = quota::remove_limit(rootfs, quota::project_id(&self.id)) {
+                self.config
+                    .diagnostics
+                    .warning(format_args!("Could not remove the storage limit: {}", e));
+            }
+        }
+
+        ownership::remove_copies(&self.config.dir, &self.config.diagnostics);
+        trace::measure(&mut spans, "volumes", || {
+            volume::unmount_volumes(&mut self.config.mounts, &self.id, &self.config.diagnostics)
+        });
+
+        // The container's processes are gone, so its output is complete once the threads finish
+        for thread in self.output_threads.drain(..) {
+            let _ = thread.join();
+        }
 
         self.pid = None;
+        self.pidfd = None;
+        self.diagnostics_pipe = None;
+        self.setup_error_pipe = None;
+        self.setup_span = None;
+        self.notify_proxy = None;
+        self.dbus_proxy = None;
+        // The container's mount namespace, which still used the filesystem, is gone by now
+        self.fuse_daemon = None;
+
+        spans
+    }
+
+    /// Returns a file descriptor that refers to the container's process (a "pidfd"), if the kernel
+    /// supports those (Linux 5.3 and up). It becomes readable when the process exits, so it can be
+    /// used with poll() or epoll to wait for many containers at once, after which
+    /// `wait_for_exit()` returns right away.
+    pub fn pidfd(&self) -> Option<BorrowedFd<'_>> {
+        self.pidfd.as_ref().map(|pidfd| pidfd.as_fd())
+    }
+
+    /// Stops all processes of the running container with the cgroup freezer, until `resume()`. The
+    /// processes don't notice that they were paused, unlike with SIGSTOP. The container needs a
+    /// cgroup of its own, see `ContainerBuilder::delegate_cgroup()`.
+    pub fn pause(&self) -> Result<(), CartonError> {
+        self.freeze(true)
+    }
+
+    /// Resumes the processes of a container that was paused with `pause()`.
+    pub fn resume(&self) -> Result<(), CartonError> {
+        self.freeze(false)
+    }
+
+    /// Returns whether the container is paused, see `pause()`.
+    pub fn is_paused(&self) -> bool {
+        self.state == ContainerState::Running
+            && self.cgroup.is_some()
+            && cgroup::is_frozen(&self.id)
+    }
+
+    fn freeze(&self, frozen: bool) -> Result<(), CartonError> {
+        if self.state != ContainerState::Running {
+            return Err(CartonError::NotRunning);
+        }
+        let Some(cgroup) = &self.cgroup else {
+            return Err(cgroup::no_cgroup(&self.id));
+        };
+
+        cgroup::freeze(cgroup, frozen)
+    }
+
+    /// Changes the limits on what the running container can use, without restarting it. The
+    /// container needs a cgroup of its own, see `ContainerBuilder::delegate_cgroup()`.
+    pub fn update_limits(&self, limits: &ResourceLimits) -> Result<(), CartonError> {
+        if self.state != ContainerState::Running {
+            return Err(CartonError::NotRunning);
+        }
+        let Some(cgroup) = &self.cgroup else {
+            return Err(cgroup::no_cgroup(&self.id));
+        };
+
+        cgroup::set_limits(cgroup, limits)
+    }
+
+    /// Sends `signal` to the container's process.
+    pub fn kill(&self, signal: Signal) -> Result<(), CartonError> {
+        let Some(pid) = self.pid else {
+            return Err(CartonError::NotRunning);
+        };
+
+        match &self.pidfd {
+            Some(pidfd) => pidfd_send_signal(pidfd, signal)?,
+            None => signal::kill(pid, signal)?,
+        }
+
+        Ok(())
+    }
+
+    /// Blocks until the container's process changes state and reaps it when it exited. With
+    /// `WNOHANG` in `flags` it returns `StillAlive` instead of blocking.
+    fn wait_for_status(&self, flags: WaitPidFlag) -> nix::Result<wait::WaitStatus> {
+        let Some(pid) = self.pid else {
+            return Err(Errno::ECHILD);
+        };
+
+        match &self.pidfd {
+            Some(pidfd) => {
+                match wait::waitid(wait::Id::PIDFd(pidfd.as_fd()), WaitPidFlag::WEXITED | flags) {
+                    // Linux 5.3 has pidfds, but can only wait for them from 5.4 on
+                    Err(Errno::EINVAL | Errno::ENOSYS) => wait::waitpid(pid, Some(flags)),
+                    result => result,
+                }
+            }
+            None => wait::waitpid(pid, Some(flags)),
+        }
+    }
+
+    /// Does the part of the setup that has to happen from outside of the container, while the
+    /// container process waits.
+    fn setup_from_outside(
+        &mut self,
+        pid: unistd::Pid,
+        rollback: &Rollback,
+        spans: &mut Vec<Span>,
+    ) -> Result<(), CartonError> {
+        if let Some(id_mappings) = &self.config.user_namespace {
+            idmap::write_user_namespace_mappings(&self.config.diagnostics, pid, id_mappings)?;
+        }
+
+        if self.config.delegate_cgroup {
+            let owner = self
+                .config
+                .user_namespace
+                .as_ref()
+                .and_then(IdMappings::host_root);
+            self.cgroup = Some(trace::measure(spans, "cgroup", || {
+                cgroup::create(&self.config.diagnostics, &self.id, pid, owner, rollback)
+            })?);
+        }
+        // Once the process is gone, systemd removes the scope by itself
+        if self.config.systemd_scope {
+            let description = format!(
+                "carton container {}",
+                self.config.name.as_deref().unwrap_or(&self.id)
+            );
+            trace::measure(spans, "cgroup", || {
+                scope::start(&self.id, pid, &description)
+            })?;
+        }
+
+        if !matches!(self.config.network, Network::Host) {
+            self.network = trace::measure(spans, "network", || self.connect_network(pid))?;
+        }
+
+        if let (Some(attachment), Some(limit)) = (&self.network, &self.config.bandwidth_limit) {
+            network::limit_bandwidth(attachment, limit)?;
+        }
+
+        if let Some(network_files) = &self.config.network_files {
+            network_files.write(
+                &self.config.dir,
+                self.config.hostname.as_deref().unwrap_or(&self.id),
+                self.network.as_ref().map(NetworkAttachment::addresses),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Connects the network namespace of the container process `pid` to the container's network,
+    /// unless it shares the host's.
+    fn connect_network(&self, pid: unistd::Pid) -> Result<Option<NetworkAttachment>, CartonError> {
+        let attachment = match &self.config.network {
+            Network::Host => return Ok(None),
+            Network::Bridge(bridge) => network::connect_bridge(bridge, &self.id, pid)?,
+            Network::Macvlan(macvlan) => {
+                network::connect_macvlan(macvlan, &self.id, pid, &self.config.dir)?
+            }
+            Network::Shared(shared) => network::connect_shared(shared),
+            Network::Cni(cni) => network::connect_cni(cni, &self.id, pid, &self.config.dir)?,
+        };
+
+        Ok(Some(attachment))
+    }
+
+    /// Blocks until the container's process exits and returns how it ended, see `ContainerExit`.
+    /// `ContainerExit::code()` is the exit code that a shell would report.
+    pub fn wait_for_exit(&mut self) -> Result<ContainerExit, CartonError> {
+        loop {
+            let exit = match self.enforce_timeout()? {
+                Some(until_next_signal) => self.wait_timeout(until_next_signal)?,
+                None => self.wait(WaitPidFlag::empty())?,
+            };
+            if let Some(exit) = exit {
+                return Ok(exit);
+            }
+        }
+    }
+
+    /// Returns how the container's process ended if it exited, without blocking.
+    pub fn try_wait(&mut self) -> Result<Option<ContainerExit>, CartonError> {
+        self.enforce_timeout()?;
+        self.wait(WaitPidFlag::WNOHANG)
+    }
+
+    /// Like `wait_for_exit()`, but gives up after `timeout` and returns `None` when the container's
+    /// process is still running by then.
+    pub fn wait_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<ContainerExit>, CartonError> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let until_next_signal = self.enforce_timeout()?;
+            if let Some(exit) = self.wait(WaitPidFlag::WNOHANG)? {
+                return Ok(Some(exit));
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            let remaining = until_next_signal.map_or(remaining, |until| remaining.min(until));
+
+            match &self.pidfd {
+                // The pidfd becomes readable when the process exits
+                Some(pidfd) => {
+                    let timeout = remaining.as_millis().clamp(1, i32::MAX as u128) as i32;
+                    match poll::poll(&mut [PollFd::new(pidfd, PollFlags::POLLIN)], timeout) {
+                        Ok(_) | Err(Errno::EINTR) => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                None => thread::sleep(remaining.min(WAIT_POLL_INTERVAL)),
+            }
+        }
+    }
+
+    /// Sends the container SIGTERM when it ran out of time, and SIGKILL when it's still there
+    /// after the grace period, see `ContainerBuilder::timeout()`. Returns how long it is until
+    /// the next of those, if any.
+    fn enforce_timeout(&mut self) -> Result<Option<Duration>, CartonError> {
+        let Some(deadline) = self.deadline else {
+            return Ok(None);
+        };
+        let now = Instant::now();
+        if now < deadline {
+            return Ok(Some(deadline - now));
+        }
+
+        let signal = if self.timed_out {
+            self.deadline = None;
+            Signal::SIGKILL
+        } else {
+            self.timed_out = true;
+            let grace_period = self
+                .config
+                .timeout_grace_period
+                .unwrap_or(DEFAULT_TIMEOUT_GRACE_PERIOD);
+            self.deadline = Some(now + grace_period);
+            Signal::SIGTERM
+        };
+        warn!(
+            "Container {} ran out of time, sending it {}",
+            self.id, signal
+        );
+        let result = match (&self.pidfd, self.pid) {
+            (Some(pidfd), _) => pidfd_send_signal(pidfd, signal),
+            (None, Some(pid)) => signal::kill(pid, signal),
+            (None, None) => return Err(CartonError::NotRunning),
+        };
+        match result {
+            // It exited in the meantime
+            Ok(()) | Err(Errno::ESRCH) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(self.deadline.map(|deadline| deadline - now))
+    }
+
+    /// Waits for the container's process with `flags` and cleans up after it when it exited.
+    fn wait(&mut self, flags: WaitPidFlag) -> Result<Option<ContainerExit>, CartonError> {
+        if self.pid.is_none() {
+            return Err(CartonError::NotRunning);
+        }
+
+        let exit = match self.wait_for_status(flags) {
+            Ok(wait::WaitStatus::Exited(_, exit_code)) => ContainerExit::Exited(exit_code),
+            Ok(wait::WaitStatus::Signaled(_, signal, core_dumped)) => {
+                ContainerExit::Signaled(signal, core_dumped)
+            }
+            Ok(wait::WaitStatus::StillAlive) | Err(Errno::EINTR) => return Ok(None),
+            Ok(status) => {
+                warn!("Process reported this instead of exiting: {:?}", status);
+                return Ok(None);
+            }
+            Err(e) => {
+                error!(
+                    "Error while waiting for child (did it already exit?) {:#?}",
+                    e
+                );
+                return Err(e.into());
+            }
+        };
+
+        // Both say more than the exit status that follows from them
+        let setup_error = self
+            .setup_error_pipe
+            .take()
+            .and_then(|pipe| exit::read_setup_failure(&pipe));
+        let exit = match setup_error {
+            Some(error) => ContainerExit::SetupFailed(error),
+            None if self.timed_out => ContainerExit::TimedOut,
+            None => exit,
+        };
+        info!("Process {}", exit);
+
+        let stop_start = SystemTime::now();
+        let spans = self.release();
+        self.config
+            .diagnostics
+            .span(&self.id, &Span::since("stop", stop_start, spans));
         self.state = ContainerState::Exited;
+        self.exit = Some(exit.clone());
+        self.deadline = None;
+
+        Ok(Some(exit))
+    }
+
+    /// Saves the state of the running container to `images_dir` with CRIU, after which the
+    /// container is stopped. It can be started again from that point with `restore()`, by this
+    /// `Container` or by one that was built with the same configuration.
+    ///
+    /// Only containers that share the network of the host or of another container can be
+    /// checkpointed, because CRIU can't recreate their connections to the host's network.
+    pub fn checkpoint(&mut self, images_dir: &Path) -> Result<(), CartonError> {
+        self.checkpoint_with(images_dir, &CheckpointOptions::new())
+    }
+
+    /// Like `checkpoint()`, with `options`. A container that's left running keeps running.
+    pub fn checkpoint_with(
+        &mut self,
+        images_dir: &Path,
+        options: &CheckpointOptions,
+    ) -> Result<(), CartonError> {
+        let Some(pid) = self.pid else {
+            return Err(CartonError::NotRunning);
+        };
+
+        let shared_network_namespace = self.config.checkpoint_network_namespace()?;
+        checkpoint::dump(
+            pid,
+            images_dir,
+            &self.config.external_mounts(),
+            shared_network_namespace.as_deref(),
+            options,
+        )?;
+        if options.leave_running {
+            return Ok(());
+        }
+
+        // CRIU killed the container's processes after dumping them
+        let _ = self.wait_for_status(WaitPidFlag::empty());
+        self.release();
+        self.state = ContainerState::Checkpointed;
+
+        Ok(())
+    }
+
+    /// Starts the container again from a checkpoint in `images_dir`, made with `checkpoint()`.
+    pub fn restore(&mut self, images_dir: &Path) -> Result<(), CartonError> {
+        self.restore_with(images_dir, &CheckpointOptions::new())
+    }
+
+    /// Like `restore()`, with the `options` that the checkpoint was made with.
+    pub fn restore_with(
+        &mut self,
+        images_dir: &Path,
+        options: &CheckpointOptions,
+    ) -> Result<(), CartonError> {
+        if let ContainerState::Running = self.state {
+            return Err(CartonError::AlreadyRunning);
+        }
+
+        let rollback = Rollback::default();
+        let result = self.restore_from(images_dir, options, &rollback);
+        if result.is_err() {
+            rollback.undo(
+                &Host::new(&self.config.diagnostics),
+                &self.config.diagnostics,
+            );
+            volume::unmount_volumes(&mut self.config.mounts, &self.id, &self.config.diagnostics);
+            if let Some(attachment) = self.network.take() {
+                network::disconnect(&attachment);
+            }
+        }
+
+        result
+    }
+
+    /// Does the work of `restore_with()`, recording the steps that have to be undone when it fails
+    /// in `rollback`.
+    fn restore_from(
+        &mut self,
+        images_dir: &Path,
+        options: &CheckpointOptions,
+        rollback: &Rollback,
+    ) -> Result<(), CartonError> {
+        self.config.validate()?;
+        self.config.verify_files()?;
+        if let Some(emulator) = &self.config.emulator {
+            emulator.register(&self.config.diagnostics)?;
+        }
+        let shared_network_namespace = self.config.checkpoint_network_namespace()?;
+
+        if let Network::Shared(shared) = &self.config.network {
+            self.network = Some(network::connect_shared(shared));
+        }
+        // The files are bind mounted from the container's directory, which might not exist yet
+        // when this is a different `Container` than the one that was checkpointed
+        if let Some(network_files) = &self.config.network_files {
+            network_files.write(
+                &self.config.dir,
+                self.config.hostname.as_deref().unwrap_or(&self.id),
+                self.network.as_ref().map(NetworkAttachment::addresses),
+            )?;
+        }
+        files::write(&self.config.files, &self.config.dir, rollback)?;
+
+        let fuse_daemon = match &self.config.fuse_rootfs {
+            Some(fuse_rootfs) => Some(fuse_rootfs.start()?),
+            None => None,
+        };
+        self.config.verify_rootfs()?;
+        self.limit_storage(rollback)?;
+        volume::mount_volumes(&mut self.config.mounts, &self.id, &self.config.diagnostics)?;
+        let rootfs = self
+            .config
+            .rootfs
+            .as_ref()
+            .and_then(|rootfs| rootfs.source.as_ref())
+            .expect("rootfs should have been validated");
+        let pid = checkpoint::restore(
+            images_dir,
+            rootfs,
+            &self.config.external_mounts(),
+            shared_network_namespace.as_deref(),
+            options,
+        )?;
+
+        self.pid = Some(pid);
+        self.pidfd = pidfd_open(pid)?;
+        self.fuse_daemon = fuse_daemon;
+        self.state = ContainerState::Running;
+        self.exit = None;
+
+        Ok(())
     }
 }
 
 #[derive(Default, Debug)]
 pub(crate) struct ContainerConfiguration {
+    /// Directory on the host for the container's own files, like its generated /etc/hosts.
+    pub(crate) dir: PathBuf,
     /// The path to the root filesystem of the container.
     pub(crate) rootfs: Option<Mount>,
     /// Command to execute inside the container.
     pub(crate) command: Option<PathBuf>,
     /// Arguments to the command
     pub(crate) arguments: Vec<String>,
+    /// Whether the command is a shell command, which `SHELL` runs.
+    pub(crate) shell_form: bool,
     /// Vita paths (like /proc, /tmp, /dev) and paths from the "host" to bind mount inside the container.
     pub(crate) mounts: Vec<Mount>,
+    /// Filesystems that the embedding program mounts itself.
+    pub(crate) mount_providers: Vec<Box<dyn MountProvider>>,
     /// Device nodes to create in /dev.
     pub(crate) devices: Vec<DeviceNode>,
+    /// How the container is connected to the network.
+    pub(crate) network: Network,
+    pub(crate) bandwidth_limit: Option<BandwidthLimit>,
+    /// Hostname of the container.
+    pub(crate) hostname: Option<String>,
+    /// NIS domain name of the container, which it otherwise shares with the host.
+    pub(crate) domainname: Option<String>,
+    /// Environment variables of the command, instead of the calling process' environment.
+    pub(crate) environment: Option<Vec<(String, String)>>,
+    /// The variables of the calling process' environment that the command gets, instead of all.
+    pub(crate) inherit_env: Option<EnvFilter>,
+    /// Variables that point the command to the host's display server, on top of its environment.
+    pub(crate) display_environment: Vec<(String, String)>,
+    /// Directory in the container in which the command starts, instead of `/`.
+    pub(crate) working_dir: Option<PathBuf>,
+    /// User and group in the container as which the command runs, instead of root.
+    pub(crate) user: Option<(u32, u32)>,
+    /// Name that the user gave the container, to refer to it by instead of its ID.
+    pub(crate) name: Option<String>,
+    /// Generated /etc/resolv.conf and /etc/hosts files, if the container should get those.
+    pub(crate) network_files: Option<NetworkFiles>,
+    /// Whether the container gets a cgroup subtree of its own to manage.
+    pub(crate) delegate_cgroup: bool,
+    /// Whether the container runs in a transient scope of the user's systemd.
+    pub(crate) systemd_scope: bool,
+    /// How long the container's command may run, see `ContainerBuilder::timeout()`.
+    pub(crate) timeout: Option<Duration>,
+    /// How long the container gets to exit after SIGTERM when it ran out of time.
+    pub(crate) timeout_grace_period: Option<Duration>,
+    /// System calls of the container to pass to a handler in the calling process.
+    pub(crate) seccomp_notify: Option<SeccompNotify>,
+    /// The only system calls that the container may make.
+    pub(crate) seccomp_profile: Option<SeccompProfile>,
+    /// Listening sockets to pass into the container, like systemd's socket activation does.
+    pub(crate) listen_fds: Option<ListenFds>,
+    /// systemd's notification socket, to pass the container's sd_notify messages on to.
+    pub(crate) notify_socket: Option<PathBuf>,
+    /// The proxy through which the container gets a filtered view of a D-Bus bus.
+    pub(crate) dbus_proxy: Option<DbusProxy>,
+    /// Unix socket to send the master end of the container's terminal to. The container only gets
+    /// a terminal when this is set.
+    pub(crate) console_socket: Option<PathBuf>,
+    /// Init binary on the host to run the command with.
+    pub(crate) init: Option<PathBuf>,
+    /// The emulator that runs the binaries of a root filesystem of another architecture.
+    pub(crate) emulator: Option<Emulator>,
+    /// FUSE daemon that provides the root filesystem.
+    pub(crate) fuse_rootfs: Option<FuseRootfs>,
+    /// How the owners of the files in the root filesystem are mapped.
+    pub(crate) rootfs_id_mappings: Option<IdMappings>,
+    /// The ID mappings of the container's user namespace, if it gets one.
+    pub(crate) user_namespace: Option<IdMappings>,
+    /// Whether the container's writes to the root filesystem go to a tmpfs instead.
+    pub(crate) ephemeral: bool,
+    /// Whether the container runs on the host's filesystem instead of a root filesystem of its own.
+    pub(crate) sandbox: bool,
+    /// How many bytes the root filesystem (or the tmpfs of an ephemeral container) can take up.
+    pub(crate) storage_limit: Option<u64>,
+    /// Expected digest of the root filesystem, which is verified before the container starts.
+    pub(crate) rootfs_digest: Option<Sha256Digest>,
+    /// Other files, like image layers, whose digests are verified before the container starts.
+    pub(crate) verified_files: Vec<(PathBuf, Sha256Digest)>,
+    /// What happens with core dumps of crashing processes in the container.
+    pub(crate) core_dumps: CoreDumps,
+    /// Code of the embedding program to run in the container process and after the start.
+    pub(crate) hooks: Hooks,
+    /// Stream to feed the container's stdin from, instead of inheriting the caller's stdin.
+    pub(crate) stdin: Option<StdinStream>,
+    /// Where to send the container's stdout and stderr in frames, instead of letting the container
+    /// inherit the caller's stdout and stderr.
+    pub(crate) multiplexed_output: Option<MultiplexedOutput>,
+    /// Where the steps and problems of the setup are reported to.
+    pub(crate) diagnostics: Reporter,
+    /// Where the settings of the container came from, for `ContainerInfo::settings`.
+    pub(crate) settings: BTreeMap<String, Setting>,
+    /// The project that the container belongs to.
+    pub(crate) project: Option<ProjectInfo>,
+    /// Secrets that the container gets in /run/secrets.
+    pub(crate) secrets: Vec<Secret>,
+    /// Whether `CREDENTIALS_DIRECTORY` points the command at the secrets.
+    pub(crate) credentials_directory: bool,
+    /// Files that the container gets with the given contents.
+    pub(crate) files: Vec<InjectedFile>,
 }
 
 impl ContainerConfiguration {
+    pub(crate) fn rootfs_source(&self) -> Option<&Path> {
+        self.rootfs
+            .as_ref()
+            .and_then(|rootfs| rootfs.source.as_deref())
+    }
+
+    /// Verifies the digests of the files that the root filesystem may be made from, like the
+    /// image of a FUSE root filesystem. This happens before anything is mounted.
+    fn verify_files(&self) -> Result<(), CartonError> {
+        for (path, digest) in &self.verified_files {
+            self.diagnostics
+                .info(format_args!("verifying the digest of {}", path.display()));
+            digest.verify(path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Verifies the digest of the root filesystem, once it's mounted if it's a FUSE filesystem.
+    fn verify_rootfs(&self) -> Result<(), CartonError> {
+        let (Some(digest), Some(rootfs)) = (&self.rootfs_digest, &self.rootfs) else {
+            return Ok(());
+        };
+        let source = rootfs
+            .source
+            .as_ref()
+            .expect("rootfs source path should not be None");
+        self.diagnostics
+            .info(format_args!("verifying the digest of {}", source.display()));
+
+        digest.verify(source)
+    }
+
+    /// Returns the bind mounts of files and directories of the host, which CRIU has to leave
+    /// alone.
+    fn external_mounts(&self) -> Vec<ExternalMount> {
+        let network_file_mounts = self
+            .network_files
+            .as_ref()
+            .map(|network_files| network_files.mounts(&self.dir))
+            .unwrap_or_default();
+        let file_mounts = files::mounts(&self.files, &self.dir);
+        let init_mount = self
+            .init
+            .as_ref()
+            .map(|init| Mount::bind(init.clone(), INIT_PATH.into(), None, None));
+        let core_dump_mount = self.core_dumps.mount().ok().flatten();
+
+        self.mounts
+            .iter()
+            .chain(&network_file_mounts)
+            .chain(&file_mounts)
+            .chain(&init_mount)
+            .chain(&core_dump_mount)
+            .filter(|mount| mount.fstype.is_none() && !mount.is_skipped())
+            .filter_map(|mount| {
+                let source = mount.source.clone()?;
+                Some((Path::new("/").join(&mount.relative_target), source))
+            })
+            .collect()
+    }
+
+    /// Returns the path of the shared network namespace that the container is in, if any, or an
+    /// error when the container's network can't be checkpointed.
+    fn checkpoint_network_namespace(&self) -> Result<Option<PathBuf>, CartonError> {
+        match &self.network {
+            Network::Host => Ok(None),
+            Network::Shared(shared) => Ok(shared.namespace_path()),
+            _ => Err(CartonError::CheckpointError(
+                "only containers on the host's network or on the network of another container can \
+                 be checkpointed"
+                    .into(),
+            )),
+        }
+    }
+
+    /// Returns where the init binary is, as the container sees it, if it has one. It's bind
+    /// mounted into the root filesystem, but a sandbox sees the host's.
+    pub(crate) fn init_path(&self) -> Option<PathBuf> {
+        let init = self.init.as_ref()?;
+        if self.sandbox {
+            Some(init.clone())
+        } else {
+            Some(Path::new("/").join(INIT_PATH))
+        }
+    }
+
     pub(crate) fn validate(&self) -> Result<(), CartonError> {
+        if self.sandbox {
+            self.validate_sandbox()?;
+        }
+
         match &self.rootfs {
+            None if self.sandbox => {}
             None => return Err(CartonError::MissingRequiredConfiguration("rootfs".into())),
             Some(root_spec) => {
                 if !root_spec
@@ -117,27 +1453,334 @@ impl ContainerConfiguration {
             }
         };
 
-        if self.command.is_none() {
-            return Err(CartonError::MissingRequiredConfiguration("command".into()));
+        // A FUSE root filesystem is empty until it's mounted. The shell is usually a symlink,
+        // whose target is only right inside the root filesystem.
+        if let (true, None, Some(rootfs)) =
+            (self.shell_form, &self.fuse_rootfs, self.rootfs_source())
+        {
+            let shell = rootfs.join(SHELL.trim_start_matches('/'));
+            if shell.symlink_metadata().is_err() {
+                return Err(CartonError::InvalidConfiguration(format!(
+                    "the root filesystem has no {} to run the shell command `{}` with",
+                    SHELL,
+                    self.arguments.last().map_or("", String::as_str)
+                )));
+            }
+        }
+
+        if let Some(name) = &self.name {
+            validate_name("container", name)?;
+        }
+        if let Some(project) = &self.project {
+            validate_name("project", &project.name)?;
+        }
+        for (index, file) in self.files.iter().enumerate() {
+            file.validate()?;
+            if self.files[..index]
+                .iter()
+                .any(|other| other.relative_target == file.relative_target)
+            {
+                return Err(CartonError::InvalidConfiguration(format!(
+                    "there is more than one file at {}",
+                    file.relative_target.display()
+                )));
+            }
+        }
+        for (index, secret) in self.secrets.iter().enumerate() {
+            validate_name("secret", &secret.name)?;
+            if self.secrets[..index]
+                .iter()
+                .any(|other| other.name == secret.name)
+            {
+                return Err(CartonError::InvalidConfiguration(format!(
+                    "there is more than one secret called {}",
+                    secret.name
+                )));
+            }
+        }
+
+        if let Some(hostname) = &self.hostname {
+            validate_uts_name("hostname", hostname)?;
+        }
+        if let Some(domainname) = &self.domainname {
+            validate_uts_name("domain name", domainname)?;
+        }
+
+        match &self.network {
+            Network::Host => {}
+            Network::Bridge(bridge) => bridge.validate()?,
+            Network::Macvlan(macvlan) => macvlan.validate()?,
+            Network::Shared(shared) => shared.validate()?,
+            Network::Cni(cni) => cni.validate()?,
+        }
+
+        if let Some(seccomp_notify) = &self.seccomp_notify {
+            seccomp_notify.validate()?;
+        }
+        if let Some(seccomp_profile) = &self.seccomp_profile {
+            seccomp_profile.validate()?;
+        }
+
+        if let Some(fuse_rootfs) = &self.fuse_rootfs {
+            fuse_rootfs.validate()?;
+        }
+
+        if let Some(id_mappings) = &self.rootfs_id_mappings {
+            id_mappings.validate()?;
+        }
+
+        if let Some(id_mappings) = &self.user_namespace {
+            id_mappings.validate()?;
+            // Joining a network namespace needs privileges in the user namespace that owns it
+            if matches!(self.network, Network::Shared(_)) {
+                return Err(CartonError::InvalidConfiguration(
+                    "a container in a user namespace can't join the network of another container"
+                        .into(),
+                ));
+            }
+        }
+
+        self.core_dumps.validate()?;
+
+        if self.ephemeral {
+            // The path ends up in the overlay's mount options, which are separated by commas and
+            // colons
+            let rootfs = self
+                .rootfs
+                .as_ref()
+                .and_then(|rootfs| rootfs.source.as_ref());
+            if rootfs.map_or(false, |rootfs| {
+                rootfs.to_string_lossy().contains([',', ':'])
+            }) {
+                return Err(CartonError::InvalidConfiguration(
+                    "the rootfs path of an ephemeral container can't contain `,` or `:`".into(),
+                ));
+            }
+        }
+
+        if let Some(working_dir) = &self.working_dir {
+            if !working_dir.is_absolute() {
+                return Err(CartonError::InvalidConfiguration(format!(
+                    "the working directory {} isn't an absolute path",
+                    working_dir.display()
+                )));
+            }
+        }
+
+        if let Some(init) = &self.init {
+            if !init.is_file() {
+                return Err(CartonError::InvalidConfiguration(format!(
+                    "init binary does not exist: {}",
+                    init.display()
+                )));
+            }
+        }
+
+        if self.delegate_cgroup {
+            cgroup::validate()?;
+        }
+        if self.delegate_cgroup && self.systemd_scope {
+            return Err(CartonError::InvalidConfiguration(
+                "a container in a systemd scope can't have a cgroup of its own too".into(),
+            ));
+        }
+
+        ownership::validate(self)?;
+
+        if self.stdin.is_some() && self.console_socket.is_some() {
+            return Err(CartonError::InvalidConfiguration(
+                "a container with a terminal reads its input from the terminal, not from a stdin \
+                 stream"
+                    .into(),
+            ));
+        }
+        if self.multiplexed_output.is_some() && self.console_socket.is_some() {
+            return Err(CartonError::InvalidConfiguration(
+                "a container with a terminal writes its output to the terminal, which can't be \
+                 multiplexed"
+                    .into(),
+            ));
+        }
+
+        if self.bandwidth_limit.is_some() && !matches!(self.network, Network::Bridge(_)) {
+            return Err(CartonError::InvalidConfiguration(
+                "a bandwidth limit can only be set for containers on a bridge network".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Sandboxes have no root filesystem, so nothing that only applies to one can be configured.
+    /// They can't create device nodes or mount the notify or D-Bus socket either, because those
+    /// would end up on the host's filesystem.
+    fn validate_sandbox(&self) -> Result<(), CartonError> {
+        let conflicts = [
+            ("a root filesystem", self.rootfs.is_some()),
+            (
+                "an ID-mapped root filesystem",
+                self.rootfs_id_mappings.is_some(),
+            ),
+            ("ephemeral", self.ephemeral),
+            ("a storage limit", self.storage_limit.is_some()),
+            ("a root filesystem digest", self.rootfs_digest.is_some()),
+            ("device nodes", !self.devices.is_empty()),
+            ("a notify socket", self.notify_socket.is_some()),
+            ("a D-Bus proxy", self.dbus_proxy.is_some()),
+        ];
+        if let Some((option, _)) = conflicts.iter().find(|(_, conflicts)| *conflicts) {
+            return Err(CartonError::InvalidConfiguration(format!(
+                "a sandbox runs on the host's filesystem and can't have {}",
+                option
+            )));
         }
 
         Ok(())
     }
 }
 
+/// Checks the name of a container or project. Names end up in the CLI and in unit file names, so
+/// they're kept simple.
+pub(crate) fn validate_name(kind: &str, name: &str) -> Result<(), CartonError> {
+    let valid = name
+        .chars()
+        .next()
+        .map_or(false, |c| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+    if !valid {
+        return Err(CartonError::InvalidConfiguration(format!(
+            "invalid {} name `{}`, names consist of letters, digits, `_`, `.` and `-` and start with a letter or digit",
+            kind, name
+        )));
+    }
+
+    Ok(())
+}
+
+/// Checks a hostname or NIS domain name: at most 64 bytes, which is what the kernel takes, in
+/// labels of letters, digits, `-` and `_` that are separated by dots and don't start with a `-`.
+fn validate_uts_name(kind: &str, name: &str) -> Result<(), CartonError> {
+    // HOST_NAME_MAX, which is the limit for domain names too
+    const MAX_LENGTH: usize = 64;
+
+    let valid = name.len() <= MAX_LENGTH
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        });
+    if !valid {
+        return Err(CartonError::InvalidConfiguration(format!(
+            "invalid {} `{}`, it should be at most {} characters of labels of letters, digits, `-` \
+             and `_`, separated by dots",
+            kind, name, MAX_LENGTH
+        )));
+    }
+
+    Ok(())
+}
+
+/// The memory for the container process' stack. It's mapped with mmap() instead of allocated on
+/// the heap, so that the kernel only hands out memory for the parts of the stack that actually get
+/// used, however large the stack may be (RLIMIT_STACK can easily be gigabytes). Below the stack
+/// lies a "guard page" that can't be read from or written to, so that a stack overflow crashes the
+/// container process right away instead of silently overwriting other memory.
 #[derive(Default, Debug)]
 pub struct ContainerBuffer {
-    /// The buffer that contains the container's process stack. If this is too small
-    /// then stack overflows will happen.
-    pub(crate) stack: Vec<u8>,
+    /// Start of the mapping, which begins with the guard page.
+    mapping: Option<NonNull<c_void>>,
+    /// Size of the whole mapping, including the guard page.
+    length: usize,
+    guard_size: usize,
 }
 
-#[derive(Default, Debug)]
+// The mapping is owned by the `ContainerBuffer`, just like the memory of a `Vec` would be
+unsafe impl Send for ContainerBuffer {}
+
+impl ContainerBuffer {
+    pub(crate) fn new(stack_size: usize) -> Result<Self, CartonError> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let stack_size = (stack_size.max(1) + page_size - 1) / page_size * page_size;
+        let length = stack_size + page_size;
+
+        let mapping = unsafe {
+            mman::mmap(
+                None,
+                NonZeroUsize::new(length).expect("stack size should not be 0"),
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_PRIVATE
+                    | MapFlags::MAP_ANONYMOUS
+                    | MapFlags::MAP_STACK
+                    | MapFlags::MAP_NORESERVE,
+                None::<BorrowedFd>,
+                0,
+            )?
+        };
+
+        // Stacks grow down, towards the lowest address
+        if let Err(e) = unsafe { mman::mprotect(mapping, page_size, ProtFlags::PROT_NONE) } {
+            let _ = unsafe { mman::munmap(mapping, length) };
+            return Err(e.into());
+        }
+
+        Ok(ContainerBuffer {
+            mapping: NonNull::new(mapping),
+            length,
+            guard_size: page_size,
+        })
+    }
+
+    /// Returns the usable part of the stack, above the guard page.
+    pub(crate) fn stack(&mut self) -> &mut [u8] {
+        match self.mapping {
+            Some(mapping) => unsafe {
+                slice::from_raw_parts_mut(
+                    mapping.as_ptr().cast::<u8>().add(self.guard_size),
+                    self.length - self.guard_size,
+                )
+            },
+            None => &mut [],
+        }
+    }
+}
+
+impl Drop for ContainerBuffer {
+    fn drop(&mut self) {
+        if let Some(mapping) = self.mapping.take() {
+            let _ = unsafe { mman::munmap(mapping.as_ptr(), self.length) };
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ContainerState {
     #[default]
     NotCreated,
+    /// The container is set up and waits for its command, see `Container::prepare()`.
+    Prepared,
     Running,
     Exited,
+    /// The container was stopped by `Container::checkpoint()` and can be restored.
+    Checkpointed,
+}
+
+impl fmt::Display for ContainerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ContainerState::NotCreated => "created",
+            ContainerState::Prepared => "prepared",
+            ContainerState::Running => "running",
+            ContainerState::Exited => "exited",
+            ContainerState::Checkpointed => "checkpointed",
+        };
+
+        f.pad(name)
+    }
 }
 
 #[derive(Debug)]
@@ -147,6 +1790,13 @@ pub struct Mount {
     fstype: Option<String>,
     flags: mount::MsFlags,
     data: Option<String>,
+    /// Skip the mount, with a warning, when its source doesn't exist
+    optional: bool,
+    /// User and group of the container that the files of the source appear to be owned by, see
+    /// `ownership`
+    owner: Option<(u32, u32)>,
+    /// Volume whose path on the host becomes the source when the container is prepared
+    volume: Option<Volume>,
 }
 
 impl Mount {
@@ -157,6 +1807,9 @@ impl Mount {
             fstype: None,
             flags: mount::MsFlags::MS_BIND | mount::MsFlags::MS_PRIVATE,
             data: None,
+            optional: false,
+            owner: None,
+            volume: None,
         }
     }
 
@@ -174,6 +1827,24 @@ impl Mount {
             fstype: None,
             flags: flags.unwrap_or(mount::MsFlags::MS_BIND | mount::MsFlags::MS_PRIVATE),
             data,
+            optional: false,
+            owner: None,
+            volume: None,
+        }
+    }
+
+    /// Defines a bind mount of a volume, whose source is only known once its driver has mounted
+    /// it, see `volume::mount_volumes()`.
+    pub(crate) fn volume(volume: Volume, relative_target: PathBuf) -> Self {
+        Mount {
+            source: None,
+            relative_target,
+            fstype: None,
+            flags: mount::MsFlags::MS_BIND | mount::MsFlags::MS_PRIVATE,
+            data: None,
+            optional: false,
+            owner: None,
+            volume: Some(volume),
         }
     }
 
@@ -186,6 +1857,9 @@ impl Mount {
             fstype: Some("proc".into()),
             flags: mount::MsFlags::empty(),
             data: None,
+            optional: false,
+            owner: None,
+            volume: None,
         }
     }
 
@@ -196,6 +1870,9 @@ impl Mount {
             fstype: Some("sysfs".into()),
             flags: mount::MsFlags::empty(),
             data: None,
+            optional: false,
+            owner: None,
+            volume: None,
         }
     }
 
@@ -206,6 +1883,44 @@ impl Mount {
             fstype: Some("tmpfs".into()),
             flags: mount::MsFlags::empty(),
             data: None,
+            optional: false,
+            owner: None,
+            volume: None,
+        }
+    }
+
+    /// Defines a tmpfs that is mounted with `flags` and the options in `data`, like `mode=0700`.
+    pub(crate) fn tmpfs_with_options(
+        relative_target: PathBuf,
+        flags: mount::MsFlags,
+        data: String,
+    ) -> Self {
+        Mount {
+            flags,
+            data: Some(data),
+            ..Mount::tmpfs(relative_target)
+        }
+    }
+
+    /// Defines the tmpfs of the container's shared memory, limited to `size` bytes.
+    pub(crate) fn shm(size: u64) -> Self {
+        Mount::tmpfs_with_options(
+            SHM_DIR.into(),
+            mount::MsFlags::MS_NOSUID | mount::MsFlags::MS_NODEV | mount::MsFlags::MS_NOEXEC,
+            shm_options(size),
+        )
+    }
+
+    pub(crate) fn cgroup2(relative_target: PathBuf) -> Self {
+        Mount {
+            source: None::<PathBuf>,
+            relative_target,
+            fstype: Some("cgroup2".into()),
+            flags: mount::MsFlags::MS_NOSUID | mount::MsFlags::MS_NODEV | mount::MsFlags::MS_NOEXEC,
+            data: None,
+            optional: false,
+            owner: None,
+            volume: None,
         }
     }
 
@@ -216,19 +1931,171 @@ impl Mount {
             fstype: Some("devpts".into()),
             flags: mount::MsFlags::empty(),
             data: None,
+            optional: false,
+            owner: None,
+            volume: None,
+        }
+    }
+
+    /// Makes the mount optional: when its source doesn't exist on this host, like `/etc/localtime`
+    /// on a minimal system, it's skipped instead of failing the container's setup.
+    pub(crate) fn optional(mut self) -> Self {
+        self.optional = true;
+        self
+    }
+
+    /// Makes the files of the source appear to be owned by user `uid` and group `gid` of the
+    /// container, see `ownership`.
+    pub(crate) fn owned(mut self, uid: u32, gid: u32) -> Self {
+        self.owner = Some((uid, gid));
+        self
+    }
+
+    pub(crate) fn owner(&self) -> Option<(u32, u32)> {
+        self.owner
+    }
+
+    pub(crate) fn mounted_volume(&self) -> Option<&Volume> {
+        self.volume.as_ref()
+    }
+
+    pub(crate) fn set_source(&mut self, source: Option<PathBuf>) {
+        self.source = source;
+    }
+
+    /// Returns a copy of the mount with another source.
+    pub(crate) fn with_source(&self, source: PathBuf) -> Self {
+        Mount {
+            source: Some(source),
+            relative_target: self.relative_target.clone(),
+            fstype: self.fstype.clone(),
+            flags: self.flags,
+            data: self.data.clone(),
+            optional: false,
+            owner: None,
+            volume: None,
         }
     }
 
-    /// Returns the absolute path where the mount has been mounted
-    pub(crate) fn mount(&self, rootfs_path: &Path) -> Result<PathBuf, CartonError> {
+    /// Returns whether the mount is skipped, because it's optional and its source doesn't exist.
+    pub(crate) fn is_skipped(&self) -> bool {
+        self.optional
+            && self
+                .source
+                .as_ref()
+                .map_or(false, |source| !source.exists())
+    }
+
+    pub(crate) fn is_procfs(&self) -> bool {
+        self.fstype.as_deref() == Some("proc")
+    }
+
+    /// Returns whether this is the container's /dev tmpfs, see `add_default_mounts()`.
+    pub(crate) fn is_dev(&self) -> bool {
+        self.fstype.as_deref() == Some("tmpfs") && self.relative_target == Path::new("dev")
+    }
+
+    pub(crate) fn is_shm(&self) -> bool {
+        self.fstype.as_deref() == Some("tmpfs") && self.relative_target == Path::new(SHM_DIR)
+    }
+
+    pub(crate) fn set_data(&mut self, data: String) {
+        self.data = Some(data);
+    }
+
+    /// Returns the step of a `Plan` that mounts this in the root filesystem at `rootfs_path`.
+    pub(crate) fn plan_step(&self, rootfs_path: &Path) -> PlanStep {
+        // Joining an empty path would add a trailing slash
+        let target = if self.relative_target.as_os_str().is_empty() {
+            rootfs_path.into()
+        } else {
+            rootfs_path.join(&self.relative_target)
+        };
+        if self.is_skipped() {
+            return PlanStep::SkipMount {
+                source: self.source.clone().unwrap_or_default(),
+                target,
+            };
+        }
+        if let (Some(volume), None) = (&self.volume, &self.source) {
+            return PlanStep::Volume {
+                name: volume.name.clone(),
+                target,
+            };
+        }
+        if let (Some((uid, gid)), Some(source)) = (self.owner, &self.source) {
+            return PlanStep::OwnedMount {
+                source: source.clone(),
+                target,
+                uid,
+                gid,
+            };
+        }
+
+        PlanStep::Mount {
+            source: self
+                .source
+                .as_ref()
+                .map(|source| fs::canonicalize(source).unwrap_or_else(|_| source.clone())),
+            target,
+            fstype: self.fstype.clone(),
+            flags: plan::flag_names(self.flags),
+            data: self.data.clone(),
+        }
+    }
+
+    /// Creates the directory or file to mount on in the root filesystem at `rootfs_path`, if it
+    /// doesn't exist yet, and returns its path.
+    pub(crate) fn create_target(
+        &self,
+        rootfs_path: &Path,
+        reporter: &Reporter,
+        rollback: &Rollback,
+    ) -> Result<PathBuf, CartonError> {
         let mount_path = rootfs_path.join(&self.relative_target);
 
         if !mount_path.exists() {
-            info!("creating {}", mount_path.display());
-            std::fs::create_dir_all(&mount_path)?;
+            reporter.info(format_args!("creating {}", mount_path.display()));
+
+            // A file (or a socket) can only be bind mounted on top of another file
+            if self.source.as_ref().map_or(false, |source| {
+                fs::metadata(source).map_or(false, |metadata| !metadata.is_dir())
+            }) {
+                if let Some(parent) = mount_path.parent() {
+                    rollback.create_dir_all(parent)?;
+                }
+                rollback.create_file(&mount_path)?;
+            } else {
+                rollback.create_dir_all(&mount_path)?;
+            }
+        }
+
+        Ok(mount_path)
+    }
+
+    /// Returns the absolute path where the mount has been mounted, or `None` when it was skipped
+    /// (see `optional()`).
+    pub(crate) fn mount(
+        &self,
+        rootfs_path: &Path,
+        syscalls: &dyn Syscalls,
+        reporter: &Reporter,
+        rollback: &Rollback,
+    ) -> Result<Option<PathBuf>, CartonError> {
+        if self.is_skipped() {
+            reporter.warning(format_args!(
+                "skipping the optional mount at {}, because {} doesn't exist",
+                rootfs_path.join(&self.relative_target).display(),
+                self.source
+                    .as_deref()
+                    .unwrap_or_else(|| Path::new(""))
+                    .display()
+            ));
+            return Ok(None);
         }
 
-        info!(
+        let mount_path = self.create_target(rootfs_path, reporter, rollback)?;
+        reporter.info(format_args!(
             "mounting {} ({}) at {}",
             &self
                 .source
@@ -236,17 +2103,36 @@ impl Mount {
                 .map_or("(no source)", |p| p.to_str().unwrap()),
             self.fstype.as_ref().map_or("bind mount", |f| f.as_str()),
             mount_path.display()
-        );
+        ));
 
-        mount::mount(
-            self.source.as_ref(),
+        let result = syscalls.mount(
+            self.source.as_deref(),
             &mount_path,
             self.fstype.as_deref(),
             self.flags,
             self.data.as_deref(),
-        )?;
+        );
+        // In a user namespace, sysfs can only be mounted in a network namespace that the user
+        // namespace owns. Otherwise the host's /sys is the best there is.
+        if matches!(&result, Err(e) if e.errno == Errno::EPERM)
+            && self.fstype.as_deref() == Some("sysfs")
+        {
+            reporter.warning(format_args!(
+                "not allowed to mount sysfs, bind mounting /sys instead"
+            ));
+            syscalls.mount(
+                Some(Path::new("/sys")),
+                &mount_path,
+                None,
+                mount::MsFlags::MS_BIND | mount::MsFlags::MS_REC,
+                None,
+            )?;
+        } else {
+            result?;
+        }
+        rollback.record(Step::Mount(mount_path.clone()));
 
-        Ok(mount_path)
+        Ok(Some(mount_path))
     }
 }
 
@@ -260,9 +2146,94 @@ pub(crate) struct DeviceNode {
     pub minor: u64,
 }
 
-fn execute_command(command: &Path, arguments: &[String]) -> isize {
-    let Ok(c_cmd) = CString::new(command.to_str().unwrap()) else {
-        return 126;
+/// Blocks until the parent signals that the container can continue. Returns false when the parent
+/// closed the pipe without doing so, which means that something went wrong.
+fn wait_for_parent(ready_read: RawFd) -> bool {
+    let mut buf = [0u8; 1];
+
+    matches!(unistd::read(ready_read, &mut buf), Ok(1))
+}
+
+/// Blocks until the parent sends the command to execute, see `Container::start()`. Returns `None`
+/// when the parent closed the pipe without sending one.
+fn wait_for_command(ready_read: RawFd) -> Option<(PathBuf, Vec<String>)> {
+    let mut length = [0u8; 4];
+    read_exact(ready_read, &mut length)?;
+    let mut message = vec![0; u32::from_ne_bytes(length) as usize];
+    read_exact(ready_read, &mut message)?;
+    let _ = unistd::close(ready_read);
+
+    let mut parts = message.strip_suffix(&[0])?.split(|byte| *byte == 0);
+    let command = PathBuf::from(OsStr::from_bytes(parts.next()?));
+    let arguments = parts
+        .map(|part| String::from_utf8_lossy(part).into_owned())
+        .collect();
+
+    Some((command, arguments))
+}
+
+/// Opens a pidfd for `pid`, which should be a child that hasn't been waited for yet, so that it
+/// can't have been replaced by another process. Returns `None` on kernels without pidfds.
+fn pidfd_open(pid: unistd::Pid) -> Result<Option<OwnedFd>, CartonError> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+
+    match Errno::result(fd) {
+        Ok(fd) => Ok(Some(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })),
+        Err(Errno::ENOSYS) => {
+            info!("This kernel has no pidfds, managing the container by its PID");
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn pidfd_send_signal(pidfd: &OwnedFd, signal: Signal) -> nix::Result<()> {
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_send_signal,
+            pidfd.as_raw_fd(),
+            signal as libc::c_int,
+            std::ptr::null::<libc::siginfo_t>(),
+            0,
+        )
+    };
+
+    Errno::result(result).map(drop)
+}
+
+/// Fills `buf` from `fd`. Returns `None` when the pipe was closed before that.
+fn read_exact(fd: RawFd, buf: &mut [u8]) -> Option<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match unistd::read(fd, &mut buf[filled..]) {
+            Ok(0) => return None,
+            Ok(read) => filled += read,
+            Err(Errno::EINTR) => continue,
+            Err(_) => return None,
+        }
+    }
+
+    Some(())
+}
+
+/// Makes the calling process run as user `uid` and group `gid`, without supplementary groups.
+pub(crate) fn switch_user(uid: u32, gid: u32) -> nix::Result<()> {
+    let (uid, gid) = (unistd::Uid::from_raw(uid), unistd::Gid::from_raw(gid));
+
+    // A user namespace of a user other than root doesn't allow setgroups(), but then the process
+    // has no groups that it could drop either
+    match unistd::setgroups(&[]) {
+        Ok(()) | Err(Errno::EPERM) => {}
+        Err(e) => return Err(e),
+    }
+    unistd::setresgid(gid, gid, gid)?;
+    unistd::setresuid(uid, uid, uid)
+}
+
+/// Replaces the container process with `command`, so this only returns when that failed.
+fn execute_command(command: &Path, arguments: &[String]) -> CartonError {
+    let Ok(c_cmd) = CString::new(command.as_os_str().as_bytes()) else {
+        return CartonError::InvalidConfiguration(format!("invalid command {:?}", command));
     };
     let mut c_args = arguments
         .iter()
@@ -270,8 +2241,13 @@ fn execute_command(command: &Path, arguments: &[String]) -> isize {
         .collect::<Vec<CString>>();
     c_args.insert(0, c_cmd.clone());
 
-    // This syscall replaces the current process with the requested command. That means that this
-    // `run_command()` function will only return if something went wrong with starting the command.
     // TODO execve()
-    unistd::execv(&c_cmd, &c_args).and(Ok(0)).unwrap()
+    let e = unistd::execv(&c_cmd, &c_args).unwrap_err();
+    CartonError::SysCallFailed(format!("executing {}: {}", command.display(), e))
+}
+
+/// Returns the options of the tmpfs on /dev/shm, which everyone can create files in, like on the
+/// host.
+pub(crate) fn shm_options(size: u64) -> String {
+    format!("mode=1777,size={}", size)
 }
@@ -1,34 +1,222 @@
 // Copyright 2023 Arjen Verstoep
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use nix::sys::resource;
 
+use crate::activation::ListenFds;
+use crate::binfmt::{Architecture, Emulator};
 use crate::consts::DEFAULT_CONTAINER_STACK_SIZE;
-use crate::container::{Container, ContainerBuffer, ContainerConfiguration, DeviceNode, Mount};
+use crate::container::{
+    shm_options, Container, ContainerBuffer, ContainerConfiguration, DeviceNode, Mount,
+    DEFAULT_SHM_SIZE, SHELL,
+};
+use crate::core_dump::CoreDumps;
+use crate::dbus::DbusProxy;
+use crate::diagnostics::Diagnostics;
+use crate::display;
+use crate::environment::EnvFilter;
 use crate::error::CartonError;
+use crate::files::InjectedFile;
+use crate::fuse::FuseRootfs;
+use crate::gpu::{self, Gpus};
+use crate::idmap::{IdMapping, IdMappings};
+use crate::integrity::Sha256Digest;
+use crate::mount_provider::MountProvider;
+use crate::mux::MultiplexedOutput;
+use crate::network::{BandwidthLimit, Network, SharedNetwork};
+use crate::network_files::NetworkFiles;
+use crate::paths::Paths;
+use crate::plan::Plan;
+use crate::pool::PreparedContainer;
+use crate::procfs::ProcfsOptions;
+use crate::seccomp::{
+    SeccompNotify, SeccompProfile, SeccompRecorder, SyscallRequest, SyscallResponse,
+};
+use crate::secrets::Secret;
+use crate::state::{ProjectInfo, Setting};
+use crate::stdin::StdinStream;
+use crate::trace::{Span, Tracer};
+use crate::volume::{Volume, VolumeDriver};
 
-#[derive(Default, Debug)]
-pub struct ContainerBuilder {
+/// Configures a container and builds it, see `build()`.
+///
+/// The builder goes through stages, so that a container without a root filesystem or a command
+/// doesn't compile: a new builder needs a root filesystem first (`rootfs()`, `fuse_rootfs()` or
+/// `sandbox()`), then a command (`command()` or `shell_command()`), and only then can it be built.
+/// The other settings can be made at any stage.
+///
+/// ```no_run
+/// # use libcarton::{CartonError, ContainerBuilder};
+/// # fn main() -> Result<(), CartonError> {
+/// let mut container = ContainerBuilder::new()
+///     .rootfs("/path/to/rootfs".into())
+///     .add_default_mounts()
+///     .command("/bin/echo".into(), Some(vec!["hello".into()]))
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// ```compile_fail
+/// # use libcarton::ContainerBuilder;
+/// // No command
+/// let container = ContainerBuilder::new().rootfs("/path/to/rootfs".into()).build();
+/// ```
+#[derive(Debug)]
+pub struct ContainerBuilder<S = Ready> {
+    id: Option<String>,
     stack_size: Option<u64>,
+    state_dir: Option<PathBuf>,
+    gpus: Option<Gpus>,
+    display: bool,
+    architecture: Option<Architecture>,
+    procfs_options: Option<ProcfsOptions>,
+    shm_size: Option<u64>,
     config: ContainerConfiguration,
+    stage: PhantomData<S>,
 }
 
-impl ContainerBuilder {
+/// The stage of a `ContainerBuilder` that needs a root filesystem.
+#[derive(Debug)]
+pub struct NeedsRootfs;
+
+/// The stage of a `ContainerBuilder` that needs a command.
+#[derive(Debug)]
+pub struct NeedsCommand;
+
+/// The stage of a `ContainerBuilder` that can be built.
+#[derive(Debug)]
+pub struct Ready;
+
+impl Default for ContainerBuilder<NeedsRootfs> {
+    fn default() -> Self {
+        ContainerBuilder {
+            id: None,
+            stack_size: None,
+            state_dir: None,
+            gpus: None,
+            display: false,
+            architecture: None,
+            procfs_options: None,
+            shm_size: None,
+            config: ContainerConfiguration::default(),
+            stage: PhantomData,
+        }
+    }
+}
+
+impl ContainerBuilder<NeedsRootfs> {
     pub fn new() -> Self {
         ContainerBuilder::default()
     }
 
-    pub fn rootfs(mut self, path: PathBuf) -> Self {
+    /// Uses the directory at `path` as the root filesystem.
+    pub fn rootfs(mut self, path: PathBuf) -> ContainerBuilder<NeedsCommand> {
         self.config.rootfs = Some(Mount::rootfs(path));
 
-        self
+        self.into_stage()
     }
 
-    pub fn command(mut self, command: PathBuf, args: Option<Vec<String>>) -> Self {
+    /// Uses the filesystem that a FUSE daemon mounts as the root filesystem, instead of a
+    /// directory (see `rootfs()`). The daemon is started when the container is prepared, and the
+    /// filesystem is unmounted when the container is gone.
+    pub fn fuse_rootfs(mut self, fuse_rootfs: FuseRootfs) -> ContainerBuilder<NeedsCommand> {
+        self.config.rootfs = Some(Mount::rootfs(fuse_rootfs.mountpoint().into()));
+        self.config.fuse_rootfs = Some(fuse_rootfs);
+
+        self.into_stage()
+    }
+
+    /// Runs the command on the host's filesystem, without a root filesystem of its own, but in
+    /// new namespaces like any other container. The container gets a /proc of its own PID
+    /// namespace; other mounts, like `add_mount()`'s, are relative to the host's `/` and only
+    /// visible inside the container. Meant for sandboxing programs of the host, rather than running
+    /// an image.
+    pub fn sandbox(mut self) -> ContainerBuilder<NeedsCommand> {
+        self.config.sandbox = true;
+        self.into_stage()
+    }
+}
+
+impl ContainerBuilder<NeedsCommand> {
+    /// Executes `command` with the arguments `args` in the container.
+    pub fn command(
+        mut self,
+        command: PathBuf,
+        args: Option<Vec<String>>,
+    ) -> ContainerBuilder<Ready> {
         self.config.command = Some(command);
         self.config.arguments = args.unwrap_or_default();
+        self.config.shell_form = false;
+        self.into_stage()
+    }
+
+    /// Runs `command` with `/bin/sh -c` of the root filesystem, instead of the command of
+    /// `command()`, so that it can have pipes, redirections and the like. Building the container
+    /// fails when the root filesystem has no `/bin/sh`.
+    pub fn shell_command(mut self, command: impl Into<String>) -> ContainerBuilder<Ready> {
+        self.config.command = Some(SHELL.into());
+        self.config.arguments = vec!["-c".into(), command.into()];
+        self.config.shell_form = true;
+        self.into_stage()
+    }
+
+    /// Builds and prepares the container without a command (see `Container::prepare()`), for a
+    /// container that's prepared ahead of time and gets its command later, like the containers of
+    /// a `ContainerPool`.
+    pub fn build_prepared(self) -> Result<PreparedContainer, CartonError> {
+        let mut container = self.into_stage::<Ready>().build()?;
+        container.prepare()?;
+
+        Ok(PreparedContainer::new(container))
+    }
+}
+
+impl<S> ContainerBuilder<S> {
+    /// Mounts the root filesystem ID-mapped, so that its files appear to be owned by other users
+    /// and groups than they are on disk, without changing them. A file that is owned by
+    /// `container_id` (or up, within the range) on disk appears to be owned by `host_id` (or up).
+    /// For instance, `IdMapping::new(1000, 0, 1)` makes the files of an image that was unpacked
+    /// by user 1000 appear to be owned by root. IDs that aren't mapped appear as `nobody`.
+    ///
+    /// Needs Linux 5.12 or later and a filesystem that supports ID-mapped mounts, like ext4,
+    /// XFS or btrfs.
+    pub fn idmap_rootfs(
+        mut self,
+        uid_mappings: Vec<IdMapping>,
+        gid_mappings: Vec<IdMapping>,
+    ) -> Self {
+        self.config.rootfs_id_mappings = Some(IdMappings {
+            uids: uid_mappings,
+            gids: gid_mappings,
+        });
+        self
+    }
+
+    /// Runs the container in a user namespace of its own, in which user and group IDs are mapped
+    /// to other IDs on the host. Root in the container is the host ID that container ID 0 is
+    /// mapped to, which doesn't have to be root on the host. See `IdMapping::current_user()` for
+    /// the mappings of a container that is started without privileges.
+    ///
+    /// Only root can map arbitrary IDs. Other users can map their own IDs, and more only with
+    /// the `newuidmap` and `newgidmap` helpers, which have to be installed.
+    pub fn user_namespace(
+        mut self,
+        uid_mappings: Vec<IdMapping>,
+        gid_mappings: Vec<IdMapping>,
+    ) -> Self {
+        self.config.user_namespace = Some(IdMappings {
+            uids: uid_mappings,
+            gids: gid_mappings,
+        });
         self
     }
 
@@ -45,12 +233,27 @@ impl ContainerBuilder {
             Mount::tmpfs("tmp".into()),
             Mount::tmpfs("dev".into()),
             Mount::devpts(),
-            Mount::tmpfs("dev/shm".into()),
+            Mount::shm(DEFAULT_SHM_SIZE),
         ]);
 
         self
     }
 
+    /// Mounts the container's /proc (see `add_default_mounts()`, and sandboxes always have one)
+    /// with `options`, like `hidepid` to hide the processes of other users.
+    pub fn procfs_options(mut self, options: ProcfsOptions) -> Self {
+        self.procfs_options = Some(options);
+        self
+    }
+
+    /// Limits the container's /dev/shm (see `add_default_mounts()`), on which programs like
+    /// browsers and databases keep their shared memory, to `bytes` (64 MiB by default). The
+    /// memory counts towards the container's memory limit.
+    pub fn shm_size(mut self, bytes: u64) -> Self {
+        self.shm_size = Some(bytes);
+        self
+    }
+
     pub fn add_mount(mut self, source: PathBuf, relative_target: PathBuf) -> Self {
         self.config
             .mounts
@@ -58,6 +261,55 @@ impl ContainerBuilder {
         self
     }
 
+    /// Like `add_mount()`, but when `source` doesn't exist on this host the mount is skipped with a
+    /// warning (see `diagnostics()`), instead of failing the container's start. That suits files
+    /// like `/etc/localtime`, or the socket of a service that isn't running everywhere.
+    pub fn add_optional_mount(mut self, source: PathBuf, relative_target: PathBuf) -> Self {
+        self.config
+            .mounts
+            .push(Mount::bind(source, relative_target, None, None).optional());
+        self
+    }
+
+    /// Like `add_mount()`, but the files of `source` appear to be owned by user `uid` and group
+    /// `gid` of the container, so that the container can use a directory of a host user that it
+    /// doesn't know. The directory is mounted ID-mapped where the filesystem supports that.
+    /// Otherwise the container gets a copy of it, and its changes never reach `source`.
+    pub fn add_owned_mount(
+        mut self,
+        source: PathBuf,
+        relative_target: PathBuf,
+        uid: u32,
+        gid: u32,
+    ) -> Self {
+        self.config
+            .mounts
+            .push(Mount::bind(source, relative_target, None, None).owned(uid, gid));
+        self
+    }
+
+    /// Mounts the volume `name` of `driver` at `relative_target`. The driver mounts it when the
+    /// container is prepared, and unmounts it when the container's process is gone, see
+    /// `VolumeDriver`.
+    pub fn add_volume(
+        mut self,
+        driver: Arc<dyn VolumeDriver>,
+        name: String,
+        relative_target: PathBuf,
+    ) -> Self {
+        self.config
+            .mounts
+            .push(Mount::volume(Volume { driver, name }, relative_target));
+        self
+    }
+
+    /// Adds a provider of filesystems that carton can't mount by itself, see `MountProvider`.
+    /// Providers mount their filesystems in the order they were added.
+    pub fn add_mount_provider<P: MountProvider + 'static>(mut self, provider: P) -> Self {
+        self.config.mount_providers.push(Box::new(provider));
+        self
+    }
+
     pub fn add_default_devices(mut self) -> Self {
         self.config.devices.extend([
             DeviceNode {
@@ -105,16 +357,495 @@ impl ContainerBuilder {
         self
     }
 
-    pub fn build(self) -> Result<Container, CartonError> {
-        let stack_size = self.determine_stack_size();
+    /// Passes GPUs of the host into the container: their device nodes are created in the
+    /// container's /dev and, for NVIDIA GPUs, the libraries and tools of the host's driver are
+    /// bind mounted at the same paths in the container. The GPUs are looked up by `build()`.
+    pub fn add_gpu(mut self, gpus: Gpus) -> Self {
+        self.gpus = Some(gpus);
+        self
+    }
 
-        Ok(Container {
+    /// Lets the container run desktop programs on the host's display: the sockets of the Wayland
+    /// compositor and the X server of the calling process' `WAYLAND_DISPLAY` and `DISPLAY` are
+    /// bind mounted into the container, which gets a runtime directory of its own in
+    /// `/run/user/<uid>` and the variables that point to them, see `display`. `build()` fails when
+    /// there is no display. A sandbox sees the host's display already.
+    pub fn add_display(mut self) -> Self {
+        self.display = true;
+        self
+    }
+
+    /// Runs the binaries of the root filesystem, which are for `architecture`, with QEMU's user
+    /// mode emulator, like an arm64 root filesystem on an x86_64 host. `build()` looks for the
+    /// emulator (`qemu-aarch64-static` and the like) in /usr/bin and /usr/local/bin, and preparing
+    /// the container registers it with the host's binfmt_misc if the architecture isn't registered
+    /// yet, which needs root. Nothing is emulated when the host runs `architecture` itself.
+    pub fn emulate(mut self, architecture: Architecture) -> Self {
+        self.architecture = Some(architecture);
+        self
+    }
+
+    /// Sets how the container is connected to the network. By default it shares the network of
+    /// the host.
+    pub fn network(mut self, network: Network) -> Self {
+        self.config.network = network;
+        self
+    }
+
+    /// Makes the container join the network namespace of `other`, which should be running. The
+    /// containers can then reach each other over localhost.
+    pub fn share_network_with(self, other: &Container) -> Self {
+        self.network(Network::Shared(SharedNetwork::from_container(other)))
+    }
+
+    /// Limits the rates at which the container can send and receive data. Only containers on a
+    /// bridge network can be limited.
+    pub fn bandwidth_limit(mut self, limit: BandwidthLimit) -> Self {
+        self.config.bandwidth_limit = Some(limit);
+        self
+    }
+
+    /// Sets the hostname of the container. Defaults to the first 12 characters of the container's
+    /// ID.
+    pub fn hostname(mut self, hostname: String) -> Self {
+        self.config.hostname = Some(hostname);
+        self
+    }
+
+    /// Sets the NIS domain name of the container, which some older software takes its domain
+    /// from. By default it's the host's.
+    pub fn domainname(mut self, domainname: String) -> Self {
+        self.config.domainname = Some(domainname);
+        self
+    }
+
+    /// Sets the environment variables of the command. By default it gets the environment of the
+    /// calling process.
+    pub fn environment(mut self, variables: Vec<(String, String)>) -> Self {
+        self.config.environment = Some(variables);
+        self
+    }
+
+    /// Passes only the variables of the calling process' environment whose names match `filter`
+    /// on to the command, with a default `PATH`, instead of the whole environment. The variables
+    /// of `environment()` are added to those, and take precedence.
+    pub fn inherit_env(mut self, filter: EnvFilter) -> Self {
+        self.config.inherit_env = Some(filter);
+        self
+    }
+
+    /// Starts the command in the directory `path` of the container, instead of in `/`.
+    pub fn working_dir(mut self, path: PathBuf) -> Self {
+        self.config.working_dir = Some(path);
+        self
+    }
+
+    /// Runs the command as user `uid` and group `gid` of the container, without supplementary
+    /// groups, instead of as root. The setup of the container still runs as root.
+    pub fn user(mut self, uid: u32, gid: u32) -> Self {
+        self.config.user = Some((uid, gid));
+        self
+    }
+
+    /// Gives the container a pseudo terminal as its stdin, stdout and stderr, and sends the other
+    /// end of it to the Unix socket at `path` once the container is prepared. This is the
+    /// `--console-socket` of OCI runtimes.
+    pub fn console_socket(mut self, path: PathBuf) -> Self {
+        self.config.console_socket = Some(path);
+        self
+    }
+
+    /// Feeds the container's stdin from `stream`, instead of letting the container inherit the
+    /// stdin of the calling process. The command sees the end of its input at the end of the
+    /// stream. A stream can only be read once, so when the container runs again, its stdin is
+    /// empty.
+    ///
+    /// ```no_run
+    /// # use libcarton::{CartonError, ContainerBuilder};
+    /// # fn main() -> Result<(), CartonError> {
+    /// let mut container = ContainerBuilder::new()
+    ///     .rootfs("/path/to/rootfs".into())
+    ///     .command("/bin/wc".into(), Some(vec!["-l".into()]))
+    ///     .stdin(&b"one\ntwo\n"[..])
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stdin<R: Read + Send + 'static>(mut self, stream: R) -> Self {
+        self.config.stdin = Some(StdinStream::new(stream));
+        self
+    }
+
+    /// Sends the container's stdout and stderr to `output` as frames that tell them apart (see
+    /// `FrameReader`), instead of letting the container inherit the stdout and stderr of the
+    /// calling process. That way a single connection can carry both. The output is complete when
+    /// waiting for the container returns.
+    ///
+    /// ```no_run
+    /// # use std::io;
+    /// # use std::os::unix::net::UnixStream;
+    /// # use std::thread;
+    /// # use libcarton::{CartonError, ContainerBuilder, FrameReader};
+    /// # fn main() -> Result<(), CartonError> {
+    /// let (server, client) = UnixStream::pair()?;
+    /// let mut container = ContainerBuilder::new()
+    ///     .rootfs("/path/to/rootfs".into())
+    ///     .command("/bin/ls".into(), Some(vec!["/missing".into(), "/".into()]))
+    ///     .multiplexed_output(server)
+    ///     .build()?;
+    ///
+    /// // Somewhere else, like at the other end of a connection
+    /// let client = thread::spawn(move || {
+    ///     FrameReader::new(client).demultiplex(&mut io::stdout(), &mut io::stderr())
+    /// });
+    /// container.run()?;
+    /// container.wait_for_exit()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn multiplexed_output<W: Write + Send + 'static>(mut self, output: W) -> Self {
+        self.config.multiplexed_output = Some(MultiplexedOutput::new(output));
+        self
+    }
+
+    /// Stops the container when its command has been running for longer than `timeout`: it gets
+    /// SIGTERM then, and SIGKILL when it's still running 10 seconds later (see
+    /// `timeout_grace_period()`), and it ends as `ContainerExit::TimedOut`. The timeout is
+    /// enforced while the calling process waits for the container, like with
+    /// `Container::wait_for_exit()`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = Some(timeout);
+        self
+    }
+
+    /// How long a container that ran out of time gets to exit after SIGTERM, before it's killed
+    /// with SIGKILL (10 seconds by default).
+    pub fn timeout_grace_period(mut self, grace_period: Duration) -> Self {
+        self.config.timeout_grace_period = Some(grace_period);
+        self
+    }
+
+    /// Gives the container a cgroup of its own that it can create cgroups in, and a cgroup
+    /// namespace in which that cgroup is the root, so that systemd or a container runtime can run
+    /// inside the container. Needs cgroup v2.
+    pub fn delegate_cgroup(mut self) -> Self {
+        self.config.delegate_cgroup = true;
+        self
+    }
+
+    /// Runs the container in a transient systemd scope of the calling user's session, so that it
+    /// shows up in `systemctl --user`, gets the limits of the session and is stopped when the user
+    /// logs out, see `scope`. Needs a user's service manager, and carton running as that user.
+    /// Can't be combined with `delegate_cgroup()`.
+    pub fn systemd_scope(mut self) -> Self {
+        self.config.systemd_scope = true;
+        self
+    }
+
+    /// Runs the init binary at `path` (on the host) as the container's first process, which starts
+    /// the command as its child. The init process passes signals on to the command and reaps
+    /// orphaned processes, which commands that don't expect to be PID 1 don't do. `carton-init`
+    /// is such a binary. It's bind mounted onto the container's /dev, so it should be linked
+    /// statically, and the container needs the /dev of `add_default_mounts()`.
+    pub fn init(mut self, path: PathBuf) -> Self {
+        self.config.init = Some(path);
+        self
+    }
+
+    /// Makes the container ephemeral: the root filesystem is covered by an overlay whose upper
+    /// layer is a tmpfs, so the container can write anywhere, but nothing it writes persists after
+    /// it exits and the root filesystem itself is never changed. Useful for test sandboxes and
+    /// untrusted code. What the container writes takes up memory, up to half of the host's RAM
+    /// (tmpfs' default size limit).
+    pub fn ephemeral(mut self) -> Self {
+        self.config.ephemeral = true;
+        self
+    }
+
+    /// Limits how much space the container's root filesystem can take up, in bytes. For an
+    /// ephemeral container, this is the size of the tmpfs that its writes go to. Otherwise the
+    /// root filesystem as a whole (including the files in it already) is limited with a project
+    /// quota, which needs a filesystem with project quotas, like XFS or ext4 mounted with
+    /// `prjquota`. Bind mounts aren't limited.
+    pub fn storage_limit(mut self, bytes: u64) -> Self {
+        self.config.storage_limit = Some(bytes);
+        self
+    }
+
+    /// Refuses to start the container unless the root filesystem has the SHA-256 digest `digest`,
+    /// see `Sha256Digest::of_path()`. The whole root filesystem is read for this every time the
+    /// container starts, which takes a while for large ones.
+    pub fn verify_rootfs(mut self, digest: Sha256Digest) -> Self {
+        self.config.rootfs_digest = Some(digest);
+        self
+    }
+
+    /// Refuses to start the container unless the file or directory at `path`, like an image
+    /// layer or the image of a FUSE root filesystem, has the SHA-256 digest `digest`. Files are
+    /// verified before the root filesystem is mounted.
+    pub fn verify_file(mut self, path: PathBuf, digest: Sha256Digest) -> Self {
+        self.config.verified_files.push((path, digest));
+        self
+    }
+
+    /// Sets what happens with the core dumps of processes that crash in the container. By
+    /// default they're handled like the host's own, which can flood the host's core dump handler
+    /// with the crashes of containers.
+    pub fn core_dumps(mut self, core_dumps: CoreDumps) -> Self {
+        self.config.core_dumps = core_dumps;
+        self
+    }
+
+    /// Reports the steps of the container's setup, and the problems it could do without (like a
+    /// device node that doesn't exist), to `diagnostics` as well as to the log. The reports of the
+    /// container process arrive when the container is started, see the `Diagnostics` trait.
+    pub fn diagnostics<D>(mut self, diagnostics: D) -> Self
+    where
+        D: Diagnostics + 'static,
+    {
+        self.config.diagnostics.set_sink(Arc::new(diagnostics));
+        self
+    }
+
+    /// Passes the spans of the container's lifecycle (creating, setting up, starting and stopping
+    /// it) to `tracer`, to find out where the time to start a container goes, see the `Tracer`
+    /// trait.
+    pub fn tracer<T>(mut self, tracer: T) -> Self
+    where
+        T: Tracer + 'static,
+    {
+        self.config.diagnostics.set_tracer(Arc::new(tracer));
+        self
+    }
+
+    /// Also reports every privileged operation of the setup to the `diagnostics()`, like each
+    /// mount with its source, target, type, flags and options, and what came of it. That's the
+    /// place to look when a mount or device node fails.
+    pub fn debug_setup(mut self) -> Self {
+        self.config.diagnostics.enable_debug();
+        self
+    }
+
+    /// Adds a hook that runs in the container's process right before it executes the command,
+    /// after the container's namespaces, mounts and terminal have been set up, like
+    /// `std::os::unix::process::CommandExt::pre_exec()`. Hooks run in the order they were added.
+    /// When a hook fails, the command isn't executed and the container exits with code 126.
+    ///
+    /// The container process has a copy of the calling process' memory, so changes that the hook
+    /// makes to the variables it captures are not visible to the caller. Only the thread that
+    /// prepares the container exists in the container process, so the hook shouldn't wait for
+    /// locks that other threads may have held.
+    pub fn pre_exec_hook<F>(mut self, hook: F) -> Self
+    where
+        F: FnMut() -> io::Result<()> + Send + 'static,
+    {
+        self.config.hooks.add_pre_exec(hook);
+        self
+    }
+
+    /// Adds a hook that runs in the calling process when the container's command has been
+    /// started, with the running container. Hooks run in the order they were added. When a hook
+    /// fails, `Container::start()` (or `run()`) returns its error, but the container keeps
+    /// running.
+    pub fn post_setup_hook<F>(mut self, hook: F) -> Self
+    where
+        F: FnMut(&Container) -> io::Result<()> + Send + 'static,
+    {
+        self.config.hooks.add_post_setup(hook);
+        self
+    }
+
+    /// Passes the system calls in `syscalls` (like `libc::SYS_mount`) that the container makes to
+    /// `handler`, which runs in a thread of the calling process and decides whether the kernel
+    /// should execute them, see `SyscallResponse`. This makes it possible to allow a system call
+    /// only with certain arguments, for instance.
+    ///
+    /// The handler only gets the system calls of the command (and the processes it starts), not
+    /// those of carton's own setup of the container.
+    pub fn seccomp_notify<F>(mut self, syscalls: Vec<i64>, handler: F) -> Self
+    where
+        F: Fn(&SyscallRequest) -> SyscallResponse + Send + Sync + 'static,
+    {
+        self.config.seccomp_notify = Some(SeccompNotify::new(syscalls, handler));
+        self
+    }
+
+    /// Like `seccomp_notify()`, for every system call of the container, like for tracing them.
+    /// Only carton's `sendmsg()` of the file descriptor that the notifications come from isn't
+    /// passed to `handler`. Every system call waits for the handler, so this slows down the
+    /// container a lot. It replaces `seccomp_notify()` and `seccomp_record()`.
+    pub fn seccomp_notify_all<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&SyscallRequest) -> SyscallResponse + Send + Sync + 'static,
+    {
+        self.config.seccomp_notify = Some(SeccompNotify::all(handler));
+        self
+    }
+
+    /// Records every system call that the container makes in `recorder`, to make a profile of
+    /// them (see `SeccompRecorder::profile()`) that the next run of the container can be limited
+    /// to with `seccomp_profile()`. This uses the same mechanism as `seccomp_notify_all()`, which it
+    /// replaces, and slows down the container's system calls a lot.
+    pub fn seccomp_record(mut self, recorder: SeccompRecorder) -> Self {
+        self.config.seccomp_notify = Some(SeccompNotify::record(recorder));
+        self
+    }
+
+    /// Only allows the container the system calls in `profile`: the others fail with `EPERM`.
+    /// Like with `seccomp_notify()`, the filter is installed right before the command is
+    /// executed.
+    pub fn seccomp_profile(mut self, profile: SeccompProfile) -> Self {
+        self.config.seccomp_profile = Some(profile);
+        self
+    }
+
+    /// Passes listening sockets into the container as file descriptors 3 and up, and sets
+    /// `LISTEN_FDS` and `LISTEN_PID` like systemd's socket activation does. See
+    /// `ListenFds::from_env()` for passing on the sockets that systemd passed to the caller.
+    pub fn listen_fds(mut self, listen_fds: ListenFds) -> Self {
+        self.config.listen_fds = Some(listen_fds);
+        self
+    }
+
+    /// Passes on the sd_notify messages of the container (like `READY=1`) to the socket at `path`,
+    /// which is usually the `NOTIFY_SOCKET` that systemd gave the calling process. Starts with `@`
+    /// for an abstract socket.
+    pub fn notify_socket(mut self, path: PathBuf) -> Self {
+        self.config.notify_socket = Some(path);
+        self
+    }
+
+    /// Gives the container a filtered view of a D-Bus bus through an xdg-dbus-proxy, which is
+    /// started when the container is prepared and stopped when it's gone, see `dbus`. Its socket
+    /// is at `/run/carton-dbus/bus` in the container, and `DBUS_SESSION_BUS_ADDRESS` (or
+    /// `DBUS_SYSTEM_BUS_ADDRESS`) points to it.
+    pub fn dbus_proxy(mut self, proxy: DbusProxy) -> Self {
+        self.config.dbus_proxy = Some(proxy);
+        self
+    }
+
+    /// Gives the container a file with `contents` at `relative_target`, like a config file, which is
+    /// bind mounted from the container's directory on the host rather than written to the root
+    /// filesystem, see `files`. An existing file at that path is covered up.
+    pub fn add_file(mut self, relative_target: PathBuf, contents: impl Into<Vec<u8>>) -> Self {
+        self.config
+            .files
+            .push(InjectedFile::new(relative_target, contents.into()));
+        self
+    }
+
+    /// Gives the container the secret `value` as the file `/run/secrets/<name>`, which only its
+    /// user can read. The secrets are on a tmpfs that only exists in the container's mount
+    /// namespace, so that they never end up on disk, see `secrets`. Names consist of letters,
+    /// digits, `_`, `.` and `-`.
+    pub fn add_secret(mut self, name: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+        self.config
+            .secrets
+            .push(Secret::new(name.into(), value.into()));
+        self
+    }
+
+    /// Points `CREDENTIALS_DIRECTORY` of the command at /run/secrets, for software that reads its
+    /// credentials the way a systemd service does, like when the secrets are the credentials of
+    /// the service that runs the container. The variable is set on top of the command's
+    /// environment, so it's there even when that only has some of the calling process' variables.
+    pub fn credentials_directory(mut self) -> Self {
+        self.config.credentials_directory = true;
+        self
+    }
+
+    /// Gives the container a name, which can be used instead of its ID to refer to it.
+    pub fn name(mut self, name: String) -> Self {
+        self.config.name = Some(name);
+        self
+    }
+
+    /// Makes the container a member of the project `name`, whose containers are described by the
+    /// manifest at `manifest`. Carton itself doesn't use it; it's recorded in the container's
+    /// `ContainerInfo`, so that the project's containers can be found.
+    pub fn project(mut self, name: String, manifest: PathBuf) -> Self {
+        self.config.project = Some(ProjectInfo { name, manifest });
+        self
+    }
+
+    /// Records where the settings of the container came from, like a config file or a command line
+    /// flag, which `ContainerInfo::settings` shows. Carton itself doesn't use them.
+    pub fn settings(mut self, settings: BTreeMap<String, Setting>) -> Self {
+        self.config.settings = settings;
+        self
+    }
+
+    /// Gives the container `id` as its ID instead of a random one, like a container that's restored
+    /// from a checkpoint (see `Container::restore()`) keeps the ID it had. The ID names the
+    /// container's directory in the state directory and its first 12 characters are the default
+    /// hostname, so it's at least 12 ASCII letters, digits, `_`, `.` or `-`, starting with a letter
+    /// or digit.
+    pub fn id(mut self, id: String) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// Sets the directory in which carton keeps the files of containers, like their generated
+    /// /etc/hosts, in a subdirectory per container. Defaults to the state directory of `Paths`,
+    /// which is `/run/carton` for root and `$XDG_RUNTIME_DIR/carton` for other users.
+    pub fn state_dir(mut self, path: PathBuf) -> Self {
+        self.state_dir = Some(path);
+        self
+    }
+
+    /// Gives the container its own /etc/resolv.conf and /etc/hosts, generated from the host's DNS
+    /// configuration, the container's hostname and its address.
+    pub fn add_network_files(mut self) -> Self {
+        self.config
+            .network_files
+            .get_or_insert_with(NetworkFiles::default);
+        self
+    }
+
+    /// Sets the DNS servers for the container's /etc/resolv.conf, instead of the ones the host
+    /// uses. Implies `add_network_files()`.
+    pub fn dns_servers(mut self, servers: Vec<IpAddr>) -> Self {
+        self.config
+            .network_files
+            .get_or_insert_with(NetworkFiles::default)
+            .dns_servers = servers;
+        self
+    }
+
+    /// Sets the DNS search domains for the container's /etc/resolv.conf, instead of the ones the
+    /// host uses. Implies `add_network_files()`.
+    pub fn dns_search(mut self, domains: Vec<String>) -> Self {
+        self.config
+            .network_files
+            .get_or_insert_with(NetworkFiles::default)
+            .dns_search = domains;
+        self
+    }
+
+    /// Adds an entry to the container's /etc/hosts. Implies `add_network_files()`.
+    pub fn add_host(mut self, name: String, address: IpAddr) -> Self {
+        self.config
+            .network_files
+            .get_or_insert_with(NetworkFiles::default)
+            .extra_hosts
+            .push((name, address));
+        self
+    }
+
+    /// Moves the builder on to the stage `T`.
+    fn into_stage<T>(self) -> ContainerBuilder<T> {
+        ContainerBuilder {
+            id: self.id,
+            stack_size: self.stack_size,
+            state_dir: self.state_dir,
+            gpus: self.gpus,
+            display: self.display,
+            architecture: self.architecture,
+            procfs_options: self.procfs_options,
+            shm_size: self.shm_size,
             config: self.config,
-            buffer: ContainerBuffer {
-                stack: vec![0; stack_size],
-            },
-            ..Default::default()
-        })
+            stage: PhantomData,
+        }
     }
 
     fn determine_stack_size(&self) -> usize {
@@ -137,3 +868,176 @@ impl ContainerBuilder {
             .unwrap_or(DEFAULT_CONTAINER_STACK_SIZE)
     }
 }
+
+impl ContainerBuilder<Ready> {
+    pub fn build(mut self) -> Result<Container, CartonError> {
+        let create_start = SystemTime::now();
+        let stack_size = self.determine_stack_size();
+        let id = match self.id.take() {
+            Some(id) => {
+                validate_id(&id)?;
+                id
+            }
+            None => generate_id(),
+        };
+
+        if self.config.hostname.is_none() {
+            self.config.hostname = Some(id[..12].into());
+        }
+
+        if let Some(gpus) = &self.gpus {
+            let (devices, mounts) = gpu::discover(gpus)?;
+            self.config.devices.extend(devices);
+            self.config.mounts.extend(mounts);
+        }
+
+        if self.display && !self.config.sandbox {
+            let display = display::discover(self.config.user.unwrap_or((0, 0)))?;
+            self.config.mounts.extend(display.mounts);
+            self.config.files.extend(display.files);
+            self.config.display_environment = display.environment;
+        }
+
+        if let Some(architecture) = self.architecture {
+            if let Some(emulator) = Emulator::find(architecture)? {
+                // A sandbox has the host's filesystem, with the emulator in it
+                if !self.config.sandbox {
+                    let target = emulator.path.strip_prefix("/").unwrap_or(&emulator.path);
+                    self.config.mounts.push(Mount::bind(
+                        emulator.path.clone(),
+                        target.into(),
+                        None,
+                        None,
+                    ));
+                }
+                self.config.emulator = Some(emulator);
+            }
+        }
+
+        // Otherwise a sandbox would see the host's processes in the host's /proc
+        if self.config.sandbox {
+            self.config.mounts.insert(0, Mount::procfs());
+        }
+
+        if let Some(options) = self.procfs_options.filter(|options| !options.is_empty()) {
+            let mut procfs_mounts = self
+                .config
+                .mounts
+                .iter_mut()
+                .filter(|mount| mount.is_procfs())
+                .peekable();
+            if procfs_mounts.peek().is_none() {
+                return Err(CartonError::InvalidConfiguration(
+                    "procfs options need a /proc mount, see add_default_mounts()".into(),
+                ));
+            }
+            for mount in procfs_mounts {
+                mount.set_data(options.to_string());
+            }
+        }
+
+        if self.config.init.is_some()
+            && !self.config.sandbox
+            && !self.config.mounts.iter().any(|mount| mount.is_dev())
+        {
+            return Err(CartonError::InvalidConfiguration(
+                "the init binary needs a /dev mount, see add_default_mounts()".into(),
+            ));
+        }
+
+        if let Some(size) = self.shm_size {
+            let Some(shm) = self.config.mounts.iter_mut().find(|mount| mount.is_shm()) else {
+                return Err(CartonError::InvalidConfiguration(
+                    "the size of /dev/shm needs a /dev/shm mount, see add_default_mounts()".into(),
+                ));
+            };
+            if size == 0 {
+                return Err(CartonError::InvalidConfiguration(
+                    "the size of /dev/shm can't be 0".into(),
+                ));
+            }
+            shm.set_data(shm_options(size));
+        }
+
+        self.config.dir = self
+            .state_dir
+            .unwrap_or_else(|| Paths::new().state_dir().into())
+            .join(&id);
+
+        let container = Container {
+            id,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since_epoch| since_epoch.as_secs()),
+            config: self.config,
+            buffer: ContainerBuffer::new(stack_size)?,
+            ..Default::default()
+        };
+        container.config.diagnostics.span(
+            &container.id,
+            &Span::since("create", create_start, Vec::new()),
+        );
+
+        Ok(container)
+    }
+
+    /// Builds the container and returns what preparing and starting it would do, like which
+    /// namespaces it gets and what is mounted where, without doing any of it. See `Plan`.
+    pub fn plan(self) -> Result<Plan, CartonError> {
+        self.build()?.plan()
+    }
+}
+
+/// Generates a random, hexadecimal container ID, similar to the ones Docker uses.
+fn generate_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// Checks an ID that was given instead of a generated one. Parts of it are sliced off by byte for
+/// the hostname and the names of network interfaces, so it has to be ASCII.
+fn validate_id(id: &str) -> Result<(), CartonError> {
+    let valid = id.len() >= 12
+        && id.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+    if !valid {
+        return Err(CartonError::InvalidConfiguration(format!(
+            "invalid container ID `{}`, IDs are at least 12 letters, digits, `_`, `.` and `-` and start with a letter or digit",
+            id
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_generated_and_chosen_ids() {
+        assert!(validate_id(&generate_id()).is_ok());
+        assert!(validate_id("web-1.example_com").is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_ids() {
+        for id in [
+            "short",
+            "../../../etc",
+            "-starts-with-dash",
+            "ünïcödé-container",
+            "has a space in it",
+        ] {
+            assert!(validate_id(id).is_err(), "{}", id);
+        }
+
+        let built = ContainerBuilder::new()
+            .rootfs("/nonexistent".into())
+            .command("/bin/true".into(), None)
+            .id("контейнер-один".into())
+            .build();
+        assert!(matches!(built, Err(CartonError::InvalidConfiguration(_))));
+    }
+}
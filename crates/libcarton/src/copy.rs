@@ -0,0 +1,432 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Copying files into and out of the root filesystem of a container, like `carton cp` does.
+//!
+//! A running container's filesystem is reached through its process (`/proc/<pid>/root`), so that
+//! the copy sees what the container sees: the writes of an ephemeral container, volumes and other
+//! mounts. Otherwise it's the container's root filesystem on the host.
+//!
+//! Paths in the container are resolved the way the container would resolve them. An absolute
+//! symlink like `/etc/passwd` points into the container's root filesystem, not into the host's,
+//! and `..` never leads out of it. Within the copied tree symlinks are copied as they are.
+//!
+//! A running container can change its files while they're copied, like replace a directory with
+//! a symlink to `/` right after it was resolved. So paths aren't resolved once and then used:
+//! every directory is opened from the one it's in, one name at a time and without following a
+//! symlink (see `resolve_in_root()`), and files are only reached with the `*at()` system calls
+//! from their directory. Whatever the container does, a copy stays within its root filesystem.
+//!
+//! Files that are copied into a container get the owner of the directory they end up in, which
+//! is usually the container's root. On the host that's whoever the container's root is there,
+//! like a subordinate user for a container in a user namespace. Files that are copied out of a
+//! container belong to the caller.
+
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path};
+
+use nix::dir::Dir;
+use nix::errno::Errno;
+use nix::fcntl::{self, AtFlags, OFlag};
+use nix::sys::stat::{self, FileStat, Mode, SFlag};
+use nix::unistd::{self, FchownatFlags, Gid, Uid, UnlinkatFlags};
+
+use crate::container::ContainerState;
+use crate::error::CartonError;
+use crate::state::ContainerInfo;
+
+/// How many symlinks a path in a container can lead through, like the kernel's limit.
+const MAX_SYMLINKS: usize = 40;
+
+/// A file by its name in a directory that's open. The name is `.` for the directory itself.
+#[derive(Debug)]
+struct Entry {
+    dir: File,
+    name: OsString,
+}
+
+/// Copies the file or directory at `source` on the host to `target` in the container of `info`.
+/// When `target` is a directory, the copy ends up in it, under the name of `source`.
+pub fn copy_to_container(
+    info: &ContainerInfo,
+    source: &Path,
+    target: &Path,
+) -> Result<(), CartonError> {
+    let root = container_root(info)?;
+    let source_entry = host_entry(source)?;
+    let target = copy_target(resolve_in_root(&root, target)?, source)?;
+    let metadata = target.dir.metadata()?;
+
+    copy_tree_at(
+        source_entry.dir.as_raw_fd(),
+        &source_entry.name,
+        target.dir.as_raw_fd(),
+        &target.name,
+        Some((Uid::from_raw(metadata.uid()), Gid::from_raw(metadata.gid()))),
+    )
+}
+
+/// Copies the file or directory at `source` in the container of `info` to `target` on the host.
+/// When `target` is a directory, the copy ends up in it, under the name of `source`.
+pub fn copy_from_container(
+    info: &ContainerInfo,
+    source: &Path,
+    target: &Path,
+) -> Result<(), CartonError> {
+    let root = container_root(info)?;
+    let resolved = resolve_in_root(&root, source)?;
+    let target = copy_target(host_entry(target)?, source)?;
+
+    copy_tree_at(
+        resolved.dir.as_raw_fd(),
+        &resolved.name,
+        target.dir.as_raw_fd(),
+        &target.name,
+        None,
+    )
+}
+
+/// Opens the root filesystem of the container of `info`, as the container sees it.
+fn container_root(info: &ContainerInfo) -> Result<File, CartonError> {
+    if info.sandbox {
+        return Err(CartonError::CopyError(format!(
+            "container {} is a sandbox, which uses the host's filesystem",
+            info.id
+        )));
+    }
+
+    let path = match (info.state, info.pid) {
+        (ContainerState::Running, Some(pid)) => format!("/proc/{}/root", pid).into(),
+        _ if info.ephemeral => {
+            return Err(CartonError::CopyError(format!(
+                "container {} isn't running, and the writes of an ephemeral container are gone \
+                 when it exits",
+                info.id
+            )))
+        }
+        _ => info.rootfs.clone(),
+    };
+
+    Ok(File::open(path)?)
+}
+
+/// Returns the entry of `path` on the host, where symlinks are followed as usual.
+fn host_entry(path: &Path) -> Result<Entry, CartonError> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Ok(Entry {
+            dir: File::open(if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            })?,
+            name: name.into(),
+        }),
+        _ => Ok(Entry {
+            dir: File::open(path)?,
+            name: ".".into(),
+        }),
+    }
+}
+
+/// Returns where a copy of `source` at `target` goes: into `target` when that's a directory.
+fn copy_target(target: Entry, source: &Path) -> Result<Entry, CartonError> {
+    match lstat_at(target.dir.as_raw_fd(), &target.name) {
+        Ok(stat) if file_type(&stat) == SFlag::S_IFDIR => {
+            let Some(name) = source.file_name() else {
+                return Err(CartonError::CopyError(format!(
+                    "{} has no name to copy it under",
+                    source.display()
+                )));
+            };
+            Ok(Entry {
+                dir: open_dir_at(target.dir.as_raw_fd(), &target.name)?,
+                name: name.into(),
+            })
+        }
+        _ => Ok(target),
+    }
+}
+
+/// Returns the entry of `path` in a container whose root filesystem is open as `root`. Symlinks on
+/// the way are followed like the container would follow them: absolute ones from `root`, and `..`
+/// doesn't go above it. A symlink at the end of `path` isn't followed, because that's what gets
+/// copied or replaced.
+///
+/// The kernel only ever gets to see one name at a time, so a directory that the container turns
+/// into a symlink halfway makes the copy fail instead of leading it elsewhere. Going up with `..`
+/// goes back to a directory that was opened before, rather than to whatever is above the current
+/// one by now.
+fn resolve_in_root(root: &File, path: &Path) -> Result<Entry, CartonError> {
+    // The directories from the root to where the resolution is, which `..` goes back through
+    let mut dirs = vec![root.try_clone()?];
+    // The components that are left, last one first
+    let mut pending = components(path);
+    let mut symlinks = 0;
+
+    while let Some(component) = pending.pop() {
+        if component == ".." {
+            if dirs.len() > 1 {
+                dirs.pop();
+            }
+            continue;
+        }
+        if pending.is_empty() {
+            let dir = dirs.pop().expect("the root stays");
+            return Ok(Entry {
+                dir,
+                name: component,
+            });
+        }
+        let parent = dirs.last().expect("the root stays").as_raw_fd();
+
+        let link = match open_dir_at(parent, &component) {
+            Ok(dir) => {
+                dirs.push(dir);
+                continue;
+            }
+            // It may be a symlink, which O_NOFOLLOW refuses to open
+            Err(e @ (Errno::ELOOP | Errno::ENOTDIR)) => {
+                fcntl::readlinkat(parent, component.as_os_str()).map_err(|_| e)?
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        symlinks += 1;
+        if symlinks > MAX_SYMLINKS {
+            return Err(CartonError::CopyError(format!(
+                "too many levels of symbolic links in {}",
+                path.display()
+            )));
+        }
+        if Path::new(&link).is_absolute() {
+            dirs.truncate(1);
+        }
+        pending.extend(components(Path::new(&link)));
+    }
+
+    // The path is the root, or ends in `..`
+    Ok(Entry {
+        dir: dirs.pop().expect("the root stays"),
+        name: ".".into(),
+    })
+}
+
+/// Returns the names and `..`s of `path`, last one first.
+fn components(path: &Path) -> Vec<OsString> {
+    path.components()
+        .rev()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.into()),
+            Component::ParentDir => Some("..".into()),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => None,
+        })
+        .collect()
+}
+
+/// Copies the file or directory at `source` to `target`, with every file of the copy owned by
+/// `owner` if given. Device nodes, sockets and FIFOs are left out, they only work where they are.
+///
+/// Files at `target` are replaced rather than written to, so that a copy never follows a symlink
+/// that's in its way. A directory at `target` gets the contents of a directory at `source`.
+pub(crate) fn copy_tree(
+    source: &Path,
+    target: &Path,
+    owner: Option<(Uid, Gid)>,
+) -> Result<(), CartonError> {
+    let source = host_entry(source)?;
+    let target = host_entry(target)?;
+
+    copy_tree_at(
+        source.dir.as_raw_fd(),
+        &source.name,
+        target.dir.as_raw_fd(),
+        &target.name,
+        owner,
+    )
+}
+
+/// Like `copy_tree()`, for the file `source_name` in the directory `source_dir` and the file
+/// `target_name` in the directory `target_dir`. Nothing is opened by a path of more than one name,
+/// and no symlink is followed.
+fn copy_tree_at(
+    source_dir: RawFd,
+    source_name: &OsStr,
+    target_dir: RawFd,
+    target_name: &OsStr,
+    owner: Option<(Uid, Gid)>,
+) -> Result<(), CartonError> {
+    let source = lstat_at(source_dir, source_name)?;
+    let kind = file_type(&source);
+
+    let target_is_dir = match lstat_at(target_dir, target_name) {
+        Ok(existing) if file_type(&existing) == SFlag::S_IFDIR => {
+            if kind != SFlag::S_IFDIR {
+                return Err(CartonError::CopyError(format!(
+                    "can't replace the directory {} with a file",
+                    Path::new(target_name).display()
+                )));
+            }
+            true
+        }
+        Ok(_) => {
+            unistd::unlinkat(Some(target_dir), target_name, UnlinkatFlags::NoRemoveDir)?;
+            false
+        }
+        Err(Errno::ENOENT) => false,
+        Err(e) => return Err(e.into()),
+    };
+    let permissions = fs::Permissions::from_mode(source.st_mode & 0o7777);
+
+    if kind == SFlag::S_IFDIR {
+        if !target_is_dir {
+            stat::mkdirat(target_dir, target_name, Mode::S_IRWXU)?;
+        }
+        let mut entries = Dir::openat(
+            source_dir,
+            source_name,
+            OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+        let target = open_dir_at(target_dir, target_name)?;
+        let mut names = Vec::new();
+        for entry in entries.iter() {
+            let name = entry?.file_name().to_bytes().to_vec();
+            if name != b"." && name != b".." {
+                names.push(OsString::from(OsStr::from_bytes(&name)));
+            }
+        }
+        for name in names {
+            copy_tree_at(entries.as_raw_fd(), &name, target.as_raw_fd(), &name, owner)?;
+        }
+        // After the files, in case the directory is read-only
+        target.set_permissions(permissions)?;
+    } else if kind == SFlag::S_IFLNK {
+        let link = fcntl::readlinkat(source_dir, source_name)?;
+        unistd::symlinkat(link.as_os_str(), Some(target_dir), target_name)?;
+    } else if kind == SFlag::S_IFREG {
+        let mut from = open_at(source_dir, source_name, OFlag::O_RDONLY)?;
+        let mut to = open_at(
+            target_dir,
+            target_name,
+            OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL,
+        )?;
+        io::copy(&mut from, &mut to)?;
+        to.set_permissions(permissions)?;
+    } else {
+        return Ok(());
+    }
+
+    if let Some((uid, gid)) = owner {
+        unistd::fchownat(
+            Some(target_dir),
+            target_name,
+            Some(uid),
+            Some(gid),
+            FchownatFlags::NoFollowSymlink,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Opens the directory `name` in the directory `dir`, unless it's a symlink.
+fn open_dir_at(dir: RawFd, name: &OsStr) -> nix::Result<File> {
+    open_at(dir, name, OFlag::O_RDONLY | OFlag::O_DIRECTORY)
+}
+
+/// Opens the file `name` in the directory `dir` with `flags`, unless it's a symlink. A file that
+/// it creates can only be read and written by its owner, until its permissions are set.
+fn open_at(dir: RawFd, name: &OsStr, flags: OFlag) -> nix::Result<File> {
+    let fd = fcntl::openat(
+        dir,
+        name,
+        flags | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+        Mode::S_IRUSR | Mode::S_IWUSR,
+    )?;
+
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Returns the status of the file `name` in the directory `dir`, of a symlink itself.
+fn lstat_at(dir: RawFd, name: &OsStr) -> nix::Result<FileStat> {
+    stat::fstatat(dir, name, AtFlags::AT_SYMLINK_NOFOLLOW)
+}
+
+fn file_type(stat: &FileStat) -> SFlag {
+    SFlag::from_bits_truncate(stat.st_mode & SFlag::S_IFMT.bits())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+    use std::process;
+
+    use super::*;
+
+    /// Creates an empty directory for the test `name`.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("carton-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn resolves_symlinks_within_the_root() {
+        let root_path = test_dir("copy-resolve");
+        fs::create_dir(root_path.join("etc")).unwrap();
+        fs::write(root_path.join("etc/passwd"), "").unwrap();
+        symlink("/", root_path.join("root")).unwrap();
+        symlink("/etc", root_path.join("abs")).unwrap();
+        symlink("../../..", root_path.join("etc/up")).unwrap();
+        let root = File::open(&root_path).unwrap();
+        let etc = fs::metadata(root_path.join("etc")).unwrap().ino();
+
+        for path in [
+            "/etc/passwd",
+            "root/etc/passwd",
+            "etc/up/etc/passwd",
+            "../../abs/passwd",
+            "abs/../etc/passwd",
+        ] {
+            let entry = resolve_in_root(&root, Path::new(path)).unwrap();
+            assert_eq!(entry.name, "passwd", "{}", path);
+            assert_eq!(entry.dir.metadata().unwrap().ino(), etc, "{}", path);
+        }
+
+        // A symlink at the end is what gets copied
+        let entry = resolve_in_root(&root, Path::new("etc/up")).unwrap();
+        assert_eq!(entry.name, "up");
+        let entry = resolve_in_root(&root, Path::new("etc/up/..")).unwrap();
+        assert_eq!(entry.name, ".");
+        assert_eq!(
+            entry.dir.metadata().unwrap().ino(),
+            root.metadata().unwrap().ino()
+        );
+
+        assert!(resolve_in_root(&root, Path::new("etc/passwd/x")).is_err());
+
+        fs::remove_dir_all(&root_path).unwrap();
+    }
+
+    #[test]
+    fn replaces_symlinks_instead_of_following_them() {
+        let dir = test_dir("copy-replace");
+        fs::write(dir.join("source"), "new").unwrap();
+        fs::write(dir.join("outside"), "old").unwrap();
+        symlink(dir.join("outside"), dir.join("target")).unwrap();
+
+        copy_tree(&dir.join("source"), &dir.join("target"), None).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("target")).unwrap(), "new");
+        assert_eq!(fs::read_to_string(dir.join("outside")).unwrap(), "old");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
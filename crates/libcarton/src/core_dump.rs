@@ -0,0 +1,121 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! What happens when a process in the container crashes and the kernel writes a core dump.
+//!
+//! Where core dumps go is decided by `/proc/sys/kernel/core_pattern`, which isn't namespaced: it's
+//! the same for the host and every container. When it's a pipe (`|/usr/lib/systemd/...`), the
+//! host's handler gets the core dumps of all containers too. When it's a file path, the kernel
+//! writes the file relative to the root directory of the crashing process, so inside the
+//! container. That's what makes it possible to collect a container's core dumps in a directory of
+//! its own: carton bind mounts that directory where the pattern points inside the container.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use nix::sys::prctl;
+use nix::sys::resource::{self, Resource};
+
+use crate::container::Mount;
+use crate::error::CartonError;
+
+const CORE_PATTERN_PATH: &str = "/proc/sys/kernel/core_pattern";
+
+/// Core dump policy of a container, see `ContainerBuilder::core_dumps()`.
+#[derive(Debug, Clone, Default)]
+pub enum CoreDumps {
+    /// Core dumps are handled like the host handles those of its own processes.
+    #[default]
+    Host,
+    /// No core dumps at all. This sets RLIMIT_CORE to 0, which stops the kernel from writing core
+    /// files and tells handlers like systemd-coredump not to store them. The container can't
+    /// raise the limit again.
+    Disabled,
+    /// Core dumps are written into this directory on the host. The crashing process needs to be
+    /// able to create files in it. Only works when the host's core_pattern is an absolute file
+    /// path, like `/var/crash/core.%e.%p`, and not when it's a pipe to a handler.
+    Directory(PathBuf),
+}
+
+impl CoreDumps {
+    pub(crate) fn validate(&self) -> Result<(), CartonError> {
+        if let CoreDumps::Directory(_) = self {
+            core_pattern_dir()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the bind mount of the core dump directory, if there is one. Creates the directory
+    /// when it doesn't exist yet.
+    pub(crate) fn mount(&self) -> Result<Option<Mount>, CartonError> {
+        if let CoreDumps::Directory(dir) = self {
+            fs::create_dir_all(dir)?;
+        }
+
+        self.bind_mount()
+    }
+
+    /// Like `mount()`, without creating the directory.
+    pub(crate) fn bind_mount(&self) -> Result<Option<Mount>, CartonError> {
+        let CoreDumps::Directory(dir) = self else {
+            return Ok(None);
+        };
+        let target = core_pattern_dir()?;
+
+        Ok(Some(Mount::bind(
+            dir.clone(),
+            target.strip_prefix("/").unwrap_or(&target).into(),
+            None,
+            None,
+        )))
+    }
+
+    /// Applies the policy to the container process. Called early in the container process, so
+    /// that carton's own setup doesn't leave a core dump behind either.
+    pub(crate) fn apply(&self) -> nix::Result<()> {
+        match self {
+            CoreDumps::Host => Ok(()),
+            CoreDumps::Disabled => {
+                // Not dumpable only lasts until the command is executed, but the limit stays
+                prctl::set_dumpable(false)?;
+                resource::setrlimit(Resource::RLIMIT_CORE, 0, 0)
+            }
+            CoreDumps::Directory(_) => {
+                // Many distributions set the soft limit to 0 by default
+                let (_, hard_limit) = resource::getrlimit(Resource::RLIMIT_CORE)?;
+                resource::setrlimit(Resource::RLIMIT_CORE, hard_limit, hard_limit)
+            }
+        }
+    }
+}
+
+/// Returns the directory that the host's core_pattern writes core dumps to.
+fn core_pattern_dir() -> Result<PathBuf, CartonError> {
+    let pattern = fs::read_to_string(CORE_PATTERN_PATH)?;
+    let pattern = pattern.trim_end();
+    let unsupported = |reason: &str| {
+        Err(CartonError::InvalidConfiguration(format!(
+            "core dumps can't be redirected into a directory, because the host's core_pattern `{}` {}",
+            pattern, reason
+        )))
+    };
+
+    if pattern.starts_with('|') || pattern.starts_with('@') {
+        return unsupported("hands them to a handler");
+    }
+    if !pattern.starts_with('/') {
+        return unsupported("is relative to the working directory of the crashing process");
+    }
+    let dir = Path::new(pattern)
+        .parent()
+        .unwrap_or_else(|| Path::new("/"));
+    if dir.to_string_lossy().contains('%') {
+        return unsupported("has a directory that depends on the crashing process");
+    }
+    if dir == Path::new("/") {
+        return unsupported("writes them into the root directory");
+    }
+
+    Ok(dir.into())
+}
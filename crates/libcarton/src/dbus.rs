@@ -0,0 +1,385 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Gives a container a filtered view of a D-Bus bus, like Flatpak does for its apps, see
+//! `ContainerBuilder::dbus_proxy()`.
+//!
+//! The session bus lets a program do anything the user can, from reading the keyring to starting
+//! other programs, so a desktop program in a container shouldn't get it as it is. Carton starts
+//! [xdg-dbus-proxy][1] when the container is prepared, which creates a socket in the container's
+//! directory and passes on only what its rules allow: which names the container may talk to, own
+//! and see, and which methods it may call. The socket's directory is bind mounted at
+//! `/run/carton-dbus` in the container, and `DBUS_SESSION_BUS_ADDRESS` (or
+//! `DBUS_SYSTEM_BUS_ADDRESS`) points to it.
+//!
+//! The proxy is tied to carton with a pipe, see `--fd` of xdg-dbus-proxy: it writes a byte to the
+//! pipe once it listens on its socket, and exits when carton closes the other end once the
+//! container is gone.
+//!
+//! A bus only lets in its own user, and the proxy passes on how a client authenticates itself. So
+//! the proxy has to run as the user of the bus, and the container's command with the same user ID.
+//!
+//! [1]: https://github.com/flatpak/xdg-dbus-proxy
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use nix::errno::Errno;
+use nix::fcntl::{self, FcntlArg, FdFlag, OFlag};
+use nix::poll::{self, PollFd, PollFlags};
+use nix::unistd::{self, Gid, Uid};
+
+use crate::container::Mount;
+use crate::error::CartonError;
+use crate::rollback::{Rollback, Step};
+
+/// Directory inside the container that holds the socket.
+const CONTAINER_SOCKET_DIR: &str = "run/carton-dbus";
+const SOCKET_NAME: &str = "bus";
+const DEFAULT_COMMAND: &str = "xdg-dbus-proxy";
+const SYSTEM_BUS_SOCKET: &str = "/run/dbus/system_bus_socket";
+/// How long the proxy gets to start listening, and to exit once the container is gone.
+const TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Which bus a `DbusProxy` proxies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbusBus {
+    Session,
+    System,
+}
+
+impl DbusBus {
+    /// The environment variable with the address of the bus.
+    fn variable(self) -> &'static str {
+        match self {
+            DbusBus::Session => "DBUS_SESSION_BUS_ADDRESS",
+            DbusBus::System => "DBUS_SYSTEM_BUS_ADDRESS",
+        }
+    }
+}
+
+/// An xdg-dbus-proxy between a container and a bus, with the rules of what the container may do
+/// on it. Without rules, the container can only talk to the bus itself.
+///
+/// ```no_run
+/// use libcarton::{ContainerBuilder, DbusProxy};
+///
+/// let builder = ContainerBuilder::new().dbus_proxy(
+///     DbusProxy::session()
+///         .talk("org.freedesktop.Notifications")
+///         .own("org.example.App"),
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct DbusProxy {
+    bus: DbusBus,
+    address: Option<String>,
+    rules: Vec<String>,
+    user: Option<(u32, u32)>,
+    log: bool,
+    command: PathBuf,
+}
+
+impl DbusProxy {
+    pub fn new(bus: DbusBus) -> Self {
+        DbusProxy {
+            bus,
+            address: None,
+            rules: Vec::new(),
+            user: None,
+            log: false,
+            command: DEFAULT_COMMAND.into(),
+        }
+    }
+
+    pub fn session() -> Self {
+        DbusProxy::new(DbusBus::Session)
+    }
+
+    pub fn system() -> Self {
+        DbusProxy::new(DbusBus::System)
+    }
+
+    /// The address of the bus, like `unix:path=/run/user/1000/bus`. Defaults to the calling
+    /// process' `DBUS_SESSION_BUS_ADDRESS` (or `DBUS_SYSTEM_BUS_ADDRESS`), or else the bus' usual
+    /// socket.
+    pub fn address(mut self, address: impl Into<String>) -> Self {
+        self.address = Some(address.into());
+        self
+    }
+
+    /// Lets the container call methods of and receive signals from `name`, like
+    /// `org.freedesktop.Notifications`. Names can end in `.*`, which also matches the names under
+    /// it.
+    pub fn talk(self, name: &str) -> Self {
+        self.rule("talk", name)
+    }
+
+    /// Lets the container own `name`, and talk to it.
+    pub fn own(self, name: &str) -> Self {
+        self.rule("own", name)
+    }
+
+    /// Lets the container see that `name` exists, without talking to it.
+    pub fn see(self, name: &str) -> Self {
+        self.rule("see", name)
+    }
+
+    /// Lets the container call the methods of `name` that match `rule`, like
+    /// `org.freedesktop.portal.*@/org/freedesktop/portal/desktop`, without talking to it
+    /// otherwise.
+    pub fn call(self, name: &str, rule: &str) -> Self {
+        self.rule("call", &format!("{}={}", name, rule))
+    }
+
+    /// Lets the container receive the signals of `name` that match `rule`.
+    pub fn broadcast(self, name: &str, rule: &str) -> Self {
+        self.rule("broadcast", &format!("{}={}", name, rule))
+    }
+
+    fn rule(mut self, kind: &str, value: &str) -> Self {
+        self.rules.push(format!("--{}={}", kind, value));
+        self
+    }
+
+    /// Runs the proxy as user `uid` and group `gid`, like the user of the session bus when the
+    /// calling process runs as root. By default it runs as the calling process' user.
+    pub fn user(mut self, uid: u32, gid: u32) -> Self {
+        self.user = Some((uid, gid));
+        self
+    }
+
+    /// Makes the proxy log the messages that pass through it, and the ones it filters out, to the
+    /// calling process' stderr.
+    pub fn log(mut self) -> Self {
+        self.log = true;
+        self
+    }
+
+    /// Runs this proxy executable instead of `xdg-dbus-proxy` from the `PATH`.
+    pub fn command(mut self, path: PathBuf) -> Self {
+        self.command = path;
+        self
+    }
+
+    /// Returns the variable that points the container's command to the proxy, and its value.
+    pub(crate) fn container_variable(&self) -> (&'static str, String) {
+        (
+            self.bus.variable(),
+            format!(
+                "unix:path={}",
+                Path::new("/")
+                    .join(CONTAINER_SOCKET_DIR)
+                    .join(SOCKET_NAME)
+                    .display()
+            ),
+        )
+    }
+
+    /// Returns the address of the bus to proxy.
+    fn bus_address(&self) -> Result<String, CartonError> {
+        if let Some(address) = &self.address {
+            return Ok(address.clone());
+        }
+        if let Some(address) = env::var_os(self.bus.variable()).filter(|value| !value.is_empty()) {
+            return Ok(address.to_string_lossy().into_owned());
+        }
+
+        let socket = match self.bus {
+            DbusBus::System => PathBuf::from(SYSTEM_BUS_SOCKET),
+            DbusBus::Session => match (env::var_os("XDG_RUNTIME_DIR"), self.user) {
+                (Some(runtime_dir), _) if !runtime_dir.is_empty() => {
+                    Path::new(&runtime_dir).join("bus")
+                }
+                (_, Some((uid, _))) => PathBuf::from(format!("/run/user/{}/bus", uid)),
+                _ => {
+                    return Err(CartonError::InvalidConfiguration(
+                        "no session bus found, DBUS_SESSION_BUS_ADDRESS isn't set".into(),
+                    ))
+                }
+            },
+        };
+
+        Ok(format!("unix:path={}", socket.display()))
+    }
+
+    /// Starts the proxy with its socket in `container_dir`, and waits until it listens on it.
+    pub(crate) fn start(
+        &self,
+        container_dir: &Path,
+        rollback: &Rollback,
+    ) -> Result<DbusProxyProcess, CartonError> {
+        let address = self.bus_address()?;
+        let dir = socket_dir(container_dir);
+        rollback.create_dir_all(container_dir)?;
+        fs::create_dir_all(&dir)?;
+        rollback.record(Step::Copy(dir.clone()));
+        if let Some((uid, gid)) = self.user {
+            unistd::chown(&dir, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))?;
+        }
+        let socket = dir.join(SOCKET_NAME);
+        let _ = fs::remove_file(&socket);
+
+        let (ready_read, ready_write) = unistd::pipe2(OFlag::O_CLOEXEC)?;
+        let (ready_read, ready_write) = unsafe {
+            (
+                OwnedFd::from_raw_fd(ready_read),
+                OwnedFd::from_raw_fd(ready_write),
+            )
+        };
+        let mut command = Command::new(&self.command);
+        command
+            .arg(format!("--fd={}", ready_write.as_raw_fd()))
+            .arg(&address)
+            .arg(&socket)
+            .arg("--filter")
+            .args(&self.rules)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null());
+        if self.log {
+            command.arg("--log");
+        }
+        if let Some((uid, gid)) = self.user {
+            command.uid(uid).gid(gid);
+        }
+        // Like a FUSE daemon, it stays out of the terminal's process group, so that a Ctrl-C
+        // meant for the container doesn't cut it off from the bus first
+        command.process_group(0);
+        let write_fd = ready_write.as_raw_fd();
+        unsafe {
+            command.pre_exec(move || {
+                fcntl::fcntl(write_fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+                Ok(())
+            });
+        }
+
+        info!(
+            "starting {} for {} at {}",
+            self.command.display(),
+            address,
+            socket.display()
+        );
+        let child = command.spawn().map_err(|e| {
+            CartonError::DbusProxyError(format!(
+                "could not start {}: {}",
+                self.command.display(),
+                e
+            ))
+        })?;
+        // Only the proxy keeps the writing end, so that reading ends when it's gone
+        drop(ready_write);
+        let mut proxy = DbusProxyProcess {
+            child: Some(child),
+            ready: None,
+        };
+
+        let failure = match wait_until_ready(&ready_read)? {
+            Startup::Ready => {
+                // The container's user may not be the proxy's, the proxy's rules decide
+                fs::set_permissions(&socket, fs::Permissions::from_mode(0o666))?;
+                proxy.ready = Some(ready_read);
+                return Ok(proxy);
+            }
+            Startup::Exited => match proxy.child.take().map(|mut child| child.wait()) {
+                Some(Ok(status)) => format!("exited with {}", status),
+                _ => "exited".into(),
+            },
+            Startup::TimedOut => format!("did not listen within {:?}", TIMEOUT),
+        };
+
+        Err(CartonError::DbusProxyError(format!(
+            "{} {} on {}",
+            self.command.display(),
+            failure,
+            socket.display()
+        )))
+    }
+}
+
+/// Returns the bind mount that puts the directory with the proxy's socket in place inside the
+/// container.
+pub(crate) fn mount(container_dir: &Path) -> Mount {
+    Mount::bind(
+        socket_dir(container_dir),
+        CONTAINER_SOCKET_DIR.into(),
+        None,
+        None,
+    )
+}
+
+fn socket_dir(container_dir: &Path) -> PathBuf {
+    container_dir.join("dbus")
+}
+
+/// How starting the proxy went.
+enum Startup {
+    Ready,
+    Exited,
+    TimedOut,
+}
+
+/// Waits for the byte that the proxy writes to `ready` once it listens.
+fn wait_until_ready(ready: &OwnedFd) -> Result<Startup, CartonError> {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let timeout = remaining.as_millis().clamp(1, i32::MAX as u128) as i32;
+        match poll::poll(&mut [PollFd::new(ready, PollFlags::POLLIN)], timeout) {
+            Ok(0) => return Ok(Startup::TimedOut),
+            Ok(_) => break,
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let mut byte = [0];
+    let mut file = File::from(ready.try_clone()?);
+    // The pipe ends without a byte when the proxy exits
+    Ok(match file.read(&mut byte)? {
+        1 => Startup::Ready,
+        _ => Startup::Exited,
+    })
+}
+
+/// A running xdg-dbus-proxy. Dropping it stops the proxy.
+#[derive(Debug)]
+pub(crate) struct DbusProxyProcess {
+    child: Option<Child>,
+    /// The reading end of the pipe that the proxy exits on once it's closed.
+    ready: Option<OwnedFd>,
+}
+
+impl Drop for DbusProxyProcess {
+    fn drop(&mut self) {
+        self.ready = None;
+        let Some(child) = &mut self.child else {
+            return;
+        };
+
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            match child.try_wait() {
+                Ok(Some(_)) => return,
+                Ok(None) => thread::sleep(POLL_INTERVAL),
+                Err(e) => {
+                    warn!("Could not wait for xdg-dbus-proxy: {}", e);
+                    return;
+                }
+            }
+        }
+        warn!("xdg-dbus-proxy did not exit, killing it");
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
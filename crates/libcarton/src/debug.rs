@@ -0,0 +1,289 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Debugging a running container in place, see `debug_container()`: like `nsenter`, with the
+//! container stopped while it's being looked at, and tools that the container doesn't have.
+//!
+//! The container's cgroup is frozen first, so that its processes stay as they are. The debug
+//! shell enters the container's namespaces (also its PID namespace, which applies to the commands
+//! that the shell runs), but not its cgroup, which would freeze the shell too. Once the shell
+//! exits, the container is thawed again, unless it was paused before.
+//!
+//! A container's root filesystem often has no shell or tools at all. With a debug root filesystem
+//! on the host (like an unpacked busybox or Alpine image), the shell runs in a private copy of
+//! the container's mount namespace, in which the debug root filesystem is mounted on top of the
+//! container's root. The container's /proc and /dev are mounted into it, so the container's own
+//! files are at `/proc/1/root`. Nothing of this is visible to the container.
+
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::warn;
+
+use nix::errno::Errno;
+use nix::mount::{self, MsFlags};
+use nix::sched::{self, CloneFlags};
+use nix::sys::signal::{self, SigHandler, Signal};
+use nix::unistd;
+
+use crate::cgroup;
+use crate::container::ContainerState;
+use crate::error::CartonError;
+use crate::health::{self, DEFAULT_PATH, NAMESPACES};
+use crate::state::ContainerInfo;
+
+/// The directories of the container that are mounted into a debug root filesystem.
+const SHARED_DIRS: [&str; 2] = ["/proc", "/dev"];
+
+/// How a container is debugged, see `debug_container()`.
+#[derive(Debug, Clone)]
+pub struct DebugOptions {
+    command: Vec<String>,
+    rootfs: Option<PathBuf>,
+    pause: bool,
+}
+
+impl Default for DebugOptions {
+    fn default() -> Self {
+        DebugOptions {
+            command: vec!["/bin/sh".into()],
+            rootfs: None,
+            pause: true,
+        }
+    }
+}
+
+impl DebugOptions {
+    pub fn new() -> Self {
+        DebugOptions::default()
+    }
+
+    /// The program to run and its arguments (`/bin/sh` by default). A program that's just a name
+    /// is looked up in the `PATH` directories of the container's (or the debug) root filesystem.
+    pub fn command(mut self, command: Vec<String>) -> Self {
+        self.command = command;
+        self
+    }
+
+    /// Runs the command from the root filesystem at `path` on the host, like one with a shell and
+    /// debugging tools, instead of from the container's. See the module documentation.
+    pub fn rootfs(mut self, path: PathBuf) -> Self {
+        self.rootfs = Some(path);
+        self
+    }
+
+    /// Leaves the container running while it's being debugged, like for a container without a
+    /// cgroup of its own, which can't be paused.
+    pub fn no_pause(mut self) -> Self {
+        self.pause = false;
+        self
+    }
+}
+
+/// Pauses the running container of `info`, runs the debug command in it with carton's stdin,
+/// stdout and stderr, and resumes the container once the command exits. Returns the exit code of
+/// the command, or 128 plus the signal that killed it, like a shell does.
+pub fn debug_container(info: &ContainerInfo, options: &DebugOptions) -> Result<i32, CartonError> {
+    let (ContainerState::Running, Some(pid)) = (info.state, info.pid) else {
+        return Err(CartonError::NotRunning);
+    };
+    let Some((program, arguments)) = options.command.split_first() else {
+        return Err(CartonError::InvalidConfiguration(
+            "the debug command can't be empty".into(),
+        ));
+    };
+
+    let mut command = Command::new(program);
+    command
+        .args(arguments)
+        .env_clear()
+        .env("PATH", DEFAULT_PATH)
+        .env(
+            "PS1",
+            format!("[debug {}] # ", &info.id[..info.id.len().min(12)]),
+        );
+    if let Some(term) = env::var_os("TERM") {
+        command.env("TERM", term);
+    }
+    enter(&mut command, pid, options.rootfs.as_deref())?;
+
+    // Paused last, so that nothing above leaves the container paused when it fails
+    let _pause = if options.pause && !cgroup::is_frozen(&info.id) {
+        Some(Pause::new(info)?)
+    } else {
+        None
+    };
+    // Like system(), so that ^C reaches the command and not carton, which has to resume the
+    // container afterwards
+    let _ignored = IgnoredSignals::new()?;
+    let status = command.status().map_err(|e| {
+        CartonError::IOError(format!("running the debug command {:?}: {}", program, e))
+    })?;
+
+    Ok(match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    })
+}
+
+/// Makes `command` run in the namespaces of process `pid`, in its root directory or in the debug
+/// root filesystem at `rootfs`.
+fn enter(command: &mut Command, pid: i32, rootfs: Option<&Path>) -> Result<(), CartonError> {
+    let mut names = NAMESPACES.to_vec();
+    names.push("pid");
+    let namespaces = health::open_namespaces(pid, &names)?;
+    // See `health::enter_container()`
+    let root = File::open(format!("/proc/{}/root", pid))?;
+    let debug_root = match rootfs {
+        Some(rootfs) => {
+            let path = CString::new(rootfs.as_os_str().as_bytes()).map_err(|_| {
+                CartonError::InvalidConfiguration(format!(
+                    "invalid debug root filesystem {}",
+                    rootfs.display()
+                ))
+            })?;
+            Some(clone_tree(&path).map_err(|e| {
+                CartonError::IOError(format!(
+                    "opening the debug root filesystem {}: {}",
+                    rootfs.display(),
+                    e
+                ))
+            })?)
+        }
+        None => None,
+    };
+    // Made here, because the forked process shouldn't allocate
+    let new_root = CString::new("/").expect("no NUL in /");
+    let shared_dirs = SHARED_DIRS
+        .iter()
+        .map(|dir| CString::new(*dir).expect("no NUL in the directory"))
+        .collect::<Vec<CString>>();
+
+    unsafe {
+        command.pre_exec(move || {
+            for signal in [Signal::SIGINT, Signal::SIGQUIT] {
+                signal::signal(signal, SigHandler::SigDfl)?;
+            }
+            health::enter_namespaces(&namespaces)?;
+            unistd::fchdir(root.as_raw_fd())?;
+            unistd::chroot(".")?;
+            unistd::chdir("/")?;
+
+            if let Some(debug_root) = &debug_root {
+                // A copy of the container's mount namespace, whose changes don't propagate back
+                sched::unshare(CloneFlags::CLONE_NEWNS)?;
+                mount::mount(
+                    None::<&str>,
+                    "/",
+                    None::<&str>,
+                    MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+                    None::<&str>,
+                )?;
+                // Copied before they're covered by the debug root filesystem
+                let trees = [clone_tree(&shared_dirs[0])?, clone_tree(&shared_dirs[1])?];
+
+                attach_tree(debug_root.as_raw_fd(), &new_root)?;
+                unistd::fchdir(debug_root.as_raw_fd())?;
+                unistd::chroot(".")?;
+                unistd::chdir("/")?;
+                for (tree, dir) in trees.iter().zip(&shared_dirs) {
+                    match attach_tree(tree.as_raw_fd(), dir) {
+                        // The debug root filesystem doesn't have the directory
+                        Err(Errno::ENOENT) => {}
+                        result => result?,
+                    }
+                }
+            }
+
+            Ok(())
+        });
+    }
+
+    Ok(())
+}
+
+/// Returns a detached copy of the directory at `path`, with the mounts underneath it.
+fn clone_tree(path: &CStr) -> nix::Result<OwnedFd> {
+    let tree = Errno::result(unsafe {
+        libc::syscall(
+            libc::SYS_open_tree,
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            libc::OPEN_TREE_CLONE | libc::OPEN_TREE_CLOEXEC | libc::AT_RECURSIVE as libc::c_uint,
+        )
+    })?;
+
+    Ok(unsafe { OwnedFd::from_raw_fd(tree as RawFd) })
+}
+
+/// Mounts the detached `tree` on top of whatever is mounted at `path`, like
+/// `idmap::attach_tree()`, without allocating.
+fn attach_tree(tree: RawFd, path: &CStr) -> nix::Result<()> {
+    Errno::result(unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            tree,
+            b"\0".as_ptr(),
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            libc::MOVE_MOUNT_F_EMPTY_PATH,
+        )
+    })?;
+
+    Ok(())
+}
+
+/// Pauses a container, and resumes it when dropped.
+struct Pause<'a> {
+    info: &'a ContainerInfo,
+}
+
+impl<'a> Pause<'a> {
+    fn new(info: &'a ContainerInfo) -> Result<Self, CartonError> {
+        cgroup::pause_container(info)?;
+
+        Ok(Pause { info })
+    }
+}
+
+impl Drop for Pause<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = cgroup::resume_container(self.info) {
+            warn!("Could not resume container {}: {}", self.info.id, e);
+        }
+    }
+}
+
+/// Ignores SIGINT and SIGQUIT in carton, until dropped.
+struct IgnoredSignals {
+    /// The handlers to put back.
+    previous: Vec<(Signal, SigHandler)>,
+}
+
+impl IgnoredSignals {
+    fn new() -> Result<Self, CartonError> {
+        let mut previous = Vec::new();
+        for signal in [Signal::SIGINT, Signal::SIGQUIT] {
+            previous.push((signal, unsafe {
+                signal::signal(signal, SigHandler::SigIgn)
+            }?));
+        }
+
+        Ok(IgnoredSignals { previous })
+    }
+}
+
+impl Drop for IgnoredSignals {
+    fn drop(&mut self) {
+        for (signal, handler) in &self.previous {
+            let _ = unsafe { signal::signal(*signal, *handler) };
+        }
+    }
+}
@@ -0,0 +1,295 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reports about how the setup of a container goes, for the embedding program to show or act on,
+//! see `ContainerBuilder::diagnostics()`.
+//!
+//! Everything that is reported is logged with the `log` crate as well. The calling process reports
+//! straight to the embedding program's `Diagnostics`. The container process sets up its own mounts
+//! and device nodes, though, in a copy of the calling process' memory, so it sends its reports
+//! through a pipe instead, as lines of JSON. `Container::start()` passes them on.
+//!
+//! With `ContainerBuilder::debug_setup()`, every privileged operation of the setup (a mount, a
+//! device node, a write to `/proc/<pid>/uid_map`) is reported as well, with its arguments and
+//! result, see `Reporter::operation()`. Otherwise those only go to the log, at the debug level.
+//!
+//! The spans of a container's lifecycle go the same way, to the embedding program's `Tracer`, see
+//! `trace`.
+
+use std::fmt;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+
+use log::log;
+
+use nix::errno::Errno;
+use nix::fcntl::{self, FcntlArg, OFlag};
+use nix::unistd;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::CartonError;
+use crate::trace::{Span, Tracer};
+
+/// The line that the container process sends when it's done with its setup. Reports themselves
+/// are never empty lines.
+const END_OF_SETUP: &[u8] = b"\n";
+
+/// How serious a `Diagnostic` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticLevel {
+    /// A privileged operation, with its arguments and result. These are only reported with
+    /// `ContainerBuilder::debug_setup()`.
+    Debug,
+    /// A step of the setup, like a mount.
+    Info,
+    /// Something that didn't work out, but that the container can do without, like a device
+    /// node that the host doesn't have.
+    Warning,
+    /// Something that made the setup fail. The error is also returned, but this may say more
+    /// about it, because it comes from the container process.
+    Error,
+}
+
+impl From<DiagnosticLevel> for log::Level {
+    fn from(level: DiagnosticLevel) -> Self {
+        match level {
+            DiagnosticLevel::Debug => log::Level::Debug,
+            DiagnosticLevel::Info => log::Level::Info,
+            DiagnosticLevel::Warning => log::Level::Warn,
+            DiagnosticLevel::Error => log::Level::Error,
+        }
+    }
+}
+
+impl fmt::Display for DiagnosticLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DiagnosticLevel::Debug => "debug",
+            DiagnosticLevel::Info => "info",
+            DiagnosticLevel::Warning => "warning",
+            DiagnosticLevel::Error => "error",
+        };
+
+        f.pad(name)
+    }
+}
+
+/// A span that the container process reports, as a line of its own.
+#[derive(Serialize, Deserialize)]
+struct SpanReport {
+    span: Span,
+}
+
+/// A single report about the setup of a container.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub level: DiagnosticLevel,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.level, self.message)
+    }
+}
+
+/// Receives the diagnostics of a container. Closures that take a `&Diagnostic` implement it too.
+///
+/// Reports come from the thread that uses the `Container`. Those of the container process' own
+/// setup arrive all at once, when the container is started.
+///
+/// ```no_run
+/// use std::sync::{Arc, Mutex};
+///
+/// use libcarton::{ContainerBuilder, DiagnosticLevel};
+///
+/// let warnings = Arc::new(Mutex::new(Vec::new()));
+/// let sink = warnings.clone();
+/// let builder = ContainerBuilder::new().diagnostics(move |diagnostic: &libcarton::Diagnostic| {
+///     if diagnostic.level >= DiagnosticLevel::Warning {
+///         sink.lock().unwrap().push(diagnostic.message.clone());
+///     }
+/// });
+/// ```
+pub trait Diagnostics: Send + Sync {
+    fn report(&self, diagnostic: &Diagnostic);
+}
+
+impl<F> Diagnostics for F
+where
+    F: Fn(&Diagnostic) + Send + Sync,
+{
+    fn report(&self, diagnostic: &Diagnostic) {
+        self(diagnostic)
+    }
+}
+
+/// Where the library reports to: the log and the embedding program's `Diagnostics` and `Tracer`,
+/// or the pipe to the calling process when it's the container process that reports.
+#[derive(Default, Clone)]
+pub(crate) struct Reporter {
+    sink: Option<Arc<dyn Diagnostics>>,
+    tracer: Option<Arc<dyn Tracer>>,
+    pipe: Option<RawFd>,
+    /// Whether privileged operations are reported, rather than only logged.
+    debug: bool,
+}
+
+impl fmt::Debug for Reporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reporter")
+            .field("sink", &self.sink.is_some())
+            .field("tracer", &self.tracer.is_some())
+            .field("pipe", &self.pipe)
+            .field("debug", &self.debug)
+            .finish()
+    }
+}
+
+impl Reporter {
+    pub(crate) fn set_sink(&mut self, sink: Arc<dyn Diagnostics>) {
+        self.sink = Some(sink);
+    }
+
+    pub(crate) fn set_tracer(&mut self, tracer: Arc<dyn Tracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    pub(crate) fn enable_debug(&mut self) {
+        self.debug = true;
+    }
+
+    /// Returns the reporter for the container process, which writes to `pipe`.
+    pub(crate) fn container_process(&self, pipe: RawFd) -> Self {
+        Reporter {
+            sink: None,
+            tracer: None,
+            pipe: Some(pipe),
+            debug: self.debug,
+        }
+    }
+
+    /// Reports the privileged operation `operation` and its result, which is passed through.
+    /// Operations that are a single system call are described like one, such as
+    /// `mknod("/rootfs/dev/null", S_IFCHR|666, 1:3)`.
+    pub(crate) fn operation<T, E: fmt::Display>(
+        &self,
+        operation: fmt::Arguments,
+        result: Result<T, E>,
+    ) -> Result<T, E> {
+        match &result {
+            Ok(_) => self.debug(format_args!("{} = ok", operation)),
+            Err(e) => self.debug(format_args!("{} = {}", operation, e)),
+        }
+
+        result
+    }
+
+    pub(crate) fn debug(&self, message: fmt::Arguments) {
+        self.report(DiagnosticLevel::Debug, message);
+    }
+
+    pub(crate) fn info(&self, message: fmt::Arguments) {
+        self.report(DiagnosticLevel::Info, message);
+    }
+
+    pub(crate) fn warning(&self, message: fmt::Arguments) {
+        self.report(DiagnosticLevel::Warning, message);
+    }
+
+    pub(crate) fn error(&self, message: fmt::Arguments) {
+        self.report(DiagnosticLevel::Error, message);
+    }
+
+    fn report(&self, level: DiagnosticLevel, message: fmt::Arguments) {
+        log!(level.into(), "{}", message);
+        if level == DiagnosticLevel::Debug && !self.debug {
+            return;
+        }
+
+        let diagnostic = Diagnostic {
+            level,
+            message: message.to_string(),
+        };
+        if let Some(sink) = &self.sink {
+            sink.report(&diagnostic);
+        }
+        if let Some(pipe) = self.pipe {
+            // Reports that don't fit in the pipe are dropped rather than holding up the setup,
+            // they're in the log anyway
+            if let Ok(mut line) = serde_json::to_vec(&diagnostic) {
+                line.push(b'\n');
+                let _ = unistd::write(pipe, &line);
+            }
+        }
+    }
+
+    /// Reports the span `span` of the container `container_id`, which has ended.
+    pub(crate) fn span(&self, container_id: &str, span: &Span) {
+        if let Some(tracer) = &self.tracer {
+            tracer.span(container_id, span);
+        }
+        if let Some(pipe) = self.pipe {
+            if let Ok(mut line) = serde_json::to_vec(&SpanReport { span: span.clone() }) {
+                line.push(b'\n');
+                let _ = unistd::write(pipe, &line);
+            }
+        }
+    }
+
+    /// Whether there's an embedding program's `Diagnostics` or `Tracer` to report to, and so
+    /// whether the container process' reports need to be passed on.
+    pub(crate) fn has_receiver(&self) -> bool {
+        self.sink.is_some() || self.tracer.is_some()
+    }
+
+    /// Tells the calling process that the container process is done with its setup.
+    pub(crate) fn end_of_setup(&self) {
+        if let Some(pipe) = self.pipe {
+            let _ = unistd::write(pipe, END_OF_SETUP);
+        }
+    }
+
+    /// Passes on the reports that the container process sent through `pipe`, until it's done with
+    /// its setup or gone, and returns the spans that it sent.
+    pub(crate) fn receive(&self, pipe: OwnedFd) -> Result<Vec<Span>, CartonError> {
+        let mut received = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            match unistd::read(pipe.as_raw_fd(), &mut buffer) {
+                Ok(0) => break,
+                Ok(size) => received.extend_from_slice(&buffer[..size]),
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+            // Every report ends with a newline, so the end of the setup is an empty line
+            if received == END_OF_SETUP || received.ends_with(b"\n\n") {
+                break;
+            }
+        }
+
+        let mut spans = Vec::new();
+        for line in received.split(|byte| *byte == b'\n') {
+            if let Ok(diagnostic) = serde_json::from_slice::<Diagnostic>(line) {
+                if let Some(sink) = &self.sink {
+                    sink.report(&diagnostic);
+                }
+            } else if let Ok(report) = serde_json::from_slice::<SpanReport>(line) {
+                spans.push(report.span);
+            }
+        }
+
+        Ok(spans)
+    }
+}
+
+/// Creates the pipe through which the container process reports. The container process' end
+/// doesn't block, see `Reporter::report()`.
+pub(crate) fn pipe() -> Result<(OwnedFd, OwnedFd), CartonError> {
+    let (read, write) = unistd::pipe2(OFlag::O_CLOEXEC)?;
+    fcntl::fcntl(write, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+
+    Ok(unsafe { (OwnedFd::from_raw_fd(read), OwnedFd::from_raw_fd(write)) })
+}
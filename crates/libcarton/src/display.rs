@@ -0,0 +1,252 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Passing the display server of the host into a container, so that it can run desktop programs,
+//! see `ContainerBuilder::add_display()`.
+//!
+//! Programs find the display server through environment variables: a Wayland compositor listens
+//! on the socket `$XDG_RUNTIME_DIR/$WAYLAND_DISPLAY`, and an X server with `DISPLAY=:N` on
+//! `/tmp/.X11-unix/XN`. The sockets of the calling process' display are bind mounted into the
+//! container, and the command gets the variables that point to them there.
+//!
+//! The container gets a runtime directory of its own, a tmpfs at `/run/user/<uid>` that only its
+//! user can use, with the Wayland socket in it. X servers also want a cookie from the user's
+//! Xauthority file. Its entries are tied to the host's hostname, which the container doesn't
+//! have, so the container gets a copy of the cookies of the display that works on any host.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use nix::mount::MsFlags;
+
+use crate::container::Mount;
+use crate::error::CartonError;
+use crate::files::InjectedFile;
+
+/// The directory with the sockets of the X servers.
+const X11_SOCKET_DIR: &str = "/tmp/.X11-unix";
+/// The `FamilyLocal` and `FamilyWild` address families of Xauthority entries.
+const FAMILY_LOCAL: u16 = 256;
+const FAMILY_WILD: u16 = 0xffff;
+
+/// What the container needs to use the host's display server.
+#[derive(Debug, Default)]
+pub(crate) struct Display {
+    pub(crate) mounts: Vec<Mount>,
+    pub(crate) files: Vec<InjectedFile>,
+    pub(crate) environment: Vec<(String, String)>,
+}
+
+/// Finds the Wayland and X11 displays of the calling process' environment, for a container whose
+/// command runs as `user`. Fails when there are neither.
+pub(crate) fn discover(user: (u32, u32)) -> Result<Display, CartonError> {
+    let runtime_dir = PathBuf::from(format!("run/user/{}", user.0));
+    let mut display = Display::default();
+
+    if let Some(socket) = wayland_socket() {
+        let name = socket
+            .file_name()
+            .expect("a socket path has a file name")
+            .to_string_lossy()
+            .into_owned();
+        display
+            .mounts
+            .push(Mount::bind(socket, runtime_dir.join(&name), None, None));
+        display.environment.push(("WAYLAND_DISPLAY".into(), name));
+    }
+
+    if let Some(number) = x11_display() {
+        let socket = Path::new(X11_SOCKET_DIR).join(format!("X{}", number));
+        let target = socket.strip_prefix("/").unwrap_or(&socket).to_path_buf();
+        display.mounts.push(Mount::bind(socket, target, None, None));
+        display
+            .environment
+            .push(("DISPLAY".into(), format!(":{}", number)));
+
+        if let Some(cookies) = xauthority_cookies(&number)? {
+            let path = runtime_dir.join("Xauthority");
+            display.environment.push((
+                "XAUTHORITY".into(),
+                Path::new("/").join(&path).to_string_lossy().into_owned(),
+            ));
+            display.files.push(InjectedFile::new(path, cookies));
+        }
+    }
+
+    if display.environment.is_empty() {
+        return Err(CartonError::InvalidConfiguration(
+            "no display found, neither WAYLAND_DISPLAY nor DISPLAY points to a display server"
+                .into(),
+        ));
+    }
+
+    // Mounted before the sockets in it
+    display.mounts.insert(
+        0,
+        Mount::tmpfs_with_options(
+            runtime_dir.clone(),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            format!("mode=0700,uid={},gid={}", user.0, user.1),
+        ),
+    );
+    display.environment.push((
+        "XDG_RUNTIME_DIR".into(),
+        Path::new("/")
+            .join(&runtime_dir)
+            .to_string_lossy()
+            .into_owned(),
+    ));
+    info!(
+        "passing the display ({}) into the container",
+        display
+            .environment
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<String>>()
+            .join(" ")
+    );
+
+    Ok(display)
+}
+
+/// Returns the socket of the Wayland compositor of `WAYLAND_DISPLAY`, which is either a path or a
+/// name in `XDG_RUNTIME_DIR`.
+fn wayland_socket() -> Option<PathBuf> {
+    let name = env::var_os("WAYLAND_DISPLAY").filter(|name| !name.is_empty())?;
+    let socket = match env::var_os("XDG_RUNTIME_DIR") {
+        _ if Path::new(&name).is_absolute() => PathBuf::from(name),
+        Some(runtime_dir) if !runtime_dir.is_empty() => Path::new(&runtime_dir).join(name),
+        _ => {
+            warn!("WAYLAND_DISPLAY is set, but XDG_RUNTIME_DIR isn't, skipping Wayland");
+            return None;
+        }
+    };
+    if !socket.exists() {
+        warn!(
+            "the Wayland socket {} doesn't exist, skipping Wayland",
+            socket.display()
+        );
+        return None;
+    }
+
+    Some(socket)
+}
+
+/// Returns the number of the local X server of `DISPLAY`, like `0` for `:0.0`.
+fn x11_display() -> Option<String> {
+    let value = env::var("DISPLAY").ok().filter(|value| !value.is_empty())?;
+    let number = match value.rsplit_once(':') {
+        Some(("" | "unix", display)) => display.split('.').next().unwrap_or_default(),
+        _ => {
+            warn!(
+                "only local X servers can be passed into a container, skipping DISPLAY={}",
+                value
+            );
+            return None;
+        }
+    };
+    if number.is_empty() || !number.bytes().all(|byte| byte.is_ascii_digit()) {
+        warn!("invalid DISPLAY={}, skipping X11", value);
+        return None;
+    }
+    let socket = Path::new(X11_SOCKET_DIR).join(format!("X{}", number));
+    if !socket.exists() {
+        warn!(
+            "the X11 socket {} doesn't exist, skipping X11",
+            socket.display()
+        );
+        return None;
+    }
+
+    Some(number.into())
+}
+
+/// Returns the cookies of the local X server with display `number` from the user's Xauthority
+/// file, rewritten to match any host, or `None` when the user has no cookies for it.
+fn xauthority_cookies(number: &str) -> Result<Option<Vec<u8>>, CartonError> {
+    let path = match env::var_os("XAUTHORITY").filter(|path| !path.is_empty()) {
+        Some(path) => PathBuf::from(path),
+        None => match env::var_os("HOME") {
+            Some(home) => Path::new(&home).join(".Xauthority"),
+            None => return Ok(None),
+        },
+    };
+    let contents = match fs::read(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(CartonError::IOError(format!(
+                "reading Xauthority file {}: {}",
+                path.display(),
+                e
+            )))
+        }
+    };
+
+    let mut cookies = Vec::new();
+    let mut rest = &contents[..];
+    while !rest.is_empty() {
+        let Some(entry) = XauthEntry::parse(&mut rest) else {
+            return Err(CartonError::IOError(format!(
+                "invalid Xauthority file {}",
+                path.display()
+            )));
+        };
+        if matches!(entry.family, FAMILY_LOCAL | FAMILY_WILD) && entry.number == number.as_bytes() {
+            entry.write_wild(&mut cookies);
+        }
+    }
+
+    Ok((!cookies.is_empty()).then_some(cookies))
+}
+
+/// An entry of an Xauthority file, which consists of a family and four fields that are each
+/// preceded by their length, all big-endian.
+struct XauthEntry<'a> {
+    family: u16,
+    number: &'a [u8],
+    name: &'a [u8],
+    data: &'a [u8],
+}
+
+impl<'a> XauthEntry<'a> {
+    /// Parses the entry at the start of `input`, and moves `input` past it.
+    fn parse(input: &mut &'a [u8]) -> Option<Self> {
+        let family = read_u16(input)?;
+        let _address = read_field(input)?;
+
+        Some(XauthEntry {
+            family,
+            number: read_field(input)?,
+            name: read_field(input)?,
+            data: read_field(input)?,
+        })
+    }
+
+    /// Writes the entry to `output` for any host.
+    fn write_wild(&self, output: &mut Vec<u8>) {
+        output.extend(FAMILY_WILD.to_be_bytes());
+        for field in [&[][..], self.number, self.name, self.data] {
+            output.extend((field.len() as u16).to_be_bytes());
+            output.extend(field);
+        }
+    }
+}
+
+fn read_u16(input: &mut &[u8]) -> Option<u16> {
+    let (bytes, rest) = (input.get(..2)?, &input[2..]);
+    *input = rest;
+
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_field<'a>(input: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let length = read_u16(input)? as usize;
+    let field = input.get(..length)?;
+    *input = &input[length..];
+
+    Some(field)
+}
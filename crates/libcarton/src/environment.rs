@@ -0,0 +1,98 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! The environment variables of the container's command.
+//!
+//! By default the command inherits the whole environment of the calling process, which can carry
+//! anything from `SSH_AUTH_SOCK` to the tokens of the user's shell. With
+//! `ContainerBuilder::inherit_env()` it only gets the variables whose names match an `EnvFilter`,
+//! like `LANG`, `TZ` and the proxy variables, on top of a default `PATH`. The variables of
+//! `ContainerBuilder::environment()` come last and win over both.
+
+use std::env;
+use std::ffi::OsString;
+
+/// The `PATH` of a command that doesn't inherit the calling process' one.
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// The names of the variables of the calling process that a container inherits, see
+/// `ContainerBuilder::inherit_env()`.
+///
+/// ```no_run
+/// use libcarton::{ContainerBuilder, EnvFilter};
+///
+/// let builder = ContainerBuilder::new()
+///     .inherit_env(EnvFilter::new().allow("LANG").allow("LC_*").allow("*_proxy"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvFilter {
+    patterns: Vec<String>,
+}
+
+impl EnvFilter {
+    pub fn new() -> Self {
+        EnvFilter::default()
+    }
+
+    /// Lets variables through whose name is `pattern`, in which a `*` stands for any number of
+    /// characters, like `LC_*`. Names are case-sensitive, so `http_proxy` and `HTTP_PROXY` are two
+    /// patterns.
+    pub fn allow(mut self, pattern: impl Into<String>) -> Self {
+        self.patterns.push(pattern.into());
+        self
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| matches_pattern(pattern, name))
+    }
+}
+
+/// Returns the environment of the command: the variables of the calling process that `filter`
+/// lets through, with a default `PATH`, followed by `variables`. Returns `None` when the command
+/// keeps the calling process' environment.
+pub(crate) fn command_environment(
+    filter: Option<&EnvFilter>,
+    variables: Option<&[(String, String)]>,
+) -> Option<Vec<(OsString, OsString)>> {
+    let inherited = match (filter, variables) {
+        (None, None) => return None,
+        (None, Some(_)) => Vec::new(),
+        (Some(filter), _) => [(OsString::from("PATH"), OsString::from(DEFAULT_PATH))]
+            .into_iter()
+            .chain(
+                env::vars_os()
+                    .filter(|(name, _)| name.to_str().map_or(false, |name| filter.matches(name))),
+            )
+            .collect(),
+    };
+
+    Some(
+        inherited
+            .into_iter()
+            .chain(
+                variables
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|(name, value)| (name.into(), value.into())),
+            )
+            .collect(),
+    )
+}
+
+/// Matches `name` against `pattern`, in which `*` matches any number of characters.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            // The rest of the pattern can start at any point of the rest of the name
+            (0..=name.len())
+                .filter(|&start| name.is_char_boundary(start))
+                .any(|start| matches_pattern(rest, &name[start..]))
+        }
+    }
+}
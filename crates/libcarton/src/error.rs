@@ -1,9 +1,10 @@
 // Copyright 2023 Arjen Verstoep
 // SPDX-License-Identifier: Apache-2.0
 
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[non_exhaustive]
 pub enum CartonError {
     #[error("missing a required configuration value: `{0}`")]
@@ -12,12 +13,46 @@ pub enum CartonError {
     InvalidConfiguration(String),
     #[error("container already running")]
     AlreadyRunning,
+    #[error("container is not running")]
+    NotRunning,
     #[error("syscall failed: {0}")]
     SysCallFailed(String),
+    /// A system call of the setup was denied. `hint` says what's probably missing, like `missing
+    /// CAP_SYS_ADMIN in this user namespace`.
+    #[error("{operation} failed: {error}, {hint}")]
+    PermissionDenied {
+        operation: String,
+        error: String,
+        hint: String,
+    },
     #[error("namespace error: {0}")]
     NamespaceError(String),
+    #[error("network error: {0}")]
+    NetworkError(String),
     #[error("I/O error: {0}")]
     IOError(String),
+    #[error("container not found: {0}")]
+    ContainerNotFound(String),
+    #[error("`{0}` matches more than one container: {1}")]
+    AmbiguousContainer(String, String),
+    #[error("invalid state file: {0}")]
+    InvalidStateFile(String),
+    #[error("checkpoint error: {0}")]
+    CheckpointError(String),
+    #[error("FUSE error: {0}")]
+    FuseError(String),
+    #[error("D-Bus proxy error: {0}")]
+    DbusProxyError(String),
+    #[error("systemd scope error: {0}")]
+    ScopeError(String),
+    #[error("integrity check failed: {0}")]
+    IntegrityError(String),
+    #[error("volume error: {0}")]
+    VolumeError(String),
+    #[error("copy error: {0}")]
+    CopyError(String),
+    #[error("container is unhealthy: {0}")]
+    Unhealthy(String),
 }
 
 impl From<std::io::Error> for CartonError {
@@ -31,3 +66,9 @@ impl From<nix::Error> for CartonError {
         CartonError::SysCallFailed(format!("{} ({})", error.desc(), error))
     }
 }
+
+impl From<serde_json::Error> for CartonError {
+    fn from(error: serde_json::Error) -> Self {
+        CartonError::InvalidStateFile(format!("{}", error))
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! How a container's process ended, see `ContainerExit`.
+//!
+//! The container's setup partly happens in the container process itself, after it was cloned:
+//! mounting its filesystems, switching to its user, executing the command. When that fails, the
+//! process reports the error through a pipe before it exits. The pipe is closed on exec, so once
+//! the command runs, nothing is written to it and the process' exit status is the command's.
+
+use std::fmt;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::str::FromStr;
+
+use nix::fcntl::OFlag;
+use nix::sys::signal::Signal;
+use nix::unistd;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::CartonError;
+
+/// The exit code of a container that ran out of time, like that of timeout(1).
+const TIMED_OUT_EXIT_CODE: i32 = 124;
+/// The exit code of a container whose setup failed, like that of `docker run`.
+const SETUP_FAILED_EXIT_CODE: i32 = 125;
+
+/// How a container's process ended, see `Container::wait_for_exit()`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(into = "ExitRecord", try_from = "ExitRecord")]
+pub enum ContainerExit {
+    /// The command exited with this exit code.
+    Exited(i32),
+    /// The command was killed by this signal, and dumped core if the flag is set. With an init
+    /// process (see `ContainerBuilder::init()`), the init process is what exits: `carton-init`
+    /// exits with 128 plus the signal when the command was killed by one, which makes that
+    /// `Exited(128 + signal)` instead, without the core dump flag.
+    Signaled(Signal, bool),
+    /// The container process failed before it could execute the command, like when a mount
+    /// failed or the command doesn't exist.
+    SetupFailed(CartonError),
+    /// The container ran out of time and was stopped, see `ContainerBuilder::timeout()`.
+    TimedOut,
+}
+
+impl ContainerExit {
+    /// Returns the exit code that a shell would report: the command's own, 128 plus the signal
+    /// that killed it, 124 when it ran out of time (like timeout(1)) and 125 when the setup
+    /// failed (like `docker run`).
+    pub fn code(&self) -> i32 {
+        match self {
+            ContainerExit::Exited(code) => *code,
+            ContainerExit::Signaled(signal, _) => 128 + *signal as i32,
+            ContainerExit::SetupFailed(_) => SETUP_FAILED_EXIT_CODE,
+            ContainerExit::TimedOut => TIMED_OUT_EXIT_CODE,
+        }
+    }
+
+    /// Returns whether the command exited with 0.
+    pub fn success(&self) -> bool {
+        *self == ContainerExit::Exited(0)
+    }
+}
+
+impl fmt::Display for ContainerExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContainerExit::Exited(code) => write!(f, "exited with {}", code),
+            ContainerExit::Signaled(signal, false) => write!(f, "was killed by {}", signal),
+            ContainerExit::Signaled(signal, true) => {
+                write!(f, "was killed by {} (core dumped)", signal)
+            }
+            ContainerExit::SetupFailed(error) => write!(f, "failed to set up: {}", error),
+            ContainerExit::TimedOut => write!(f, "ran out of time"),
+        }
+    }
+}
+
+/// How a `ContainerExit` is stored, with the signal by name.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ExitRecord {
+    Exited { code: i32 },
+    Signaled { signal: String, core_dumped: bool },
+    SetupFailed { error: CartonError },
+    TimedOut,
+}
+
+impl From<ContainerExit> for ExitRecord {
+    fn from(exit: ContainerExit) -> Self {
+        match exit {
+            ContainerExit::Exited(code) => ExitRecord::Exited { code },
+            ContainerExit::Signaled(signal, core_dumped) => ExitRecord::Signaled {
+                signal: signal.as_str().into(),
+                core_dumped,
+            },
+            ContainerExit::SetupFailed(error) => ExitRecord::SetupFailed { error },
+            ContainerExit::TimedOut => ExitRecord::TimedOut,
+        }
+    }
+}
+
+impl TryFrom<ExitRecord> for ContainerExit {
+    type Error = String;
+
+    fn try_from(record: ExitRecord) -> Result<Self, Self::Error> {
+        Ok(match record {
+            ExitRecord::Exited { code } => ContainerExit::Exited(code),
+            ExitRecord::Signaled {
+                signal,
+                core_dumped,
+            } => ContainerExit::Signaled(
+                Signal::from_str(&signal).map_err(|_| format!("unknown signal {}", signal))?,
+                core_dumped,
+            ),
+            ExitRecord::SetupFailed { error } => ContainerExit::SetupFailed(error),
+            ExitRecord::TimedOut => ContainerExit::TimedOut,
+        })
+    }
+}
+
+/// Returns the pipe through which the container process reports the error of its setup, see the
+/// module documentation. Neither end blocks: the error is written all at once into an empty pipe,
+/// and read after the process is gone, while containers that were prepared later may still have
+/// a copy of the writing end.
+pub(crate) fn pipe() -> Result<(OwnedFd, OwnedFd), CartonError> {
+    let (read, write) = unistd::pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)?;
+
+    Ok(unsafe { (OwnedFd::from_raw_fd(read), OwnedFd::from_raw_fd(write)) })
+}
+
+/// Reports `error` from the container process, through the writing end of `pipe()`.
+pub(crate) fn report_setup_failure(pipe: RawFd, error: &CartonError) {
+    if let Ok(message) = serde_json::to_vec(error) {
+        let _ = unistd::write(pipe, &message);
+    }
+}
+
+/// Returns the error that the container process reported through the reading end of `pipe()`, if
+/// any.
+pub(crate) fn read_setup_failure(pipe: &OwnedFd) -> Option<CartonError> {
+    let mut message = Vec::new();
+    let mut buffer = [0; 4096];
+    // Ends with EAGAIN once the pipe is empty
+    loop {
+        match unistd::read(pipe.as_raw_fd(), &mut buffer) {
+            Ok(read) if read > 0 => message.extend_from_slice(&buffer[..read]),
+            _ => break,
+        }
+    }
+
+    serde_json::from_slice(&message).ok()
+}
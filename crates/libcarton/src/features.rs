@@ -0,0 +1,339 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! What the host supports that containers can use, for `carton info` and for the setup of
+//! containers.
+//!
+//! Everything is found out from /proc and /sys, or by making a system call with arguments that it
+//! rejects, which tells whether the kernel knows the call at all. Nothing on the host changes, and
+//! callers other than root get the same answers.
+//!
+//! Before a container is set up, `adapt()` compares its configuration with what the host
+//! supports. What the container can do without is left out, with a warning: a cgroup of its own
+//! on a host without cgroup v2, or where the caller can't create one. What it can't do without
+//! fails the setup right away, with an error that says what's missing, instead of the `EPERM` or
+//! `EINVAL` of some system call halfway through the setup.
+//!
+//! A container that's started by a user other than root without a user namespace gets one, with
+//! the mappings of `IdMapping::current_user()`, because that's the only way such a user can create
+//! the other namespaces. Only what needs root on the host, like a bridge network, fails then. The
+//! setup of the mount namespace itself
+//! falls back to `chroot()` where `pivot_root()` isn't possible, see `namespace::mount_rootfs()`.
+
+use std::fs;
+use std::path::Path;
+
+use nix::errno::Errno;
+use nix::sys::statfs::{self, TMPFS_MAGIC};
+use nix::unistd::{self, AccessFlags};
+
+use serde::Serialize;
+
+use crate::cgroup;
+use crate::container::ContainerConfiguration;
+use crate::diagnostics::Reporter;
+use crate::error::CartonError;
+use crate::idmap::{IdMapping, IdMappings};
+use crate::network::Network;
+use crate::privsep;
+
+/// The cgroup hierarchies that are mounted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CgroupVersion {
+    /// Only cgroup v1, which carton can't delegate to containers.
+    V1,
+    /// Only cgroup v2 ("unified"), at /sys/fs/cgroup.
+    V2,
+    /// cgroup v1 for the controllers, and a cgroup v2 hierarchy without them at
+    /// /sys/fs/cgroup/unified.
+    Hybrid,
+    None,
+}
+
+/// The mode of SELinux, when it's enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SelinuxMode {
+    Enforcing,
+    Permissive,
+}
+
+/// The features of the host that matter to containers.
+#[derive(Debug, Clone, Serialize)]
+pub struct HostFeatures {
+    /// Release of the running kernel, like `6.1.0-18-amd64`.
+    pub kernel: String,
+    pub cgroup_version: CgroupVersion,
+    /// The controllers of the cgroup v2 hierarchy, which carton hands to containers with a cgroup
+    /// of their own (see `ContainerBuilder::delegate_cgroup()`).
+    pub cgroup_controllers: Vec<String>,
+    /// Whether user namespaces can be created at all.
+    pub user_namespaces: bool,
+    /// Whether users other than root can create user namespaces. Some distributions restrict that.
+    pub unprivileged_user_namespaces: bool,
+    /// Whether processes can install seccomp filters.
+    pub seccomp: bool,
+    /// Whether a seccomp filter can hand system calls to a supervisor (Linux 5.0 and up), which
+    /// `ContainerBuilder::seccomp_notify()` needs.
+    pub seccomp_user_notification: bool,
+    pub apparmor: bool,
+    pub selinux: Option<SelinuxMode>,
+    /// Whether the kernel has overlayfs, which ephemeral containers write to (see
+    /// `ContainerBuilder::ephemeral()`), either built in or as a module.
+    pub overlayfs: bool,
+    /// Whether the kernel has ID-mapped mounts (Linux 5.12 and up), which
+    /// `ContainerBuilder::idmap_rootfs()` uses. Not every filesystem supports them.
+    pub idmapped_mounts: bool,
+}
+
+impl HostFeatures {
+    pub fn detect() -> Self {
+        let kernel = read("/proc/sys/kernel/osrelease").unwrap_or_default();
+        let (cgroup_version, cgroup_controllers) = cgroups();
+        let seccomp_actions = read("/proc/sys/kernel/seccomp/actions_avail").unwrap_or_default();
+
+        HostFeatures {
+            cgroup_version,
+            cgroup_controllers,
+            user_namespaces: read("/proc/sys/user/max_user_namespaces")
+                .and_then(|max| max.parse::<u64>().ok())
+                .map_or(false, |max| max > 0),
+            unprivileged_user_namespaces: unprivileged_user_namespaces(),
+            seccomp: read("/proc/self/status")
+                .map_or(false, |status| status.contains("\nSeccomp:")),
+            seccomp_user_notification: seccomp_actions
+                .split_whitespace()
+                .any(|a| a == "user_notif"),
+            apparmor: read("/sys/module/apparmor/parameters/enabled")
+                .map_or(false, |enabled| enabled == "Y"),
+            selinux: read("/sys/fs/selinux/enforce").map(|enforce| {
+                if enforce == "1" {
+                    SelinuxMode::Enforcing
+                } else {
+                    SelinuxMode::Permissive
+                }
+            }),
+            overlayfs: has_filesystem("overlay", &kernel),
+            idmapped_mounts: has_mount_setattr(),
+            kernel,
+        }
+    }
+}
+
+/// Leaves out what the host doesn't support and the container can do without, reporting a
+/// warning for each, and fails when the container needs something that the host doesn't support.
+pub(crate) fn adapt(
+    config: &mut ContainerConfiguration,
+    features: &HostFeatures,
+    reporter: &Reporter,
+) -> Result<(), CartonError> {
+    let unsupported = |what: &str| Err(CartonError::InvalidConfiguration(what.into()));
+
+    if !unistd::geteuid().is_root() {
+        if let Some(option) = root_only(config) {
+            return Err(CartonError::InvalidConfiguration(
+                match privsep::unprivileged_user() {
+                    Some(user) => format!(
+                        "carton dropped its privileges to {}, and only root can set up a \
+                         container with {}",
+                        user, option
+                    ),
+                    None => format!("only root can set up a container with {}", option),
+                },
+            ));
+        }
+        if config.user_namespace.is_none() {
+            run_rootless(config, features, reporter)?;
+        }
+    }
+
+    if config.delegate_cgroup {
+        if let Some(reason) = no_cgroup_reason(features) {
+            reporter.warning(format_args!(
+                "the container runs without a cgroup of its own, because {}, so it can't be \
+                 limited or paused",
+                reason
+            ));
+            config.delegate_cgroup = false;
+        }
+    }
+
+    if config.user_namespace.is_some() {
+        if !features.user_namespaces {
+            return unsupported(
+                "the kernel has user namespaces disabled (user.max_user_namespaces is 0)",
+            );
+        }
+        if !features.unprivileged_user_namespaces && !unistd::geteuid().is_root() {
+            return unsupported(
+                "the host only allows root to create user namespaces (see \
+                 kernel.unprivileged_userns_clone and \
+                 kernel.apparmor_restrict_unprivileged_userns)",
+            );
+        }
+    }
+
+    if config.seccomp_notify.is_some() && !features.seccomp_user_notification {
+        return unsupported(
+            "the kernel can't hand system calls to a supervisor (seccomp user notification needs \
+             Linux 5.0 or later)",
+        );
+    }
+
+    if config.seccomp_profile.is_some() && !features.seccomp {
+        return unsupported("the kernel can't filter system calls (seccomp is disabled)");
+    }
+
+    if config.rootfs_id_mappings.is_some() && !features.idmapped_mounts {
+        return unsupported("the kernel has no ID-mapped mounts (they need Linux 5.12 or later)");
+    }
+
+    if config.ephemeral && !features.overlayfs {
+        return unsupported(
+            "an ephemeral container needs overlayfs, which the kernel doesn't have",
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns what the container needs that only root can set up, if anything. The privileged helper
+/// of `privsep` doesn't do any of it either.
+fn root_only(config: &ContainerConfiguration) -> Option<&'static str> {
+    let root_only = [
+        (
+            "a bridge, macvlan or CNI network",
+            matches!(
+                config.network,
+                Network::Bridge(_) | Network::Macvlan(_) | Network::Cni(_)
+            ),
+        ),
+        (
+            "an ID-mapped root filesystem",
+            config.rootfs_id_mappings.is_some(),
+        ),
+        ("a storage limit", config.storage_limit.is_some()),
+    ];
+    root_only
+        .iter()
+        .find(|(_, needed)| *needed)
+        .map(|(option, _)| *option)
+}
+
+/// Gives the container of a user other than root a user namespace in which root is that user.
+fn run_rootless(
+    config: &mut ContainerConfiguration,
+    features: &HostFeatures,
+    reporter: &Reporter,
+) -> Result<(), CartonError> {
+    if !features.user_namespaces || !features.unprivileged_user_namespaces {
+        return Err(CartonError::InvalidConfiguration(
+            "only root can run containers on this host, because it doesn't let other users create \
+             user namespaces"
+                .into(),
+        ));
+    }
+
+    let (uids, gids) = IdMapping::current_user()?;
+    reporter.info(format_args!(
+        "not running as root, so the container runs in a user namespace in which root is user {}",
+        unistd::geteuid()
+    ));
+    config.user_namespace = Some(IdMappings { uids, gids });
+
+    Ok(())
+}
+
+/// Returns why the container can't have a cgroup of its own, if it can't.
+fn no_cgroup_reason(features: &HostFeatures) -> Option<String> {
+    if !matches!(
+        features.cgroup_version,
+        CgroupVersion::V2 | CgroupVersion::Hybrid
+    ) {
+        return Some("the host has no cgroup v2 hierarchy".into());
+    }
+
+    // The cgroup of the container goes under the one for all containers, which may not be there yet
+    let root = cgroup::cgroup2_root().ok()?;
+    let parent = cgroup::containers_cgroup(&root);
+    let writable = if parent.exists() { &parent } else { &root };
+    match unistd::access(writable, AccessFlags::W_OK) {
+        Ok(()) => None,
+        Err(_) => Some(format!(
+            "this user can't create cgroups in {}",
+            writable.display()
+        )),
+    }
+}
+
+/// Returns the contents of the file at `path` without the trailing newline, if it can be read.
+fn read(path: &str) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|contents| contents.trim_end().into())
+}
+
+fn cgroups() -> (CgroupVersion, Vec<String>) {
+    let Ok(root) = cgroup::cgroup2_root() else {
+        let version = if statfs::statfs("/sys/fs/cgroup")
+            .map_or(false, |fs| fs.filesystem_type() == TMPFS_MAGIC)
+        {
+            CgroupVersion::V1
+        } else {
+            CgroupVersion::None
+        };
+        return (version, Vec::new());
+    };
+
+    let version = if root == Path::new("/sys/fs/cgroup") {
+        CgroupVersion::V2
+    } else {
+        CgroupVersion::Hybrid
+    };
+    let controllers = read(&format!("{}/cgroup.controllers", root.display()))
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(String::from)
+        .collect();
+
+    (version, controllers)
+}
+
+/// Debian and older Ubuntu releases have a switch for user namespaces of unprivileged users,
+/// and Ubuntu 23.10 and up has AppArmor restrict them.
+fn unprivileged_user_namespaces() -> bool {
+    let disabled = read("/proc/sys/kernel/unprivileged_userns_clone").map_or(false, |v| v == "0");
+    let restricted =
+        read("/proc/sys/kernel/apparmor_restrict_unprivileged_userns").map_or(false, |v| v == "1");
+
+    !disabled && !restricted
+}
+
+/// Returns whether the kernel has the filesystem `name`, or can load it as a module.
+fn has_filesystem(name: &str, kernel: &str) -> bool {
+    let registered = read("/proc/filesystems").map_or(false, |filesystems| {
+        filesystems
+            .lines()
+            .any(|line| line.split_whitespace().last() == Some(name))
+    });
+
+    registered || Path::new(&format!("/lib/modules/{}/kernel/fs/{}fs", kernel, name)).exists()
+}
+
+/// Returns whether the kernel knows `mount_setattr()`, the system call that ID-maps mounts. It's
+/// called without a file descriptor, which fails either way.
+fn has_mount_setattr() -> bool {
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            -1,
+            b"\0".as_ptr(),
+            0,
+            std::ptr::null::<u8>(),
+            0,
+        )
+    };
+
+    Errno::result(result) != Err(Errno::ENOSYS)
+}
@@ -0,0 +1,95 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Small files whose contents are given with the container, like a config file, see
+//! `ContainerBuilder::add_file()`.
+//!
+//! Like /etc/hosts (see `NetworkFiles`), each file is written to the container's own directory on
+//! the host and bind mounted at its path in the container, so that the root filesystem is left
+//! alone and an image can be configured without building another one. The container's changes to
+//! a file end up in its copy in the container's directory. Files aren't meant for secrets, which
+//! would end up on disk that way; those are for `ContainerBuilder::add_secret()`.
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::container::Mount;
+use crate::error::CartonError;
+use crate::rollback::{Rollback, Step};
+
+/// The directory in the container's directory with the files.
+const FILES_DIR: &str = "files";
+
+/// A file that the container gets at `relative_target`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct InjectedFile {
+    pub(crate) relative_target: PathBuf,
+    pub(crate) contents: Vec<u8>,
+}
+
+impl InjectedFile {
+    pub(crate) fn new(relative_target: PathBuf, contents: Vec<u8>) -> Self {
+        InjectedFile {
+            relative_target,
+            contents,
+        }
+    }
+
+    /// Checks that the file ends up inside the root filesystem.
+    pub(crate) fn validate(&self) -> Result<(), CartonError> {
+        let inside = self
+            .relative_target
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if !inside || self.relative_target.file_name().is_none() {
+            return Err(CartonError::InvalidConfiguration(format!(
+                "the file at {} has to be a path of a file in the container, without `..`",
+                self.relative_target.display()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns where the file with this index in the container's files is kept on the host. Files are
+/// numbered, because their paths in the container can be nested as deep as they like.
+fn source(container_dir: &Path, index: usize) -> PathBuf {
+    container_dir.join(FILES_DIR).join(index.to_string())
+}
+
+/// Writes `files` to `container_dir`, before the container process bind mounts them.
+pub(crate) fn write(
+    files: &[InjectedFile],
+    container_dir: &Path,
+    rollback: &Rollback,
+) -> Result<(), CartonError> {
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    let dir = container_dir.join(FILES_DIR);
+    fs::create_dir_all(&dir)?;
+    rollback.record(Step::Copy(dir));
+    for (index, file) in files.iter().enumerate() {
+        fs::write(source(container_dir, index), &file.contents)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the bind mounts that put the written files in place inside the container.
+pub(crate) fn mounts(files: &[InjectedFile], container_dir: &Path) -> Vec<Mount> {
+    files
+        .iter()
+        .enumerate()
+        .map(|(index, file)| {
+            Mount::bind(
+                source(container_dir, index),
+                file.relative_target.clone(),
+                None,
+                None,
+            )
+        })
+        .collect()
+}
@@ -0,0 +1,252 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Root filesystems that are provided by a FUSE daemon, like an image that is unpacked on the fly
+//! (squashfuse) or pulled on demand.
+//!
+//! Carton starts the daemon before the container, with the mountpoint as its last argument, and
+//! waits until the daemon has mounted its filesystem there. That mountpoint then serves as the
+//! container's root filesystem. Once the container is gone, carton unmounts the filesystem, after
+//! which FUSE daemons exit by themselves, and stops the daemon if it doesn't.
+//!
+//! Only the user that mounted a FUSE filesystem can access it, unless it was mounted with the
+//! `allow_other` option. Carton runs the daemon as the same user as the container, so that's only
+//! needed when carton itself runs in a user namespace.
+
+use std::fs;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use nix::mount::{self, MntFlags};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+
+use crate::error::CartonError;
+
+const FUSE_DEVICE: &str = "/dev/fuse";
+const DEFAULT_MOUNT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a daemon gets to exit by itself after its filesystem was unmounted.
+const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A FUSE daemon that provides a container's root filesystem, see
+/// `ContainerBuilder::fuse_rootfs()`.
+#[derive(Debug, Clone)]
+pub struct FuseRootfs {
+    command: PathBuf,
+    arguments: Vec<String>,
+    mountpoint: PathBuf,
+    allow_other: bool,
+    mount_timeout: Duration,
+}
+
+impl FuseRootfs {
+    /// The daemon is started as `command arguments... mountpoint`. It should stay in the
+    /// foreground (most daemons have a `-f` option for that), so that carton can stop it if it
+    /// doesn't exit after the filesystem was unmounted.
+    pub fn new(command: PathBuf, arguments: Vec<String>, mountpoint: PathBuf) -> Self {
+        FuseRootfs {
+            command,
+            arguments,
+            mountpoint,
+            allow_other: false,
+            mount_timeout: DEFAULT_MOUNT_TIMEOUT,
+        }
+    }
+
+    /// Passes `-o allow_other` to the daemon, so that other users can access the filesystem.
+    pub fn allow_other(mut self) -> Self {
+        self.allow_other = true;
+        self
+    }
+
+    /// How long to wait for the daemon to mount the filesystem. Defaults to 10 seconds.
+    pub fn mount_timeout(mut self, timeout: Duration) -> Self {
+        self.mount_timeout = timeout;
+        self
+    }
+
+    pub(crate) fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), CartonError> {
+        if !Path::new(FUSE_DEVICE).exists() {
+            return Err(CartonError::InvalidConfiguration(format!(
+                "a FUSE root filesystem needs {}, is the fuse module loaded?",
+                FUSE_DEVICE
+            )));
+        }
+        if !self.mountpoint.is_dir() {
+            return Err(CartonError::InvalidConfiguration(format!(
+                "FUSE mountpoint does not exist or is not a directory: {}",
+                self.mountpoint.display()
+            )));
+        }
+        if is_mounted(&fs::canonicalize(&self.mountpoint)?)? {
+            return Err(CartonError::InvalidConfiguration(format!(
+                "something is mounted at {} already",
+                self.mountpoint.display()
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Starts the daemon and waits until it has mounted the filesystem.
+    pub(crate) fn start(&self) -> Result<FuseDaemon, CartonError> {
+        // Resolving the path once it's mounted would have to ask the daemon
+        let mountpoint = fs::canonicalize(&self.mountpoint)?;
+
+        let mut command = Command::new(&self.command);
+        if self.allow_other {
+            command.args(["-o", "allow_other"]);
+        }
+        command
+            .args(&self.arguments)
+            .arg(&self.mountpoint)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null());
+
+        // Keep the daemon out of the terminal's process group, so that a Ctrl-C meant for the
+        // container doesn't take its root filesystem away first
+        command.process_group(0);
+
+        info!("starting FUSE daemon {}", self.command.display());
+        let child = command.spawn().map_err(|e| {
+            CartonError::FuseError(format!("could not start {}: {}", self.command.display(), e))
+        })?;
+        let mut daemon = FuseDaemon {
+            child: Some(child),
+            mountpoint: mountpoint.clone(),
+            mounted: false,
+        };
+
+        let deadline = Instant::now() + self.mount_timeout;
+        loop {
+            if is_mounted(&mountpoint)? {
+                daemon.mounted = true;
+                return Ok(daemon);
+            }
+
+            // Daemons that don't stay in the foreground exit successfully once they've forked
+            if let Some(child) = &mut daemon.child {
+                if let Some(status) = child.try_wait()? {
+                    daemon.child = None;
+                    if !status.success() {
+                        return Err(CartonError::FuseError(format!(
+                            "{} exited with {} before mounting {}",
+                            self.command.display(),
+                            status,
+                            self.mountpoint.display()
+                        )));
+                    }
+                }
+            }
+
+            if Instant::now() >= deadline {
+                return Err(CartonError::FuseError(format!(
+                    "{} did not mount {} within {:?}",
+                    self.command.display(),
+                    self.mountpoint.display(),
+                    self.mount_timeout
+                )));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// A running FUSE daemon. Dropping it unmounts the filesystem and stops the daemon.
+#[derive(Debug)]
+pub(crate) struct FuseDaemon {
+    /// The daemon's process, unless it forked into the background.
+    child: Option<Child>,
+    mountpoint: PathBuf,
+    mounted: bool,
+}
+
+impl Drop for FuseDaemon {
+    fn drop(&mut self) {
+        if self.mounted {
+            info!(
+                "unmounting FUSE filesystem at {}",
+                self.mountpoint.display()
+            );
+            if let Err(e) = mount::umount2(&self.mountpoint, MntFlags::MNT_DETACH) {
+                warn!("Could not unmount {}: {}", self.mountpoint.display(), e);
+            }
+        }
+
+        let Some(child) = &mut self.child else {
+            return;
+        };
+        let deadline = Instant::now() + EXIT_TIMEOUT;
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    if !status.success() {
+                        warn!("FUSE daemon exited with {}", status);
+                    }
+                    return;
+                }
+                Ok(None) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Could not wait for the FUSE daemon: {}", e);
+                    return;
+                }
+            }
+        }
+
+        warn!("FUSE daemon did not exit after unmounting, terminating it");
+        let _ = signal::kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM);
+        let deadline = Instant::now() + EXIT_TIMEOUT;
+        while Instant::now() < deadline {
+            if let Ok(Some(_)) = child.try_wait() {
+                return;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// Returns whether `path`, which should be canonical, is a mount point according to
+/// /proc/self/mountinfo.
+fn is_mounted(path: &Path) -> Result<bool, CartonError> {
+    let path = path.to_string_lossy();
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+
+    // The mount point is the fifth field, with spaces and the like escaped as octal numbers
+    Ok(mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .any(|mount_point| unescape(mount_point) == path))
+}
+
+fn unescape(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let octal = chars.by_ref().take(3).collect::<String>();
+            if let Ok(byte) = u8::from_str_radix(&octal, 8) {
+                unescaped.push(byte as char);
+                continue;
+            }
+            unescaped.push(c);
+            unescaped.push_str(&octal);
+        } else {
+            unescaped.push(c);
+        }
+    }
+
+    unescaped
+}
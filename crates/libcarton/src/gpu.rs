@@ -0,0 +1,189 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Passing GPUs of the host into a container.
+//!
+//! A program uses a GPU through device nodes in /dev and through the user space part of the
+//! driver, a set of libraries that has to match the kernel driver of the host exactly. For NVIDIA
+//! GPUs these are `/dev/nvidia0` and up (one per GPU), a few control devices like
+//! `/dev/nvidiactl` and libraries like `libcuda.so`. Other GPUs (Intel, AMD) are used through
+//! `/dev/dri` with the Mesa libraries that the container's root filesystem brings itself.
+//!
+//! Containers don't have a device cgroup (yet), so the device nodes only have to be created for
+//! the container to be able to use them.
+
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use nix::sys::stat;
+
+use crate::container::{DeviceNode, Mount};
+use crate::error::CartonError;
+
+const DEV_DIR: &str = "/dev";
+/// Devices that every NVIDIA GPU needs, next to the `/dev/nvidiaN` of the GPU itself.
+const NVIDIA_CONTROL_DEVICES: [&str; 5] = [
+    "nvidiactl",
+    "nvidia-uvm",
+    "nvidia-uvm-tools",
+    "nvidia-modeset",
+    "nvidia-caps/nvidia-cap1",
+];
+/// Where distributions put the NVIDIA driver libraries.
+const LIBRARY_DIRS: [&str; 4] = [
+    "/usr/lib/x86_64-linux-gnu",
+    "/usr/lib/aarch64-linux-gnu",
+    "/usr/lib64",
+    "/usr/lib",
+];
+/// Name prefixes of the libraries of the NVIDIA driver that CUDA, NVML, OpenGL and Vulkan
+/// programs use.
+const NVIDIA_LIBRARY_PREFIXES: [&str; 8] = [
+    "libcuda.so",
+    "libnvidia-",
+    "libnvcuvid.so",
+    "libnvoptix.so",
+    "libGLX_nvidia.so",
+    "libEGL_nvidia.so",
+    "libGLESv1_CM_nvidia.so",
+    "libGLESv2_nvidia.so",
+];
+const NVIDIA_BINARIES: [&str; 3] = [
+    "/usr/bin/nvidia-smi",
+    "/usr/bin/nvidia-debugdump",
+    "/usr/bin/nvidia-persistenced",
+];
+
+/// Which GPUs to pass into a container.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Gpus {
+    /// All NVIDIA GPUs and everything in `/dev/dri`.
+    All,
+    /// The NVIDIA GPUs with these indices, as `nvidia-smi` numbers them.
+    Nvidia(Vec<u32>),
+}
+
+/// Finds the device nodes and driver files for `gpus`. Returns the device nodes to create and the
+/// files to bind mount at the same path inside the container.
+pub(crate) fn discover(gpus: &Gpus) -> Result<(Vec<DeviceNode>, Vec<Mount>), CartonError> {
+    let dev_dir = Path::new(DEV_DIR);
+    let mut devices = Vec::new();
+
+    let nvidia_gpus = match gpus {
+        Gpus::All => numbered_nvidia_devices(dev_dir)?,
+        Gpus::Nvidia(indices) => indices
+            .iter()
+            .map(|index| format!("nvidia{}", index))
+            .collect(),
+    };
+    for name in &nvidia_gpus {
+        devices.push(device_node(dev_dir, Path::new(name))?.ok_or_else(|| {
+            CartonError::InvalidConfiguration(format!("there is no GPU /dev/{}", name))
+        })?);
+    }
+    if !nvidia_gpus.is_empty() {
+        for name in NVIDIA_CONTROL_DEVICES {
+            devices.extend(device_node(dev_dir, Path::new(name))?);
+        }
+    }
+
+    if *gpus == Gpus::All {
+        if let Ok(entries) = fs::read_dir(dev_dir.join("dri")) {
+            for entry in entries.flatten() {
+                let path = Path::new("dri").join(entry.file_name());
+                devices.extend(device_node(dev_dir, &path)?);
+            }
+        }
+    }
+
+    if devices.is_empty() {
+        return Err(CartonError::InvalidConfiguration(
+            "no GPUs found in /dev".into(),
+        ));
+    }
+
+    let mounts = if nvidia_gpus.is_empty() {
+        Vec::new()
+    } else {
+        nvidia_driver_files()
+            .into_iter()
+            .map(|path| {
+                let target = path.strip_prefix("/").unwrap_or(&path).to_path_buf();
+                Mount::bind(path, target, None, None)
+            })
+            .collect()
+    };
+
+    info!(
+        "passing {} GPU device(s) and {} driver file(s) into the container",
+        devices.len(),
+        mounts.len()
+    );
+
+    Ok((devices, mounts))
+}
+
+/// Returns the names of `/dev/nvidia0`, `/dev/nvidia1`, etc.
+fn numbered_nvidia_devices(dev_dir: &Path) -> Result<Vec<String>, CartonError> {
+    let mut names = fs::read_dir(dev_dir)?
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| {
+            name.strip_prefix("nvidia")
+                .map_or(false, |index| index.parse::<u32>().is_ok())
+        })
+        .collect::<Vec<String>>();
+    names.sort();
+
+    Ok(names)
+}
+
+/// Returns the device node at `path` (relative to `dev_dir`), if it exists.
+fn device_node(dev_dir: &Path, path: &Path) -> Result<Option<DeviceNode>, CartonError> {
+    let metadata = match fs::metadata(dev_dir.join(path)) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if !metadata.file_type().is_char_device() {
+        return Ok(None);
+    }
+
+    Ok(Some(DeviceNode {
+        path: path.into(),
+        major: stat::major(metadata.rdev()),
+        minor: stat::minor(metadata.rdev()),
+    }))
+}
+
+/// Finds the libraries and tools of the host's NVIDIA driver.
+fn nvidia_driver_files() -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for dir in LIBRARY_DIRS {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if NVIDIA_LIBRARY_PREFIXES
+                .iter()
+                .any(|prefix| name.starts_with(prefix))
+            {
+                files.push(entry.path());
+            }
+        }
+    }
+    files.extend(
+        NVIDIA_BINARIES
+            .iter()
+            .map(PathBuf::from)
+            .filter(|path| path.exists()),
+    );
+    files.sort();
+
+    files
+}
@@ -0,0 +1,304 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Health checks: a command that runs inside a running container and exits with 0 when the
+//! container is ready to do its job, like `pg_isready` for a database.
+//!
+//! The command runs in a process of its own that moves into the container's cgroup, and enters
+//! the container's user, mount, network, UTS, IPC, cgroup and PID namespaces and its root
+//! directory, like `nsenter` would. Entering a PID namespace only applies to the children of a
+//! process, so that process forks once more for the command, and waits for it. The command runs
+//! as the container's user (see `ContainerBuilder::user()`) with the container's seccomp profile,
+//! so it can do what the container's own processes can, and nothing more. A filter of
+//! `ContainerBuilder::seccomp_notify()` isn't installed, because its handler runs in the carton
+//! process that started the container. It gets a default `PATH` and nothing else of the
+//! environment.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use nix::errno::Errno;
+use nix::libc;
+use nix::sched::{self, CloneFlags};
+use nix::sys::prctl;
+use nix::sys::signal::Signal;
+use nix::sys::wait::{self, WaitStatus};
+use nix::unistd::{self, ForkResult, Gid, Uid};
+
+use crate::cgroup;
+use crate::container::{self, Container, ContainerState};
+use crate::error::CartonError;
+use crate::seccomp;
+use crate::state::ContainerInfo;
+
+/// The namespaces that the command enters, the user namespace first, so that it's privileged in
+/// the others.
+pub(crate) const NAMESPACES: [&str; 6] = ["user", "mnt", "net", "uts", "ipc", "cgroup"];
+/// The `PATH` in which the command is looked up when it's just a name.
+pub(crate) const DEFAULT_PATH: &str =
+    "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+/// How often `check()` sees whether the command exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A command that tells whether a container is healthy, by exiting with 0.
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use libcarton::{CartonError, ContainerBuilder, HealthCheck};
+/// # fn main() -> Result<(), CartonError> {
+/// let mut container = ContainerBuilder::new()
+///     .rootfs("/path/to/rootfs".into())
+///     .command("/usr/bin/postgres".into(), None)
+///     .build()?;
+/// container.run()?;
+///
+/// HealthCheck::new(vec!["pg_isready".into()])
+///     .interval(Duration::from_millis(500))
+///     .wait_until_healthy(&mut container)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    command: Vec<String>,
+    interval: Duration,
+    timeout: Duration,
+    retries: u32,
+}
+
+impl HealthCheck {
+    /// `command` is the program and its arguments. A program that's just a name is looked up in
+    /// the `PATH` directories of the container's root filesystem.
+    pub fn new(command: Vec<String>) -> Self {
+        HealthCheck {
+            command,
+            interval: Duration::from_secs(2),
+            timeout: Duration::from_secs(10),
+            retries: 15,
+        }
+    }
+
+    /// How long to wait after a failed check before checking again (2 seconds by default).
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How long the command may take; it's killed and counts as failed after that (10 seconds by
+    /// default).
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many failed checks `wait_until_healthy()` takes before it gives up (15 by default).
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Runs the command once in the running container of `info`, and returns whether it exited
+    /// with 0 within the timeout.
+    pub fn check(&self, info: &ContainerInfo) -> Result<bool, CartonError> {
+        let (ContainerState::Running, Some(pid)) = (info.state, info.pid) else {
+            return Err(CartonError::NotRunning);
+        };
+        let Some((program, arguments)) = self.command.split_first() else {
+            return Err(CartonError::InvalidConfiguration(
+                "the command of a health check can't be empty".into(),
+            ));
+        };
+
+        let mut command = Command::new(program);
+        command
+            .args(arguments)
+            .env_clear()
+            .env("PATH", DEFAULT_PATH)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        enter_container(&mut command, pid, info)?;
+
+        let mut child = command.spawn().map_err(|e| {
+            CartonError::IOError(format!("running the health check {:?}: {}", program, e))
+        })?;
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                if !status.success() {
+                    info!("Health check {:?} failed: {}", self.command, status);
+                }
+                return Ok(status.success());
+            }
+            if Instant::now() >= deadline {
+                info!(
+                    "Health check {:?} did not finish within {:?}, killing it",
+                    self.command, self.timeout
+                );
+                let _ = child.kill();
+                let _ = child.wait();
+                return Ok(false);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Checks `container` every interval until it's healthy. Fails when it isn't after the number
+    /// of retries, or when it exits in the meantime.
+    pub fn wait_until_healthy(&self, container: &mut Container) -> Result<(), CartonError> {
+        for attempt in 0..=self.retries {
+            if let Some(exit) = container.try_wait()? {
+                return Err(CartonError::Unhealthy(format!(
+                    "it {} before it became healthy",
+                    exit
+                )));
+            }
+            if self.check(&container.info())? {
+                return Ok(());
+            }
+            if attempt < self.retries {
+                thread::sleep(self.interval);
+            }
+        }
+
+        Err(CartonError::Unhealthy(format!(
+            "{:?} failed {} times",
+            self.command,
+            self.retries + 1
+        )))
+    }
+}
+
+/// Makes `command` run in the cgroup, the namespaces and the root directory of process `pid`, the
+/// process of the container of `info`, as the container's user and with its seccomp profile.
+fn enter_container(
+    command: &mut Command,
+    pid: i32,
+    info: &ContainerInfo,
+) -> Result<(), CartonError> {
+    let mut names = NAMESPACES.to_vec();
+    names.push("pid");
+    let namespaces = open_namespaces(pid, &names)?;
+    // Opened before entering the mount namespace, in which the container's root directory can be
+    // underneath the root of the namespace, when the container was set up with chroot()
+    let root = File::open(format!("/proc/{}/root", pid))?;
+    // A container without a cgroup of its own is in carton's, which a user may not be allowed to
+    // move processes into, even though they're in it already
+    let cgroup_procs = match (
+        cgroup::of_process(&pid.to_string())?,
+        cgroup::of_process("self")?,
+    ) {
+        (Some(theirs), Some(ours)) if theirs != ours => Some(
+            OpenOptions::new()
+                .write(true)
+                .open(cgroup::cgroup2_root()?.join(theirs).join("cgroup.procs"))?,
+        ),
+        _ => None,
+    };
+    let filter = match &info.seccomp_profile {
+        Some(profile) => Some(profile.filter()?),
+        None => None,
+    };
+    let user = info.user;
+
+    unsafe {
+        // Runs in the forked process right before the command is executed, so carton itself stays
+        // where it is
+        command.pre_exec(move || {
+            if let Some(mut cgroup_procs) = cgroup_procs.as_ref() {
+                cgroup_procs.write_all(b"0")?;
+            }
+            enter_namespaces(&namespaces)?;
+            unistd::fchdir(root.as_raw_fd())?;
+            unistd::chroot(".")?;
+            unistd::chdir("/")?;
+            fork_into_pid_namespace()?;
+
+            // Like the container process does right before it executes its command
+            if let Some(filter) = &filter {
+                seccomp::install_filter(filter, 0)?;
+            }
+            if let Some((uid, gid)) = user {
+                container::switch_user(uid, gid)?;
+            }
+
+            Ok(())
+        });
+    }
+
+    Ok(())
+}
+
+/// Forks, and returns in the child, which is in the PID namespace that the calling process
+/// entered. The parent stays behind as the process that `check()` waits for and kills: it waits
+/// for the child and exits like it. Called in a forked process.
+fn fork_into_pid_namespace() -> io::Result<()> {
+    match unsafe { unistd::fork() }? {
+        ForkResult::Child => {
+            // So that the command doesn't outlive a health check that timed out
+            prctl::set_pdeathsig(Signal::SIGKILL)?;
+            Ok(())
+        }
+        ForkResult::Parent { child } => {
+            // One of them is the pipe through which `Command::spawn()` learns whether the command
+            // could be executed, which it reads until every copy of it is closed
+            if unsafe { libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0) } != 0 {
+                for fd in 3..1024 {
+                    unsafe { libc::close(fd) };
+                }
+            }
+            let code = loop {
+                match wait::waitpid(child, None) {
+                    Err(Errno::EINTR) => continue,
+                    Ok(WaitStatus::Exited(_, code)) => break code,
+                    Ok(WaitStatus::Signaled(_, signal, _)) => break 128 + signal as i32,
+                    _ => break 1,
+                }
+            };
+            unsafe { libc::_exit(code) }
+        }
+    }
+}
+
+/// Opens the namespaces `names` of process `pid`, leaving out the ones that carton is in already.
+pub(crate) fn open_namespaces(
+    pid: i32,
+    names: &[&'static str],
+) -> Result<Vec<(&'static str, File)>, CartonError> {
+    let mut namespaces = Vec::new();
+    for name in names {
+        let path = format!("/proc/{}/ns/{}", pid, name);
+        let own = format!("/proc/self/ns/{}", name);
+        let (Ok(theirs), Ok(ours)) = (fs::metadata(&path), fs::metadata(own)) else {
+            // The kernel doesn't have this kind of namespace
+            continue;
+        };
+        // Entering the user namespace that the process is in already fails
+        if (theirs.dev(), theirs.ino()) != (ours.dev(), ours.ino()) {
+            namespaces.push((*name, File::open(&path)?));
+        }
+    }
+
+    Ok(namespaces)
+}
+
+/// Enters `namespaces`, as root of the user namespace. Called in a forked process.
+pub(crate) fn enter_namespaces(namespaces: &[(&str, File)]) -> nix::Result<()> {
+    for (name, namespace) in namespaces {
+        sched::setns(namespace, CloneFlags::empty())?;
+        if *name == "user" {
+            unistd::setresgid(Gid::from_raw(0), Gid::from_raw(0), Gid::from_raw(0))?;
+            unistd::setresuid(Uid::from_raw(0), Uid::from_raw(0), Uid::from_raw(0))?;
+        }
+    }
+
+    Ok(())
+}
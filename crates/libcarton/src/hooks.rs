@@ -0,0 +1,69 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Code of the embedding program that runs while a container is set up, see
+//! `ContainerBuilder::pre_exec_hook()` and `ContainerBuilder::post_setup_hook()`.
+
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+
+use crate::container::Container;
+
+type PreExecHook = dyn FnMut() -> io::Result<()> + Send;
+type PostSetupHook = dyn FnMut(&Container) -> io::Result<()> + Send;
+
+/// The hooks are only ever called through a shared reference to the container's configuration
+/// (the container process gets a copy of it), so they're behind a `Mutex` to be callable.
+#[derive(Default)]
+pub(crate) struct Hooks {
+    pre_exec: Vec<Mutex<Box<PreExecHook>>>,
+    post_setup: Vec<Mutex<Box<PostSetupHook>>>,
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("pre_exec", &self.pre_exec.len())
+            .field("post_setup", &self.post_setup.len())
+            .finish()
+    }
+}
+
+impl Hooks {
+    pub(crate) fn add_pre_exec<F>(&mut self, hook: F)
+    where
+        F: FnMut() -> io::Result<()> + Send + 'static,
+    {
+        self.pre_exec.push(Mutex::new(Box::new(hook)));
+    }
+
+    pub(crate) fn add_post_setup<F>(&mut self, hook: F)
+    where
+        F: FnMut(&Container) -> io::Result<()> + Send + 'static,
+    {
+        self.post_setup.push(Mutex::new(Box::new(hook)));
+    }
+
+    /// Runs the pre-exec hooks in the order they were added, until one of them fails.
+    pub(crate) fn run_pre_exec(&self) -> io::Result<()> {
+        for hook in &self.pre_exec {
+            // Nothing else locks the hooks in the container process, but a poisoned lock is fine too
+            let mut hook = hook.lock().unwrap_or_else(|e| e.into_inner());
+            (*hook)()?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the post-setup hooks for `container` in the order they were added, until one of them
+    /// fails.
+    pub(crate) fn run_post_setup(&self, container: &Container) -> io::Result<()> {
+        for hook in &self.post_setup {
+            let mut hook = hook.lock().unwrap_or_else(|e| e.into_inner());
+            (*hook)(container)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,375 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! ID-mapped mounts, which show the files of a filesystem with other owners than the ones they
+//! have on disk.
+//!
+//! Every file on disk has a user and group ID, and a program that unpacks an image as an
+//! unprivileged user ends up with files that are all owned by that user. Instead of changing the
+//! owner of every file (which takes long for large images and has to be done again for every
+//! other mapping), an ID-mapped mount (Linux 5.12 and up) translates the IDs as files are
+//! accessed. The translation is described by a user namespace: an ID on disk is treated as an ID
+//! inside the namespace, and shown as the ID it's mapped to outside of it. Carton creates such a
+//! user namespace just for the mapping, with a short-lived process.
+//!
+//! Not all filesystems support ID-mapped mounts, see `mount_setattr(2)`.
+//!
+//! The same kind of mappings make up the user namespace that a container can run in. Only root
+//! can map arbitrary IDs. Other users can only map their own user and group ID, unless the setuid
+//! `newuidmap` and `newgidmap` helpers (from shadow) write the mappings for them, which allow the
+//! ranges of "subordinate" IDs that /etc/subuid and /etc/subgid assign to the user. When carton
+//! started as root and dropped its privileges, its own privileged helper does that instead, see
+//! `privsep`.
+
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::Command;
+
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::libc;
+use nix::sched::{self, CloneFlags};
+use nix::sys::signal::{self, Signal::SIGCHLD, Signal::SIGKILL};
+use nix::sys::wait;
+use nix::unistd::{self, Gid, Pid, Uid, User};
+
+use crate::diagnostics::Reporter;
+use crate::error::CartonError;
+use crate::privsep;
+
+// These aren't in the libc crate (yet), see include/uapi/linux/mount.h
+const MOUNT_ATTR_IDMAP: u64 = 0x0010_0000;
+
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+pub(crate) const SUBUID_PATH: &str = "/etc/subuid";
+pub(crate) const SUBGID_PATH: &str = "/etc/subgid";
+/// The process that holds on to the user namespace only needs a tiny stack.
+const HELPER_STACK_SIZE: usize = 64 * 1024;
+
+/// Maps a range of IDs, like a line of `/proc/<pid>/uid_map` or a `LinuxIDMapping` of the OCI
+/// runtime spec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdMapping {
+    /// First ID of the range as the container sees it.
+    pub container_id: u32,
+    /// First ID of the range on the host.
+    pub host_id: u32,
+    /// Number of IDs in the range.
+    pub size: u32,
+}
+
+impl IdMapping {
+    pub fn new(container_id: u32, host_id: u32, size: u32) -> Self {
+        IdMapping {
+            container_id,
+            host_id,
+            size,
+        }
+    }
+
+    /// Returns user and group ID mappings for a user namespace that the calling user can set up
+    /// without privileges ("rootless"): root in the container is the calling user, and the IDs
+    /// from 1 up are the first range of subordinate IDs that /etc/subuid and /etc/subgid assign
+    /// to the user, if there is one.
+    pub fn current_user() -> Result<(Vec<IdMapping>, Vec<IdMapping>), CartonError> {
+        let uid = unistd::geteuid();
+        let gid = unistd::getegid();
+        let name = User::from_uid(uid)?.map(|user| user.name);
+        let name = name.as_deref();
+
+        let mut uid_mappings = vec![IdMapping::new(0, uid.as_raw(), 1)];
+        if let Some((start, size)) = subordinate_ids(SUBUID_PATH, name, uid.as_raw())? {
+            uid_mappings.push(IdMapping::new(1, start, size));
+        }
+        let mut gid_mappings = vec![IdMapping::new(0, gid.as_raw(), 1)];
+        if let Some((start, size)) = subordinate_ids(SUBGID_PATH, name, uid.as_raw())? {
+            gid_mappings.push(IdMapping::new(1, start, size));
+        }
+
+        Ok((uid_mappings, gid_mappings))
+    }
+}
+
+/// Returns the first range of subordinate IDs in `path` (/etc/subuid or /etc/subgid) of the user
+/// with `name` or `uid`, as its start and size. The lines look like `alice:100000:65536`, and
+/// can use the user ID instead of the name.
+pub(crate) fn subordinate_ids(
+    path: &str,
+    name: Option<&str>,
+    uid: u32,
+) -> Result<Option<(u32, u32)>, CartonError> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let uid = uid.to_string();
+
+    Ok(contents.lines().find_map(|line| {
+        let mut fields = line.trim().split(':');
+        let user = fields.next()?;
+        if Some(user) != name && user != uid {
+            return None;
+        }
+        let start = fields.next()?.parse().ok()?;
+        let size = fields.next()?.parse().ok()?;
+        Some((start, size))
+    }))
+}
+
+/// User and group ID mappings for an ID-mapped mount or a user namespace, see
+/// `ContainerBuilder::idmap_rootfs()` and `ContainerBuilder::user_namespace()`.
+#[derive(Debug, Clone)]
+pub(crate) struct IdMappings {
+    pub(crate) uids: Vec<IdMapping>,
+    pub(crate) gids: Vec<IdMapping>,
+}
+
+impl IdMappings {
+    pub(crate) fn validate(&self) -> Result<(), CartonError> {
+        // The kernel accepts up to 340 lines, but they have to fit in a single page
+        for mappings in [&self.uids, &self.gids] {
+            if mappings.is_empty() || mappings.len() > 340 {
+                return Err(CartonError::InvalidConfiguration(
+                    "ID mappings need between 1 and 340 ranges of user and group IDs".into(),
+                ));
+            }
+            if mappings.iter().any(|mapping| mapping.size == 0) {
+                return Err(CartonError::InvalidConfiguration(
+                    "ID mappings can't have an empty range".into(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the host user and group that root in the namespace maps to, if it's mapped.
+    pub(crate) fn host_root(&self) -> Option<(Uid, Gid)> {
+        self.host_ids(0, 0)
+    }
+
+    /// Returns the host user and group that user `uid` and group `gid` in the namespace map to, if
+    /// they're mapped.
+    pub(crate) fn host_ids(&self, uid: u32, gid: u32) -> Option<(Uid, Gid)> {
+        let host_id = |mappings: &[IdMapping], id: u32| {
+            mappings.iter().find_map(|mapping| {
+                let offset = id.checked_sub(mapping.container_id)?;
+                (offset < mapping.size).then(|| mapping.host_id + offset)
+            })
+        };
+
+        Some((
+            Uid::from_raw(host_id(&self.uids, uid)?),
+            Gid::from_raw(host_id(&self.gids, gid)?),
+        ))
+    }
+
+    /// Creates a user namespace with these mappings, and returns a file descriptor that refers to
+    /// it. The namespace stays around for as long as the file descriptor is open.
+    pub(crate) fn user_namespace(&self) -> Result<OwnedFd, CartonError> {
+        let (ready_read, ready_write) = unistd::pipe2(OFlag::O_CLOEXEC)?;
+        let mut stack = vec![0u8; HELPER_STACK_SIZE];
+
+        // A user namespace only exists while a process is in it, or while something refers to it.
+        // This process waits until the mappings are written and the namespace has been opened.
+        let pid = unsafe {
+            sched::clone(
+                Box::new(|| {
+                    let _ = unistd::close(ready_write);
+                    let _ = unistd::read(ready_read, &mut [0]);
+                    0
+                }),
+                &mut stack,
+                CloneFlags::CLONE_NEWUSER,
+                Some(SIGCHLD as i32),
+            )
+        };
+        let _ = unistd::close(ready_read);
+        let pid = match pid {
+            Ok(pid) => pid,
+            Err(e) => {
+                let _ = unistd::close(ready_write);
+                return Err(e.into());
+            }
+        };
+
+        // Part of making an ID-mapped copy of the root filesystem, which is reported as a whole
+        let user_namespace = write_mappings(&Reporter::default(), pid, self)
+            .and_then(|_| Ok(OwnedFd::from(File::open(format!("/proc/{}/ns/user", pid))?)));
+
+        // Closing the pipe lets the process exit, unless a process that was started on another
+        // thread in the meantime inherited a copy of it
+        let _ = unistd::close(ready_write);
+        let _ = signal::kill(pid, SIGKILL);
+        let _ = wait::waitpid(pid, None);
+
+        user_namespace
+    }
+}
+
+/// Writes the mappings of the user namespace of the process `pid`, which the container process
+/// waits for before it does anything else. The writes are reported to `reporter`.
+pub(crate) fn write_user_namespace_mappings(
+    reporter: &Reporter,
+    pid: Pid,
+    mappings: &IdMappings,
+) -> Result<(), CartonError> {
+    if unistd::geteuid().is_root() {
+        return write_mappings(reporter, pid, mappings);
+    }
+
+    let maps_own_id = |mappings: &[IdMapping], id: u32| matches!(mappings, [mapping] if mapping.size == 1 && mapping.host_id == id);
+    if maps_own_id(&mappings.uids, unistd::geteuid().as_raw())
+        && maps_own_id(&mappings.gids, unistd::getegid().as_raw())
+    {
+        // Otherwise the process could drop its supplementary groups, which might deny it access
+        // to files that are accessible to everyone else
+        let path = format!("/proc/{}/setgroups", pid);
+        reporter.operation(
+            format_args!("write({:?}, \"deny\")", path),
+            fs::write(&path, "deny"),
+        )?;
+        return write_mappings(reporter, pid, mappings);
+    }
+
+    if let Some(result) = privsep::write_mappings(reporter, pid, mappings) {
+        return result;
+    }
+    run_id_map_helper(reporter, "newuidmap", pid, &mappings.uids)?;
+    run_id_map_helper(reporter, "newgidmap", pid, &mappings.gids)
+}
+
+fn run_id_map_helper(
+    reporter: &Reporter,
+    helper: &str,
+    pid: Pid,
+    mappings: &[IdMapping],
+) -> Result<(), CartonError> {
+    let mut command = Command::new(helper);
+    command.arg(pid.to_string());
+    for mapping in mappings {
+        command.args([
+            mapping.container_id.to_string(),
+            mapping.host_id.to_string(),
+            mapping.size.to_string(),
+        ]);
+    }
+
+    let description = format!("{:?}", command);
+    let output = reporter
+        .operation(format_args!("{}", description), command.output())
+        .map_err(|e| {
+            CartonError::NamespaceError(format!(
+                "mapping more than one ID needs {} (from shadow): {}",
+                helper, e
+            ))
+        })?;
+    if !output.status.success() {
+        return Err(CartonError::NamespaceError(format!(
+            "{} failed: {}",
+            helper,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(())
+}
+
+fn write_mappings(reporter: &Reporter, pid: Pid, mappings: &IdMappings) -> Result<(), CartonError> {
+    for (file, mappings) in [("uid_map", &mappings.uids), ("gid_map", &mappings.gids)] {
+        let path = format!("/proc/{}/{}", pid, file);
+        let lines = mapping_lines(mappings);
+        reporter.operation(
+            format_args!("write({:?}, {:?})", path, lines),
+            fs::write(&path, &lines),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Returns `mappings` in the format of `/proc/<pid>/uid_map`.
+pub(crate) fn mapping_lines(mappings: &[IdMapping]) -> String {
+    mappings
+        .iter()
+        .map(|mapping| {
+            format!(
+                "{} {} {}\n",
+                mapping.container_id, mapping.host_id, mapping.size
+            )
+        })
+        .collect()
+}
+
+/// Creates an ID-mapped copy of the directory (or mount) at `path`, which maps IDs like the user
+/// namespace `user_namespace` does. The copy isn't attached anywhere yet, see `attach_tree()`.
+///
+/// This has to be done by a process that is privileged in the initial user namespace, so it's done
+/// before the container process (which may be in a user namespace of its own) exists.
+///
+/// Filesystems that don't support ID-mapped mounts fail with `EINVAL`, and kernels from before
+/// 5.12 with `ENOSYS`.
+pub(crate) fn idmapped_tree(path: &Path, user_namespace: &OwnedFd) -> nix::Result<OwnedFd> {
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| Errno::EINVAL)?;
+
+    let tree = Errno::result(unsafe {
+        libc::syscall(
+            libc::SYS_open_tree,
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            libc::OPEN_TREE_CLONE | libc::OPEN_TREE_CLOEXEC,
+        )
+    })?;
+    let tree = unsafe { OwnedFd::from_raw_fd(tree as RawFd) };
+
+    let attr = MountAttr {
+        attr_set: MOUNT_ATTR_IDMAP,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: user_namespace.as_raw_fd() as u64,
+    };
+    Errno::result(unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            tree.as_raw_fd(),
+            b"\0".as_ptr(),
+            libc::AT_EMPTY_PATH,
+            &attr as *const MountAttr,
+            std::mem::size_of::<MountAttr>(),
+        )
+    })?;
+
+    Ok(tree)
+}
+
+/// Mounts a tree that was created with `idmapped_tree()` at `path`, on top of whatever is mounted
+/// there.
+pub(crate) fn attach_tree(tree: RawFd, path: &Path) -> nix::Result<()> {
+    // Like any other system call that gets a path with a NUL byte in it
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| Errno::EINVAL)?;
+
+    Errno::result(unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            tree,
+            b"\0".as_ptr(),
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            libc::MOVE_MOUNT_F_EMPTY_PATH,
+        )
+    })?;
+
+    Ok(())
+}
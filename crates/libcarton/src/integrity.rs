@@ -0,0 +1,284 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Verifies that a root filesystem or an image file has the expected content before a container
+//! starts, by comparing SHA-256 digests.
+//!
+//! The digest of a file is the SHA-256 hash of its content, like `sha256sum` prints. The digest of
+//! a directory covers the whole tree: the name, type, permissions and owner of every entry, the
+//! content of regular files, the targets of symlinks and the numbers of device nodes. Timestamps
+//! aren't included, so a copy of a tree has the same digest as long as the owners are preserved.
+//! `carton digest` prints the digest of a file or directory.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::error::CartonError;
+
+const DIGEST_PREFIX: &str = "sha256:";
+const DIGEST_SIZE: usize = 32;
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A SHA-256 digest, written as `sha256:` followed by 64 hexadecimal digits, like the digests of
+/// OCI image layers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sha256Digest([u8; DIGEST_SIZE]);
+
+impl Sha256Digest {
+    /// Computes the digest of the file or directory at `path`. Symlinks aren't followed.
+    pub fn of_path(path: &Path) -> Result<Self, CartonError> {
+        let metadata = fs::symlink_metadata(path)?;
+        if metadata.is_dir() {
+            let mut hasher = Sha256::new();
+            hash_tree(&mut hasher, path, Path::new("."))?;
+            Ok(hasher.finish())
+        } else {
+            file_digest(path)
+        }
+    }
+
+    /// Returns an error when the file or directory at `path` doesn't have this digest.
+    pub(crate) fn verify(&self, path: &Path) -> Result<(), CartonError> {
+        let actual = Sha256Digest::of_path(path).map_err(|e| {
+            CartonError::IntegrityError(format!("could not verify {}: {}", path.display(), e))
+        })?;
+        if actual != *self {
+            return Err(CartonError::IntegrityError(format!(
+                "{} has digest {}, expected {}",
+                path.display(),
+                actual,
+                self
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Sha256Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(DIGEST_PREFIX)?;
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Sha256Digest {
+    type Err = CartonError;
+
+    /// Parses a digest with or without the `sha256:` prefix.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let hex = value.strip_prefix(DIGEST_PREFIX).unwrap_or(value);
+        let invalid = || {
+            CartonError::InvalidConfiguration(format!(
+                "invalid SHA-256 digest `{}`, expected {}<64 hexadecimal digits>",
+                value, DIGEST_PREFIX
+            ))
+        };
+        if hex.len() != DIGEST_SIZE * 2 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+
+        let mut digest = [0u8; DIGEST_SIZE];
+        for (i, byte) in digest.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+
+        Ok(Sha256Digest(digest))
+    }
+}
+
+/// SHA-256 as specified in FIPS 180-4. There's no SHA-256 implementation in the dependencies, and
+/// the kernel's (through an `AF_ALG` socket) isn't available on every kernel.
+struct Sha256 {
+    state: [u32; 8],
+    /// Data that doesn't fill a block yet.
+    block: Vec<u8>,
+    /// Number of bytes hashed so far.
+    length: u64,
+}
+
+const BLOCK_SIZE: usize = 64;
+
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+impl Sha256 {
+    fn new() -> Self {
+        Sha256 {
+            state: INITIAL_STATE,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            length: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+
+        if !self.block.is_empty() {
+            let size = data.len().min(BLOCK_SIZE - self.block.len());
+            self.block.extend_from_slice(&data[..size]);
+            data = &data[size..];
+            if self.block.len() < BLOCK_SIZE {
+                return;
+            }
+            let block = std::mem::take(&mut self.block);
+            self.compress(&block);
+        }
+
+        let mut blocks = data.chunks_exact(BLOCK_SIZE);
+        for block in &mut blocks {
+            self.compress(block);
+        }
+        self.block.extend_from_slice(blocks.remainder());
+    }
+
+    fn finish(mut self) -> Sha256Digest {
+        let bit_length = self.length.wrapping_mul(8);
+
+        // A 1 bit, zeros up to 8 bytes before the end of a block, and the length in bits
+        let mut padding = vec![0x80u8];
+        let padded_length = (self.length as usize + 1) % BLOCK_SIZE;
+        padding.resize(1 + (BLOCK_SIZE + 56 - padded_length) % BLOCK_SIZE, 0);
+        self.update(&padding);
+        self.update(&bit_length.to_be_bytes());
+
+        let mut digest = [0u8; DIGEST_SIZE];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+
+        Sha256Digest(digest)
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let mut schedule = [0u32; 64];
+        for (word, bytes) in schedule.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..64 {
+            let s0 = schedule[i - 15].rotate_right(7)
+                ^ schedule[i - 15].rotate_right(18)
+                ^ (schedule[i - 15] >> 3);
+            let s1 = schedule[i - 2].rotate_right(17)
+                ^ schedule[i - 2].rotate_right(19)
+                ^ (schedule[i - 2] >> 10);
+            schedule[i] = schedule[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(schedule[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let temp1 = h
+                .wrapping_add(s1)
+                .wrapping_add(choice)
+                .wrapping_add(ROUND_CONSTANTS[i])
+                .wrapping_add(schedule[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(majority);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+
+        for (word, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+}
+
+fn file_digest(path: &Path) -> Result<Sha256Digest, CartonError> {
+    let mut hasher = Sha256::new();
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        let size = file.read(&mut buffer)?;
+        if size == 0 {
+            break;
+        }
+        hasher.update(&buffer[..size]);
+    }
+
+    Ok(hasher.finish())
+}
+
+/// Adds the entries of the directory at `path`, which is `relative_path` in the tree, to the
+/// digest of the tree. Every entry is a line of NUL-separated fields, in the order of the names'
+/// bytes.
+fn hash_tree(hasher: &mut Sha256, path: &Path, relative_path: &Path) -> Result<(), CartonError> {
+    let mut entries = fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by(|a, b| a.file_name().as_bytes().cmp(b.file_name().as_bytes()));
+
+    for entry in entries {
+        let path = entry.path();
+        let relative_path = relative_path.join(entry.file_name());
+        let metadata = fs::symlink_metadata(&path)?;
+        let file_type = metadata.file_type();
+
+        let (kind, content) = if file_type.is_dir() {
+            ("d", Vec::new())
+        } else if file_type.is_file() {
+            ("f", file_digest(&path)?.to_string().into_bytes())
+        } else if file_type.is_symlink() {
+            ("l", fs::read_link(&path)?.into_os_string().into_vec())
+        } else if file_type.is_char_device() {
+            ("c", metadata.rdev().to_string().into_bytes())
+        } else if file_type.is_block_device() {
+            ("b", metadata.rdev().to_string().into_bytes())
+        } else {
+            // FIFOs and sockets
+            ("p", Vec::new())
+        };
+
+        hasher.update(relative_path.as_os_str().as_bytes());
+        hasher.update(
+            format!(
+                "\0{}\0{:o}\0{}\0{}\0",
+                kind,
+                metadata.mode() & 0o7777,
+                metadata.uid(),
+                metadata.gid()
+            )
+            .as_bytes(),
+        );
+        hasher.update(&content);
+        hasher.update(b"\n");
+
+        if file_type.is_dir() {
+            hash_tree(hasher, &path, &relative_path)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,103 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sending signals to containers that may have been started by another carton process, like
+//! `carton kill` does.
+//!
+//! A signal goes to the container's first process (PID 1 in the container), or to all of its
+//! processes. Those are the processes in its cgroup, when it has one of its own (see
+//! `ContainerBuilder::delegate_cgroup()`), and the processes in its PID namespace otherwise. A
+//! process that the container starts while they're being signalled can miss the signal, except
+//! SIGKILL of a cgroup, which the kernel sends to all of them at once (since Linux 5.14). A
+//! container that shares carton's PID namespace and has no cgroup can't be told apart from the
+//! host's processes, so it can only be signalled as a whole with its first process.
+//!
+//! PID 1 of a PID namespace only gets the signals that it handles, apart from SIGKILL and SIGSTOP
+//! from outside of the namespace. So a container whose command doesn't handle SIGTERM ignores it,
+//! unless it runs with an init (see `ContainerBuilder::init()`).
+
+use std::str::FromStr;
+
+use log::info;
+
+use nix::errno::Errno;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+
+use crate::cgroup;
+use crate::container::ContainerState;
+use crate::error::CartonError;
+use crate::state::ContainerInfo;
+use crate::stats;
+
+/// Parses a signal by its name, with or without `SIG` (`SIGTERM`, `TERM`, `term`), or by its
+/// number (`15`).
+pub fn parse_signal(value: &str) -> Result<Signal, CartonError> {
+    let unknown = || CartonError::InvalidConfiguration(format!("unknown signal `{}`", value));
+
+    if let Ok(number) = value.parse::<i32>() {
+        return Signal::try_from(number).map_err(|_| unknown());
+    }
+
+    let name = value.to_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{}", name)
+    };
+    Signal::from_str(&name).map_err(|_| unknown())
+}
+
+/// Sends `signal` to the first process of the running container of `info`, or with `all` to all
+/// of its processes.
+pub fn signal_container(
+    info: &ContainerInfo,
+    signal: Signal,
+    all: bool,
+) -> Result<(), CartonError> {
+    let (ContainerState::Running, Some(pid)) = (info.state, info.pid) else {
+        return Err(CartonError::NotRunning);
+    };
+
+    let pids = match (all, cgroup::path(&info.id)) {
+        (false, _) => vec![pid],
+        (true, Some(path)) => {
+            if signal == Signal::SIGKILL && cgroup::kill(&path)? {
+                return Ok(());
+            }
+            cgroup::pids(&path)?
+        }
+        (true, None) => stats::namespace_pids(pid)?,
+    };
+    info!("sending {} to processes {:?}", signal, pids);
+    for pid in pids {
+        match signal::kill(Pid::from_raw(pid), signal) {
+            // The process exited in the meantime
+            Ok(()) | Err(Errno::ESRCH) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_signals() {
+        for value in ["SIGTERM", "TERM", "term", "SigTerm", "15"] {
+            assert_eq!(parse_signal(value), Ok(Signal::SIGTERM), "{}", value);
+        }
+        assert_eq!(parse_signal("9"), Ok(Signal::SIGKILL));
+        assert_eq!(parse_signal("usr1"), Ok(Signal::SIGUSR1));
+    }
+
+    #[test]
+    fn rejects_unknown_signals() {
+        for value in ["", "SIG", "FOO", "SIGSIGTERM", "0", "-15", "65", "TERM "] {
+            assert!(parse_signal(value).is_err(), "{}", value);
+        }
+    }
+}
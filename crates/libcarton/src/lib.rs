@@ -1,11 +1,101 @@
 // Copyright 2023 Arjen Verstoep
 // SPDX-License-Identifier: Apache-2.0
 
-pub use container::Container;
-pub use container_builder::ContainerBuilder;
+pub use activation::ListenFds;
+pub use binfmt::Architecture;
+pub use cgroup::{pause_container, resume_container, update_limits, ResourceLimits};
+pub use checkpoint::{checkpoint_container, CheckpointOptions};
+pub use cni::CniNetwork;
+pub use consts::{DEFAULT_DATA_DIR, DEFAULT_STATE_DIR};
+pub use container::{Container, ContainerState};
+pub use container_builder::{ContainerBuilder, NeedsCommand, NeedsRootfs, Ready};
+pub use copy::{copy_from_container, copy_to_container};
+pub use core_dump::CoreDumps;
+pub use dbus::{DbusBus, DbusProxy};
+pub use debug::{debug_container, DebugOptions};
+pub use diagnostics::{Diagnostic, DiagnosticLevel, Diagnostics};
+pub use environment::EnvFilter;
+pub use error::CartonError;
+pub use exit::ContainerExit;
+pub use features::{CgroupVersion, HostFeatures, SelinuxMode};
+pub use fuse::FuseRootfs;
+pub use gpu::Gpus;
+pub use health::HealthCheck;
+pub use idmap::IdMapping;
+pub use integrity::Sha256Digest;
+pub use kill::{parse_signal, signal_container};
+pub use manager::ContainerManager;
+pub use mount_provider::MountProvider;
+pub use mux::{Frame, FrameReader, FrameWriter, StdioStream};
+pub use network::{
+    BandwidthLimit, BridgeNetwork, MacAddress, MacvlanKind, MacvlanNetwork, Network, Route,
+    SharedNetwork,
+};
+pub use paths::Paths;
+pub use plan::{Plan, PlanStep};
+pub use pool::{ContainerPool, PreparedContainer};
+pub use privsep::drop_privileges;
+pub use procfs::{HidePid, ProcfsOptions};
+pub use seccomp::{SeccompProfile, SeccompRecorder, SyscallRequest, SyscallResponse};
+pub use state::{ContainerInfo, LoadedContainer, ProjectInfo, Setting, SettingSource, StateStore};
+pub use stats::ContainerStats;
+pub use syscall_names::{syscall_name, syscall_number};
+pub use trace::{Span, Tracer};
+pub use volume::{LocalVolumes, VolumeDriver};
 
+pub use nix::sys::signal::Signal;
+
+mod activation;
+mod binfmt;
+mod capabilities;
+mod cgroup;
+mod checkpoint;
+mod cni;
+mod console;
 mod consts;
 mod container;
 mod container_builder;
+mod copy;
+mod core_dump;
+mod dbus;
+mod debug;
+mod diagnostics;
+mod display;
+mod environment;
 mod error;
+mod exit;
+mod features;
+mod files;
+mod fuse;
+mod gpu;
+mod health;
+mod hooks;
+mod idmap;
+mod integrity;
+mod kill;
+mod manager;
+mod mount_provider;
+mod mux;
 mod namespace;
+mod network;
+mod network_files;
+mod notify;
+mod ownership;
+mod paths;
+mod plan;
+mod pool;
+mod privsep;
+mod procfs;
+mod quota;
+mod rollback;
+mod schema;
+mod scope;
+mod seccomp;
+mod secrets;
+mod state;
+mod stats;
+mod stdin;
+mod syscall_names;
+mod syscalls;
+mod trace;
+mod volume;
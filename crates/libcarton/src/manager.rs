@@ -0,0 +1,230 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use nix::errno::Errno;
+use nix::poll::{self, PollFd, PollFlags};
+use nix::sys::signal::Signal::{SIGKILL, SIGTERM};
+
+use crate::container::{Container, ContainerState};
+use crate::error::CartonError;
+use crate::exit::ContainerExit;
+
+/// How often `wait_any()` checks on containers that have no pidfd to poll.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Owns a number of containers and keeps track of them for a process that runs many containers at
+/// once, like a supervisor or a service that runs jobs in containers. It's the in-process
+/// equivalent of looking up containers in a `StateStore`.
+///
+/// Instead of every container being waited for on its own thread, `wait_any()` waits for all of
+/// them at once and reaps the ones that exited.
+///
+/// ```no_run
+/// # use libcarton::{CartonError, ContainerBuilder, ContainerManager};
+/// # fn main() -> Result<(), CartonError> {
+/// let mut manager = ContainerManager::new();
+/// for name in ["web", "worker"] {
+///     let container = manager.add(
+///         ContainerBuilder::new()
+///             .name(name.into())
+///             .rootfs("/path/to/rootfs".into())
+///             .command("/bin/sleep".into(), Some(vec!["60".into()]))
+///             .build()?,
+///     )?;
+///     container.run()?;
+/// }
+///
+/// for (id, exit) in manager.wait_any(None)? {
+///     println!("{} {}", id, exit);
+/// }
+/// manager.stop_all(std::time::Duration::from_secs(10))?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct ContainerManager {
+    containers: Vec<Container>,
+}
+
+impl ContainerManager {
+    pub fn new() -> Self {
+        ContainerManager::default()
+    }
+
+    /// Starts managing `container` and returns it, so that it can be run. Fails when the manager
+    /// already has a container with the same name.
+    pub fn add(&mut self, container: Container) -> Result<&mut Container, CartonError> {
+        if let Some(name) = container.name() {
+            if self.get(name).is_some() {
+                return Err(CartonError::InvalidConfiguration(format!(
+                    "there already is a container called {}",
+                    name
+                )));
+            }
+        }
+
+        self.containers.push(container);
+
+        Ok(self
+            .containers
+            .last_mut()
+            .expect("container was just added"))
+    }
+
+    /// Finds a container by its ID or its name.
+    pub fn get(&self, id_or_name: &str) -> Option<&Container> {
+        self.containers
+            .iter()
+            .find(|container| matches(container, id_or_name))
+    }
+
+    pub fn get_mut(&mut self, id_or_name: &str) -> Option<&mut Container> {
+        self.containers
+            .iter_mut()
+            .find(|container| matches(container, id_or_name))
+    }
+
+    /// Stops managing a container and hands it back.
+    pub fn remove(&mut self, id_or_name: &str) -> Option<Container> {
+        let index = self
+            .containers
+            .iter()
+            .position(|container| matches(container, id_or_name))?;
+
+        Some(self.containers.remove(index))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Container> {
+        self.containers.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Container> {
+        self.containers.iter_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.containers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.containers.is_empty()
+    }
+
+    /// Reaps the containers whose process exited, without blocking. Returns the ID of each of them
+    /// and how it ended.
+    pub fn reap(&mut self) -> Result<Vec<(String, ContainerExit)>, CartonError> {
+        let mut exited = Vec::new();
+        for container in self.running_mut() {
+            if let Some(exit) = container.try_wait()? {
+                exited.push((container.id().to_string(), exit));
+            }
+        }
+
+        Ok(exited)
+    }
+
+    /// Blocks until at least one of the running containers exits, or until `timeout` passed, and
+    /// reaps the containers that exited. Returns right away when no container is running.
+    pub fn wait_any(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<(String, ContainerExit)>, CartonError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let exited = self.reap()?;
+            if !exited.is_empty() || self.running_mut().next().is_none() {
+                return Ok(exited);
+            }
+
+            let remaining =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if remaining == Some(Duration::ZERO) {
+                return Ok(exited);
+            }
+
+            let pidfds = self
+                .running()
+                .filter_map(Container::pidfd)
+                .collect::<Vec<_>>();
+
+            // The pidfd of a container becomes readable when its process exits. Without pidfds
+            // (on kernels before 5.3) the containers are checked on every so often instead.
+            if pidfds.len() == self.running().count() {
+                let timeout = remaining.map_or(-1, |remaining| {
+                    remaining.as_millis().clamp(1, i32::MAX as u128) as i32
+                });
+                let mut poll_fds = pidfds
+                    .iter()
+                    .map(|pidfd| PollFd::new(pidfd, PollFlags::POLLIN))
+                    .collect::<Vec<_>>();
+                match poll::poll(&mut poll_fds, timeout) {
+                    Ok(_) | Err(Errno::EINTR) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            } else {
+                thread::sleep(remaining.map_or(WAIT_POLL_INTERVAL, |remaining| {
+                    remaining.min(WAIT_POLL_INTERVAL)
+                }));
+            }
+        }
+    }
+
+    /// Stops all containers: running containers get SIGTERM and are killed when they haven't
+    /// exited after `timeout`, prepared containers are discarded.
+    pub fn stop_all(&mut self, timeout: Duration) -> Result<(), CartonError> {
+        for container in self.running_mut() {
+            if let Err(e) = container.kill(SIGTERM) {
+                warn!("Could not stop container {}: {}", container.id(), e);
+            }
+        }
+
+        let deadline = Instant::now() + timeout;
+        while self.running().next().is_some() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            self.wait_any(Some(remaining))?;
+        }
+
+        for container in self.running_mut() {
+            info!(
+                "Container {} did not stop within {:?}, killing it",
+                container.id(),
+                timeout
+            );
+            container.kill(SIGKILL)?;
+            container.wait_for_exit()?;
+        }
+
+        for container in &mut self.containers {
+            if container.state() == ContainerState::Prepared {
+                container.discard();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn running(&self) -> impl Iterator<Item = &Container> {
+        self.containers
+            .iter()
+            .filter(|container| container.state() == ContainerState::Running)
+    }
+
+    fn running_mut(&mut self) -> impl Iterator<Item = &mut Container> {
+        self.containers
+            .iter_mut()
+            .filter(|container| container.state() == ContainerState::Running)
+    }
+}
+
+fn matches(container: &Container, id_or_name: &str) -> bool {
+    container.id() == id_or_name || container.name() == Some(id_or_name)
+}
@@ -0,0 +1,61 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+use std::path::Path;
+
+use crate::error::CartonError;
+
+/// Sets up filesystems inside a container that carton doesn't know how to mount itself, like a
+/// network filesystem or a FUSE filesystem that loads files on demand. Providers are added with
+/// `ContainerBuilder::add_mount_provider()`.
+///
+/// ```no_run
+/// # use std::path::Path;
+/// # use libcarton::{CartonError, ContainerBuilder, MountProvider};
+/// # use nix::mount::{self, MsFlags};
+/// #[derive(Debug)]
+/// struct Nfs {
+///     export: String,
+///     target: &'static str,
+/// }
+///
+/// impl MountProvider for Nfs {
+///     fn mount(&self, rootfs: &Path) -> Result<(), CartonError> {
+///         let target = rootfs.join(self.target);
+///         std::fs::create_dir_all(&target)?;
+///         mount::mount(
+///             Some(self.export.as_str()),
+///             &target,
+///             Some("nfs4"),
+///             MsFlags::empty(),
+///             Some("vers=4.2,addr=192.168.1.10"),
+///         )?;
+///
+///         Ok(())
+///     }
+/// }
+///
+/// # fn main() -> Result<(), CartonError> {
+/// let container = ContainerBuilder::new()
+///     .rootfs("/path/to/rootfs".into())
+///     .add_default_mounts()
+///     .add_mount_provider(Nfs {
+///         export: "192.168.1.10:/srv/data".into(),
+///         target: "data",
+///     })
+///     .command("/usr/bin/app".into(), None)
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait MountProvider: fmt::Debug + Send + Sync {
+    /// Mounts the provider's filesystems under `rootfs`, the path of the container's root
+    /// filesystem before it becomes `/`. Called in the container process, in the container's
+    /// mount namespace, after carton's own mounts (like /proc and the mounts added with
+    /// `ContainerBuilder::add_mount()`) and before the device nodes are created. Mounts made here
+    /// are not visible outside of the container.
+    ///
+    /// An error stops the container before its command is executed.
+    fn mount(&self, rootfs: &Path) -> Result<(), CartonError>;
+}
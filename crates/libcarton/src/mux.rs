@@ -0,0 +1,357 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Multiplexing the container's stdout and stderr into a single stream of frames, see
+//! `ContainerBuilder::multiplexed_output()`.
+//!
+//! A single stream, like a connection to a client, can only carry both outputs when the client
+//! can tell them apart afterwards. So every chunk of output becomes a frame, in the format of
+//! Docker's attach streams:
+//!
+//! ```text
+//! +--------+---------+-----------------------+-------------------+
+//! | stream | 0  0  0 | length (u32, big end) | payload (length)  |
+//! +--------+---------+-----------------------+-------------------+
+//! ```
+//!
+//! The stream is 0 for stdin, 1 for stdout and 2 for stderr. A payload is at most 1 MiB long;
+//! longer output is split over several frames, and a reader refuses longer frames rather than
+//! allocating whatever the header says. Frames of one stream arrive in the order in which the
+//! container wrote them; the order between stdout and stderr is the order in which carton read
+//! them, like a terminal would show them.
+//!
+//! The container's stdout and stderr are the writing ends of two pipes, which are close-on-exec
+//! in the calling process. A thread per pipe turns what it reads into frames, until the pipe ends
+//! with the last of the container's processes.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use log::warn;
+
+use nix::fcntl::OFlag;
+use nix::unistd;
+
+use crate::error::CartonError;
+
+/// Length of the header in front of every frame.
+const HEADER_LEN: usize = 8;
+/// Length of the longest payload of a frame.
+const MAX_PAYLOAD_LEN: usize = 1024 * 1024;
+
+/// The standard stream that a frame belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StdioStream {
+    Stdin,
+    Stdout,
+    Stderr,
+}
+
+impl StdioStream {
+    /// The stream's ID in the header of a frame, which is also its file descriptor.
+    pub fn id(self) -> u8 {
+        match self {
+            StdioStream::Stdin => 0,
+            StdioStream::Stdout => 1,
+            StdioStream::Stderr => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(StdioStream::Stdin),
+            1 => Some(StdioStream::Stdout),
+            2 => Some(StdioStream::Stderr),
+            _ => None,
+        }
+    }
+}
+
+/// A chunk of one of the streams.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub stream: StdioStream,
+    pub payload: Vec<u8>,
+}
+
+/// Writes frames to a stream.
+#[derive(Debug)]
+pub struct FrameWriter<W> {
+    inner: W,
+}
+
+impl<W: Write> FrameWriter<W> {
+    pub fn new(inner: W) -> Self {
+        FrameWriter { inner }
+    }
+
+    /// Writes `payload` as a frame of `stream`, or as several when it doesn't fit in one, and
+    /// flushes them.
+    pub fn write_frame(&mut self, stream: StdioStream, payload: &[u8]) -> io::Result<()> {
+        for chunk in payload.chunks(MAX_PAYLOAD_LEN) {
+            let mut header = [0; HEADER_LEN];
+            header[0] = stream.id();
+            header[4..].copy_from_slice(&(chunk.len() as u32).to_be_bytes());
+            self.inner.write_all(&header)?;
+            self.inner.write_all(chunk)?;
+        }
+
+        self.inner.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+/// Reads the frames that a `FrameWriter` wrote, like the output of a container with
+/// `ContainerBuilder::multiplexed_output()`.
+///
+/// ```
+/// # use libcarton::{FrameReader, FrameWriter, StdioStream};
+/// # fn main() -> std::io::Result<()> {
+/// let mut writer = FrameWriter::new(Vec::new());
+/// writer.write_frame(StdioStream::Stdout, b"out\n")?;
+/// writer.write_frame(StdioStream::Stderr, b"err\n")?;
+///
+/// let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+/// FrameReader::new(&writer.into_inner()[..]).demultiplex(&mut stdout, &mut stderr)?;
+/// assert_eq!(stdout, b"out\n");
+/// assert_eq!(stderr, b"err\n");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct FrameReader<R> {
+    inner: R,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        FrameReader { inner }
+    }
+
+    /// Reads the next frame, or returns `None` at the end of the stream. A stream that ends in
+    /// the middle of a frame is an `UnexpectedEof` error, a header with an unknown stream or a
+    /// length of more than 1 MiB an `InvalidData` error.
+    pub fn read_frame(&mut self) -> io::Result<Option<Frame>> {
+        let mut header = [0; HEADER_LEN];
+        // Only the end of the stream before the first byte of a header is a clean end
+        let read = loop {
+            match self.inner.read(&mut header) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        if read == 0 {
+            return Ok(None);
+        }
+        self.inner.read_exact(&mut header[read..])?;
+
+        let stream = StdioStream::from_id(header[0]).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown stream {} in a frame header", header[0]),
+            )
+        })?;
+        let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        if len > MAX_PAYLOAD_LEN {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "a frame of {} bytes is longer than the maximum of {}",
+                    len, MAX_PAYLOAD_LEN
+                ),
+            ));
+        }
+        let mut payload = vec![0; len];
+        self.inner.read_exact(&mut payload)?;
+
+        Ok(Some(Frame { stream, payload }))
+    }
+
+    /// Reads frames until the end of the stream, and writes the payloads of stdout and stderr to
+    /// `stdout` and `stderr`. Frames of stdin, which only clients send, are skipped.
+    pub fn demultiplex<O: Write, E: Write>(
+        &mut self,
+        stdout: &mut O,
+        stderr: &mut E,
+    ) -> io::Result<()> {
+        while let Some(frame) = self.read_frame()? {
+            match frame.stream {
+                StdioStream::Stdin => {}
+                StdioStream::Stdout => {
+                    stdout.write_all(&frame.payload)?;
+                    stdout.flush()?;
+                }
+                StdioStream::Stderr => {
+                    stderr.write_all(&frame.payload)?;
+                    stderr.flush()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+type Output = Box<dyn Write + Send>;
+
+/// Where the container's multiplexed output goes. It's shared by the threads that read the
+/// container's stdout and stderr, and by the runs of a container that's started more than once.
+#[derive(Clone)]
+pub(crate) struct MultiplexedOutput {
+    writer: Arc<Mutex<FrameWriter<Output>>>,
+}
+
+impl fmt::Debug for MultiplexedOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MultiplexedOutput").finish_non_exhaustive()
+    }
+}
+
+impl MultiplexedOutput {
+    pub(crate) fn new<W: Write + Send + 'static>(output: W) -> Self {
+        MultiplexedOutput {
+            writer: Arc::new(Mutex::new(FrameWriter::new(Box::new(output)))),
+        }
+    }
+
+    /// Starts turning what the container writes into `stdout` and `stderr`, the reading ends of
+    /// its pipes, into frames. The threads finish when the pipes end, which is when the last of
+    /// the container's processes is gone.
+    pub(crate) fn start(&self, stdout: OwnedFd, stderr: OwnedFd) -> Vec<JoinHandle<()>> {
+        [(StdioStream::Stdout, stdout), (StdioStream::Stderr, stderr)]
+            .into_iter()
+            .map(|(stream, pipe)| {
+                let writer = self.writer.clone();
+                thread::spawn(move || forward(stream, File::from(pipe), &writer))
+            })
+            .collect()
+    }
+}
+
+fn forward(stream: StdioStream, mut pipe: File, writer: &Mutex<FrameWriter<Output>>) {
+    let mut buffer = [0; 32 * 1024];
+    let mut failed = false;
+
+    loop {
+        let len = match pipe.read(&mut buffer) {
+            Ok(0) => return,
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("Could not read the container's {:?}: {}", stream, e);
+                return;
+            }
+        };
+        // After the output fails, the pipe is still drained, because the container would block
+        // once it's full
+        if failed {
+            continue;
+        }
+
+        let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writer.write_frame(stream, &buffer[..len]) {
+            warn!("Could not pass on the container's {:?}: {}", stream, e);
+            failed = true;
+        }
+    }
+}
+
+/// Returns the pipes for the container's stdout and stderr, as their reading and writing ends.
+/// All of them are close-on-exec.
+pub(crate) fn pipes() -> Result<[(OwnedFd, OwnedFd); 2], CartonError> {
+    let pipe = || -> Result<_, CartonError> {
+        let (read, write) = unistd::pipe2(OFlag::O_CLOEXEC)?;
+        Ok(unsafe { (OwnedFd::from_raw_fd(read), OwnedFd::from_raw_fd(write)) })
+    };
+
+    Ok([pipe()?, pipe()?])
+}
+
+/// Makes the writing ends of `pipes` the stdout and stderr of the container process. Called right
+/// before it executes the command.
+pub(crate) fn attach(pipes: &[(OwnedFd, OwnedFd); 2]) -> nix::Result<()> {
+    // The copies that dup2() makes aren't close-on-exec, so they survive execv()
+    for (fd, (_, write)) in [1, 2].into_iter().zip(pipes) {
+        unistd::dup2(write.as_raw_fd(), fd)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(bytes: &[u8]) -> io::Result<Vec<Frame>> {
+        let mut reader = FrameReader::new(bytes);
+        let mut frames = Vec::new();
+        while let Some(frame) = reader.read_frame()? {
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+
+    #[test]
+    fn writes_and_reads_frames() {
+        let mut writer = FrameWriter::new(Vec::new());
+        writer.write_frame(StdioStream::Stdout, b"out").unwrap();
+        writer.write_frame(StdioStream::Stderr, b"err").unwrap();
+        // Nothing to write, so no frame
+        writer.write_frame(StdioStream::Stdout, b"").unwrap();
+        let bytes = writer.into_inner();
+
+        assert_eq!(&bytes[..HEADER_LEN], [1, 0, 0, 0, 0, 0, 0, 3]);
+        assert_eq!(
+            frames(&bytes).unwrap(),
+            [
+                Frame {
+                    stream: StdioStream::Stdout,
+                    payload: b"out".to_vec()
+                },
+                Frame {
+                    stream: StdioStream::Stderr,
+                    payload: b"err".to_vec()
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_long_payloads() {
+        let payload = vec![7; 2 * MAX_PAYLOAD_LEN + 1];
+        let mut writer = FrameWriter::new(Vec::new());
+        writer.write_frame(StdioStream::Stdout, &payload).unwrap();
+
+        let frames = frames(&writer.into_inner()).unwrap();
+        let lens: Vec<usize> = frames.iter().map(|frame| frame.payload.len()).collect();
+        assert_eq!(lens, [MAX_PAYLOAD_LEN, MAX_PAYLOAD_LEN, 1]);
+        let read: Vec<u8> = frames.into_iter().flat_map(|frame| frame.payload).collect();
+        assert_eq!(read, payload);
+    }
+
+    #[test]
+    fn rejects_invalid_frames() {
+        let error = |bytes: &[u8]| frames(bytes).unwrap_err().kind();
+
+        // Unknown stream
+        assert_eq!(error(&[3, 0, 0, 0, 0, 0, 0, 0]), ErrorKind::InvalidData);
+        // Longer than the maximum, without the payload that it announces
+        assert_eq!(
+            error(&[1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]),
+            ErrorKind::InvalidData
+        );
+        // Ends in the header, and in the payload
+        assert_eq!(error(&[1, 0, 0]), ErrorKind::UnexpectedEof);
+        assert_eq!(
+            error(&[1, 0, 0, 0, 0, 0, 0, 2, b'x']),
+            ErrorKind::UnexpectedEof
+        );
+    }
+}
@@ -1,26 +1,189 @@
 // Copyright 2023 Arjen Verstoep
 // SPDX-License-Identifier: Apache-2.0
 
+use std::fs;
+use std::os::fd::RawFd;
 use std::path::Path;
 
-use nix::mount;
-use nix::sys::stat;
-use nix::unistd;
+use nix::errno::Errno;
+use nix::mount::{MntFlags, MsFlags};
 
-use crate::container::{ContainerConfiguration, DeviceNode, Mount};
+use crate::cgroup;
+use crate::console;
+use crate::container::{ContainerConfiguration, DeviceNode, Mount, EPHEMERAL_DIR, INIT_PATH};
+use crate::dbus;
+use crate::diagnostics::Reporter;
 use crate::error::CartonError;
+use crate::files;
+use crate::notify;
+use crate::ownership::OwnedSource;
+use crate::rollback::{Rollback, Step};
+use crate::secrets;
+use crate::syscalls::Syscalls;
+
+/// Symlinks in /dev, with their targets.
+pub(crate) const DEV_SYMLINKS: [(&str, &str); 4] = [
+    ("fd", "/proc/self/fd"),
+    ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"),
+    ("stderr", "/proc/self/fd/2"),
+];
 
 /// Does the entire dance of setting up all the elements of the new processes' namespace, like
 /// creating devices nodes and actually mounting the root partition.
-pub(crate) fn setup_namespaces(config: &ContainerConfiguration) -> Result<(), CartonError> {
+///
+/// `idmapped_rootfs` is an ID-mapped copy of the root filesystem that was prepared by the parent
+/// (see `idmap::idmapped_tree()`), if the root filesystem should be ID-mapped. `owned_sources` are
+/// what to mount instead of the sources of owned mounts (see `ownership::prepare()`). `console` is the
+/// path of the container's terminal, if it has one. The system calls are made through
+/// `syscalls`, the steps of the setup are reported to `reporter`, and recorded in `rollback` to be
+/// undone when a later step fails.
+pub(crate) fn setup_namespaces(
+    config: &ContainerConfiguration,
+    syscalls: &dyn Syscalls,
+    reporter: &Reporter,
+    rollback: &Rollback,
+    idmapped_rootfs: Option<RawFd>,
+    owned_sources: &[Option<OwnedSource>],
+    console: Option<&Path>,
+) -> Result<(), CartonError> {
+    setup_uts_namespace(config, syscalls)?;
+
     if config.rootfs.is_some() {
-        setup_mount_namespace(config)?;
+        setup_mount_namespace(
+            config,
+            syscalls,
+            reporter,
+            rollback,
+            idmapped_rootfs,
+            owned_sources,
+            console,
+        )?;
+    } else if config.sandbox {
+        setup_sandbox_mount_namespace(
+            config,
+            syscalls,
+            reporter,
+            rollback,
+            owned_sources,
+            console,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// A sandbox keeps the host's filesystem, but in a mount namespace of its own, so that its mounts
+/// (like the /proc of its own PID namespace, see `ContainerBuilder::build()`) stay out of the
+/// host's sight. There's no pivot_root(), mounts are relative to the host's `/`.
+fn setup_sandbox_mount_namespace(
+    config: &ContainerConfiguration,
+    syscalls: &dyn Syscalls,
+    reporter: &Reporter,
+    rollback: &Rollback,
+    owned_sources: &[Option<OwnedSource>],
+    console: Option<&Path>,
+) -> Result<(), CartonError> {
+    let root = Path::new("/");
+    if has_shared_mounts()? {
+        syscalls.mount(
+            None,
+            root,
+            None,
+            MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+            None,
+        )?;
+    }
+
+    mount_all(config, root, syscalls, reporter, rollback, owned_sources)?;
+
+    if let Some(network_files) = &config.network_files {
+        for mount in network_files.mounts(&config.dir) {
+            mount.mount(root, syscalls, reporter, rollback)?;
+        }
+    }
+    for mount in files::mounts(&config.files, &config.dir) {
+        mount.mount(root, syscalls, reporter, rollback)?;
+    }
+
+    if !config.secrets.is_empty() {
+        secrets::mount_secrets(
+            &config.secrets,
+            config.user.unwrap_or((0, 0)),
+            root,
+            syscalls,
+            reporter,
+            rollback,
+        )?;
+    }
+
+    for provider in config.mount_providers.iter() {
+        reporter.info(format_args!("mounting {:?}", provider));
+        provider.mount(root)?;
+    }
+
+    if config.delegate_cgroup {
+        cgroup::mount().mount(root, syscalls, reporter, rollback)?;
+    }
+
+    if let Some(mount) = config.core_dumps.mount()? {
+        mount.mount(root, syscalls, reporter, rollback)?;
+    }
+
+    if let Some(console) = console {
+        console::mount_console(syscalls, reporter, rollback, console, root)?;
+    }
+
+    Ok(())
+}
+
+/// The UTS namespace contains the hostname and the NIS domain name. It starts out as a copy of the
+/// host's.
+fn setup_uts_namespace(
+    config: &ContainerConfiguration,
+    syscalls: &dyn Syscalls,
+) -> Result<(), CartonError> {
+    if let Some(hostname) = &config.hostname {
+        syscalls.sethostname(hostname)?;
+    }
+    if let Some(domainname) = &config.domainname {
+        syscalls.setdomainname(domainname)?;
+    }
+
+    Ok(())
+}
+
+/// Mounts the mounts of `config` in the root filesystem at `rootfs_path`, owned mounts from their
+/// entry in `owned_sources`.
+fn mount_all(
+    config: &ContainerConfiguration,
+    rootfs_path: &Path,
+    syscalls: &dyn Syscalls,
+    reporter: &Reporter,
+    rollback: &Rollback,
+    owned_sources: &[Option<OwnedSource>],
+) -> Result<(), CartonError> {
+    for (index, mount) in config.mounts.iter().enumerate() {
+        match owned_sources.get(index).and_then(Option::as_ref) {
+            Some(source) => source.mount(mount, rootfs_path, syscalls, reporter, rollback)?,
+            None => {
+                mount.mount(rootfs_path, syscalls, reporter, rollback)?;
+            }
+        }
     }
 
     Ok(())
 }
 
-fn setup_mount_namespace(config: &ContainerConfiguration) -> Result<(), CartonError> {
+fn setup_mount_namespace(
+    config: &ContainerConfiguration,
+    syscalls: &dyn Syscalls,
+    reporter: &Reporter,
+    rollback: &Rollback,
+    idmapped_rootfs: Option<RawFd>,
+    owned_sources: &[Option<OwnedSource>],
+    console: Option<&Path>,
+) -> Result<(), CartonError> {
     let rootfs = config
         .rootfs
         .as_ref()
@@ -30,15 +193,106 @@ fn setup_mount_namespace(config: &ContainerConfiguration) -> Result<(), CartonEr
         .as_ref()
         .expect("rootfs source path should not be None");
 
-    prepare_rootfs(rootfs)?;
+    prepare_rootfs(syscalls, rootfs)?;
+    rollback.record(Step::Mount(rootfs_source.clone()));
+
+    // Before anything is mounted inside the root filesystem, because the mapped copy of the mount
+    // doesn't include its submounts
+    if let Some(tree) = idmapped_rootfs {
+        reporter.info(format_args!(
+            "mapping the owners of the files in {}",
+            rootfs_source.display()
+        ));
+        syscalls.move_mount(tree, rootfs_source)?;
+        rollback.record(Step::Mount(rootfs_source.clone()));
+    }
+
+    // On top of the ID-mapped root filesystem, so that it becomes the lower layer
+    if config.ephemeral {
+        mount_ephemeral_overlay(
+            syscalls,
+            reporter,
+            rollback,
+            rootfs_source,
+            &config.dir.join(EPHEMERAL_DIR),
+            config.storage_limit,
+        )?;
+    }
+
+    mount_all(
+        config,
+        rootfs_source,
+        syscalls,
+        reporter,
+        rollback,
+        owned_sources,
+    )?;
 
-    for mount in config.mounts.iter() {
-        mount.mount(rootfs_source)?;
+    // The parent has written the files by now (the container waits for it to do so)
+    if let Some(network_files) = &config.network_files {
+        for mount in network_files.mounts(&config.dir) {
+            mount.mount(rootfs_source, syscalls, reporter, rollback)?;
+        }
+    }
+    for mount in files::mounts(&config.files, &config.dir) {
+        mount.mount(rootfs_source, syscalls, reporter, rollback)?;
     }
 
-    create_device_nodes(&rootfs_source.join("dev"), &config.devices)?;
+    if !config.secrets.is_empty() {
+        secrets::mount_secrets(
+            &config.secrets,
+            config.user.unwrap_or((0, 0)),
+            rootfs_source,
+            syscalls,
+            reporter,
+            rollback,
+        )?;
+    }
+
+    for provider in config.mount_providers.iter() {
+        reporter.info(format_args!("mounting {:?}", provider));
+        provider.mount(rootfs_source)?;
+    }
+
+    // After the other mounts, because it goes on top of /sys
+    if config.delegate_cgroup {
+        cgroup::mount().mount(rootfs_source, syscalls, reporter, rollback)?;
+    }
+
+    if config.notify_socket.is_some() {
+        notify::mount(&config.dir).mount(rootfs_source, syscalls, reporter, rollback)?;
+    }
+
+    if config.dbus_proxy.is_some() {
+        dbus::mount(&config.dir).mount(rootfs_source, syscalls, reporter, rollback)?;
+    }
+
+    if let Some(init) = &config.init {
+        Mount::bind(init.clone(), INIT_PATH.into(), None, None).mount(
+            rootfs_source,
+            syscalls,
+            reporter,
+            rollback,
+        )?;
+    }
+
+    if let Some(mount) = config.core_dumps.mount()? {
+        mount.mount(rootfs_source, syscalls, reporter, rollback)?;
+    }
+
+    create_device_nodes(
+        syscalls,
+        reporter,
+        rollback,
+        &rootfs_source.join("dev"),
+        &config.devices,
+    )?;
+
+    if let Some(console) = console {
+        console::mount_console(syscalls, reporter, rollback, console, rootfs_source)?;
+    }
 
-    mount_rootfs(rootfs)?;
+    mount_rootfs(syscalls, reporter, rootfs)?;
 
     Ok(())
 }
@@ -48,45 +302,168 @@ fn setup_mount_namespace(config: &ContainerConfiguration) -> Result<(), CartonEr
 ///
 /// If we don't do this first, further mounts will either not pass into the mount namespace after
 /// pivot_root() or affect the "host" system, messing up things.
-fn prepare_rootfs(rootfs: &Mount) -> Result<(), CartonError> {
+fn prepare_rootfs(syscalls: &dyn Syscalls, rootfs: &Mount) -> Result<(), CartonError> {
+    let source = rootfs
+        .source
+        .as_deref()
+        .expect("rootfs source path should not be None");
+
     // Remount root within our mount namespace and mark it as private, so that any changes to it
     // (like a umount) will not (try) to affect the real root partition.
-    mount::mount(
-        None::<&str>,
-        "/",
-        None::<&str>,
-        mount::MsFlags::MS_REC | mount::MsFlags::MS_PRIVATE,
-        None::<&str>,
-    )?;
+    //
+    // When carton itself runs inside a container all mounts may be private already, and the
+    // container runtime may not allow changing them, so it's left alone in that case.
+    if has_shared_mounts()? {
+        syscalls.mount(
+            None,
+            Path::new("/"),
+            None,
+            MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+            None,
+        )?;
+    }
 
     // Prepare the new root filesystem for mounting
-    mount::mount(
-        rootfs.source.as_ref(),
-        rootfs.source.as_ref().unwrap(),
-        None::<&str>,
-        mount::MsFlags::MS_BIND | mount::MsFlags::MS_PRIVATE,
-        None::<&str>,
+    syscalls.mount(
+        Some(source),
+        source,
+        None,
+        MsFlags::MS_BIND | MsFlags::MS_PRIVATE,
+        None,
     )?;
 
     Ok(())
 }
 
-fn create_device_nodes(dev_path: &Path, devices: &[DeviceNode]) -> Result<(), CartonError> {
-    let device_perm = stat::Mode::from_bits(0o0666).unwrap();
+/// Mounts an overlay on top of the root filesystem at `rootfs`, with its upper layer on a tmpfs at
+/// `tmpfs_dir`. Whatever the container writes ends up in the tmpfs, which only exists in the
+/// container's mount namespace and is gone when the container is. The tmpfs holds at most
+/// `size_limit` bytes, if given.
+fn mount_ephemeral_overlay(
+    syscalls: &dyn Syscalls,
+    reporter: &Reporter,
+    rollback: &Rollback,
+    rootfs: &Path,
+    tmpfs_dir: &Path,
+    size_limit: Option<u64>,
+) -> Result<(), CartonError> {
+    reporter.info(format_args!(
+        "mounting an ephemeral overlay on {}",
+        rootfs.display()
+    ));
+    let mut options = String::from("mode=0700");
+    if let Some(size_limit) = size_limit {
+        options.push_str(&format!(",size={}", size_limit));
+    }
+    syscalls.mount(
+        None,
+        tmpfs_dir,
+        Some("tmpfs"),
+        MsFlags::empty(),
+        Some(&options),
+    )?;
+    rollback.record(Step::Mount(tmpfs_dir.into()));
+
+    // The work directory has to be on the same filesystem as the upper directory
+    let upper = tmpfs_dir.join("upper");
+    let work = tmpfs_dir.join("work");
+    fs::create_dir(&upper)?;
+    fs::create_dir(&work)?;
+
+    // The lower directory is resolved before the overlay is mounted on top of it
+    syscalls.mount(
+        None,
+        rootfs,
+        Some("overlay"),
+        MsFlags::empty(),
+        Some(&format!(
+            "lowerdir={},upperdir={},workdir={}",
+            rootfs.display(),
+            upper.display(),
+            work.display()
+        )),
+    )?;
+    rollback.record(Step::Mount(rootfs.into()));
+
+    Ok(())
+}
+
+/// Returns whether any mount in the current mount namespace propagates mount events to or from
+/// other mount namespaces. These are marked with `shared:` in /proc/self/mountinfo.
+fn has_shared_mounts() -> Result<bool, CartonError> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+
+    // The optional fields, like `shared:1`, come after the first six fields and end with a `-`
+    Ok(mountinfo.lines().any(|line| {
+        line.split_whitespace()
+            .skip(6)
+            .take_while(|field| *field != "-")
+            .any(|field| field.starts_with("shared:"))
+    }))
+}
+
+/// Returns whether carton runs in a user namespace, like inside a rootless container. The initial
+/// user namespace maps all user IDs onto themselves.
+pub(crate) fn in_user_namespace() -> bool {
+    fs::read_to_string("/proc/self/uid_map").map_or(false, |uid_map| {
+        uid_map.split_whitespace().collect::<Vec<&str>>() != ["0", "0", "4294967295"]
+    })
+}
+
+fn create_device_nodes(
+    syscalls: &dyn Syscalls,
+    reporter: &Reporter,
+    rollback: &Rollback,
+    dev_path: &Path,
+    devices: &[DeviceNode],
+) -> Result<(), CartonError> {
+    // Device nodes can't be created in a user namespace other than the initial one, but the ones
+    // that the outer container has can be bind mounted
+    let bind_devices = in_user_namespace();
+    if bind_devices {
+        reporter.info(format_args!(
+            "running in a user namespace, bind mounting device nodes"
+        ));
+    }
+
     for node in devices {
-        stat::mknod(
-            &dev_path.join(&node.path),
-            stat::SFlag::S_IFCHR,
-            device_perm,
-            stat::makedev(node.major, node.minor),
-        )?;
+        let node_path = dev_path.join(&node.path);
+        // Some devices live in a subdirectory, like /dev/dri/card0
+        if let Some(parent) = node_path.parent() {
+            rollback.create_dir_all(parent)?;
+        }
+
+        if bind_devices {
+            let host_path = Path::new("/dev").join(&node.path);
+            if !host_path.exists() {
+                reporter.warning(format_args!(
+                    "Skipping {}, which doesn't exist",
+                    host_path.display()
+                ));
+                continue;
+            }
+            rollback.create_file(&node_path)?;
+            syscalls.mount(Some(&host_path), &node_path, None, MsFlags::MS_BIND, None)?;
+            rollback.record(Step::Mount(node_path));
+        } else {
+            syscalls.mknod(&node_path, node.major, node.minor)?;
+            rollback.record(Step::File(node_path));
+        }
     }
 
-    // These are symlinks from /proc on the "old" (current) root filesystem
-    unistd::symlinkat("/proc/self/fd", None, &dev_path.join("fd"))?;
-    unistd::symlinkat("/proc/self/fd/0", None, &dev_path.join("stdin"))?;
-    unistd::symlinkat("/proc/self/fd/1", None, &dev_path.join("stdout"))?;
-    unistd::symlinkat("/proc/self/fd/2", None, &dev_path.join("stderr"))?;
+    // These are symlinks from /proc on the "old" (current) root filesystem. Programs can do
+    // without them (the root filesystem may even have its own), so they don't fail the setup.
+    for (name, target) in DEV_SYMLINKS {
+        let link_path = dev_path.join(name);
+        match syscalls.symlink(Path::new(target), &link_path) {
+            Ok(()) => rollback.record(Step::File(link_path)),
+            Err(e) => reporter.warning(format_args!(
+                "failed to create the {} symlink: {}",
+                link_path.display(),
+                e
+            )),
+        }
+    }
 
     Ok(())
 }
@@ -94,7 +471,22 @@ fn create_device_nodes(dev_path: &Path, devices: &[DeviceNode]) -> Result<(), Ca
 /// Replacing the root mount inside the contaier consists of a few steps. This function marks all
 /// mount points with the right flags and then does the all-important `pivot_root()` that replaces
 /// the root mount inside the container with the new root filesystem.
-fn mount_rootfs(rootfs: &Mount) -> Result<(), CartonError> {
+///
+/// `pivot_root()` fails with `EINVAL` when the current root can't be unmounted, like the ramfs of
+/// an initramfs that a system runs from. The root filesystem is then moved onto `/` and entered
+/// with `chroot()` instead, like `switch_root` does. The host's root stays underneath, where a
+/// process in the container with `CAP_SYS_CHROOT` could get at it, so that's reported as a
+/// warning.
+fn mount_rootfs(
+    syscalls: &dyn Syscalls,
+    reporter: &Reporter,
+    rootfs: &Mount,
+) -> Result<(), CartonError> {
+    let source = rootfs
+        .source
+        .as_deref()
+        .expect("rootfs source path should not be None");
+
     // Pivot root to the new bind mount
     //
     // Instead of using a temporary "put_old" directory to mount the current root on, like
@@ -103,25 +495,209 @@ fn mount_rootfs(rootfs: &Mount) -> Result<(), CartonError> {
     // This stacks the mounts with the "old root" at the top of the stack. By umounting that layer
     // we get to the new "fake" root like we want, without having to create/delete a temporary
     // directory.
-    unistd::pivot_root(
-        rootfs.source.as_ref().unwrap(),
-        rootfs.source.as_ref().unwrap(),
-    )?;
+    match syscalls.pivot_root(source, source) {
+        Ok(()) => {}
+        Err(e) if e.errno == Errno::EINVAL => return chroot_rootfs(syscalls, reporter, source),
+        Err(e) => return Err(e.into()),
+    }
 
     // Re-mount the root yet again but mark it as "MS_SLAVE" so umount events will
     // in no circumstance propagate to outside the namespace.
     // See: https://github.com/opencontainers/runc/pull/1500.
-    mount::mount(
-        None::<&str>,
-        "/",
-        None::<&str>,
-        mount::MsFlags::MS_SLAVE | mount::MsFlags::MS_REC,
-        None::<&str>,
+    syscalls.mount(
+        None,
+        Path::new("/"),
+        None,
+        MsFlags::MS_SLAVE | MsFlags::MS_REC,
+        None,
     )?;
 
     // Unmount the "old" root filesystem which is currently sitting on top of the mount stack
     // (the "/" endpoint has been mounted multiple times at this point)
-    mount::umount2("/", mount::MntFlags::MNT_DETACH)?;
+    syscalls.umount(Path::new("/"), MntFlags::MNT_DETACH)?;
+
+    Ok(())
+}
+
+/// Makes the root filesystem at `source` the container's root without `pivot_root()`, see
+/// `mount_rootfs()`.
+fn chroot_rootfs(
+    syscalls: &dyn Syscalls,
+    reporter: &Reporter,
+    source: &Path,
+) -> Result<(), CartonError> {
+    reporter.warning(format_args!(
+        "pivot_root() isn't possible on this host, the container's root filesystem is entered \
+         with chroot() instead, which processes with CAP_SYS_CHROOT can escape"
+    ));
+
+    syscalls.chdir(source)?;
+    syscalls.mount(Some(source), Path::new("/"), None, MsFlags::MS_MOVE, None)?;
+    syscalls.chroot(Path::new("."))?;
+    syscalls.chdir(Path::new("/"))?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+
+    use super::*;
+    use crate::syscalls::{Recording, Syscall};
+
+    /// Creates an empty directory for the root filesystem of the test `name`.
+    fn rootfs_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("carton-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Sets up the namespaces of `config` with `syscalls`, and returns the calls that were made.
+    /// Making `/` private is left out, because it depends on the mounts of the host.
+    fn set_up(
+        config: &ContainerConfiguration,
+        syscalls: &Recording,
+        rollback: &Rollback,
+    ) -> Result<Vec<Syscall>, CartonError> {
+        setup_namespaces(
+            config,
+            syscalls,
+            &Reporter::default(),
+            rollback,
+            None,
+            &[],
+            None,
+        )?;
+
+        let mut calls = syscalls.calls();
+        calls.retain(|call| {
+            !matches!(call, Syscall::Mount { target, flags, .. }
+                if target == Path::new("/") && *flags == MsFlags::MS_REC | MsFlags::MS_PRIVATE)
+        });
+        Ok(calls)
+    }
+
+    fn mount(target: PathBuf, fstype: &str, flags: MsFlags, data: Option<&str>) -> Syscall {
+        Syscall::Mount {
+            source: None,
+            target,
+            fstype: Some(fstype.into()),
+            flags,
+            data: data.map(String::from),
+        }
+    }
+
+    #[test]
+    fn mounts_in_order_and_pivots_into_the_rootfs() {
+        let rootfs = rootfs_dir("mount-order");
+        let config = ContainerConfiguration {
+            rootfs: Some(Mount::rootfs(rootfs.clone())),
+            mounts: vec![
+                Mount::procfs(),
+                Mount::tmpfs_with_options("tmp".into(), MsFlags::MS_NOSUID, "mode=1777".into()),
+            ],
+            hostname: Some("box".into()),
+            ..Default::default()
+        };
+
+        let calls = set_up(&config, &Recording::default(), &Rollback::default()).unwrap();
+        let mut expected = vec![
+            Syscall::SetHostname("box".into()),
+            Syscall::Mount {
+                source: Some(rootfs.clone()),
+                target: rootfs.clone(),
+                fstype: None,
+                flags: MsFlags::MS_BIND | MsFlags::MS_PRIVATE,
+                data: None,
+            },
+            mount(rootfs.join("proc"), "proc", MsFlags::empty(), None),
+            mount(
+                rootfs.join("tmp"),
+                "tmpfs",
+                MsFlags::MS_NOSUID,
+                Some("mode=1777"),
+            ),
+        ];
+        expected.extend(DEV_SYMLINKS.iter().map(|(name, target)| Syscall::Symlink {
+            target: target.into(),
+            path: rootfs.join("dev").join(name),
+        }));
+        expected.extend([
+            Syscall::PivotRoot(rootfs.clone()),
+            Syscall::Mount {
+                source: None,
+                target: "/".into(),
+                fstype: None,
+                flags: MsFlags::MS_SLAVE | MsFlags::MS_REC,
+                data: None,
+            },
+            Syscall::Umount("/".into()),
+        ]);
+        assert_eq!(calls, expected);
+        assert!(rootfs.join("proc").is_dir());
+        assert!(rootfs.join("tmp").is_dir());
+
+        fs::remove_dir_all(&rootfs).unwrap();
+    }
+
+    #[test]
+    fn mounts_a_sandbox_on_the_hosts_root() {
+        let config = ContainerConfiguration {
+            mounts: vec![Mount::procfs()],
+            sandbox: true,
+            ..Default::default()
+        };
+
+        let calls = set_up(&config, &Recording::default(), &Rollback::default()).unwrap();
+        assert_eq!(
+            calls,
+            [mount("/proc".into(), "proc", MsFlags::empty(), None)]
+        );
+    }
+
+    #[test]
+    fn stops_at_a_failing_mount_and_rolls_back() {
+        let rootfs = rootfs_dir("failing-mount");
+        let config = ContainerConfiguration {
+            rootfs: Some(Mount::rootfs(rootfs.clone())),
+            mounts: vec![
+                Mount::procfs(),
+                Mount::tmpfs("tmp".into()),
+                Mount::tmpfs("run".into()),
+            ],
+            ..Default::default()
+        };
+        let syscalls = Recording::default().fail_mount(rootfs.join("tmp"), Errno::EPERM);
+        let rollback = Rollback::default();
+
+        let error = set_up(&config, &syscalls, &rollback).unwrap_err();
+        assert!(
+            matches!(&error, CartonError::PermissionDenied { .. }),
+            "{:?}",
+            error
+        );
+        let calls = syscalls.calls();
+        assert_eq!(
+            calls.last(),
+            Some(&mount(rootfs.join("proc"), "proc", MsFlags::empty(), None))
+        );
+        assert!(!rootfs.join("run").exists());
+
+        rollback.undo(&syscalls, &Reporter::default());
+        assert_eq!(
+            syscalls.calls()[calls.len()..],
+            [
+                Syscall::Umount(rootfs.join("proc")),
+                Syscall::Umount(rootfs.clone())
+            ]
+        );
+        assert!(!rootfs.join("proc").exists());
+        assert!(!rootfs.join("tmp").exists());
+
+        fs::remove_dir(&rootfs).unwrap();
+    }
+}
@@ -0,0 +1,1189 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Networking for containers that get their own network namespace.
+//!
+//! A network namespace starts out with nothing but a loopback interface, so for a container to
+//! reach the outside world carton creates a "veth" pair: two virtual network interfaces that act
+//! like the ends of a cable. One end is attached to a bridge on the host, the other end is moved
+//! into the container's namespace. The bridge acts as the gateway for all containers and traffic
+//! leaving the bridge is NAT'ed ("masqueraded") behind the host's own address.
+//!
+//! Instead of talking netlink directly, the interfaces are configured with the `ip` and `iptables`
+//! tools, which keeps the steps easy to follow and to reproduce by hand.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::iter;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
+
+use log::{info, warn};
+
+use nix::errno::Errno;
+use nix::fcntl::{self, FlockArg};
+use nix::sched::{self, CloneFlags};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+
+use crate::cni::{self, CniAttachment, CniNetwork};
+use crate::consts::{DEFAULT_BRIDGE_IPV6_SUBNET, DEFAULT_BRIDGE_NAME, DEFAULT_BRIDGE_SUBNET};
+use crate::container::Container;
+use crate::error::CartonError;
+use crate::paths::Paths;
+use crate::state::ContainerInfo;
+
+/// How the container is connected to the network.
+#[derive(Default, Debug, Clone)]
+pub enum Network {
+    /// The container shares the network namespace of the host.
+    #[default]
+    Host,
+    /// The container gets its own network namespace, connected to a bridge on the host.
+    Bridge(BridgeNetwork),
+    /// The container gets its own network namespace with a macvlan or ipvlan interface on top of
+    /// one of the host's network interfaces, putting it directly on the host's network.
+    Macvlan(MacvlanNetwork),
+    /// The container joins the network namespace of another container, so that they can reach
+    /// each other over localhost, like the containers of a Kubernetes pod.
+    Shared(SharedNetwork),
+    /// The container gets its own network namespace, which is connected by CNI plugins.
+    Cni(CniNetwork),
+}
+
+/// A bridge on the host (`carton0` by default) that containers are connected to, with addresses
+/// handed out from `subnet` and, when IPv6 is enabled, from `ipv6_subnet`.
+#[derive(Debug, Clone)]
+pub struct BridgeNetwork {
+    pub(crate) name: String,
+    pub(crate) subnet: Ipv4Network,
+    pub(crate) ipv6_subnet: Ipv6Network,
+    pub(crate) ipv4: bool,
+    pub(crate) ipv6: bool,
+    /// Directory in which the address leases are kept, in a subdirectory per bridge.
+    pub(crate) lease_dir: PathBuf,
+    /// Static address of the container, or `None` to get the first free address of the subnet.
+    pub(crate) address: Option<Ipv4Addr>,
+    pub(crate) interface: InterfaceSettings,
+}
+
+impl Default for BridgeNetwork {
+    fn default() -> Self {
+        BridgeNetwork {
+            name: DEFAULT_BRIDGE_NAME.into(),
+            subnet: DEFAULT_BRIDGE_SUBNET
+                .parse()
+                .expect("default subnet should be valid"),
+            ipv6_subnet: DEFAULT_BRIDGE_IPV6_SUBNET
+                .parse()
+                .expect("default IPv6 subnet should be valid"),
+            ipv4: true,
+            ipv6: false,
+            lease_dir: Paths::new().lease_dir(),
+            address: None,
+            interface: InterfaceSettings::default(),
+        }
+    }
+}
+
+impl BridgeNetwork {
+    pub fn new() -> Self {
+        BridgeNetwork::default()
+    }
+
+    pub fn name(mut self, name: String) -> Self {
+        self.name = name;
+        self
+    }
+
+    pub fn subnet(mut self, subnet: Ipv4Network) -> Self {
+        self.subnet = subnet;
+        self
+    }
+
+    pub fn ipv6_subnet(mut self, ipv6_subnet: Ipv6Network) -> Self {
+        self.ipv6_subnet = ipv6_subnet;
+        self
+    }
+
+    /// Whether containers get an IPv4 address (enabled by default).
+    pub fn ipv4(mut self, enabled: bool) -> Self {
+        self.ipv4 = enabled;
+        self
+    }
+
+    /// Whether containers get an IPv6 address (disabled by default). The bridge needs the host to
+    /// forward IPv6 traffic, which makes the kernel ignore router advertisements on interfaces that
+    /// have `accept_ra` set to 1. A host that configures its own IPv6 address with SLAAC should set
+    /// `accept_ra` to 2 before enabling this.
+    pub fn ipv6(mut self, enabled: bool) -> Self {
+        self.ipv6 = enabled;
+        self
+    }
+
+    pub fn lease_dir(mut self, lease_dir: PathBuf) -> Self {
+        self.lease_dir = lease_dir;
+        self
+    }
+
+    /// Gives the container this address, which should be a free address in the subnet.
+    pub fn address(mut self, address: Ipv4Addr) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    pub fn mac_address(mut self, mac_address: MacAddress) -> Self {
+        self.interface.mac_address = Some(mac_address);
+        self
+    }
+
+    /// Adds a route next to the default route via the bridge.
+    pub fn add_route(mut self, route: Route) -> Self {
+        self.interface.routes.push(route);
+        self
+    }
+
+    /// The first address of the subnet is assigned to the bridge itself, making it the default
+    /// gateway of the containers.
+    fn gateway(&self) -> Ipv4Addr {
+        self.host_addresses(1).0.expect("IPv4 should be enabled")
+    }
+
+    fn ipv6_gateway(&self) -> Ipv6Addr {
+        self.host_addresses(1).1.expect("IPv6 should be enabled")
+    }
+
+    /// Returns the addresses with host number `host` in the subnets that are enabled. A container
+    /// gets the same host number in both subnets, so `10.88.0.5` goes together with `fd88::5`.
+    fn host_addresses(&self, host: u128) -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+        let ipv4 = self
+            .ipv4
+            .then(|| Ipv4Addr::from(u32::from(self.subnet.network()) + host as u32));
+        let ipv6 = self
+            .ipv6
+            .then(|| Ipv6Addr::from(u128::from(self.ipv6_subnet.network()) + host));
+
+        (ipv4, ipv6)
+    }
+
+    /// Returns the number of host numbers in the (smallest) subnet, including the ones of the
+    /// network itself and of the gateway. IPv4 subnets lose the last one to the broadcast address.
+    fn host_count(&self) -> u128 {
+        if self.ipv4 {
+            return (1u128 << (32 - self.subnet.prefix())) - 1;
+        }
+
+        1u128
+            .checked_shl(128 - self.ipv6_subnet.prefix() as u32)
+            .unwrap_or(u128::MAX)
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), CartonError> {
+        // Interface names are limited to 15 characters (IFNAMSIZ - 1)
+        if self.name.is_empty() || self.name.len() > 15 {
+            return Err(CartonError::InvalidConfiguration(format!(
+                "bridge name should be 1 to 15 characters long: {}",
+                self.name
+            )));
+        }
+
+        if !self.ipv4 && !self.ipv6 {
+            return Err(CartonError::InvalidConfiguration(
+                "a bridge network needs IPv4, IPv6 or both".into(),
+            ));
+        }
+
+        // The network address, the gateway and the broadcast address leave no room for containers
+        // in anything smaller than a /30
+        if self.ipv4 && self.subnet.prefix() > 30 {
+            return Err(CartonError::InvalidConfiguration(format!(
+                "subnet is too small: {}",
+                self.subnet
+            )));
+        }
+
+        if self.ipv6 {
+            if self.ipv6_subnet.prefix() > 126 {
+                return Err(CartonError::InvalidConfiguration(format!(
+                    "IPv6 subnet is too small: {}",
+                    self.ipv6_subnet
+                )));
+            }
+
+            if self.ipv4 && 128 - self.ipv6_subnet.prefix() < 32 - self.subnet.prefix() {
+                return Err(CartonError::InvalidConfiguration(format!(
+                    "IPv6 subnet {} should have room for as many containers as subnet {}",
+                    self.ipv6_subnet, self.subnet
+                )));
+            }
+        }
+
+        if self.address.is_some() && !self.ipv4 {
+            return Err(CartonError::InvalidConfiguration(
+                "a static address needs IPv4 to be enabled".into(),
+            ));
+        }
+
+        self.interface.validate(self.ipv4, self.ipv6)?;
+
+        if let Some(address) = self.address {
+            if !self.subnet.contains(address)
+                || address == self.subnet.network()
+                || address == self.subnet.broadcast()
+                || address == self.gateway()
+            {
+                return Err(CartonError::InvalidConfiguration(format!(
+                    "address {} is not available in subnet {}",
+                    address, self.subnet
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// An interface that is stacked on top of a network interface of the host (the "parent"), so that
+/// the container appears as a separate machine on the parent's network.
+#[derive(Debug, Clone)]
+pub struct MacvlanNetwork {
+    pub(crate) parent: String,
+    pub(crate) kind: MacvlanKind,
+    /// Static address of the container, or `None` to get one with DHCP.
+    pub(crate) address: Option<Ipv4Network>,
+    pub(crate) gateway: Option<Ipv4Addr>,
+    pub(crate) ipv4: bool,
+    /// The interface configures its own IPv6 addresses when there's a router on the network that
+    /// sends router advertisements (SLAAC).
+    pub(crate) ipv6: bool,
+    pub(crate) interface: InterfaceSettings,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacvlanKind {
+    /// Every container gets its own MAC address.
+    Macvlan,
+    /// All containers share the MAC address of the parent, which is needed on networks (like most
+    /// Wi-Fi networks) that only accept one MAC address per port.
+    Ipvlan,
+}
+
+impl MacvlanNetwork {
+    pub fn new(parent: String, kind: MacvlanKind) -> Self {
+        MacvlanNetwork {
+            parent,
+            kind,
+            address: None,
+            gateway: None,
+            ipv4: true,
+            ipv6: true,
+            interface: InterfaceSettings::default(),
+        }
+    }
+
+    /// Gives the container a static address (and the prefix length of its network) instead of
+    /// requesting one with DHCP.
+    pub fn address(mut self, address: Ipv4Network) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Sets the default gateway when the container has a static address. With DHCP the gateway is
+    /// provided by the DHCP server.
+    pub fn gateway(mut self, gateway: Ipv4Addr) -> Self {
+        self.gateway = Some(gateway);
+        self
+    }
+
+    /// Whether the container gets an IPv4 address, with DHCP or a static one (enabled by default).
+    pub fn ipv4(mut self, enabled: bool) -> Self {
+        self.ipv4 = enabled;
+        self
+    }
+
+    /// Whether the container configures IPv6 addresses (enabled by default).
+    pub fn ipv6(mut self, enabled: bool) -> Self {
+        self.ipv6 = enabled;
+        self
+    }
+
+    /// Sets the MAC address of a macvlan interface. An ipvlan interface always has the MAC address
+    /// of its parent.
+    pub fn mac_address(mut self, mac_address: MacAddress) -> Self {
+        self.interface.mac_address = Some(mac_address);
+        self
+    }
+
+    pub fn add_route(mut self, route: Route) -> Self {
+        self.interface.routes.push(route);
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), CartonError> {
+        if self.parent.is_empty() || !Path::new("/sys/class/net").join(&self.parent).exists() {
+            return Err(CartonError::InvalidConfiguration(format!(
+                "parent interface does not exist: {:?}",
+                self.parent
+            )));
+        }
+
+        if self.gateway.is_some() && self.address.is_none() {
+            return Err(CartonError::InvalidConfiguration(
+                "a gateway can only be set together with a static address".into(),
+            ));
+        }
+
+        if self.kind == MacvlanKind::Ipvlan && self.interface.mac_address.is_some() {
+            return Err(CartonError::InvalidConfiguration(
+                "an ipvlan interface can't have its own MAC address".into(),
+            ));
+        }
+
+        if !self.ipv4 && !self.ipv6 {
+            return Err(CartonError::InvalidConfiguration(
+                "a macvlan or ipvlan network needs IPv4, IPv6 or both".into(),
+            ));
+        }
+
+        if self.address.is_some() && !self.ipv4 {
+            return Err(CartonError::InvalidConfiguration(
+                "a static address needs IPv4 to be enabled".into(),
+            ));
+        }
+
+        self.interface.validate(self.ipv4, self.ipv6)
+    }
+}
+
+/// Maximum rates of the traffic to (ingress) and from (egress) a container, in bits per second.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BandwidthLimit {
+    pub(crate) ingress: Option<u64>,
+    pub(crate) egress: Option<u64>,
+}
+
+impl BandwidthLimit {
+    pub fn new() -> Self {
+        BandwidthLimit::default()
+    }
+
+    pub fn ingress(mut self, bits_per_second: u64) -> Self {
+        self.ingress = Some(bits_per_second);
+        self
+    }
+
+    pub fn egress(mut self, bits_per_second: u64) -> Self {
+        self.egress = Some(bits_per_second);
+        self
+    }
+}
+
+/// Settings of the container's network interface that apply to several kinds of networks.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct InterfaceSettings {
+    pub(crate) mac_address: Option<MacAddress>,
+    /// Routes in addition to the default route.
+    pub(crate) routes: Vec<Route>,
+}
+
+impl InterfaceSettings {
+    fn validate(&self, ipv4: bool, ipv6: bool) -> Result<(), CartonError> {
+        for route in &self.routes {
+            let enabled = match route.destination {
+                IpNetwork::V4(_) => ipv4 && route.gateway.is_ipv4(),
+                IpNetwork::V6(_) => ipv6 && route.gateway.is_ipv6(),
+            };
+            if !enabled {
+                return Err(CartonError::InvalidConfiguration(format!(
+                    "route to {} via {} doesn't match the enabled IP versions",
+                    route.destination, route.gateway
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A route to `destination` through `gateway`. Both should be of the same IP version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub destination: IpNetwork,
+    pub gateway: IpAddr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl FromStr for MacAddress {
+    type Err = CartonError;
+
+    /// Parses the usual notation of six colon-separated hexadecimal bytes, like
+    /// `02:42:ac:11:00:02`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || CartonError::InvalidConfiguration(format!("invalid MAC address: {}", s));
+
+        let mut bytes = [0u8; 6];
+        let mut parts = s.split(':');
+        for byte in bytes.iter_mut() {
+            let part = parts
+                .next()
+                // from_str_radix() also takes a sign, like `+f`
+                .filter(|part| part.len() == 2 && part.bytes().all(|b| b.is_ascii_hexdigit()))
+                .ok_or_else(invalid)?;
+            *byte = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+
+        // The lowest bit of the first byte marks multicast addresses, which an interface can't have
+        if bytes[0] & 1 == 1 {
+            return Err(invalid());
+        }
+
+        Ok(MacAddress(bytes))
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+/// The network namespace of another ("infra") container.
+#[derive(Debug, Clone)]
+pub struct SharedNetwork {
+    /// PID of the infra container's process, if it's running.
+    pub(crate) pid: Option<Pid>,
+    /// Address of the infra container, if it has one of its own.
+    pub(crate) address: Option<IpAddr>,
+    pub(crate) ipv6_address: Option<Ipv6Addr>,
+}
+
+impl SharedNetwork {
+    pub fn from_container(container: &Container) -> Self {
+        SharedNetwork {
+            pid: container.pid,
+            address: container
+                .network
+                .as_ref()
+                .and_then(|attachment| attachment.address),
+            ipv6_address: container
+                .network
+                .as_ref()
+                .and_then(|attachment| attachment.ipv6_address),
+        }
+    }
+
+    pub fn from_info(info: &ContainerInfo) -> Self {
+        SharedNetwork {
+            pid: info.pid.map(Pid::from_raw),
+            address: info.ip_address,
+            ipv6_address: info.ipv6_address,
+        }
+    }
+
+    pub(crate) fn namespace_path(&self) -> Option<PathBuf> {
+        self.pid
+            .map(|pid| PathBuf::from(format!("/proc/{}/ns/net", pid)))
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), CartonError> {
+        match self.namespace_path() {
+            Some(path) if path.exists() => Ok(()),
+            _ => Err(CartonError::InvalidConfiguration(
+                "the container to share the network with is not running".into(),
+            )),
+        }
+    }
+}
+
+/// The result of connecting a container to a network, needed to disconnect it again.
+#[derive(Debug, Default)]
+pub(crate) struct NetworkAttachment {
+    /// Address of the container on the network, if it's known. This is the IPv4 address when the
+    /// container has one.
+    pub(crate) address: Option<IpAddr>,
+    pub(crate) ipv6_address: Option<Ipv6Addr>,
+    /// The host's end of a veth pair.
+    host_interface: Option<String>,
+    /// The file that holds the address lease on a bridge.
+    lease_path: Option<PathBuf>,
+    /// PID file of the DHCP client that keeps renewing the container's address.
+    dhcp_client_pid_file: Option<PathBuf>,
+    /// What the CNI plugins need to disconnect the container again.
+    cni: Option<CniAttachment>,
+}
+
+impl NetworkAttachment {
+    pub(crate) fn addresses(&self) -> Vec<IpAddr> {
+        self.address
+            .into_iter()
+            .chain(self.ipv6_address.map(IpAddr::from))
+            .collect()
+    }
+}
+
+/// Connects the network namespace of the container process `pid` to the bridge, creating the
+/// bridge and NAT rules first if they don't exist yet.
+pub(crate) fn connect_bridge(
+    network: &BridgeNetwork,
+    container_id: &str,
+    pid: Pid,
+) -> Result<NetworkAttachment, CartonError> {
+    ensure_bridge(network)?;
+    ensure_nat(network)?;
+
+    let (host, lease_path) = lease_address(network, container_id, pid)?;
+    let (address, ipv6_address) = network.host_addresses(host);
+    let host_interface = format!("veth{}", &container_id[..8]);
+    let attachment = NetworkAttachment {
+        address: address.map(IpAddr::from),
+        ipv6_address,
+        host_interface: Some(host_interface.clone()),
+        lease_path: Some(lease_path),
+        ..Default::default()
+    };
+
+    if let Err(e) = configure_veth_pair(network, &host_interface, host, container_id, pid) {
+        disconnect(&attachment);
+        return Err(e);
+    }
+
+    Ok(attachment)
+}
+
+/// Moves a new macvlan or ipvlan interface into the network namespace of the container process
+/// `pid` and gives it an address. DHCP clients write their files to `container_dir`.
+pub(crate) fn connect_macvlan(
+    network: &MacvlanNetwork,
+    container_id: &str,
+    pid: Pid,
+    container_dir: &Path,
+) -> Result<NetworkAttachment, CartonError> {
+    let interface = format!("mvl{}", &container_id[..8]);
+    let kind = match network.kind {
+        MacvlanKind::Macvlan => ["macvlan", "mode", "bridge"],
+        MacvlanKind::Ipvlan => ["ipvlan", "mode", "l2"],
+    };
+
+    ip(&[
+        &["link", "add", &interface, "link", &network.parent, "type"][..],
+        &kind,
+    ]
+    .concat())?;
+    if let Err(e) = ip(&["link", "set", &interface, "netns", &pid.to_string()]) {
+        let _ = ip(&["link", "del", &interface]);
+        return Err(e);
+    }
+
+    // From here on the commands are executed inside the container's network namespace. The
+    // interface disappears together with the namespace, so there's no need to clean it up when
+    // anything goes wrong.
+    ip_in_namespace(pid, &["link", "set", "lo", "up"])?;
+    ip_in_namespace(pid, &["link", "set", &interface, "name", "eth0"])?;
+    set_mac_address(pid, &network.interface)?;
+    if !network.ipv6 {
+        disable_ipv6(pid)?;
+    }
+    ip_in_namespace(pid, &["link", "set", "eth0", "up"])?;
+
+    let attachment = match network.address {
+        None if !network.ipv4 => NetworkAttachment::default(),
+        None => request_dhcp_address(pid, container_dir)?,
+        Some(address) => {
+            ip_in_namespace(pid, &["addr", "add", &address.to_string(), "dev", "eth0"])?;
+            if let Some(gateway) = network.gateway {
+                ip_in_namespace(
+                    pid,
+                    &["route", "add", "default", "via", &gateway.to_string()],
+                )?;
+            }
+
+            NetworkAttachment {
+                address: Some(address.ip().into()),
+                ..Default::default()
+            }
+        }
+    };
+
+    add_routes(pid, &network.interface)?;
+
+    Ok(attachment)
+}
+
+/// The container process joins the shared network namespace by itself, so there's nothing to set
+/// up here.
+pub(crate) fn connect_shared(network: &SharedNetwork) -> NetworkAttachment {
+    NetworkAttachment {
+        address: network.address,
+        ipv6_address: network.ipv6_address,
+        ..Default::default()
+    }
+}
+
+/// Lets the CNI plugins of the network connect the network namespace of the container process
+/// `pid`.
+pub(crate) fn connect_cni(
+    network: &CniNetwork,
+    container_id: &str,
+    pid: Pid,
+    container_dir: &Path,
+) -> Result<NetworkAttachment, CartonError> {
+    let (address, ipv6_address, cni_attachment) =
+        cni::add(network, container_id, pid, container_dir)?;
+
+    Ok(NetworkAttachment {
+        address: address.map(IpAddr::from),
+        ipv6_address,
+        cni: Some(cni_attachment),
+        ..Default::default()
+    })
+}
+
+/// Limits the traffic through the host's end of the container's veth pair. What the host sends
+/// into the veth is what the container receives, so the container's ingress is shaped with a token
+/// bucket filter on the veth's egress side. Traffic coming from the container can't be queued
+/// there, so anything above the egress limit is dropped ("policed") instead. Because the limits
+/// live on the host, the container can't lift them.
+pub(crate) fn limit_bandwidth(
+    attachment: &NetworkAttachment,
+    limit: &BandwidthLimit,
+) -> Result<(), CartonError> {
+    let Some(host_interface) = &attachment.host_interface else {
+        return Err(CartonError::NetworkError(
+            "bandwidth limits need a veth pair".into(),
+        ));
+    };
+
+    if let Some(rate) = limit.ingress {
+        tc(&[
+            "qdisc",
+            "add",
+            "dev",
+            host_interface,
+            "root",
+            "tbf",
+            "rate",
+            &format!("{}bit", rate),
+            "burst",
+            &bandwidth_burst(rate).to_string(),
+            "latency",
+            "50ms",
+        ])?;
+    }
+
+    if let Some(rate) = limit.egress {
+        tc(&[
+            "qdisc",
+            "add",
+            "dev",
+            host_interface,
+            "handle",
+            "ffff:",
+            "ingress",
+        ])?;
+        tc(&[
+            "filter",
+            "add",
+            "dev",
+            host_interface,
+            "parent",
+            "ffff:",
+            "protocol",
+            "all",
+            "u32",
+            "match",
+            "u32",
+            "0",
+            "0",
+            "police",
+            "rate",
+            &format!("{}bit", rate),
+            "burst",
+            &bandwidth_burst(rate).to_string(),
+            "drop",
+            "flowid",
+            ":1",
+        ])?;
+    }
+
+    Ok(())
+}
+
+/// Returns how many bytes can be sent at once before the limit kicks in: 10ms worth of traffic,
+/// but at least 64KiB. A veth passes on packets of up to 64KiB (before they're split up for the
+/// wire) and anything bigger than the burst would never get through.
+fn bandwidth_burst(rate: u64) -> u64 {
+    (rate / 8 / 100).max(64 * 1024)
+}
+
+/// Undoes what was done to connect the container to its network. Interfaces that were moved into
+/// the container's network namespace are removed by the kernel when the namespace is destroyed.
+pub(crate) fn disconnect(attachment: &NetworkAttachment) {
+    if let Some(cni_attachment) = &attachment.cni {
+        cni::del(cni_attachment);
+    }
+
+    if let Some(host_interface) = &attachment.host_interface {
+        // When the container's namespace is destroyed the kernel removes both ends of the veth pair
+        // by itself, so it might be gone already
+        if Path::new("/sys/class/net").join(host_interface).exists() {
+            if let Err(e) = ip(&["link", "del", host_interface]) {
+                warn!("Could not remove {}: {}", host_interface, e);
+            }
+        }
+    }
+
+    if let Some(lease_path) = &attachment.lease_path {
+        if let Err(e) = fs::remove_file(lease_path) {
+            warn!("Could not release lease {}: {}", lease_path.display(), e);
+        }
+    }
+
+    if let Some(pid_file) = &attachment.dhcp_client_pid_file {
+        stop_dhcp_client(pid_file);
+    }
+}
+
+/// Creates the bridge if it doesn't exist yet and gives it the gateway addresses. A bridge that
+/// was created for IPv4 only gets its IPv6 address when the first container with IPv6 comes along.
+fn ensure_bridge(network: &BridgeNetwork) -> Result<(), CartonError> {
+    if !Path::new("/sys/class/net").join(&network.name).exists() {
+        info!("Creating bridge {}", network.name);
+        ip(&["link", "add", "name", &network.name, "type", "bridge"])?;
+    }
+
+    let addresses = ip(&["-o", "addr", "show", "dev", &network.name])?;
+    let has_address = |address: &str| addresses.split_whitespace().any(|field| field == address);
+
+    if network.ipv4 {
+        let gateway = format!("{}/{}", network.gateway(), network.subnet.prefix());
+        if !has_address(&gateway) {
+            ip(&["addr", "add", &gateway, "dev", &network.name])?;
+        }
+    }
+    if network.ipv6 {
+        let gateway = format!(
+            "{}/{}",
+            network.ipv6_gateway(),
+            network.ipv6_subnet.prefix()
+        );
+        if !has_address(&gateway) {
+            // Duplicate address detection would keep the address unusable for a second or so
+            ip(&["addr", "add", &gateway, "dev", &network.name, "nodad"])?;
+        }
+    }
+
+    ip(&["link", "set", &network.name, "up"])?;
+
+    Ok(())
+}
+
+/// Makes sure that the host forwards traffic from and to the bridge and that traffic leaving the
+/// bridge gets the host's address as its source ("masquerading"). The addresses of the IPv6 subnet
+/// are usually not routable either (they're unique local addresses by default), so IPv6 traffic is
+/// masqueraded too.
+fn ensure_nat(network: &BridgeNetwork) -> Result<(), CartonError> {
+    if network.ipv4 {
+        fs::write("/proc/sys/net/ipv4/ip_forward", "1")?;
+        ensure_nat_rules("iptables", &network.name, &network.subnet.to_string())?;
+    }
+
+    if network.ipv6 {
+        fs::write("/proc/sys/net/ipv6/conf/all/forwarding", "1")?;
+        ensure_nat_rules("ip6tables", &network.name, &network.ipv6_subnet.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// Adds the masquerading and forwarding rules for `subnet` with `tool` (`iptables` or `ip6tables`),
+/// unless they're there already.
+fn ensure_nat_rules(tool: &str, bridge: &str, subnet: &str) -> Result<(), CartonError> {
+    let rules: [&[&str]; 3] = [
+        &[
+            "-t",
+            "nat",
+            "POSTROUTING",
+            "-s",
+            subnet,
+            "!",
+            "-o",
+            bridge,
+            "-j",
+            "MASQUERADE",
+        ],
+        &["-t", "filter", "FORWARD", "-i", bridge, "-j", "ACCEPT"],
+        &["-t", "filter", "FORWARD", "-o", bridge, "-j", "ACCEPT"],
+    ];
+
+    for rule in rules {
+        // The table comes before the command, the chain and rule specification after it
+        let (table, chain_and_spec) = rule.split_at(2);
+        let check = [table, &["-C"], chain_and_spec].concat();
+        if run(Command::new(tool).args(&check)).is_err() {
+            let append = [table, &["-A"], chain_and_spec].concat();
+            run(Command::new(tool).args(&append))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Hands out the first free host number of the subnet(s), see `BridgeNetwork::host_addresses()`.
+/// Every address in use is "leased" by creating a file named after it in the lease directory
+/// (after the IPv4 address when the container gets both). Creating the file fails when another
+/// container already holds the lease, so concurrently started containers never get the same
+/// address. The lease directory is locked while an address is leased, so that two containers
+/// can't both find the same lease stale and reclaim it, and a lease is never seen half-written.
+fn lease_address(
+    network: &BridgeNetwork,
+    container_id: &str,
+    pid: Pid,
+) -> Result<(u128, PathBuf), CartonError> {
+    let lease_dir = network.lease_dir.join(&network.name);
+    fs::create_dir_all(&lease_dir)?;
+    let lock = File::open(&lease_dir)?;
+    fcntl::flock(lock.as_raw_fd(), FlockArg::LockExclusive)?;
+
+    // Host number 0 is the network itself and 1 is the gateway
+    let candidates: Box<dyn Iterator<Item = u128>> = match network.address {
+        Some(address) => Box::new(iter::once(
+            (u32::from(address) - u32::from(network.subnet.network())) as u128,
+        )),
+        None => Box::new(2..network.host_count()),
+    };
+
+    for host in candidates {
+        let address = match network.host_addresses(host) {
+            (Some(address), _) => IpAddr::from(address),
+            (None, Some(address)) => IpAddr::from(address),
+            (None, None) => unreachable!("validated that IPv4 or IPv6 is enabled"),
+        };
+        let lease_path = lease_dir.join(address.to_string());
+
+        for _ in 0..2 {
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&lease_path)
+            {
+                Ok(mut file) => {
+                    writeln!(file, "{} {}", container_id, pid)?;
+                    info!("Leased address {} to container {}", address, container_id);
+                    return Ok((host, lease_path));
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    if !is_stale_lease(&lease_path) {
+                        break;
+                    }
+
+                    // The container holding the lease is gone without releasing it (carton was
+                    // probably killed), so reclaim the address
+                    info!("Removing stale lease {}", lease_path.display());
+                    match fs::remove_file(&lease_path) {
+                        // Released in the meantime, which doesn't take the lock
+                        Err(e) if e.kind() == ErrorKind::NotFound => {}
+                        result => result?,
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    match network.address {
+        Some(address) => Err(CartonError::NetworkError(format!(
+            "address {} is already in use",
+            address
+        ))),
+        None => Err(CartonError::NetworkError(format!(
+            "no free addresses left on bridge {}",
+            network.name
+        ))),
+    }
+}
+
+/// A lease is stale when the process that was given the address doesn't exist anymore.
+fn is_stale_lease(lease_path: &Path) -> bool {
+    let Ok(contents) = fs::read_to_string(lease_path) else {
+        return false;
+    };
+
+    match contents
+        .split_whitespace()
+        .nth(1)
+        .and_then(|pid| pid.parse::<i32>().ok())
+    {
+        Some(pid) => signal::kill(Pid::from_raw(pid), None) == Err(Errno::ESRCH),
+        None => false,
+    }
+}
+
+fn configure_veth_pair(
+    network: &BridgeNetwork,
+    host_interface: &str,
+    host: u128,
+    container_id: &str,
+    pid: Pid,
+) -> Result<(), CartonError> {
+    let container_interface = format!("ceth{}", &container_id[..8]);
+
+    ip(&[
+        "link",
+        "add",
+        host_interface,
+        "type",
+        "veth",
+        "peer",
+        "name",
+        &container_interface,
+    ])?;
+    ip(&["link", "set", host_interface, "master", &network.name, "up"])?;
+    ip(&[
+        "link",
+        "set",
+        &container_interface,
+        "netns",
+        &pid.to_string(),
+    ])?;
+
+    // From here on the commands are executed inside the container's network namespace
+    let (address, ipv6_address) = network.host_addresses(host);
+    ip_in_namespace(pid, &["link", "set", "lo", "up"])?;
+    ip_in_namespace(pid, &["link", "set", &container_interface, "name", "eth0"])?;
+    set_mac_address(pid, &network.interface)?;
+    if !network.ipv6 {
+        disable_ipv6(pid)?;
+    }
+    if let Some(address) = address {
+        let address = format!("{}/{}", address, network.subnet.prefix());
+        ip_in_namespace(pid, &["addr", "add", &address, "dev", "eth0"])?;
+    }
+    if let Some(address) = ipv6_address {
+        let address = format!("{}/{}", address, network.ipv6_subnet.prefix());
+        ip_in_namespace(pid, &["addr", "add", &address, "dev", "eth0", "nodad"])?;
+    }
+    ip_in_namespace(pid, &["link", "set", "eth0", "up"])?;
+    if network.ipv4 {
+        let gateway = network.gateway().to_string();
+        ip_in_namespace(pid, &["route", "add", "default", "via", &gateway])?;
+    }
+    if network.ipv6 {
+        let gateway = network.ipv6_gateway().to_string();
+        ip_in_namespace(pid, &["-6", "route", "add", "default", "via", &gateway])?;
+    }
+    add_routes(pid, &network.interface)?;
+
+    Ok(())
+}
+
+/// Sets the MAC address of eth0 inside the container, which can only be done while it's down.
+fn set_mac_address(pid: Pid, settings: &InterfaceSettings) -> Result<(), CartonError> {
+    if let Some(mac_address) = settings.mac_address {
+        ip_in_namespace(
+            pid,
+            &["link", "set", "eth0", "address", &mac_address.to_string()],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Keeps the kernel from configuring IPv6 addresses on eth0 inside the container, both the
+/// link-local one and the ones from router advertisements. Like the MAC address, this can only be
+/// changed while the interface is down.
+fn disable_ipv6(pid: Pid) -> Result<(), CartonError> {
+    ip_in_namespace(pid, &["link", "set", "eth0", "addrgenmode", "none"])?;
+
+    Ok(())
+}
+
+fn add_routes(pid: Pid, settings: &InterfaceSettings) -> Result<(), CartonError> {
+    for route in &settings.routes {
+        ip_in_namespace(
+            pid,
+            &[
+                "route",
+                "add",
+                &route.destination.to_string(),
+                "via",
+                &route.gateway.to_string(),
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Runs a DHCP client inside the container's network namespace until it has obtained an address
+/// for eth0. The client keeps running in the background to renew the lease.
+///
+/// dhclient normally applies the lease with a script that also rewrites /etc/resolv.conf, but it
+/// runs in the host's mount namespace, so it gets a script that only configures the interface.
+fn request_dhcp_address(pid: Pid, container_dir: &Path) -> Result<NetworkAttachment, CartonError> {
+    fs::create_dir_all(container_dir)?;
+
+    let script_path = container_dir.join("dhclient-script");
+    fs::write(&script_path, DHCLIENT_SCRIPT)?;
+    fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755))?;
+
+    let pid_file = container_dir.join("dhclient.pid");
+    let mut command = Command::new("dhclient");
+    command
+        .arg("-1")
+        .arg("-sf")
+        .arg(&script_path)
+        .arg("-pf")
+        .arg(&pid_file)
+        .arg("-lf")
+        .arg(container_dir.join("dhclient.leases"))
+        .arg("eth0");
+    let started = run(in_namespace(&mut command, pid)?).and_then(|_| {
+        // `ip -o` prints a line like "2: eth0    inet 192.168.1.20/24 brd ... scope global eth0 ..."
+        let mut command = Command::new("ip");
+        command.args(["-4", "-o", "addr", "show", "dev", "eth0"]);
+        run(in_namespace(&mut command, pid)?)
+    });
+    let output = match started {
+        Ok(output) => output,
+        Err(e) => {
+            // The container isn't connected, so nothing else stops the client
+            stop_dhcp_client(&pid_file);
+            for path in [&pid_file, &script_path] {
+                let _ = fs::remove_file(path);
+            }
+            return Err(e);
+        }
+    };
+    let address = output
+        .split_whitespace()
+        .skip_while(|field| *field != "inet")
+        .nth(1)
+        .and_then(|address| address.split('/').next()?.parse::<IpAddr>().ok());
+
+    Ok(NetworkAttachment {
+        address,
+        dhcp_client_pid_file: Some(pid_file),
+        ..Default::default()
+    })
+}
+
+/// Stops the DHCP client of `request_dhcp_address()` whose PID is in `pid_file`, if it's running.
+fn stop_dhcp_client(pid_file: &Path) {
+    let pid = fs::read_to_string(pid_file)
+        .ok()
+        .and_then(|pid| pid.trim().parse::<i32>().ok());
+    if let Some(pid) = pid {
+        let _ = signal::kill(Pid::from_raw(pid), Signal::SIGTERM);
+    }
+}
+
+const DHCLIENT_SCRIPT: &str = r#"#!/bin/sh
+case "$reason" in
+    BOUND|RENEW|REBIND|REBOOT)
+        ip addr flush dev "$interface"
+        ip addr add "$new_ip_address/$new_subnet_mask" dev "$interface"
+        for router in $new_routers; do
+            ip route replace default via "$router" dev "$interface"
+            break
+        done
+        ;;
+esac
+"#;
+
+fn ip(args: &[&str]) -> Result<String, CartonError> {
+    run(Command::new("ip").args(args))
+}
+
+fn tc(args: &[&str]) -> Result<String, CartonError> {
+    run(Command::new("tc").args(args))
+}
+
+/// Runs `ip` inside the network namespace of process `pid`.
+fn ip_in_namespace(pid: Pid, args: &[&str]) -> Result<String, CartonError> {
+    let mut command = Command::new("ip");
+    command.args(args);
+
+    run(in_namespace(&mut command, pid)?)
+}
+
+/// Makes `command` run inside the network namespace of process `pid`, like `nsenter --net` would.
+fn in_namespace(command: &mut Command, pid: Pid) -> Result<&mut Command, CartonError> {
+    let namespace = fs::File::open(format!("/proc/{}/ns/net", pid))?;
+
+    unsafe {
+        // Runs in the forked process right before the command is executed. Only the new process
+        // enters the namespace, carton itself stays where it is.
+        command.pre_exec(move || {
+            sched::setns(&namespace, CloneFlags::CLONE_NEWNET).map_err(std::io::Error::from)
+        });
+    }
+
+    Ok(command)
+}
+
+/// Runs `command` and returns what it printed to stdout.
+fn run(command: &mut Command) -> Result<String, CartonError> {
+    info!("running {:?}", command);
+
+    let output = command
+        .output()
+        .map_err(|e| CartonError::NetworkError(format!("could not run {:?}: {}", command, e)))?;
+
+    if !output.status.success() {
+        return Err(CartonError::NetworkError(format!(
+            "{:?} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_prints_mac_addresses() {
+        let address = "02:42:AC:11:00:02".parse::<MacAddress>().unwrap();
+
+        assert_eq!(address, MacAddress([0x02, 0x42, 0xac, 0x11, 0x00, 0x02]));
+        assert_eq!(address.to_string(), "02:42:ac:11:00:02");
+    }
+
+    #[test]
+    fn rejects_invalid_mac_addresses() {
+        for address in [
+            "",
+            "02:42:ac:11:00",
+            "02:42:ac:11:00:02:03",
+            "2:42:ac:11:00:02",
+            "02:42:ac:11:00:0g",
+            "+2:42:ac:11:00:02",
+            "02-42-ac-11-00-02",
+            // Multicast
+            "01:00:5e:00:00:01",
+        ] {
+            assert!(address.parse::<MacAddress>().is_err(), "{}", address);
+        }
+    }
+}
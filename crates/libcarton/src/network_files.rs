@@ -0,0 +1,176 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::Write;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use crate::container::Mount;
+use crate::error::CartonError;
+
+const HOST_RESOLV_CONF: &str = "/etc/resolv.conf";
+/// When systemd-resolved is used, /etc/resolv.conf only points at its local stub resolver. This
+/// file contains the DNS servers that the stub resolver uses.
+const SYSTEMD_RESOLVED_RESOLV_CONF: &str = "/run/systemd/resolve/resolv.conf";
+/// Used when the host's resolv.conf contains no nameservers that are reachable from the container.
+const FALLBACK_DNS_SERVERS: [IpAddr; 4] = [
+    IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)),
+    IpAddr::V4(Ipv4Addr::new(8, 8, 4, 4)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8844)),
+];
+
+/// The /etc/resolv.conf and /etc/hosts files of the container. They are generated in the
+/// container's own directory on the host and then bind mounted over the ones in the rootfs, so the
+/// rootfs itself is left alone.
+#[derive(Default, Debug, Clone)]
+pub(crate) struct NetworkFiles {
+    /// DNS servers for resolv.conf. When empty, the servers of the host are used.
+    pub(crate) dns_servers: Vec<IpAddr>,
+    /// Search domains for resolv.conf. When empty, the search domains of the host are used.
+    pub(crate) dns_search: Vec<String>,
+    /// Additional entries for /etc/hosts.
+    pub(crate) extra_hosts: Vec<(String, IpAddr)>,
+}
+
+impl NetworkFiles {
+    /// Returns the bind mounts that put the generated files in place inside the container.
+    pub(crate) fn mounts(&self, container_dir: &Path) -> Vec<Mount> {
+        ["resolv.conf", "hosts"]
+            .into_iter()
+            .map(|name| {
+                Mount::bind(
+                    container_dir.join(name),
+                    PathBuf::from("etc").join(name),
+                    None,
+                    None,
+                )
+            })
+            .collect()
+    }
+
+    /// Generates the files in `container_dir`. `addresses` are the container's addresses on its
+    /// own network, or `None` when it shares the network of the host.
+    pub(crate) fn write(
+        &self,
+        container_dir: &Path,
+        hostname: &str,
+        addresses: Option<Vec<IpAddr>>,
+    ) -> Result<(), CartonError> {
+        fs::create_dir_all(container_dir)?;
+
+        info!(
+            "writing resolv.conf and hosts to {}",
+            container_dir.display()
+        );
+        fs::write(
+            container_dir.join("resolv.conf"),
+            self.resolv_conf(addresses.as_deref()),
+        )?;
+        fs::write(
+            container_dir.join("hosts"),
+            self.hosts(hostname, addresses.as_deref().unwrap_or_default()),
+        )?;
+
+        Ok(())
+    }
+
+    fn resolv_conf(&self, addresses: Option<&[IpAddr]>) -> String {
+        let (mut host_servers, host_search) = read_host_resolv_conf(addresses.is_some());
+
+        // A container with only an IPv4 address can't reach nameservers on IPv6 addresses, and
+        // vice versa. There's no telling when the container's addresses are unknown, like when it
+        // configures its IPv6 address itself with SLAAC.
+        let reachable = |server: &IpAddr| match addresses {
+            Some(addresses) if !addresses.is_empty() => addresses
+                .iter()
+                .any(|address| address.is_ipv4() == server.is_ipv4()),
+            _ => true,
+        };
+        host_servers.retain(reachable);
+
+        let mut servers = if self.dns_servers.is_empty() {
+            host_servers
+        } else {
+            self.dns_servers.clone()
+        };
+        if servers.is_empty() {
+            servers = FALLBACK_DNS_SERVERS.into_iter().filter(reachable).collect();
+        }
+
+        let search = if self.dns_search.is_empty() {
+            host_search
+        } else {
+            self.dns_search.clone()
+        };
+
+        let mut contents = String::new();
+        for server in servers {
+            let _ = writeln!(contents, "nameserver {}", server);
+        }
+        if !search.is_empty() {
+            let _ = writeln!(contents, "search {}", search.join(" "));
+        }
+
+        contents
+    }
+
+    fn hosts(&self, hostname: &str, addresses: &[IpAddr]) -> String {
+        let mut contents =
+            String::from("127.0.0.1\tlocalhost\n::1\tlocalhost ip6-localhost ip6-loopback\n");
+
+        // Like Debian does for machines without a fixed address, the hostname resolves to
+        // 127.0.1.1 when the container has no address of its own
+        if addresses.is_empty() {
+            let _ = writeln!(contents, "127.0.1.1\t{}", hostname);
+        }
+        for address in addresses {
+            let _ = writeln!(contents, "{}\t{}", address, hostname);
+        }
+
+        for (name, address) in &self.extra_hosts {
+            let _ = writeln!(contents, "{}\t{}", address, name);
+        }
+
+        contents
+    }
+}
+
+/// Returns the nameservers and search domains of the host. Nameservers on the loopback interface
+/// (like systemd-resolved's 127.0.0.53) are not reachable from a container with its own network,
+/// so in that case those are left out.
+fn read_host_resolv_conf(own_network: bool) -> (Vec<IpAddr>, Vec<String>) {
+    let (mut servers, search) = parse_resolv_conf(HOST_RESOLV_CONF);
+
+    if own_network {
+        servers.retain(|server| !server.is_loopback());
+        if servers.is_empty() {
+            servers = parse_resolv_conf(SYSTEMD_RESOLVED_RESOLV_CONF).0;
+            servers.retain(|server| !server.is_loopback());
+        }
+    }
+
+    (servers, search)
+}
+
+fn parse_resolv_conf(path: &str) -> (Vec<IpAddr>, Vec<String>) {
+    let contents = fs::read_to_string(path).unwrap_or_default();
+
+    let mut servers = vec![];
+    let mut search = vec![];
+    for line in contents.lines() {
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some("nameserver") => {
+                servers.extend(fields.next().and_then(|s| s.parse::<IpAddr>().ok()))
+            }
+            Some("search") => search = fields.map(String::from).collect(),
+            _ => {}
+        }
+    }
+
+    (servers, search)
+}
@@ -0,0 +1,135 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Passes on the [sd_notify][1] messages of a container to systemd.
+//!
+//! A service with `Type=notify` tells systemd that it's ready (or reloading, stopping, etc.) by
+//! sending a datagram like `READY=1` to the Unix socket in its `NOTIFY_SOCKET` environment
+//! variable. That socket lives outside of the container's root filesystem, and systemd only
+//! accepts messages from the main process of the service (carton) anyway. So carton gives the
+//! container a socket of its own and forwards whatever arrives on it to systemd.
+//!
+//! [1]: https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html
+
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{info, warn};
+
+use nix::sys::socket::{self, MsgFlags, UnixAddr};
+
+use crate::container::Mount;
+use crate::error::CartonError;
+
+/// Directory inside the container that holds the socket.
+const CONTAINER_SOCKET_DIR: &str = "run/carton-notify";
+const SOCKET_NAME: &str = "notify.sock";
+/// How long the proxy blocks on the socket before it checks whether it should stop.
+const RECEIVE_TIMEOUT: Duration = Duration::from_millis(100);
+/// The largest message that systemd accepts.
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+/// Returns the value of `NOTIFY_SOCKET` for the container.
+pub(crate) fn container_socket_path() -> PathBuf {
+    Path::new("/").join(CONTAINER_SOCKET_DIR).join(SOCKET_NAME)
+}
+
+/// Returns the bind mount that puts the directory with the socket in place inside the container.
+pub(crate) fn mount(container_dir: &Path) -> Mount {
+    Mount::bind(
+        socket_dir(container_dir),
+        CONTAINER_SOCKET_DIR.into(),
+        None,
+        None,
+    )
+}
+
+fn socket_dir(container_dir: &Path) -> PathBuf {
+    container_dir.join("notify")
+}
+
+/// Forwards messages from the container's socket to systemd's socket, on a thread of its own,
+/// until it's dropped.
+#[derive(Debug)]
+pub(crate) struct NotifyProxy {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NotifyProxy {
+    /// Creates the container's socket in `container_dir` and starts forwarding to
+    /// `host_socket`, which may be an abstract socket name that starts with `@`.
+    pub(crate) fn start(container_dir: &Path, host_socket: &Path) -> Result<Self, CartonError> {
+        let host_address = match host_socket.as_os_str().as_bytes() {
+            [b'@', name @ ..] => UnixAddr::new_abstract(name)?,
+            _ => UnixAddr::new(host_socket)?,
+        };
+
+        let dir = socket_dir(container_dir);
+        std::fs::create_dir_all(&dir)?;
+        let socket_path = dir.join(SOCKET_NAME);
+        let _ = std::fs::remove_file(&socket_path);
+        let socket = UnixDatagram::bind(&socket_path)?;
+        socket.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+        let host = UnixDatagram::unbound()?;
+
+        info!(
+            "forwarding sd_notify messages from {} to {}",
+            socket_path.display(),
+            host_socket.display()
+        );
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = thread::spawn({
+            let stop = stop.clone();
+            move || {
+                let mut buf = vec![0; MAX_MESSAGE_SIZE];
+                while !stop.load(Ordering::Relaxed) {
+                    let Ok(length) = socket.recv(&mut buf) else {
+                        continue;
+                    };
+
+                    let message = forwarded_message(&buf[..length]);
+                    if message.is_empty() {
+                        continue;
+                    }
+                    if let Err(e) =
+                        socket::sendto(host.as_raw_fd(), &message, &host_address, MsgFlags::empty())
+                    {
+                        warn!("Could not pass on sd_notify message: {}", e);
+                    }
+                }
+            }
+        });
+
+        Ok(NotifyProxy {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for NotifyProxy {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Leaves out `MAINPID=`, because the PIDs inside the container mean nothing to systemd and carton
+/// is the service's main process anyway.
+fn forwarded_message(message: &[u8]) -> Vec<u8> {
+    message
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.is_empty() && !line.starts_with(b"MAINPID="))
+        .collect::<Vec<_>>()
+        .join(&b'\n')
+}
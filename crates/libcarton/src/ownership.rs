@@ -0,0 +1,183 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bind mounts of host directories whose files appear to be owned by a user of the container, see
+//! `ContainerBuilder::add_owned_mount()`.
+//!
+//! The files of a host directory are owned by some user of the host, who usually doesn't exist in
+//! the container (or is someone else there, or `nobody` in a user namespace). So the container's
+//! user can't write to it, unless the files get another owner. Changing the owner of the files
+//! themselves would take them away from the host's user, so carton does one of these instead:
+//!
+//! * It mounts the directory ID-mapped (see `idmap`), so that the files of the directory's owner
+//!   appear to be owned by the container's user, and the files that the container creates end up
+//!   owned by the directory's owner on disk. Files of other owners appear to be owned by the
+//!   overflow user (`nobody`).
+//! * When the filesystem doesn't support ID-mapped mounts, it copies the directory into the
+//!   container's directory and changes the owner of every file of the copy. The container works
+//!   on the copy, so its changes never reach the host's directory, and the copy is removed when
+//!   the container exits.
+//!
+//! Both happen before the container process exists, like the ID-mapped copy of the root
+//! filesystem, because the container process may not be privileged enough to do them.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use nix::errno::Errno;
+use nix::unistd::{Gid, Uid};
+
+use crate::container::{ContainerConfiguration, Mount};
+use crate::copy;
+use crate::diagnostics::Reporter;
+use crate::error::CartonError;
+use crate::idmap::{self, IdMapping, IdMappings};
+use crate::rollback::{Rollback, Step};
+use crate::syscalls::Syscalls;
+
+/// Directory in the container's directory with the copies of owned mounts.
+const COPIES_DIR: &str = "owned";
+
+/// What the container process mounts instead of the source of an owned mount.
+#[derive(Debug)]
+pub(crate) enum OwnedSource {
+    /// An ID-mapped copy of the source, which isn't attached anywhere yet.
+    Tree(OwnedFd),
+    /// A copy of the source with files that are owned by the container's user.
+    Copy(PathBuf),
+}
+
+impl OwnedSource {
+    /// Mounts this at the target of `mount` in the root filesystem at `rootfs_path`.
+    pub(crate) fn mount(
+        &self,
+        mount: &Mount,
+        rootfs_path: &Path,
+        syscalls: &dyn Syscalls,
+        reporter: &Reporter,
+        rollback: &Rollback,
+    ) -> Result<(), CartonError> {
+        match self {
+            OwnedSource::Tree(tree) => {
+                let mount_path = mount.create_target(rootfs_path, reporter, rollback)?;
+                reporter.info(format_args!(
+                    "mounting an ID-mapped copy of {} at {}",
+                    mount
+                        .source
+                        .as_deref()
+                        .unwrap_or_else(|| Path::new(""))
+                        .display(),
+                    mount_path.display()
+                ));
+                syscalls.move_mount(tree.as_raw_fd(), &mount_path)?;
+                rollback.record(Step::Mount(mount_path));
+            }
+            OwnedSource::Copy(copy) => {
+                mount
+                    .with_source(copy.clone())
+                    .mount(rootfs_path, syscalls, reporter, rollback)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the host user and group that user `uid` and group `gid` of the container are.
+fn host_owner(config: &ContainerConfiguration, uid: u32, gid: u32) -> Option<(Uid, Gid)> {
+    match &config.user_namespace {
+        Some(id_mappings) => id_mappings.host_ids(uid, gid),
+        None => Some((Uid::from_raw(uid), Gid::from_raw(gid))),
+    }
+}
+
+/// Checks that the owners of the owned mounts exist in the container's user namespace.
+pub(crate) fn validate(config: &ContainerConfiguration) -> Result<(), CartonError> {
+    for (uid, gid) in config.mounts.iter().filter_map(Mount::owner) {
+        if host_owner(config, uid, gid).is_none() {
+            return Err(CartonError::InvalidConfiguration(format!(
+                "the owner {}:{} of a mount isn't mapped in the container's user namespace",
+                uid, gid
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Prepares the owned mounts of `config`, and returns what to mount instead of their sources, by
+/// the index of the mount in `config.mounts`. Other mounts, skipped ones and ones whose source
+/// already has the right owner have no entry.
+pub(crate) fn prepare(
+    config: &ContainerConfiguration,
+    rollback: &Rollback,
+) -> Result<Vec<Option<OwnedSource>>, CartonError> {
+    let reporter = &config.diagnostics;
+    let mut sources = Vec::with_capacity(config.mounts.len());
+
+    for (index, mount) in config.mounts.iter().enumerate() {
+        let (Some((uid, gid)), Some(source), false) =
+            (mount.owner(), &mount.source, mount.is_skipped())
+        else {
+            sources.push(None);
+            continue;
+        };
+        let (host_uid, host_gid) =
+            host_owner(config, uid, gid).expect("owners should have been validated");
+        let metadata = fs::metadata(source)?;
+        if (metadata.uid(), metadata.gid()) == (host_uid.as_raw(), host_gid.as_raw()) {
+            sources.push(None);
+            continue;
+        }
+
+        // Files of the source's owner on disk appear to be owned by the container's user
+        let id_mappings = IdMappings {
+            uids: vec![IdMapping::new(metadata.uid(), host_uid.as_raw(), 1)],
+            gids: vec![IdMapping::new(metadata.gid(), host_gid.as_raw(), 1)],
+        };
+        let tree = reporter.operation(
+            format_args!(
+                "creating an ID-mapped copy of {:?} owned by {}:{}",
+                source, host_uid, host_gid
+            ),
+            idmap::idmapped_tree(source, &id_mappings.user_namespace()?),
+        );
+        match tree {
+            Ok(tree) => sources.push(Some(OwnedSource::Tree(tree))),
+            Err(Errno::EINVAL | Errno::ENOSYS | Errno::EOPNOTSUPP) => {
+                reporter.warning(format_args!(
+                    "{} can't be mounted ID-mapped, so the container gets a copy of it",
+                    source.display()
+                ));
+                let copies = config.dir.join(COPIES_DIR);
+                rollback.create_dir_all(&copies)?;
+                let copy = copies.join(index.to_string());
+                rollback.record(Step::Copy(copy.clone()));
+                reporter.operation(
+                    format_args!(
+                        "copying {:?} to {:?} owned by {}:{}",
+                        source, copy, host_uid, host_gid
+                    ),
+                    copy::copy_tree(source, &copy, Some((host_uid, host_gid))),
+                )?;
+                sources.push(Some(OwnedSource::Copy(copy)));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(sources)
+}
+
+/// Removes the copies of owned mounts in the container directory `dir`, once the container
+/// process is gone.
+pub(crate) fn remove_copies(dir: &Path, reporter: &Reporter) {
+    match fs::remove_dir_all(dir.join(COPIES_DIR)) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => reporter.warning(format_args!("Could not remove the copies of mounts: {}", e)),
+    }
+}
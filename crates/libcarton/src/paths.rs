@@ -0,0 +1,118 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use nix::unistd;
+
+use crate::consts::{DEFAULT_DATA_DIR, DEFAULT_STATE_DIR};
+
+/// Environment variables that override the default directories.
+const STATE_DIR_VARIABLE: &str = "CARTON_STATE_DIR";
+const DATA_DIR_VARIABLE: &str = "CARTON_DATA_DIR";
+
+/// The directories where carton keeps its files.
+///
+/// * The state directory holds what only matters while the system is up: the state of the
+///   containers and their generated files, and the address leases of bridge networks. It's
+///   `/run/carton` for root and `$XDG_RUNTIME_DIR/carton` for other users.
+/// * The data directory holds what should survive a reboot. It's `/var/lib/carton` for root and
+///   `$XDG_DATA_HOME/carton` (`~/.local/share/carton`) for other users.
+///
+/// The `CARTON_STATE_DIR` and `CARTON_DATA_DIR` environment variables override the defaults.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paths {
+    state_dir: PathBuf,
+    data_dir: PathBuf,
+}
+
+impl Default for Paths {
+    fn default() -> Self {
+        Paths::new()
+    }
+}
+
+impl Paths {
+    /// Returns the directories for the user that runs carton.
+    pub fn new() -> Self {
+        let root = unistd::geteuid().is_root();
+
+        let state_dir = env_dir(STATE_DIR_VARIABLE).unwrap_or_else(|| {
+            if root {
+                DEFAULT_STATE_DIR.into()
+            } else {
+                user_runtime_dir().join("carton")
+            }
+        });
+        let data_dir = env_dir(DATA_DIR_VARIABLE).unwrap_or_else(|| {
+            if root {
+                DEFAULT_DATA_DIR.into()
+            } else {
+                user_data_dir().join("carton")
+            }
+        });
+
+        Paths {
+            state_dir,
+            data_dir,
+        }
+    }
+
+    pub fn with_state_dir(mut self, state_dir: PathBuf) -> Self {
+        self.state_dir = state_dir;
+        self
+    }
+
+    pub fn with_data_dir(mut self, data_dir: PathBuf) -> Self {
+        self.data_dir = data_dir;
+        self
+    }
+
+    pub fn state_dir(&self) -> &Path {
+        &self.state_dir
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    /// Directory with the volumes of `LocalVolumes`, a directory per volume.
+    pub fn volume_dir(&self) -> PathBuf {
+        self.data_dir.join("volumes")
+    }
+
+    /// Directory with the address leases of the bridge networks, a directory per network.
+    pub fn lease_dir(&self) -> PathBuf {
+        self.state_dir.join("networks")
+    }
+}
+
+fn env_dir(variable: &str) -> Option<PathBuf> {
+    env::var_os(variable)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+/// `$XDG_RUNTIME_DIR`, which systemd sets up for logged in users as `/run/user/<uid>`. Without a
+/// login session there's only the temporary directory.
+fn user_runtime_dir() -> PathBuf {
+    env_dir("XDG_RUNTIME_DIR").unwrap_or_else(|| {
+        let uid = unistd::geteuid();
+        let run_user = Path::new("/run/user").join(uid.to_string());
+        if run_user.is_dir() {
+            run_user
+        } else {
+            env::temp_dir().join(format!("carton-{}", uid))
+        }
+    })
+}
+
+fn user_data_dir() -> PathBuf {
+    env_dir("XDG_DATA_HOME").unwrap_or_else(|| {
+        env_dir("HOME")
+            .unwrap_or_else(env::temp_dir)
+            .join(".local")
+            .join("share")
+    })
+}
@@ -0,0 +1,452 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! What preparing and starting a container would do, without doing any of it, see
+//! `ContainerBuilder::plan()` and `Container::plan()`.
+//!
+//! The steps are listed in the order in which `Container::prepare()` and the container process
+//! take them, so when the setup changes, the plan has to change along with it.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use nix::mount::MsFlags;
+
+use crate::cgroup;
+use crate::console::CONSOLE_PATH;
+use crate::container::{ContainerConfiguration, Mount, EPHEMERAL_DIR, INIT_PATH};
+use crate::dbus;
+use crate::error::CartonError;
+use crate::files;
+use crate::idmap;
+use crate::namespace::DEV_SYMLINKS;
+use crate::network::Network;
+use crate::notify;
+use crate::scope;
+use crate::secrets::{self, SECRETS_DIR};
+
+/// Placeholder for the PID of the container process, which doesn't exist yet.
+pub(crate) const PID_PLACEHOLDER: &str = "<pid>";
+
+/// A step of the setup of a container.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlanStep {
+    /// The container process is created in new namespaces of these types, like `mnt` and `pid`.
+    Namespaces(Vec<String>),
+    /// The container process joins the network namespace at this path.
+    JoinNetworkNamespace(PathBuf),
+    /// A directory is created on the host.
+    CreateDirectory(PathBuf),
+    /// `value` is written to the file at `path`, like the `cgroup.procs` file of a cgroup.
+    Write { path: PathBuf, value: String },
+    /// The container process is moved into this transient scope of the user's systemd.
+    SystemdScope(String),
+    /// The hostname is set, in the container's UTS namespace.
+    Hostname(String),
+    /// The NIS domain name is set, in the container's UTS namespace.
+    Domainname(String),
+    /// A filesystem is mounted. Bind mounts have no `fstype`.
+    Mount {
+        source: Option<PathBuf>,
+        target: PathBuf,
+        fstype: Option<String>,
+        flags: Vec<String>,
+        data: Option<String>,
+    },
+    /// A directory of the host is bind mounted with its files owned by user `uid` and group `gid`
+    /// of the container, ID-mapped or as a copy, see `ContainerBuilder::add_owned_mount()`.
+    OwnedMount {
+        source: PathBuf,
+        target: PathBuf,
+        uid: u32,
+        gid: u32,
+    },
+    /// A volume is mounted by its driver, and bind mounted here.
+    Volume { name: String, target: PathBuf },
+    /// An optional mount is skipped, because its source doesn't exist.
+    SkipMount { source: PathBuf, target: PathBuf },
+    /// The root filesystem at this path is mounted on top of itself, with the owners of its files
+    /// mapped.
+    IdMappedMount(PathBuf),
+    /// A `MountProvider` mounts its filesystems.
+    MountProvider(String),
+    /// A character device node is created.
+    DeviceNode {
+        path: PathBuf,
+        major: u64,
+        minor: u64,
+    },
+    /// A symlink is created.
+    Symlink { path: PathBuf, target: PathBuf },
+    /// A secret is written to the file at this path, see `ContainerBuilder::add_secret()`.
+    Secret(PathBuf),
+    /// The container's terminal is bind mounted here.
+    Console(PathBuf),
+    /// The root filesystem at this path becomes the root of the container's mount namespace.
+    PivotRoot(PathBuf),
+    /// The command is executed.
+    Exec {
+        command: PathBuf,
+        arguments: Vec<String>,
+    },
+}
+
+impl fmt::Display for PlanStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanStep::Namespaces(namespaces) => {
+                write!(f, "create namespaces: {}", namespaces.join(", "))
+            }
+            PlanStep::JoinNetworkNamespace(path) => {
+                write!(f, "join network namespace {}", path.display())
+            }
+            PlanStep::Hostname(hostname) => write!(f, "set hostname to {}", hostname),
+            PlanStep::Domainname(domainname) => write!(f, "set domain name to {}", domainname),
+            PlanStep::CreateDirectory(path) => write!(f, "create directory {}", path.display()),
+            PlanStep::Write { path, value } => write!(f, "write {:?} to {}", value, path.display()),
+            PlanStep::SystemdScope(unit) => {
+                write!(f, "move the container process into systemd scope {}", unit)
+            }
+            PlanStep::Mount {
+                source,
+                target,
+                fstype,
+                flags,
+                data,
+            } => {
+                write!(
+                    f,
+                    "mount {} on {} ({}",
+                    source
+                        .as_ref()
+                        .map_or_else(|| "none".into(), |source| source.display().to_string()),
+                    target.display(),
+                    fstype.as_deref().unwrap_or("bind")
+                )?;
+                for option in flags.iter().chain(data) {
+                    write!(f, ", {}", option)?;
+                }
+                write!(f, ")")
+            }
+            PlanStep::OwnedMount {
+                source,
+                target,
+                uid,
+                gid,
+            } => write!(
+                f,
+                "mount {} on {} owned by {}:{} (ID-mapped, or a copy)",
+                source.display(),
+                target.display(),
+                uid,
+                gid
+            ),
+            PlanStep::Volume { name, target } => {
+                write!(f, "mount volume {} on {}", name, target.display())
+            }
+            PlanStep::SkipMount { source, target } => write!(
+                f,
+                "skip mounting {} on {} (optional, it doesn't exist)",
+                source.display(),
+                target.display()
+            ),
+            PlanStep::IdMappedMount(path) => {
+                write!(f, "mount {} ID-mapped on itself", path.display())
+            }
+            PlanStep::MountProvider(provider) => write!(f, "mount {}", provider),
+            PlanStep::DeviceNode { path, major, minor } => {
+                write!(
+                    f,
+                    "create device node {} ({}:{})",
+                    path.display(),
+                    major,
+                    minor
+                )
+            }
+            PlanStep::Symlink { path, target } => {
+                write!(
+                    f,
+                    "create symlink {} -> {}",
+                    path.display(),
+                    target.display()
+                )
+            }
+            PlanStep::Secret(path) => write!(f, "write a secret to {}", path.display()),
+            PlanStep::Console(path) => write!(f, "mount the terminal on {}", path.display()),
+            PlanStep::PivotRoot(path) => write!(f, "pivot_root to {}", path.display()),
+            PlanStep::Exec { command, arguments } => {
+                write!(f, "exec {}", command.display())?;
+                for argument in arguments {
+                    if argument.is_empty() || argument.contains(char::is_whitespace) {
+                        write!(f, " {:?}", argument)?;
+                    } else {
+                        write!(f, " {}", argument)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The steps that setting up a container takes. It's displayed as a numbered list.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Plan {
+    steps: Vec<PlanStep>,
+}
+
+impl Plan {
+    pub fn steps(&self) -> &[PlanStep] {
+        &self.steps
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (number, step) in self.steps.iter().enumerate() {
+            writeln!(f, "{:>3}. {}", number + 1, step)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the plan for the container `id` with the (validated) configuration `config`.
+pub(crate) fn plan(id: &str, config: &ContainerConfiguration) -> Result<Plan, CartonError> {
+    let mut steps = vec![PlanStep::Namespaces(namespaces(config))];
+
+    if config.ephemeral {
+        steps.push(PlanStep::CreateDirectory(config.dir.join(EPHEMERAL_DIR)));
+    }
+
+    // From outside of the container, once its process exists
+    if let Some(id_mappings) = &config.user_namespace {
+        for (file, mappings) in [
+            ("uid_map", &id_mappings.uids),
+            ("gid_map", &id_mappings.gids),
+        ] {
+            steps.push(PlanStep::Write {
+                path: Path::new("/proc").join(PID_PLACEHOLDER).join(file),
+                value: idmap::mapping_lines(mappings),
+            });
+        }
+    }
+    if config.delegate_cgroup {
+        steps.extend(cgroup::plan(id)?);
+    }
+    if config.systemd_scope {
+        steps.push(PlanStep::SystemdScope(scope::unit_name(id)));
+    }
+
+    // By the container process, see `namespace::setup_namespaces()`
+    if let Network::Shared(shared) = &config.network {
+        if let Some(path) = shared.namespace_path() {
+            steps.push(PlanStep::JoinNetworkNamespace(path));
+        }
+    }
+    if let Some(hostname) = &config.hostname {
+        steps.push(PlanStep::Hostname(hostname.clone()));
+    }
+    if let Some(domainname) = &config.domainname {
+        steps.push(PlanStep::Domainname(domainname.clone()));
+    }
+    if config.sandbox {
+        steps.extend(sandbox_mounts(config)?);
+        steps.extend(exec(config));
+        return Ok(Plan { steps });
+    }
+    let Some(rootfs) = config.rootfs_source() else {
+        return Ok(Plan { steps });
+    };
+    let rootfs = fs::canonicalize(rootfs).unwrap_or_else(|_| rootfs.into());
+
+    steps.push(Mount::rootfs(rootfs.clone()).plan_step(&rootfs));
+    if config.rootfs_id_mappings.is_some() {
+        steps.push(PlanStep::IdMappedMount(rootfs.clone()));
+    }
+    if config.ephemeral {
+        let tmpfs_dir = config.dir.join(EPHEMERAL_DIR);
+        let mut options = vec!["mode=0700".to_string()];
+        if let Some(size_limit) = config.storage_limit {
+            options.push(format!("size={}", size_limit));
+        }
+        steps.push(PlanStep::Mount {
+            source: None,
+            target: tmpfs_dir.clone(),
+            fstype: Some("tmpfs".into()),
+            flags: Vec::new(),
+            data: Some(options.join(",")),
+        });
+        steps.push(PlanStep::Mount {
+            source: None,
+            target: rootfs.clone(),
+            fstype: Some("overlay".into()),
+            flags: Vec::new(),
+            data: Some(format!(
+                "lowerdir={},upperdir={},workdir={}",
+                rootfs.display(),
+                tmpfs_dir.join("upper").display(),
+                tmpfs_dir.join("work").display()
+            )),
+        });
+    }
+
+    steps.extend(config.mounts.iter().map(|mount| mount.plan_step(&rootfs)));
+    if let Some(network_files) = &config.network_files {
+        steps.extend(
+            network_files
+                .mounts(&config.dir)
+                .iter()
+                .map(|mount| mount.plan_step(&rootfs)),
+        );
+    }
+    steps.extend(files(config, &rootfs));
+    steps.extend(secrets(config, &rootfs));
+    steps.extend(
+        config
+            .mount_providers
+            .iter()
+            .map(|provider| PlanStep::MountProvider(format!("{:?}", provider))),
+    );
+    if config.delegate_cgroup {
+        steps.push(cgroup::mount().plan_step(&rootfs));
+    }
+    if config.notify_socket.is_some() {
+        steps.push(notify::mount(&config.dir).plan_step(&rootfs));
+    }
+    if config.dbus_proxy.is_some() {
+        steps.push(dbus::mount(&config.dir).plan_step(&rootfs));
+    }
+    if let Some(init) = &config.init {
+        steps.push(Mount::bind(init.clone(), INIT_PATH.into(), None, None).plan_step(&rootfs));
+    }
+    if let Some(mount) = config.core_dumps.bind_mount()? {
+        steps.push(mount.plan_step(&rootfs));
+    }
+
+    let dev = rootfs.join("dev");
+    steps.extend(config.devices.iter().map(|node| PlanStep::DeviceNode {
+        path: dev.join(&node.path),
+        major: node.major,
+        minor: node.minor,
+    }));
+    for (name, target) in DEV_SYMLINKS {
+        steps.push(PlanStep::Symlink {
+            path: dev.join(name),
+            target: target.into(),
+        });
+    }
+    if config.console_socket.is_some() {
+        steps.push(PlanStep::Console(rootfs.join(CONSOLE_PATH)));
+    }
+    steps.push(PlanStep::PivotRoot(rootfs));
+    steps.extend(exec(config));
+
+    Ok(Plan { steps })
+}
+
+/// Returns the mounts of a sandbox, see `namespace::setup_sandbox_mount_namespace()`.
+fn sandbox_mounts(config: &ContainerConfiguration) -> Result<Vec<PlanStep>, CartonError> {
+    let root = Path::new("/");
+    let mut steps: Vec<PlanStep> = config
+        .mounts
+        .iter()
+        .map(|mount| mount.plan_step(root))
+        .collect();
+    if let Some(network_files) = &config.network_files {
+        steps.extend(
+            network_files
+                .mounts(&config.dir)
+                .iter()
+                .map(|mount| mount.plan_step(root)),
+        );
+    }
+    steps.extend(files(config, root));
+    steps.extend(secrets(config, root));
+    steps.extend(
+        config
+            .mount_providers
+            .iter()
+            .map(|provider| PlanStep::MountProvider(format!("{:?}", provider))),
+    );
+    if config.delegate_cgroup {
+        steps.push(cgroup::mount().plan_step(root));
+    }
+    if let Some(mount) = config.core_dumps.bind_mount()? {
+        steps.push(mount.plan_step(root));
+    }
+    if config.console_socket.is_some() {
+        steps.push(PlanStep::Console(root.join(CONSOLE_PATH)));
+    }
+
+    Ok(steps)
+}
+
+/// Returns the steps that put the files of `ContainerBuilder::add_file()` in place, see `files`.
+fn files(config: &ContainerConfiguration, rootfs: &Path) -> Vec<PlanStep> {
+    files::mounts(&config.files, &config.dir)
+        .iter()
+        .map(|mount| mount.plan_step(rootfs))
+        .collect()
+}
+
+/// Returns the steps that put the secrets in place, see `secrets::mount_secrets()`.
+fn secrets(config: &ContainerConfiguration, rootfs: &Path) -> Vec<PlanStep> {
+    if config.secrets.is_empty() {
+        return Vec::new();
+    }
+
+    let dir = rootfs.join(SECRETS_DIR);
+    [secrets::mount().plan_step(rootfs)]
+        .into_iter()
+        .chain(
+            config
+                .secrets
+                .iter()
+                .map(|secret| PlanStep::Secret(dir.join(&secret.name))),
+        )
+        .collect()
+}
+
+fn exec(config: &ContainerConfiguration) -> Option<PlanStep> {
+    let command = config.command.as_ref()?;
+
+    Some(match config.init_path() {
+        Some(init) => {
+            let mut arguments = vec!["--".into(), command.display().to_string()];
+            arguments.extend(config.arguments.iter().cloned());
+            PlanStep::Exec {
+                command: init,
+                arguments,
+            }
+        }
+        None => PlanStep::Exec {
+            command: command.clone(),
+            arguments: config.arguments.clone(),
+        },
+    })
+}
+
+/// Returns the names of the mount flags in `flags`, like `MS_BIND`.
+pub(crate) fn flag_names(flags: MsFlags) -> Vec<String> {
+    flags.iter_names().map(|(name, _)| name.into()).collect()
+}
+
+fn namespaces(config: &ContainerConfiguration) -> Vec<String> {
+    let mut namespaces = vec!["uts", "mnt", "pid"];
+    if matches!(
+        config.network,
+        Network::Bridge(_) | Network::Macvlan(_) | Network::Cni(_)
+    ) {
+        namespaces.push("net");
+    }
+    if config.user_namespace.is_some() {
+        namespaces.push("user");
+    }
+    if config.delegate_cgroup {
+        namespaces.push("cgroup");
+    }
+
+    namespaces.into_iter().map(String::from).collect()
+}
@@ -0,0 +1,146 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+use crate::container::Container;
+use crate::error::CartonError;
+use crate::state::ContainerInfo;
+
+/// Keeps a number of prepared containers around (see `Container::prepare()`), so that a command
+/// can be started in a container without waiting for namespaces, mounts and networking to be set
+/// up. Useful for running lots of short-lived commands, like a function-as-a-service platform
+/// does.
+///
+/// ```no_run
+/// # use libcarton::{CartonError, ContainerBuilder, ContainerPool};
+/// # fn main() -> Result<(), CartonError> {
+/// let mut pool = ContainerPool::new(4, || {
+///     ContainerBuilder::new()
+///         .rootfs("/path/to/rootfs".into())
+///         .add_default_mounts()
+///         .build_prepared()
+/// })?;
+///
+/// let mut container = pool
+///     .take()?
+///     .start_command("/bin/echo".into(), vec!["hello".into()])?;
+///
+/// // Prepare a replacement while the command runs
+/// pool.fill()?;
+/// container.wait_for_exit()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ContainerPool<F>
+where
+    F: FnMut() -> Result<PreparedContainer, CartonError>,
+{
+    size: usize,
+    /// Builds and prepares a new container every time it's called.
+    factory: F,
+    containers: VecDeque<PreparedContainer>,
+}
+
+impl<F> ContainerPool<F>
+where
+    F: FnMut() -> Result<PreparedContainer, CartonError>,
+{
+    /// Creates a pool of `size` containers, which `factory` builds and prepares right away, see
+    /// `ContainerBuilder::build_prepared()`.
+    pub fn new(size: usize, factory: F) -> Result<Self, CartonError> {
+        let mut pool = ContainerPool {
+            size,
+            factory,
+            containers: VecDeque::with_capacity(size),
+        };
+        pool.fill()?;
+
+        Ok(pool)
+    }
+
+    /// Prepares new containers until the pool is full again. Taking a container doesn't do this by
+    /// itself, so that it can be done when there's time for it.
+    pub fn fill(&mut self) -> Result<(), CartonError> {
+        while self.containers.len() < self.size {
+            self.containers.push_back((self.factory)()?);
+        }
+
+        Ok(())
+    }
+
+    /// Takes a prepared container from the pool, or prepares one on the spot when the pool is
+    /// empty.
+    pub fn take(&mut self) -> Result<PreparedContainer, CartonError> {
+        match self.containers.pop_front() {
+            Some(container) => Ok(container),
+            None => (self.factory)(),
+        }
+    }
+
+    /// Returns the number of prepared containers in the pool.
+    pub fn len(&self) -> usize {
+        self.containers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.containers.is_empty()
+    }
+}
+
+/// The message of the `expect()`s of a `PreparedContainer`, which has its container until it
+/// starts.
+const TAKEN: &str =
+    "the container is only taken when it starts, which consumes the PreparedContainer";
+
+/// A container that was prepared without a command, see `ContainerBuilder::build_prepared()`. It
+/// becomes a `Container` that runs once it's given a command. Dropping it stops its process and
+/// cleans up what was set up for it.
+pub struct PreparedContainer {
+    /// Only taken by `start_command()`.
+    container: Option<Container>,
+}
+
+impl PreparedContainer {
+    pub(crate) fn new(container: Container) -> Self {
+        PreparedContainer {
+            container: Some(container),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        self.container().id()
+    }
+
+    pub fn info(&self) -> ContainerInfo {
+        self.container().info()
+    }
+
+    /// Executes `command` with `arguments` in the container, and returns the running container.
+    pub fn start_command(
+        mut self,
+        command: PathBuf,
+        arguments: Vec<String>,
+    ) -> Result<Container, CartonError> {
+        // When this fails, dropping the container cleans up after it
+        self.container
+            .as_mut()
+            .expect(TAKEN)
+            .start_command(command, arguments)?;
+
+        Ok(self.container.take().expect(TAKEN))
+    }
+
+    fn container(&self) -> &Container {
+        self.container.as_ref().expect(TAKEN)
+    }
+}
+
+impl Drop for PreparedContainer {
+    fn drop(&mut self) {
+        if let Some(container) = &mut self.container {
+            container.discard();
+        }
+    }
+}
@@ -0,0 +1,348 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Privilege separation: carton started as root drops to an unprivileged user before it does
+//! anything else, and keeps a small helper process that stays root for the operations that need
+//! it, see `drop_privileges()`.
+//!
+//! Everything that parses input (arguments, config files, manifests, the root filesystem) then
+//! runs as the unprivileged user, which runs its containers rootless, like carton started by that
+//! user would. The helper only does what a user can't do on its own, and what the setuid
+//! `newuidmap` and `newgidmap` helpers would otherwise do: writing the ID mappings of a user
+//! namespace with the ranges of subordinate IDs that /etc/subuid and /etc/subgid assign to the
+//! user. It checks that the process belongs to the user and that every mapping is either the
+//! user's own ID or within its subordinate range, like those helpers do.
+//!
+//! The helper is forked before the process has any other threads, and talks to carton over a
+//! socket pair with a JSON request per line, each answered with a JSON `Result`. It exits when
+//! carton closes its end.
+
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixStream;
+use std::process;
+use std::sync::Mutex;
+
+use nix::fcntl::{self, OFlag};
+use nix::sys::prctl;
+use nix::sys::stat::Mode;
+use nix::unistd::{self, ForkResult, Gid, Pid, Uid, User};
+use serde::{Deserialize, Serialize};
+
+use crate::diagnostics::Reporter;
+use crate::error::CartonError;
+use crate::idmap::{self, IdMapping, IdMappings, SUBGID_PATH, SUBUID_PATH};
+
+/// carton's end of the socket to the helper, once it dropped its privileges.
+static HELPER: Mutex<Option<Helper>> = Mutex::new(None);
+
+struct Helper {
+    socket: UnixStream,
+    /// Name of the user that carton dropped its privileges to.
+    user: String,
+}
+
+/// Asks the helper to write the mappings of the user namespace of a process.
+#[derive(Debug, Serialize, Deserialize)]
+struct MappingRequest {
+    pid: i32,
+    /// Container ID, host ID and size of each mapping.
+    uids: Vec<[u32; 3]>,
+    gids: Vec<[u32; 3]>,
+}
+
+/// The user that carton runs as after dropping its privileges, which the helper acts for.
+struct Unprivileged {
+    uid: Uid,
+    gid: Gid,
+    name: String,
+}
+
+/// Switches the calling process from root to `user` (a name from the passwd database), leaving a
+/// helper process behind that stays root, see the module documentation. Has to be called while
+/// the process has only one thread, before anything else happens, so that the helper starts out
+/// without anything that was parsed.
+///
+/// When the process isn't root to begin with, this only checks that it already runs as `user`.
+pub fn drop_privileges(user: &str) -> Result<(), CartonError> {
+    let passwd = User::from_name(user)?.ok_or_else(|| {
+        CartonError::InvalidConfiguration(format!("the user {} doesn't exist", user))
+    })?;
+    if !unistd::geteuid().is_root() {
+        if unistd::geteuid() == passwd.uid {
+            return Ok(());
+        }
+        return Err(CartonError::InvalidConfiguration(format!(
+            "carton has to be started as root or as {} to drop its privileges to {}",
+            user, user
+        )));
+    }
+    if passwd.uid.is_root() {
+        return Err(CartonError::InvalidConfiguration(format!(
+            "can't drop privileges to {}, which is root",
+            user
+        )));
+    }
+
+    let unprivileged = Unprivileged {
+        uid: passwd.uid,
+        gid: passwd.gid,
+        name: passwd.name.clone(),
+    };
+    let (carton, helper) = UnixStream::pair()?;
+    // SAFETY: the process has a single thread, so the child can do anything the parent could
+    match unsafe { unistd::fork() }? {
+        ForkResult::Child => {
+            drop(carton);
+            serve(helper, &unprivileged);
+            process::exit(0);
+        }
+        ForkResult::Parent { .. } => drop(helper),
+    }
+
+    unistd::setgroups(&[])?;
+    unistd::setresgid(passwd.gid, passwd.gid, passwd.gid)?;
+    unistd::setresuid(passwd.uid, passwd.uid, passwd.uid)?;
+    // Changing credentials makes the process undumpable, which would leave its /proc/<pid> (and
+    // that of the containers it creates) owned by root, where the helper can't tell it's the
+    // user's and the user can't write to it
+    prctl::set_dumpable(true)?;
+
+    env::set_var("HOME", &passwd.dir);
+    env::set_var("USER", &passwd.name);
+    env::set_var("LOGNAME", &passwd.name);
+    // These point to root's directories, which the user can't write to
+    for variable in ["XDG_RUNTIME_DIR", "XDG_DATA_HOME", "XDG_CONFIG_HOME"] {
+        env::remove_var(variable);
+    }
+
+    *HELPER.lock().unwrap() = Some(Helper {
+        socket: carton,
+        user: passwd.name,
+    });
+    Ok(())
+}
+
+/// Returns the name of the user that carton dropped its privileges to, if it did.
+pub(crate) fn unprivileged_user() -> Option<String> {
+    HELPER
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|helper| helper.user.clone())
+}
+
+/// Has the helper write the mappings of the user namespace of the process `pid`. Returns `None`
+/// when there is no helper because carton didn't drop its privileges.
+pub(crate) fn write_mappings(
+    reporter: &Reporter,
+    pid: Pid,
+    mappings: &IdMappings,
+) -> Option<Result<(), CartonError>> {
+    let mut helper = HELPER.lock().unwrap();
+    let helper = helper.as_mut()?;
+
+    let triples = |mappings: &[IdMapping]| {
+        mappings
+            .iter()
+            .map(|mapping| [mapping.container_id, mapping.host_id, mapping.size])
+            .collect()
+    };
+    let request = MappingRequest {
+        pid: pid.as_raw(),
+        uids: triples(&mappings.uids),
+        gids: triples(&mappings.gids),
+    };
+
+    Some(reporter.operation(
+        format_args!("helper: write mappings of process {}: {:?}", pid, request),
+        send(&mut helper.socket, &request),
+    ))
+}
+
+fn send(helper: &mut UnixStream, request: &MappingRequest) -> Result<(), CartonError> {
+    let lost = |e: std::io::Error| {
+        CartonError::NamespaceError(format!("the privileged helper is gone: {}", e))
+    };
+    let invalid = |e: serde_json::Error| {
+        CartonError::NamespaceError(format!(
+            "invalid message to or from the privileged helper: {}",
+            e
+        ))
+    };
+    let mut line = serde_json::to_vec(request).map_err(invalid)?;
+    line.push(b'\n');
+    helper.write_all(&line).map_err(lost)?;
+
+    let mut reply = String::new();
+    BufReader::new(&*helper)
+        .read_line(&mut reply)
+        .map_err(lost)?;
+    if reply.is_empty() {
+        return Err(CartonError::NamespaceError(
+            "the privileged helper exited".into(),
+        ));
+    }
+    serde_json::from_str(&reply).map_err(invalid)?
+}
+
+/// Answers the requests that come in over `socket` until carton closes it.
+fn serve(socket: UnixStream, user: &Unprivileged) {
+    let mut replies = match socket.try_clone() {
+        Ok(replies) => replies,
+        Err(_) => return,
+    };
+    for line in BufReader::new(socket).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        let result = serde_json::from_str(&line)
+            .map_err(|e| CartonError::NamespaceError(format!("invalid request: {}", e)))
+            .and_then(|request| handle(&request, user));
+        let Ok(mut reply) = serde_json::to_vec(&result) else {
+            return;
+        };
+        reply.push(b'\n');
+        if replies.write_all(&reply).is_err() {
+            return;
+        }
+    }
+}
+
+fn handle(request: &MappingRequest, user: &Unprivileged) -> Result<(), CartonError> {
+    let denied = |what: String| CartonError::PermissionDenied {
+        operation: format!("writing the ID mappings of process {}", request.pid),
+        error: what,
+        hint: format!(
+            "{} can only map its own IDs and its ranges in {} and {}",
+            user.name, SUBUID_PATH, SUBGID_PATH
+        ),
+    };
+
+    // Everything below goes through this directory, so it's about the same process even if the
+    // process exits and its ID is reused
+    let process = fcntl::open(
+        format!("/proc/{}", request.pid).as_str(),
+        OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    // SAFETY: the descriptor was just opened and isn't used elsewhere
+    let process = unsafe { File::from_raw_fd(process) };
+    if process.metadata()?.uid() != user.uid.as_raw() {
+        return Err(denied(format!(
+            "the process doesn't belong to {}",
+            user.name
+        )));
+    }
+
+    let subuids = idmap::subordinate_ids(SUBUID_PATH, Some(&user.name), user.uid.as_raw())?;
+    let subgids = idmap::subordinate_ids(SUBGID_PATH, Some(&user.name), user.uid.as_raw())?;
+    let mappings = IdMappings {
+        uids: allowed(&request.uids, user.uid.as_raw(), subuids).map_err(denied)?,
+        gids: allowed(&request.gids, user.gid.as_raw(), subgids).map_err(denied)?,
+    };
+
+    // A group that's only mapped because it's the user's own could be dropped with setgroups(),
+    // which would get the container past files that deny that group access (CVE-2018-7169), so
+    // like newgidmap, the namespace can't call it then. That has to be settled before gid_map is
+    // written.
+    if !mappings
+        .gids
+        .iter()
+        .all(|mapping| subordinate(mapping.host_id, mapping.size, subgids))
+    {
+        write_at(&process, "setgroups", "deny")?;
+    }
+    write_at(&process, "uid_map", &idmap::mapping_lines(&mappings.uids))?;
+    write_at(&process, "gid_map", &idmap::mapping_lines(&mappings.gids))?;
+
+    Ok(())
+}
+
+/// Writes `contents` to the file `name` in the directory `dir`.
+fn write_at(dir: &File, name: &str, contents: &str) -> Result<(), CartonError> {
+    let file = fcntl::openat(
+        dir.as_raw_fd(),
+        name,
+        OFlag::O_WRONLY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    // SAFETY: the descriptor was just opened and isn't used elsewhere
+    let mut file = unsafe { File::from_raw_fd(file) };
+    file.write_all(contents.as_bytes())?;
+
+    Ok(())
+}
+
+/// Returns whether the `size` IDs from `host_id` are all within the range `subordinate` (start
+/// and size).
+fn subordinate(host_id: u32, size: u32, subordinate: Option<(u32, u32)>) -> bool {
+    subordinate.map_or(false, |(start, count)| {
+        host_id >= start
+            && u64::from(host_id) + u64::from(size) <= u64::from(start) + u64::from(count)
+    })
+}
+
+/// Returns `requested` (container ID, host ID and size of each mapping) as mappings if every one
+/// of them maps only `own_id`, or IDs within the range `subordinate` (start and size).
+fn allowed(
+    requested: &[[u32; 3]],
+    own_id: u32,
+    subordinate: Option<(u32, u32)>,
+) -> Result<Vec<IdMapping>, String> {
+    requested
+        .iter()
+        .map(|&[container_id, host_id, size]| {
+            let end = u64::from(host_id) + u64::from(size);
+            let own = host_id == own_id && size == 1;
+            if size == 0 || !(own || self::subordinate(host_id, size, subordinate)) {
+                return Err(format!(
+                    "the host IDs {}-{} are neither the user's own ID nor subordinate IDs of the \
+                     user",
+                    host_id,
+                    end.saturating_sub(1)
+                ));
+            }
+            Ok(IdMapping::new(container_id, host_id, size))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_own_and_subordinate_ids() {
+        let subordinate = Some((100_000, 65_536));
+
+        assert_eq!(
+            allowed(&[[0, 1000, 1], [1, 100_000, 65_536]], 1000, subordinate),
+            Ok(vec![
+                IdMapping::new(0, 1000, 1),
+                IdMapping::new(1, 100_000, 65_536)
+            ])
+        );
+        assert!(allowed(&[[0, 1000, 2]], 1000, subordinate).is_err());
+        assert!(allowed(&[[0, 0, 1]], 1000, subordinate).is_err());
+        assert!(allowed(&[[1, 100_000, 65_537]], 1000, subordinate).is_err());
+        assert!(allowed(&[[1, 99_999, 10]], 1000, subordinate).is_err());
+        assert!(allowed(&[[1, 100_000, 10]], 1000, None).is_err());
+        assert!(allowed(&[[0, 1000, 0]], 1000, subordinate).is_err());
+    }
+
+    #[test]
+    fn tells_subordinate_ids_apart() {
+        let range = Some((100_000, 65_536));
+
+        assert!(subordinate(100_000, 65_536, range));
+        assert!(subordinate(100_010, 10, range));
+        assert!(!subordinate(1000, 1, range));
+        assert!(!subordinate(100_000, 65_537, range));
+        assert!(!subordinate(u32::MAX, 1, range));
+        assert!(!subordinate(100_000, 1, None));
+    }
+}
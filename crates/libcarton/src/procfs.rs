@@ -0,0 +1,109 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Mount options of the container's /proc, which limit what its processes can learn about each
+//! other and about the host, see "Mount options" in proc(5).
+//!
+//! Every mount of procfs has options of its own (since Linux 5.8), so the container's /proc can be
+//! stricter than the host's.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::CartonError;
+
+/// Who can see the `/proc/<pid>` directories of processes of other users.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HidePid {
+    /// Everyone can see every process, which is the default.
+    Off,
+    /// Everyone can list the directories, but only read the details of their own processes.
+    NoAccess,
+    /// The processes of other users are invisible.
+    Invisible,
+    /// Only the processes that one may ptrace() are visible.
+    Ptraceable,
+}
+
+impl HidePid {
+    /// Returns the value of the `hidepid=` option. The numbers work on kernels from before 5.8,
+    /// which only take numbers.
+    fn value(self) -> u8 {
+        match self {
+            HidePid::Off => 0,
+            HidePid::NoAccess => 1,
+            HidePid::Invisible => 2,
+            HidePid::Ptraceable => 4,
+        }
+    }
+}
+
+impl FromStr for HidePid {
+    type Err = CartonError;
+
+    /// Parses the names of proc(5) (like `invisible`) or their numbers (like `2`).
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" | "0" => Ok(HidePid::Off),
+            "noaccess" | "1" => Ok(HidePid::NoAccess),
+            "invisible" | "2" => Ok(HidePid::Invisible),
+            "ptraceable" | "4" => Ok(HidePid::Ptraceable),
+            _ => Err(CartonError::InvalidConfiguration(format!(
+                "invalid hidepid value `{}`, expected off, noaccess, invisible or ptraceable",
+                value
+            ))),
+        }
+    }
+}
+
+/// Mount options of the container's /proc, see `ContainerBuilder::procfs_options()`.
+///
+/// ```no_run
+/// use libcarton::{ContainerBuilder, HidePid, ProcfsOptions};
+///
+/// let builder = ContainerBuilder::new()
+///     .add_default_mounts()
+///     .procfs_options(ProcfsOptions::new().hidepid(HidePid::Invisible).subset_pid());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProcfsOptions {
+    hidepid: Option<HidePid>,
+    subset_pid: bool,
+}
+
+impl ProcfsOptions {
+    pub fn new() -> Self {
+        ProcfsOptions::default()
+    }
+
+    pub fn hidepid(mut self, hidepid: HidePid) -> Self {
+        self.hidepid = Some(hidepid);
+        self
+    }
+
+    /// Leaves everything out of /proc but the process directories (`subset=pid`), so that files
+    /// like `/proc/cpuinfo` and `/proc/sys` aren't there. Needs Linux 5.8 or later.
+    pub fn subset_pid(mut self) -> Self {
+        self.subset_pid = true;
+        self
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.hidepid.is_none() && !self.subset_pid
+    }
+}
+
+impl fmt::Display for ProcfsOptions {
+    /// Formats the options like the data of a mount, like `hidepid=2,subset=pid`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut options = Vec::new();
+        if let Some(hidepid) = self.hidepid {
+            options.push(format!("hidepid={}", hidepid.value()));
+        }
+        if self.subset_pid {
+            options.push("subset=pid".into());
+        }
+
+        write!(f, "{}", options.join(","))
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Limits on how much disk space a container's root filesystem can take up, with project quotas.
+//!
+//! Filesystems with project quotas (XFS, and ext4 created with `-O quota,project` and mounted
+//! with `prjquota`) count the space of every file towards the quota of its project, a number that
+//! files and directories carry in their extended attributes. Carton gives every file of the root
+//! filesystem the container's project ID, marks the directories so that new files inherit it, and
+//! sets a limit for the project. Writes that go beyond the limit fail with ENOSPC (or EDQUOT).
+//!
+//! An ephemeral container's writes go to a tmpfs instead, which is limited with its `size` option.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::Path;
+
+use log::info;
+
+use nix::errno::Errno;
+use nix::libc;
+use nix::{ioctl_read, ioctl_write_ptr};
+
+use crate::error::CartonError;
+
+// These aren't in the libc crate (yet), see include/uapi/linux/fs.h and include/uapi/linux/quota.h
+const FS_XFLAG_PROJINHERIT: u32 = 0x0000_0200;
+const Q_SETQUOTA: u32 = 0x80_0008;
+const PRJQUOTA: u32 = 2;
+const QIF_BLIMITS: u32 = 1;
+/// Quota limits are counted in blocks of this size, whatever the filesystem's block size is.
+const QUOTA_BLOCK_SIZE: u64 = 1024;
+
+#[repr(C)]
+#[derive(Default)]
+struct FsXattr {
+    xflags: u32,
+    extsize: u32,
+    nextents: u32,
+    projid: u32,
+    cowextsize: u32,
+    pad: [u8; 8],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct IfDqblk {
+    bhardlimit: u64,
+    bsoftlimit: u64,
+    curspace: u64,
+    ihardlimit: u64,
+    isoftlimit: u64,
+    curinodes: u64,
+    btime: u64,
+    itime: u64,
+    valid: u32,
+}
+
+ioctl_read!(get_xattr, b'X', 31, FsXattr);
+ioctl_write_ptr!(set_xattr, b'X', 32, FsXattr);
+
+/// Returns the project ID for the container `id`, which is derived from the (random) ID. Project
+/// 0 is the default project of all files, so it's never used.
+pub(crate) fn project_id(id: &str) -> u32 {
+    let project_id = u32::from_str_radix(id.get(..8).unwrap_or(id), 16).unwrap_or(0);
+
+    project_id.max(1)
+}
+
+/// Limits the space that the files in `dir` (on the same filesystem) can take up to `limit`
+/// bytes, by assigning them to `project_id` and limiting that project. This includes the files
+/// that are in `dir` already.
+pub(crate) fn limit(dir: &Path, project_id: u32, limit: u64) -> Result<(), CartonError> {
+    info!(
+        "limiting {} to {} bytes with project quota {}",
+        dir.display(),
+        limit,
+        project_id
+    );
+    let root = File::open(dir)?;
+    let device = root.metadata()?.dev();
+    assign_project(&root, project_id).map_err(|e| unsupported(dir, e))?;
+    assign_tree(dir, device, project_id).map_err(|e| unsupported(dir, e))?;
+
+    set_block_limit(&root, project_id, limit / QUOTA_BLOCK_SIZE)
+}
+
+/// Removes the limit of `project_id` on the filesystem of `dir` again. The files keep their project
+/// ID, which doesn't matter once the project has no limit.
+pub(crate) fn remove_limit(dir: &Path, project_id: u32) -> Result<(), CartonError> {
+    set_block_limit(&File::open(dir)?, project_id, 0)
+}
+
+fn set_block_limit(file: &File, project_id: u32, blocks: u64) -> Result<(), CartonError> {
+    let quota = IfDqblk {
+        bhardlimit: blocks,
+        bsoftlimit: blocks,
+        valid: QIF_BLIMITS,
+        ..Default::default()
+    };
+    // quotactl_fd() (Linux 5.14 and up) saves looking up the filesystem's block device
+    Errno::result(unsafe {
+        libc::syscall(
+            libc::SYS_quotactl_fd,
+            file.as_raw_fd(),
+            (Q_SETQUOTA << 8) | PRJQUOTA,
+            project_id,
+            &quota as *const IfDqblk,
+        )
+    })
+    .map_err(|e| {
+        CartonError::InvalidConfiguration(format!(
+            "could not set a project quota, is the filesystem mounted with prjquota? ({})",
+            e
+        ))
+    })?;
+
+    Ok(())
+}
+
+/// Assigns the files and directories in `dir` to the project, without crossing into other
+/// filesystems. Symlinks and special files can't be opened to change their project, but they take
+/// up next to no space.
+fn assign_tree(dir: &Path, device: u64, project_id: u32) -> Result<(), CartonError> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if !file_type.is_dir() && !file_type.is_file() {
+            continue;
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+            .open(entry.path())?;
+        if file.metadata()?.dev() != device {
+            continue;
+        }
+        assign_project(&file, project_id)?;
+
+        if file_type.is_dir() {
+            assign_tree(&entry.path(), device, project_id)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn assign_project(file: &File, project_id: u32) -> nix::Result<()> {
+    let mut attributes = FsXattr::default();
+    unsafe { get_xattr(file.as_raw_fd(), &mut attributes) }?;
+    attributes.projid = project_id;
+    if file.metadata().map_or(false, |metadata| metadata.is_dir()) {
+        attributes.xflags |= FS_XFLAG_PROJINHERIT;
+    }
+    unsafe { set_xattr(file.as_raw_fd(), &attributes) }?;
+
+    Ok(())
+}
+
+fn unsupported(dir: &Path, error: impl fmt::Display) -> CartonError {
+    CartonError::InvalidConfiguration(format!(
+        "could not assign {} to a quota project, storage limits need a filesystem with project \
+         quotas, like XFS or ext4: {}",
+        dir.display(),
+        error
+    ))
+}
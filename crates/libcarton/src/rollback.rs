@@ -0,0 +1,124 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Undoes the setup of a container that failed halfway.
+//!
+//! Setting up a container leaves things behind on the host: the directories and files that mount
+//! points and device nodes need in the root filesystem (which is a directory of the host), the
+//! container's cgroup and its storage limit. Every step that does so is recorded in a `Rollback`,
+//! and when a later step fails, they're undone in reverse order. The mounts themselves are in the
+//! container's mount namespace, which disappears with the container process, but they're recorded
+//! too, because the directories underneath can't be removed while something is mounted on them.
+//!
+//! The calling process and the container process each have a `Rollback` of their own for the
+//! steps they take.
+
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use nix::mount::MntFlags;
+
+use crate::cgroup;
+use crate::diagnostics::Reporter;
+use crate::quota;
+use crate::syscalls::Syscalls;
+
+/// A step of the setup that can be undone.
+#[derive(Debug)]
+pub(crate) enum Step {
+    /// A directory that was created. It's only removed when it's empty.
+    Directory(PathBuf),
+    /// A file, device node or symlink that was created.
+    File(PathBuf),
+    /// A filesystem that was mounted.
+    Mount(PathBuf),
+    /// A copy of a file or directory, which is removed with everything in it.
+    Copy(PathBuf),
+    /// The cgroup of the container.
+    Cgroup(PathBuf),
+    /// The project quota that limits the root filesystem.
+    StorageLimit { dir: PathBuf, project_id: u32 },
+}
+
+/// The steps taken so far. It's used through shared references, like the container's
+/// configuration, so the steps are in a `RefCell`.
+#[derive(Debug, Default)]
+pub(crate) struct Rollback {
+    steps: RefCell<Vec<Step>>,
+}
+
+impl Rollback {
+    pub(crate) fn record(&self, step: Step) {
+        self.steps.borrow_mut().push(step);
+    }
+
+    /// Like `fs::create_dir_all()`, but records the directories that it creates.
+    pub(crate) fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        if path.is_dir() {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+
+        match fs::create_dir(path) {
+            Ok(()) => {
+                self.record(Step::Directory(path.into()));
+                Ok(())
+            }
+            // Someone else may have been quicker, which is fine as long as it's a directory
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && path.is_dir() => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Creates an empty file at `path`, if there's nothing there yet, and records it.
+    pub(crate) fn create_file(&self, path: &Path) -> io::Result<()> {
+        if path.exists() {
+            return Ok(());
+        }
+        File::create(path)?;
+        self.record(Step::File(path.into()));
+
+        Ok(())
+    }
+
+    /// Undoes the steps in reverse order, unmounting through `syscalls`. Steps that can't be undone
+    /// are reported as warnings, after which the rest are still undone.
+    pub(crate) fn undo(&self, syscalls: &dyn Syscalls, reporter: &Reporter) {
+        let steps = self.steps.take();
+        if steps.is_empty() {
+            return;
+        }
+
+        reporter.info(format_args!("rolling back {} setup steps", steps.len()));
+        for step in steps.iter().rev() {
+            let result = match step {
+                Step::Directory(path) => fs::remove_dir(path).map_err(|e| e.to_string()),
+                Step::File(path) => fs::remove_file(path).map_err(|e| e.to_string()),
+                Step::Mount(path) => syscalls
+                    .umount(path, MntFlags::MNT_DETACH)
+                    .map_err(|e| e.to_string()),
+                Step::Copy(path) if path.is_dir() => {
+                    fs::remove_dir_all(path).map_err(|e| e.to_string())
+                }
+                Step::Copy(path) => match fs::remove_file(path) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => Err(e.to_string()),
+                    _ => Ok(()),
+                },
+                Step::Cgroup(path) => {
+                    cgroup::remove(path);
+                    Ok(())
+                }
+                Step::StorageLimit { dir, project_id } => {
+                    quota::remove_limit(dir, *project_id).map_err(|e| e.to_string())
+                }
+            };
+            if let Err(e) = result {
+                reporter.warning(format_args!("Could not undo {:?}: {}", step, e));
+            }
+        }
+    }
+}
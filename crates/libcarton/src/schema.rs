@@ -0,0 +1,176 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Versions of the files that carton writes and reads back later, like a container's state file,
+//! see `Schema`.
+//!
+//! A container's state outlives the carton that created it: after an upgrade, the new carton has
+//! to read the state files of the containers that the old one started. Each file carries the
+//! version of its layout in a `schema_version` field. A file without one predates versioning and
+//! counts as version 1. When the layout changes, the version goes up by one, and a migration that
+//! rewrites a file of the previous version into the new layout is added to the list of the
+//! schema. Files are migrated one version at a time as they're read (as JSON values, before
+//! they're deserialized), and written in the current version.
+//!
+//! A file of a version newer than carton knows of is refused, with an error that says so, rather
+//! than misread.
+
+use serde_json::{Map, Value};
+
+/// The field with the version of a file.
+const VERSION_FIELD: &str = "schema_version";
+
+/// Rewrites a file of one version into the layout of the next.
+pub(crate) type Migration = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+/// The layout of a kind of file, with the migrations that lead up to its current version.
+pub(crate) struct Schema {
+    /// What the file is, for errors, like "state file".
+    pub(crate) kind: &'static str,
+    /// The migration from version 1 to 2 first, then from 2 to 3 and so on.
+    pub(crate) migrations: &'static [Migration],
+}
+
+impl Schema {
+    /// Returns the version that carton writes.
+    pub(crate) fn version(&self) -> u64 {
+        self.migrations.len() as u64 + 1
+    }
+
+    /// Sets the version of `value`, a file that's about to be written, to the current one.
+    pub(crate) fn stamp(&self, value: &mut Value) {
+        if let Value::Object(fields) = value {
+            fields.insert(VERSION_FIELD.into(), self.version().into());
+        }
+    }
+
+    /// Brings `value`, a file that was read, up to the current version, and takes its version
+    /// field out so that it deserializes like before versioning.
+    pub(crate) fn migrate(&self, value: &mut Value) -> Result<(), String> {
+        let Value::Object(fields) = value else {
+            return Err(format!("a {} should be a JSON object", self.kind));
+        };
+        let version = match fields.remove(VERSION_FIELD) {
+            None => 1,
+            Some(version) => version
+                .as_u64()
+                .filter(|version| *version >= 1)
+                .ok_or_else(|| {
+                    format!("invalid {} of {}: {}", VERSION_FIELD, self.kind, version)
+                })?,
+        };
+        if version > self.version() {
+            return Err(format!(
+                "the {} was written by a newer carton, it has schema version {} and this carton \
+                 only knows up to version {}",
+                self.kind,
+                version,
+                self.version()
+            ));
+        }
+
+        for (from, migration) in self
+            .migrations
+            .iter()
+            .enumerate()
+            .skip(version as usize - 1)
+        {
+            migration(fields).map_err(|e| {
+                format!(
+                    "migrating {} from schema version {} to {}: {}",
+                    self.kind,
+                    from + 1,
+                    from + 2,
+                    e
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn add_b(fields: &mut Map<String, Value>) -> Result<(), String> {
+        fields.insert("b".into(), json!(2));
+        Ok(())
+    }
+
+    fn rename_a(fields: &mut Map<String, Value>) -> Result<(), String> {
+        let a = fields.remove("a").ok_or("no a")?;
+        fields.insert("c".into(), a);
+        Ok(())
+    }
+
+    const SCHEMA: Schema = Schema {
+        kind: "test file",
+        migrations: &[add_b, rename_a],
+    };
+
+    #[test]
+    fn migrates_files_without_a_version_from_version_1() {
+        let mut value = json!({"a": 1});
+        SCHEMA.migrate(&mut value).unwrap();
+
+        assert_eq!(value, json!({"b": 2, "c": 1}));
+    }
+
+    #[test]
+    fn migrates_from_the_version_of_the_file() {
+        let mut value = json!({"schema_version": 2, "a": 1});
+        SCHEMA.migrate(&mut value).unwrap();
+
+        assert_eq!(value, json!({"c": 1}));
+
+        let mut value = json!({"schema_version": 3, "c": 1});
+        SCHEMA.migrate(&mut value).unwrap();
+
+        assert_eq!(value, json!({"c": 1}));
+    }
+
+    #[test]
+    fn refuses_newer_versions() {
+        let mut value = json!({"schema_version": 4, "c": 1});
+
+        assert_eq!(
+            SCHEMA.migrate(&mut value),
+            Err(
+                "the test file was written by a newer carton, it has schema version 4 and this \
+                 carton only knows up to version 3"
+                    .into()
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_versions() {
+        for version in [json!(0), json!(-1), json!(1.5), json!("2"), json!(null)] {
+            let mut value = json!({"schema_version": version, "a": 1});
+            assert!(SCHEMA.migrate(&mut value).is_err(), "{}", version);
+        }
+        assert!(SCHEMA.migrate(&mut json!([1])).is_err());
+    }
+
+    #[test]
+    fn reports_failed_migrations() {
+        let mut value = json!({"schema_version": 2});
+
+        assert_eq!(
+            SCHEMA.migrate(&mut value),
+            Err("migrating test file from schema version 2 to 3: no a".into())
+        );
+    }
+
+    #[test]
+    fn stamps_the_current_version() {
+        let mut value = json!({"c": 1});
+        SCHEMA.stamp(&mut value);
+
+        assert_eq!(value, json!({"schema_version": 3, "c": 1}));
+    }
+}
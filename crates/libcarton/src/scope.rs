@@ -0,0 +1,97 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Running a container in a transient systemd scope of the user's session, see
+//! `ContainerBuilder::systemd_scope()`.
+//!
+//! In a desktop or login session, the user's own service manager (`systemd --user`) keeps track of
+//! the user's programs in units, with the limits of the session's slices, and stops them when the
+//! user logs out. Carton asks it to create a scope unit `carton-<id>.scope` with the container's
+//! process in it (`StartTransientUnit` on its D-Bus interface, with `busctl`), before the container
+//! process gets going. The container's processes then show up in `systemctl --user status` and
+//! `systemd-cgls`, and systemd removes the scope once they're gone.
+//!
+//! The service manager moves the process into the scope's cgroup, which it can only do for
+//! processes in its own part of the cgroup tree. So this is for carton running as the user of the
+//! session, not as root.
+
+use std::fs;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use nix::unistd::Pid;
+
+use crate::error::CartonError;
+
+/// How long systemd gets to move the container's process into the scope.
+const TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Returns the name of the scope of the container `id`.
+pub(crate) fn unit_name(id: &str) -> String {
+    format!("carton-{}.scope", id)
+}
+
+/// Creates the scope of the container `id` (described as `description`) with its process `pid` in
+/// it, and waits until the process is in the scope's cgroup.
+pub(crate) fn start(id: &str, pid: Pid, description: &str) -> Result<(), CartonError> {
+    let unit = unit_name(id);
+    info!("moving the container process into systemd scope {}", unit);
+
+    let output = Command::new("busctl")
+        .args([
+            "--user",
+            "call",
+            "org.freedesktop.systemd1",
+            "/org/freedesktop/systemd1",
+            "org.freedesktop.systemd1.Manager",
+            "StartTransientUnit",
+            "ssa(sv)a(sa(sv))",
+            &unit,
+            // Fails when a unit of that name exists already
+            "fail",
+            "3",
+            "PIDs",
+            "au",
+            "1",
+            &pid.to_string(),
+            "Description",
+            "s",
+            description,
+            // A scope whose processes were killed is gone as well
+            "CollectMode",
+            "s",
+            "inactive-or-failed",
+            // No auxiliary units
+            "0",
+        ])
+        .output()
+        .map_err(|e| CartonError::ScopeError(format!("could not run busctl: {}", e)))?;
+    if !output.status.success() {
+        return Err(CartonError::ScopeError(format!(
+            "could not create {} with the user's service manager: {}",
+            unit,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    // Starting the scope is a job of its own, which the call doesn't wait for
+    let suffix = format!("/{}", unit);
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let cgroups = fs::read_to_string(format!("/proc/{}/cgroup", pid))?;
+        if cgroups.lines().any(|line| line.ends_with(&suffix)) {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(CartonError::ScopeError(format!(
+                "systemd did not move the container process into {} within {:?}",
+                unit, TIMEOUT
+            )));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
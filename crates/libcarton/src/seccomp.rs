@@ -0,0 +1,641 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Handling of selected system calls of the container by the process that started it, through
+//! seccomp's [user space notification][1].
+//!
+//! seccomp lets a process install a filter, a small BPF program that the kernel runs on every
+//! system call the process (and its children) makes. Usually the filter decides to allow the call
+//! or to fail it, but it can also decide to "notify" a supervisor. The kernel then puts the calling
+//! thread on hold and hands the system call to whoever holds the "listener" file descriptor that
+//! was created with the filter. The supervisor can let the kernel go ahead with the call, or make
+//! it fail or return a value without executing it.
+//!
+//! The container process installs the filter right before it executes the command, and sends the
+//! listener to carton over a socket, because their file descriptor tables aren't shared. A thread
+//! in carton then passes the system calls to a handler until the container exits.
+//!
+//! The same mechanism learns which system calls a container makes, see `SeccompRecorder`: its
+//! filter hands every system call to a handler that records it and lets it go ahead. The one
+//! exception is the `sendmsg()` that sends the listener, which would wait for the listener
+//! otherwise. That `sendmsg()` is recorded anyway, because the container process made it. The
+//! result is a `SeccompProfile`, whose filter allows those system calls and fails all others with
+//! `EPERM`. Either filter is installed at the same point, so a profile also has the system calls
+//! that carton makes between installing the filter and executing the command.
+//!
+//! [1]: https://man7.org/linux/man-pages/man2/seccomp_unotify.2.html
+
+use std::collections::BTreeSet;
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, IoSlice, IoSliceMut};
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::warn;
+
+use nix::errno::Errno;
+use nix::libc;
+use nix::poll::{self, PollFd, PollFlags};
+use nix::sys::socket::{
+    self, AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockFlag, SockType,
+};
+use nix::{cmsg_space, ioctl_readwrite};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::CartonError;
+use crate::schema::Schema;
+use crate::syscall_names::{syscall_name, syscall_number};
+
+/// The layout of saved profiles, see the `schema` module.
+const PROFILE_SCHEMA: Schema = Schema {
+    kind: "seccomp profile",
+    migrations: &[],
+};
+
+// These aren't in the libc crate (yet), see include/uapi/linux/seccomp.h
+const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc0_0000;
+const SECCOMP_FILTER_FLAG_NEW_LISTENER: libc::c_ulong = 1 << 3;
+const SECCOMP_USER_NOTIF_FLAG_CONTINUE: u32 = 1;
+
+/// Offsets of the system call number, the architecture and the lower half of the first argument
+/// (on little-endian architectures) in `struct seccomp_data`.
+const DATA_NR_OFFSET: u32 = 0;
+const DATA_ARCH_OFFSET: u32 = 4;
+const DATA_FIRST_ARG_OFFSET: u32 = 16;
+/// The most instructions that a BPF program can have (`BPF_MAXINSNS`).
+const MAX_INSTRUCTIONS: usize = 4096;
+
+/// The architecture that system call numbers are checked against, because another architecture
+/// (like 32-bit x86 on x86-64) has different numbers.
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+#[repr(C)]
+struct SeccompNotif {
+    id: u64,
+    pid: u32,
+    flags: u32,
+    data: libc::seccomp_data,
+}
+
+#[repr(C)]
+struct SeccompNotifResp {
+    id: u64,
+    val: i64,
+    error: i32,
+    flags: u32,
+}
+
+ioctl_readwrite!(notif_recv, b'!', 0, SeccompNotif);
+ioctl_readwrite!(notif_send, b'!', 1, SeccompNotifResp);
+
+/// A system call of the container's process that is waiting for a decision.
+#[derive(Debug, Clone)]
+pub struct SyscallRequest {
+    /// PID of the calling process, as seen from the host. Pointer arguments point into the memory
+    /// of this process, which can be read through `/proc/<pid>/mem`. Note that another thread of
+    /// the process could change that memory after it has been checked.
+    pub pid: i32,
+    /// The system call number, like `libc::SYS_mount`.
+    pub syscall: i64,
+    pub args: [u64; 6],
+}
+
+impl SyscallRequest {
+    /// Returns the name of the system call, like `mount`, if carton knows it.
+    pub fn name(&self) -> Option<&'static str> {
+        syscall_name(self.syscall)
+    }
+
+    /// Reads the NUL-terminated string at `address` in the memory of the calling process, like
+    /// the path of `openat()`, without the NUL. Reads no more than `PATH_MAX` bytes.
+    pub fn read_string(&self, address: u64) -> io::Result<Vec<u8>> {
+        let memory = File::open(format!("/proc/{}/mem", self.pid))?;
+        let mut string = Vec::new();
+        let mut chunk = [0; 256];
+        while string.len() < libc::PATH_MAX as usize {
+            // A short read means that the rest of the chunk isn't mapped
+            let read = match memory.read_at(&mut chunk, address + string.len() as u64) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(_) if !string.is_empty() => break,
+                Err(e) => return Err(e),
+            };
+            if let Some(end) = chunk[..read].iter().position(|byte| *byte == 0) {
+                string.extend_from_slice(&chunk[..end]);
+                return Ok(string);
+            }
+            string.extend_from_slice(&chunk[..read]);
+        }
+        string.truncate(libc::PATH_MAX as usize);
+
+        Ok(string)
+    }
+}
+
+/// What to do with a system call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallResponse {
+    /// Let the kernel execute the system call as usual.
+    Continue,
+    /// Return this value without executing the system call.
+    Return(i64),
+    /// Fail with this error without executing the system call.
+    Error(Errno),
+}
+
+type Handler = dyn Fn(&SyscallRequest) -> SyscallResponse + Send + Sync;
+
+/// The system calls that a filter passes to the handler.
+#[derive(Debug, Clone)]
+enum Selection {
+    Syscalls(Vec<i64>),
+    All,
+}
+
+/// The system calls to pass to a handler, see `ContainerBuilder::seccomp_notify()`.
+#[derive(Clone)]
+pub(crate) struct SeccompNotify {
+    selection: Selection,
+    handler: Arc<Handler>,
+}
+
+impl fmt::Debug for SeccompNotify {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SeccompNotify")
+            .field("selection", &self.selection)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SeccompNotify {
+    pub(crate) fn new<F>(syscalls: Vec<i64>, handler: F) -> Self
+    where
+        F: Fn(&SyscallRequest) -> SyscallResponse + Send + Sync + 'static,
+    {
+        SeccompNotify {
+            selection: Selection::Syscalls(syscalls),
+            handler: Arc::new(handler),
+        }
+    }
+
+    /// Returns the notifications that pass every system call of the container to `handler`.
+    pub(crate) fn all<F>(handler: F) -> Self
+    where
+        F: Fn(&SyscallRequest) -> SyscallResponse + Send + Sync + 'static,
+    {
+        SeccompNotify {
+            selection: Selection::All,
+            handler: Arc::new(handler),
+        }
+    }
+
+    /// Returns the notifications that record every system call of the container in `recorder`.
+    pub(crate) fn record(recorder: SeccompRecorder) -> Self {
+        SeccompNotify::all(move |request: &SyscallRequest| {
+            recorder.record(request.syscall);
+            SyscallResponse::Continue
+        })
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), CartonError> {
+        if AUDIT_ARCH.is_none() {
+            return Err(CartonError::InvalidConfiguration(
+                "seccomp notifications are not supported on this architecture".into(),
+            ));
+        }
+        // A jump in a BPF program can't skip more than 255 instructions
+        if let Selection::Syscalls(syscalls) = &self.selection {
+            if syscalls.is_empty() || syscalls.len() > 254 {
+                return Err(CartonError::InvalidConfiguration(
+                    "seccomp notifications need between 1 and 254 system calls".into(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Creates the socket over which the container process sends the listener. Returns the end
+    /// for carton and the end for the container process.
+    pub(crate) fn socket_pair() -> Result<(OwnedFd, OwnedFd), CartonError> {
+        let (supervisor, container) = socket::socketpair(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            None,
+            SockFlag::SOCK_CLOEXEC,
+        )?;
+
+        Ok((supervisor, container))
+    }
+
+    /// Installs the filter in the calling process and sends the listener over `socket`. Called by
+    /// the container process right before it executes the command, because the filter applies to
+    /// the system calls that carton itself makes too.
+    pub(crate) fn install(&self, socket: RawFd) -> nix::Result<()> {
+        let filter = match &self.selection {
+            Selection::Syscalls(syscalls) => notify_filter(syscalls),
+            Selection::All => record_filter(socket),
+        };
+        let listener = install_filter(&filter, SECCOMP_FILTER_FLAG_NEW_LISTENER)? as RawFd;
+
+        let fds = [listener];
+        socket::sendmsg::<()>(
+            socket,
+            &[IoSlice::new(&[0])],
+            &[ControlMessage::ScmRights(&fds)],
+            MsgFlags::empty(),
+            None,
+        )?;
+
+        // The command doesn't need the listener, and shouldn't be able to answer its own calls
+        nix::unistd::close(listener)
+    }
+
+    /// Starts a thread that receives the listener over `socket` and answers the system calls of
+    /// the container with the handler. The thread stops when the container's processes are gone,
+    /// or when the container process exits without sending a listener.
+    pub(crate) fn supervise(&self, socket: OwnedFd) {
+        let handler = self.handler.clone();
+
+        thread::spawn(move || {
+            let listener = match receive_listener(&socket) {
+                Ok(Some(listener)) => listener,
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("Could not receive the seccomp listener: {}", e);
+                    return;
+                }
+            };
+            drop(socket);
+
+            if let Err(e) = answer_syscalls(&listener, handler.as_ref()) {
+                warn!("Stopped answering seccomp notifications: {}", e);
+            }
+        });
+    }
+}
+
+/// Records the system calls that a container makes, to make a `SeccompProfile` of them, see
+/// `ContainerBuilder::seccomp_record()`. Clones record into the same set.
+///
+/// ```no_run
+/// # use libcarton::{CartonError, ContainerBuilder, SeccompRecorder};
+/// # fn main() -> Result<(), CartonError> {
+/// let recorder = SeccompRecorder::new();
+/// let mut container = ContainerBuilder::new()
+///     .rootfs("/path/to/rootfs".into())
+///     .command("/usr/bin/app".into(), None)
+///     .seccomp_record(recorder.clone())
+///     .build()?;
+/// container.run()?;
+/// container.wait_for_exit()?;
+///
+/// recorder.profile().save("app.json".as_ref())?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SeccompRecorder {
+    syscalls: Arc<Mutex<BTreeSet<i64>>>,
+}
+
+impl SeccompRecorder {
+    pub fn new() -> Self {
+        SeccompRecorder::default()
+    }
+
+    fn record(&self, syscall: i64) {
+        self.syscalls
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(syscall);
+    }
+
+    /// Returns a profile that allows the system calls that were recorded so far.
+    pub fn profile(&self) -> SeccompProfile {
+        let mut syscalls = self
+            .syscalls
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        // See the module documentation
+        syscalls.insert(libc::SYS_sendmsg);
+
+        SeccompProfile::new(syscalls.into_iter().map(|syscall| {
+            syscall_name(syscall)
+                .map(String::from)
+                // A system call that's newer than carton is kept by its number
+                .unwrap_or_else(|| syscall.to_string())
+        }))
+    }
+}
+
+/// The system calls that a container may make, on one architecture. Other system calls fail with
+/// `EPERM`, see `ContainerBuilder::seccomp_profile()`. A profile is saved as JSON, like
+/// `{"architecture": "x86_64", "syscalls": ["brk", "execve", "read", "write"]}`. System calls that
+/// carton has no name for are given by their number. A saved profile carries a schema version as
+/// well, see the `schema` module.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SeccompProfile {
+    /// The architecture that the system call numbers are for, like `std::env::consts::ARCH`.
+    architecture: String,
+    syscalls: BTreeSet<String>,
+}
+
+impl SeccompProfile {
+    /// Returns the profile that allows `syscalls` (like `read`) on the host's architecture.
+    pub fn new<I>(syscalls: I) -> Self
+    where
+        I: IntoIterator<Item = String>,
+    {
+        SeccompProfile {
+            architecture: env::consts::ARCH.into(),
+            syscalls: syscalls.into_iter().collect(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, CartonError> {
+        let contents = fs::read(path).map_err(|e| {
+            CartonError::IOError(format!("reading seccomp profile {}: {}", path.display(), e))
+        })?;
+        let invalid = |e: String| {
+            CartonError::InvalidConfiguration(format!(
+                "invalid seccomp profile {}: {}",
+                path.display(),
+                e
+            ))
+        };
+        let mut value: Value =
+            serde_json::from_slice(&contents).map_err(|e| invalid(e.to_string()))?;
+        PROFILE_SCHEMA.migrate(&mut value).map_err(invalid)?;
+
+        serde_json::from_value(value).map_err(|e| invalid(e.to_string()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), CartonError> {
+        let mut value = serde_json::to_value(self)?;
+        PROFILE_SCHEMA.stamp(&mut value);
+        let mut contents = serde_json::to_vec_pretty(&value)?;
+        contents.push(b'\n');
+        fs::write(path, contents).map_err(|e| {
+            CartonError::IOError(format!("writing seccomp profile {}: {}", path.display(), e))
+        })
+    }
+
+    /// Returns the names of the system calls that the profile allows.
+    pub fn syscalls(&self) -> impl Iterator<Item = &str> {
+        self.syscalls.iter().map(String::as_str)
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), CartonError> {
+        if AUDIT_ARCH.is_none() || self.architecture != env::consts::ARCH {
+            return Err(CartonError::InvalidConfiguration(format!(
+                "the seccomp profile is for {}, not for {}",
+                self.architecture,
+                env::consts::ARCH
+            )));
+        }
+        self.numbers()?;
+
+        Ok(())
+    }
+
+    /// Returns the numbers of the system calls.
+    fn numbers(&self) -> Result<Vec<i64>, CartonError> {
+        let numbers = self
+            .syscalls
+            .iter()
+            .map(|name| {
+                syscall_number(name)
+                    .or_else(|| name.parse().ok())
+                    .ok_or_else(|| {
+                        CartonError::InvalidConfiguration(format!(
+                            "the seccomp profile has an unknown system call `{}`",
+                            name
+                        ))
+                    })
+            })
+            .collect::<Result<Vec<i64>, CartonError>>()?;
+        if allow_filter(&numbers).len() > MAX_INSTRUCTIONS {
+            return Err(CartonError::InvalidConfiguration(format!(
+                "the seccomp profile has too many system calls ({})",
+                numbers.len()
+            )));
+        }
+
+        Ok(numbers)
+    }
+
+    /// Installs the filter in the calling process. Called by the container process right before
+    /// it executes the command, like `SeccompNotify::install()`.
+    pub(crate) fn install(&self) -> Result<(), CartonError> {
+        install_filter(&self.filter()?, 0)?;
+
+        Ok(())
+    }
+
+    /// Returns the BPF program that `install()` installs, for a forked process that shouldn't
+    /// allocate, see `install_filter()`.
+    pub(crate) fn filter(&self) -> Result<Vec<libc::sock_filter>, CartonError> {
+        Ok(allow_filter(&self.numbers()?))
+    }
+}
+
+/// Installs the BPF program `filter` in the calling process, and returns what seccomp() returned,
+/// which is the listener with `SECCOMP_FILTER_FLAG_NEW_LISTENER`.
+pub(crate) fn install_filter(
+    filter: &[libc::sock_filter],
+    flags: libc::c_ulong,
+) -> nix::Result<libc::c_long> {
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr() as *mut libc::sock_filter,
+    };
+
+    Errno::result(unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            flags,
+            &program as *const libc::sock_fprog,
+        )
+    })
+}
+
+/// Builds a BPF program that notifies the supervisor of the selected system calls and allows
+/// all others.
+fn notify_filter(syscalls: &[i64]) -> Vec<libc::sock_filter> {
+    let count = syscalls.len() as u8;
+    let mut filter = vec![
+        statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_ARCH_OFFSET),
+        // Anything that isn't the native architecture is allowed, like it would be without
+        // the filter
+        jump(AUDIT_ARCH.unwrap_or_default(), 0, count + 1),
+        statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_NR_OFFSET),
+    ];
+    for (i, syscall) in syscalls.iter().enumerate() {
+        // Jumps are relative to the next instruction, so this lands on the "notify" return
+        filter.push(jump(*syscall as u32, count - i as u8, 0));
+    }
+    filter.push(statement(
+        libc::BPF_RET | libc::BPF_K,
+        libc::SECCOMP_RET_ALLOW,
+    ));
+    filter.push(statement(
+        libc::BPF_RET | libc::BPF_K,
+        SECCOMP_RET_USER_NOTIF,
+    ));
+
+    filter
+}
+
+/// Builds a BPF program that notifies the supervisor of all system calls, except for the
+/// `sendmsg()` on `socket` that sends it the listener.
+fn record_filter(socket: RawFd) -> Vec<libc::sock_filter> {
+    vec![
+        statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_ARCH_OFFSET),
+        jump(AUDIT_ARCH.unwrap_or_default(), 0, 4),
+        statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_NR_OFFSET),
+        jump(libc::SYS_sendmsg as u32, 0, 3),
+        statement(
+            libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+            DATA_FIRST_ARG_OFFSET,
+        ),
+        jump(socket as u32, 0, 1),
+        statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW),
+        statement(libc::BPF_RET | libc::BPF_K, SECCOMP_RET_USER_NOTIF),
+    ]
+}
+
+/// Builds a BPF program that allows `syscalls` of the native architecture and fails all other
+/// system calls with `EPERM`, also those of other architectures (like 32-bit x86 on x86-64), which
+/// would get around the filter otherwise.
+fn allow_filter(syscalls: &[i64]) -> Vec<libc::sock_filter> {
+    let deny = statement(
+        libc::BPF_RET | libc::BPF_K,
+        libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+    );
+    let mut filter = vec![
+        statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_ARCH_OFFSET),
+        jump(AUDIT_ARCH.unwrap_or_default(), 1, 0),
+        deny,
+        statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_NR_OFFSET),
+    ];
+    for syscall in syscalls {
+        // Each system call has a return of its own, so that no jump is longer than one
+        // instruction, however many system calls there are
+        filter.push(jump(*syscall as u32, 0, 1));
+        filter.push(statement(
+            libc::BPF_RET | libc::BPF_K,
+            libc::SECCOMP_RET_ALLOW,
+        ));
+    }
+    filter.push(deny);
+
+    filter
+}
+
+fn statement(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+/// Compares the loaded value with `k` and skips `jt` instructions when it's equal, or `jf`
+/// instructions when it's not.
+fn jump(k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+fn receive_listener(socket: &OwnedFd) -> nix::Result<Option<OwnedFd>> {
+    let mut buf = [0u8; 1];
+    let mut iov = [IoSliceMut::new(&mut buf)];
+    let mut cmsg_buffer = cmsg_space!([RawFd; 1]);
+
+    let message = socket::recvmsg::<()>(
+        socket.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg_buffer),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+
+    for cmsg in message.cmsgs() {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            if let Some(fd) = fds.first() {
+                return Ok(Some(unsafe { OwnedFd::from_raw_fd(*fd) }));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+fn answer_syscalls(listener: &OwnedFd, handler: &Handler) -> nix::Result<()> {
+    loop {
+        // The listener "hangs up" once all processes that use the filter are gone
+        let mut poll_fds = [PollFd::new(listener, PollFlags::POLLIN)];
+        match poll::poll(&mut poll_fds, -1) {
+            Ok(_) => {}
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e),
+        }
+        let revents = poll_fds[0].revents().unwrap_or(PollFlags::empty());
+        if !revents.contains(PollFlags::POLLIN) {
+            return Ok(());
+        }
+
+        // The kernel insists on a zeroed struct
+        let mut notification = unsafe { mem::zeroed::<SeccompNotif>() };
+        match unsafe { notif_recv(listener.as_raw_fd(), &mut notification) } {
+            Ok(_) => {}
+            // The calling thread was interrupted by a signal before the notification was received
+            Err(Errno::ENOENT | Errno::EINTR) => continue,
+            Err(e) => return Err(e),
+        }
+
+        let request = SyscallRequest {
+            pid: notification.pid as i32,
+            syscall: notification.data.nr as i64,
+            args: notification.data.args,
+        };
+        let mut response = SeccompNotifResp {
+            id: notification.id,
+            val: 0,
+            error: 0,
+            flags: 0,
+        };
+        match handler(&request) {
+            SyscallResponse::Continue => response.flags = SECCOMP_USER_NOTIF_FLAG_CONTINUE,
+            SyscallResponse::Return(value) => response.val = value,
+            SyscallResponse::Error(errno) => response.error = -(errno as i32),
+        }
+
+        match unsafe { notif_send(listener.as_raw_fd(), &mut response) } {
+            // The calling thread was interrupted or is gone, so there's no one to answer anymore
+            Ok(_) | Err(Errno::ENOENT) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Secrets, like passwords and keys, as files in /run/secrets, see `ContainerBuilder::add_secret()`.
+//!
+//! The container process mounts a tmpfs on /run/secrets in its own mount namespace, writes the
+//! secrets into it and makes it read-only, before it switches to the root filesystem. The secrets
+//! only ever exist in memory and are gone with the container: they aren't written to the root
+//! filesystem (or the writable layer of an ephemeral container), the container's directory or its
+//! state file, which only has their names. Only the container's user can read them.
+//!
+//! A checkpoint of the container does contain them, like the rest of its memory.
+
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use nix::mount::MsFlags;
+use nix::unistd::{self, Gid, Uid};
+
+use crate::container::Mount;
+use crate::diagnostics::Reporter;
+use crate::error::CartonError;
+use crate::rollback::Rollback;
+use crate::syscalls::Syscalls;
+
+/// Where the secrets are in the container, relative to its root.
+pub(crate) const SECRETS_DIR: &str = "run/secrets";
+/// The variable in which systemd tells a service where its credentials are, see
+/// `ContainerBuilder::credentials_directory()`.
+pub(crate) const CREDENTIALS_DIRECTORY: &str = "CREDENTIALS_DIRECTORY";
+
+/// A secret of a container, which becomes the file `/run/secrets/<name>`.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct Secret {
+    pub(crate) name: String,
+    value: Vec<u8>,
+}
+
+impl Secret {
+    pub(crate) fn new(name: String, value: Vec<u8>) -> Self {
+        Secret { name, value }
+    }
+}
+
+/// Leaves the value out, so that it doesn't end up in logs.
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Secret")
+            .field("name", &self.name)
+            .field("value", &format_args!("({} bytes)", self.value.len()))
+            .finish()
+    }
+}
+
+/// Returns the mount of the tmpfs that holds the secrets.
+pub(crate) fn mount() -> Mount {
+    Mount::tmpfs_with_options(SECRETS_DIR.into(), flags(), "mode=0700".into())
+}
+
+/// Returns the mount flags of the tmpfs, which never holds anything to execute.
+fn flags() -> MsFlags {
+    MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC
+}
+
+/// Mounts the tmpfs in the root filesystem at `rootfs_path`, writes `secrets` into it for `owner`
+/// (the container's user and group), and makes it read-only.
+pub(crate) fn mount_secrets(
+    secrets: &[Secret],
+    owner: (u32, u32),
+    rootfs_path: &Path,
+    syscalls: &dyn Syscalls,
+    reporter: &Reporter,
+    rollback: &Rollback,
+) -> Result<(), CartonError> {
+    let dir = mount()
+        .mount(rootfs_path, syscalls, reporter, rollback)?
+        .expect("the tmpfs for secrets is not optional");
+    let (uid, gid) = (Uid::from_raw(owner.0), Gid::from_raw(owner.1));
+
+    for secret in secrets {
+        let path = dir.join(&secret.name);
+        reporter.info(format_args!("writing secret {}", path.display()));
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o400)
+            .open(&path)?
+            .write_all(&secret.value)?;
+        unistd::chown(&path, Some(uid), Some(gid))?;
+    }
+    unistd::chown(&dir, Some(uid), Some(gid))?;
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o500))?;
+
+    // The container only reads them
+    syscalls.mount(
+        None,
+        &dir,
+        None,
+        MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY | flags(),
+        None,
+    )?;
+
+    Ok(())
+}
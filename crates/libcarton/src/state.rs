@@ -0,0 +1,539 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv6Addr};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use nix::errno::Errno;
+use nix::fcntl::{self, FlockArg};
+use nix::sys::signal;
+use nix::unistd::Pid;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::cgroup;
+use crate::container::{self, ContainerState};
+use crate::error::CartonError;
+use crate::exit::ContainerExit;
+use crate::schema::Schema;
+use crate::seccomp::SeccompProfile;
+
+const STATE_FILE_NAME: &str = "state.json";
+/// File in the root directory that's locked while the store is changed in ways that depend on what
+/// it contains, like giving a container a name that no other container has.
+const LOCK_FILE_NAME: &str = ".lock";
+/// How often `StateStore::wait()` checks on a container.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long `StateStore::wait()` gives the carton process that started a container to record how
+/// it ended, after the container's process is gone.
+const EXIT_CODE_GRACE_PERIOD: Duration = Duration::from_secs(1);
+/// The layout of state files, see the `schema` module.
+const STATE_SCHEMA: Schema = Schema {
+    kind: "state file",
+    migrations: &[exit_from_exit_code],
+};
+
+/// A snapshot of a container's configuration and status. This is what gets written to the state
+/// directory so that other carton processes (like `carton ps`) can find out about the container.
+/// State files carry a schema version, and those of older carton versions are migrated when
+/// they're read.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContainerInfo {
+    pub id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    pub state: ContainerState,
+    /// Whether the processes of a running container are paused, see `Container::pause()`.
+    #[serde(default)]
+    pub paused: bool,
+    /// PID of the container's process, as seen from the "host".
+    pub pid: Option<i32>,
+    /// How the container's process ended. Unknown when the carton process that started the
+    /// container went away first.
+    #[serde(default)]
+    pub exit: Option<ContainerExit>,
+    /// Address of the container on its own network, if it has one. This is the IPv4 address when
+    /// the container has both an IPv4 and an IPv6 address.
+    #[serde(default)]
+    pub ip_address: Option<IpAddr>,
+    #[serde(default)]
+    pub ipv6_address: Option<Ipv6Addr>,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// NIS domain name of the container, if it has one of its own.
+    #[serde(default)]
+    pub domainname: Option<String>,
+    /// Root filesystem of the container, which is empty for a sandbox.
+    pub rootfs: PathBuf,
+    /// Whether the container is a sandbox, which runs on the host's filesystem.
+    #[serde(default)]
+    pub sandbox: bool,
+    /// Whether the container writes to an overlay on a tmpfs instead of to its root filesystem.
+    #[serde(default)]
+    pub ephemeral: bool,
+    /// Files and directories of the host that are bind mounted into the container, as `(path in
+    /// the container, path on the host)`.
+    #[serde(default)]
+    pub bind_mounts: Vec<(PathBuf, PathBuf)>,
+    /// Names of the volumes that the container mounts, see `ContainerBuilder::add_volume()`.
+    #[serde(default)]
+    pub volumes: Vec<String>,
+    pub command: PathBuf,
+    pub arguments: Vec<String>,
+    /// Unix timestamp (in seconds) of when the container was created.
+    pub created: u64,
+    /// Where the settings of the container came from, by name, as the program that started it
+    /// recorded them with `ContainerBuilder::settings()`.
+    #[serde(default)]
+    pub settings: BTreeMap<String, Setting>,
+    /// The project that the container belongs to, if any, see `ContainerBuilder::project()`.
+    #[serde(default)]
+    pub project: Option<ProjectInfo>,
+    /// Names of the secrets that the container gets in /run/secrets, see
+    /// `ContainerBuilder::add_secret()`. Their values aren't recorded anywhere.
+    #[serde(default)]
+    pub secrets: Vec<String>,
+    /// The transient systemd scope of the user's session that the container runs in, if any, see
+    /// `ContainerBuilder::systemd_scope()`.
+    #[serde(default)]
+    pub scope: Option<String>,
+    /// The user and group that the container's command runs as, when that's not root, see
+    /// `ContainerBuilder::user()`.
+    #[serde(default)]
+    pub user: Option<(u32, u32)>,
+    /// The system calls that the container's processes are limited to, if any, see
+    /// `ContainerBuilder::seccomp_profile()`.
+    #[serde(default)]
+    pub seccomp_profile: Option<SeccompProfile>,
+}
+
+/// A group of containers that are started, stopped and restarted together, like the services of a
+/// compose file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProjectInfo {
+    pub name: String,
+    /// The manifest that describes the containers of the project.
+    pub manifest: PathBuf,
+}
+
+/// The value of a setting and where it came from, see `ContainerInfo::settings`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Setting {
+    pub value: serde_json::Value,
+    pub source: SettingSource,
+    /// The values of the sources that this one overrides, lowest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub overridden: Vec<Setting>,
+}
+
+/// Where a setting came from. Each one overrides the ones before it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SettingSource {
+    Default,
+    ConfigFile(PathBuf),
+    /// The `config.json` of an OCI bundle.
+    Bundle(PathBuf),
+    Flag,
+}
+
+impl fmt::Display for SettingSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingSource::Default => write!(f, "the default"),
+            SettingSource::ConfigFile(path) => write!(f, "{}", path.display()),
+            SettingSource::Bundle(path) => write!(f, "{}", path.display()),
+            SettingSource::Flag => write!(f, "the command line"),
+        }
+    }
+}
+
+/// Keeps track of containers by storing their `ContainerInfo` in a directory per container, under
+/// a common root directory (`/run/carton` by default).
+#[derive(Debug)]
+pub struct StateStore {
+    root: PathBuf,
+}
+
+impl StateStore {
+    pub fn new(root: PathBuf) -> Self {
+        StateStore { root }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Writes the state of the container of `info`. A container that's saved already keeps the
+    /// name it has in the store, because it may have been renamed since it was created, see
+    /// `rename()`.
+    pub fn save(&self, info: &ContainerInfo) -> Result<(), CartonError> {
+        let _lock = self.lock()?;
+        match self.load(&info.id) {
+            Ok(saved) if saved.name != info.name => self.write(&ContainerInfo {
+                name: saved.name,
+                ..info.clone()
+            }),
+            _ => self.write(info),
+        }
+    }
+
+    /// Writes the state of the new container of `info`, before it runs. Its name, if it has one,
+    /// is checked and claimed while the store is locked, so that of two containers that are
+    /// started with the same name at the same time, only one gets it (see `check_name()`).
+    pub fn create(&self, info: &ContainerInfo) -> Result<(), CartonError> {
+        let _lock = self.lock()?;
+        if let Some(name) = &info.name {
+            self.check_name(&info.id, name)?;
+        }
+
+        self.write(info)
+    }
+
+    /// Gives the container that `reference` refers to (see `resolve()`) the name `name`, and
+    /// returns its updated state. The name has to be valid and free, see `check_name()`.
+    pub fn rename(&self, reference: &str, name: &str) -> Result<ContainerInfo, CartonError> {
+        let _lock = self.lock()?;
+        let mut info = self.resolve(reference)?;
+        self.check_name(&info.id, name)?;
+
+        info.name = Some(name.into());
+        self.write(&info)?;
+
+        Ok(info)
+    }
+
+    /// Checks that the container `id` can be called `name`: it has to be a valid name, which no
+    /// other container has, and which isn't the start of another container's ID, because
+    /// `resolve()` would then no longer find that container by that prefix. Called with the lock
+    /// of the store held.
+    fn check_name(&self, id: &str, name: &str) -> Result<(), CartonError> {
+        container::validate_name("container", name)?;
+
+        for other in self.list()?.into_iter().filter(|other| other.id != id) {
+            let short_id = &other.id[..other.id.len().min(12)];
+            if other.name.as_deref() == Some(name) {
+                return Err(CartonError::InvalidConfiguration(format!(
+                    "the name {} is already in use by container {}",
+                    name, short_id
+                )));
+            }
+            if other.id.starts_with(name) {
+                return Err(CartonError::InvalidConfiguration(format!(
+                    "the name {} is the start of the ID of container {}",
+                    name, short_id
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Takes the lock of the store, which is held until the returned file is closed. While it's
+    /// held, no container is added, renamed or saved: `create()`, `rename()` and `save()` wait for
+    /// it, so they can't be called by whoever holds it.
+    pub fn lock(&self) -> Result<File, CartonError> {
+        fs::create_dir_all(&self.root)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.root.join(LOCK_FILE_NAME))?;
+        fcntl::flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+
+        Ok(file)
+    }
+
+    fn write(&self, info: &ContainerInfo) -> Result<(), CartonError> {
+        let container_dir = self.root.join(&info.id);
+        fs::create_dir_all(&container_dir)?;
+
+        // Write to a temporary file first and then move it into place, so that a reader never sees
+        // a half-written state file.
+        let tmp_path = container_dir.join(format!("{}.tmp", STATE_FILE_NAME));
+        let mut value = serde_json::to_value(info)?;
+        STATE_SCHEMA.stamp(&mut value);
+        fs::write(&tmp_path, serde_json::to_vec_pretty(&value)?)?;
+        fs::rename(&tmp_path, container_dir.join(STATE_FILE_NAME))?;
+
+        Ok(())
+    }
+
+    pub fn load(&self, id: &str) -> Result<ContainerInfo, CartonError> {
+        Ok(refresh_state(self.read(id)?))
+    }
+
+    /// Blocks until the container `id` (its full ID) has stopped, and returns its state then. That
+    /// includes how it ended, unless the carton process that started it went away first.
+    ///
+    /// Only the process that started a container can wait for it directly, so other processes
+    /// watch its state file instead, which that process updates when the container exits.
+    pub fn wait(&self, id: &str) -> Result<ContainerInfo, CartonError> {
+        let mut gone_since = None;
+
+        loop {
+            let info = self.read(id)?;
+            let is_stopped = !matches!(
+                info.state,
+                ContainerState::Running | ContainerState::Prepared
+            );
+            if is_stopped {
+                return Ok(info);
+            }
+
+            if !info.pid.map_or(false, process_exists) {
+                let gone_since = *gone_since.get_or_insert_with(Instant::now);
+                if gone_since.elapsed() >= EXIT_CODE_GRACE_PERIOD {
+                    return Ok(ContainerInfo {
+                        state: ContainerState::Exited,
+                        pid: None,
+                        ..info
+                    });
+                }
+            }
+
+            thread::sleep(WAIT_POLL_INTERVAL);
+        }
+    }
+
+    /// Returns the state of the container `id` as it was saved.
+    fn read(&self, id: &str) -> Result<ContainerInfo, CartonError> {
+        let state_path = self.root.join(id).join(STATE_FILE_NAME);
+        let contents = match fs::read(state_path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(CartonError::ContainerNotFound(id.into()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut value: Value = serde_json::from_slice(&contents)?;
+        STATE_SCHEMA
+            .migrate(&mut value)
+            .map_err(CartonError::InvalidStateFile)?;
+
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Finds the container that `reference` refers to, which can be its ID, its name or a prefix of
+    /// its ID (like the 12 characters that `carton ps` shows). A full ID or a name takes precedence
+    /// over a prefix, and a prefix has to be unique.
+    pub fn resolve(&self, reference: &str) -> Result<ContainerInfo, CartonError> {
+        let containers = self.list()?;
+
+        if let Some(info) = containers
+            .iter()
+            .find(|info| info.id == reference || info.name.as_deref() == Some(reference))
+        {
+            return Ok(info.clone());
+        }
+
+        let mut matches = containers
+            .into_iter()
+            .filter(|info| !reference.is_empty() && info.id.starts_with(reference))
+            .collect::<Vec<ContainerInfo>>();
+        match matches.len() {
+            0 => Err(self.unreadable(reference)?),
+            1 => Ok(matches.remove(0)),
+            _ => Err(CartonError::AmbiguousContainer(
+                reference.into(),
+                matches
+                    .iter()
+                    .map(|info| &info.id[..info.id.len().min(12)])
+                    .collect::<Vec<&str>>()
+                    .join(", "),
+            )),
+        }
+    }
+
+    /// Returns the error of loading the container whose ID is or starts with `reference`, when
+    /// `resolve()` didn't find it because its state file couldn't be read (like one of a newer
+    /// carton), so that the error says why. Returns `ContainerNotFound` otherwise.
+    fn unreadable(&self, reference: &str) -> Result<CartonError, CartonError> {
+        Ok(self
+            .load_all()?
+            .into_iter()
+            .find_map(|(id, result)| match result {
+                Err(e) if !reference.is_empty() && id.starts_with(reference) => Some(e),
+                _ => None,
+            })
+            .unwrap_or_else(|| CartonError::ContainerNotFound(reference.into())))
+    }
+
+    /// Returns all known containers, oldest first.
+    pub fn list(&self) -> Result<Vec<ContainerInfo>, CartonError> {
+        let mut containers = vec![];
+        for (id, result) in self.load_all()? {
+            match result {
+                Ok(info) => containers.push(info),
+                Err(e) => warn!("Skipping container {}: {}", id, e),
+            }
+        }
+        containers.sort_by_key(|info| info.created);
+
+        Ok(containers)
+    }
+
+    /// Returns every container in the store, in no particular order, with the error of reading its
+    /// state instead when that fails. `list()` leaves those out.
+    pub fn load_all(&self) -> Result<Vec<LoadedContainer>, CartonError> {
+        let entries = match fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut containers = vec![];
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let id = entry.file_name().to_string_lossy().into_owned();
+            let result = self.load(&id);
+            containers.push((id, result));
+        }
+
+        Ok(containers)
+    }
+
+    pub fn remove(&self, id: &str) -> Result<(), CartonError> {
+        match fs::remove_dir_all(self.root.join(id)) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Err(CartonError::ContainerNotFound(id.into()))
+            }
+            result => Ok(result?),
+        }
+    }
+}
+
+/// The ID of a container in the store, with its state or the error of loading it.
+pub type LoadedContainer = (String, Result<ContainerInfo, CartonError>);
+
+/// The carton process that started a container might have been killed before it got the chance to
+/// update the state file, so a "running" container's process might not actually exist anymore.
+/// Whether it's paused can change from outside of that process too.
+fn refresh_state(mut info: ContainerInfo) -> ContainerInfo {
+    if let (ContainerState::Running, Some(pid)) = (info.state, info.pid) {
+        if !process_exists(pid) {
+            info.state = ContainerState::Exited;
+            info.pid = None;
+        }
+    }
+    info.paused = info.state == ContainerState::Running && cgroup::is_frozen(&info.id);
+
+    info
+}
+
+/// Migrates a state file from version 1, which had the exit code (or 128 plus the signal that
+/// killed the process) in `exit_code` and a `timed_out` flag, to version 2, which has `exit`. An
+/// exit code above 128 stays an exit code, because the command may have exited with it itself.
+fn exit_from_exit_code(fields: &mut Map<String, Value>) -> Result<(), String> {
+    let code = fields.remove("exit_code").unwrap_or(Value::Null);
+    let timed_out = fields.remove("timed_out").unwrap_or(Value::Bool(false));
+    let exit = match (code, timed_out) {
+        (_, Value::Bool(true)) => json!({"type": "timed_out"}),
+        (Value::Null, Value::Bool(false)) => Value::Null,
+        (Value::Number(code), Value::Bool(false)) if code.is_i64() => {
+            json!({"type": "exited", "code": code})
+        }
+        (code, timed_out) => {
+            return Err(format!(
+                "invalid exit_code {} or timed_out {}",
+                code, timed_out
+            ))
+        }
+    };
+    fields.insert("exit".into(), exit);
+
+    Ok(())
+}
+
+fn process_exists(pid: i32) -> bool {
+    !matches!(signal::kill(Pid::from_raw(pid), None), Err(Errno::ESRCH))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Migrates `value`, a state file of schema version 1 with `fields` added.
+    fn migrate_v1(fields: Value) -> Result<Value, String> {
+        let mut value = json!({
+            "id": "0123456789abcdef",
+            "state": "exited",
+            "pid": null,
+            "rootfs": "/rootfs",
+            "command": "/bin/true",
+            "arguments": [],
+            "created": 1700000000,
+        });
+        value
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        STATE_SCHEMA.migrate(&mut value)?;
+
+        Ok(value)
+    }
+
+    fn exit(value: Value) -> Option<ContainerExit> {
+        serde_json::from_value::<ContainerInfo>(value).unwrap().exit
+    }
+
+    #[test]
+    fn migrates_exit_codes() {
+        let value = migrate_v1(json!({"exit_code": 3, "timed_out": false})).unwrap();
+
+        assert_eq!(value["exit"], json!({"type": "exited", "code": 3}));
+        assert!(value.get("exit_code").is_none());
+        assert!(value.get("timed_out").is_none());
+        assert_eq!(exit(value), Some(ContainerExit::Exited(3)));
+    }
+
+    #[test]
+    fn migrates_unknown_exits() {
+        assert_eq!(exit(migrate_v1(json!({"exit_code": null})).unwrap()), None);
+        assert_eq!(exit(migrate_v1(json!({})).unwrap()), None);
+    }
+
+    #[test]
+    fn migrates_timeouts() {
+        for fields in [
+            json!({"exit_code": 124, "timed_out": true}),
+            json!({"exit_code": null, "timed_out": true}),
+        ] {
+            assert_eq!(
+                exit(migrate_v1(fields).unwrap()),
+                Some(ContainerExit::TimedOut)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_exit_codes() {
+        assert!(migrate_v1(json!({"exit_code": "3"})).is_err());
+        assert!(migrate_v1(json!({"exit_code": 1.5})).is_err());
+        assert!(migrate_v1(json!({"exit_code": 3, "timed_out": "no"})).is_err());
+    }
+
+    #[test]
+    fn leaves_current_state_files_alone() {
+        let exit = json!({"type": "exited", "code": 3});
+        let value = migrate_v1(json!({"schema_version": 2, "exit": exit})).unwrap();
+
+        assert_eq!(value["exit"], exit);
+        assert!(value.get("schema_version").is_none());
+    }
+}
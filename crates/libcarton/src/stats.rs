@@ -0,0 +1,196 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! What a running container uses: CPU time, memory, processes and network traffic, for
+//! `carton stats`.
+//!
+//! A container with a cgroup of its own (see `ContainerBuilder::delegate_cgroup()`) has the
+//! kernel's accounting of its cgroup, which includes the cgroups it created inside it. Other
+//! containers share the cgroup of the program that started them, so their numbers are added up
+//! from the processes in their PID namespace instead. Memory is then the sum of the processes'
+//! resident memory, which counts memory that they share more than once.
+//!
+//! The network traffic is what went through the interfaces of the container's network namespace,
+//! other than loopback. A container that shares the host's network has no traffic of its own.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use nix::unistd::{self, SysconfVar};
+
+use crate::cgroup;
+use crate::container::ContainerState;
+use crate::error::CartonError;
+use crate::state::ContainerInfo;
+
+/// A snapshot of what a running container uses. The CPU usage is the difference between two
+/// snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerStats {
+    /// CPU time that the container's processes used so far, in user and kernel mode.
+    pub cpu_time: Duration,
+    /// Memory that the container uses, in bytes.
+    pub memory: u64,
+    /// The most memory that the container can use, in bytes, if it's limited.
+    pub memory_limit: Option<u64>,
+    /// Number of processes in the container.
+    pub pids: u64,
+    /// Bytes that the container received and sent over its network, unless it shares the
+    /// host's network.
+    pub network: Option<(u64, u64)>,
+}
+
+impl ContainerStats {
+    /// Takes a snapshot of what the container of `info` uses. It has to be running.
+    pub fn collect(info: &ContainerInfo) -> Result<Self, CartonError> {
+        let (ContainerState::Running, Some(pid)) = (info.state, info.pid) else {
+            return Err(CartonError::NotRunning);
+        };
+
+        let processes = namespace_processes(pid)?;
+        let mut stats = ContainerStats {
+            cpu_time: processes.iter().map(|process| process.cpu_time).sum(),
+            memory: processes.iter().map(|process| process.rss).sum(),
+            memory_limit: None,
+            pids: processes.len() as u64,
+            network: network(pid)?,
+        };
+
+        if let Some(cgroup) = cgroup::path(&info.id) {
+            // Only the files of the controllers that are enabled for the cgroup exist
+            if let Some(usage) = read_key(&cgroup.join("cpu.stat"), "usage_usec") {
+                stats.cpu_time = Duration::from_micros(usage);
+            }
+            if let Some(memory) = read_value(&cgroup.join("memory.current")) {
+                stats.memory = memory;
+            }
+            stats.memory_limit = read_value(&cgroup.join("memory.max"));
+            if let Some(pids) = read_value(&cgroup.join("pids.current")) {
+                stats.pids = pids;
+            }
+        }
+
+        Ok(stats)
+    }
+}
+
+struct Process {
+    cpu_time: Duration,
+    rss: u64,
+}
+
+/// Returns the PIDs (on the host) of the processes in the PID namespace of process `pid`, which
+/// are the processes of a container. Fails when that's carton's own PID namespace, whose processes
+/// aren't only the container's.
+pub(crate) fn namespace_pids(pid: i32) -> Result<Vec<i32>, CartonError> {
+    let namespace = fs::read_link(format!("/proc/{}/ns/pid", pid))?;
+    if fs::read_link("/proc/self/ns/pid")? == namespace {
+        return Err(CartonError::NamespaceError(format!(
+            "process {} is in carton's own PID namespace, so the processes of its container \
+             can't be told apart from the others",
+            pid
+        )));
+    }
+
+    let mut pids = Vec::new();
+    for entry in fs::read_dir("/proc")? {
+        let Some(pid) = entry?
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        else {
+            continue;
+        };
+        // Processes that exit in the meantime are left out
+        if fs::read_link(format!("/proc/{}/ns/pid", pid)).ok().as_ref() == Some(&namespace) {
+            pids.push(pid);
+        }
+    }
+
+    Ok(pids)
+}
+
+/// Returns the processes in the PID namespace of process `pid`.
+fn namespace_processes(pid: i32) -> Result<Vec<Process>, CartonError> {
+    let ticks_per_second = unistd::sysconf(SysconfVar::CLK_TCK)?.unwrap_or(100) as u64;
+    let page_size = unistd::sysconf(SysconfVar::PAGE_SIZE)?.unwrap_or(4096) as u64;
+
+    let mut processes = Vec::new();
+    for pid in namespace_pids(pid)? {
+        let proc_dir = PathBuf::from(format!("/proc/{}", pid));
+        let (Ok(stat), Ok(statm)) = (
+            fs::read_to_string(proc_dir.join("stat")),
+            fs::read_to_string(proc_dir.join("statm")),
+        ) else {
+            continue;
+        };
+
+        // The name of the command is in parentheses and can contain anything, so the other fields
+        // are the ones after the last parenthesis. utime and stime are the 14th and 15th field.
+        let Some(end_of_name) = stat.rfind(')') else {
+            continue;
+        };
+        let ticks = stat[end_of_name + 1..]
+            .split_whitespace()
+            .skip(11)
+            .take(2)
+            .filter_map(|ticks| ticks.parse::<u64>().ok())
+            .sum::<u64>();
+        let pages = statm
+            .split_whitespace()
+            .nth(1)
+            .and_then(|pages| pages.parse::<u64>().ok())
+            .unwrap_or(0);
+
+        processes.push(Process {
+            cpu_time: Duration::from_millis(ticks * 1000 / ticks_per_second),
+            rss: pages * page_size,
+        });
+    }
+
+    Ok(processes)
+}
+
+/// Returns the bytes that the network namespace of process `pid` received and sent, or `None`
+/// when that's the host's network namespace.
+fn network(pid: i32) -> Result<Option<(u64, u64)>, CartonError> {
+    let namespace = fs::read_link(format!("/proc/{}/ns/net", pid))?;
+    if fs::read_link("/proc/self/ns/net")? == namespace {
+        return Ok(None);
+    }
+
+    // Two lines of headers, then `interface: rx_bytes rx_packets ... tx_bytes tx_packets ...`
+    let dev = fs::read_to_string(format!("/proc/{}/net/dev", pid))?;
+    let (mut received, mut sent) = (0, 0);
+    for line in dev.lines().skip(2) {
+        let Some((interface, counters)) = line.split_once(':') else {
+            continue;
+        };
+        if interface.trim() == "lo" {
+            continue;
+        }
+        let counters = counters
+            .split_whitespace()
+            .map(|counter| counter.parse::<u64>().unwrap_or(0))
+            .collect::<Vec<u64>>();
+        received += counters.first().copied().unwrap_or(0);
+        sent += counters.get(8).copied().unwrap_or(0);
+    }
+
+    Ok(Some((received, sent)))
+}
+
+/// Returns the number in the cgroup file at `path`, or `None` if there's no such file or it says
+/// `max`.
+fn read_value(path: &Path) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Returns the number of `key` in the flat keyed cgroup file at `path`, like `cpu.stat`.
+fn read_key(path: &Path, key: &str) -> Option<u64> {
+    fs::read_to_string(path)
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(' ')?.parse().ok())
+}
@@ -0,0 +1,119 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Feeding the container's stdin from a stream of the embedding program, see
+//! `ContainerBuilder::stdin()`.
+//!
+//! Without a stream, the container process simply inherits the stdin of the calling process, like
+//! any child process would. With one, it gets the reading end of a pipe as its stdin right before
+//! it executes the command. A thread of the calling process copies the stream into the writing
+//! end and closes it at the end of the stream, which the command sees as the end of its input.
+//! That only works when nothing else holds on to the writing end. It's close-on-exec, so the
+//! container process' copy of it disappears with the execve() of the command. Containers that
+//! are prepared later don't execute anything until they get a command though, so they'd keep the
+//! copy they inherited for as long as they wait. That's why the writing ends that are in use are
+//! kept in `WRITERS`, which every container process closes right after it's cloned.
+//!
+//! When the command exits (or closes its stdin) before the end of the stream, writing fails with
+//! `EPIPE` and the thread stops.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+
+use log::warn;
+
+use nix::fcntl::OFlag;
+use nix::unistd;
+
+use crate::error::CartonError;
+
+type Stream = Box<dyn Read + Send>;
+
+/// The writing ends that threads are still copying streams into. A writing end is only closed
+/// while this is locked, and it's locked while a container process is cloned, so the list the
+/// container process sees matches the file descriptors it inherited.
+static WRITERS: Mutex<Vec<RawFd>> = Mutex::new(Vec::new());
+
+/// Keeps `WRITERS` locked, from before a container process is cloned until its own writing end,
+/// if any, is added.
+pub(crate) struct Writers(MutexGuard<'static, Vec<RawFd>>);
+
+impl Writers {
+    pub(crate) fn lock() -> Self {
+        Writers(WRITERS.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Closes the copies of the writing ends that the container process inherited. Called in the
+    /// container process, which only has a copy of the lock and must not touch it otherwise.
+    pub(crate) fn close_inherited(&self) {
+        for fd in self.0.iter() {
+            let _ = unistd::close(*fd);
+        }
+    }
+}
+
+/// The stream that becomes the container's stdin. It's taken out of the `Mutex` when the container
+/// is prepared, because the configuration is only ever used through a shared reference.
+pub(crate) struct StdinStream {
+    stream: Mutex<Option<Stream>>,
+}
+
+impl fmt::Debug for StdinStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StdinStream").finish_non_exhaustive()
+    }
+}
+
+impl StdinStream {
+    pub(crate) fn new<R: Read + Send + 'static>(stream: R) -> Self {
+        StdinStream {
+            stream: Mutex::new(Some(Box::new(stream))),
+        }
+    }
+
+    /// Starts copying the stream into `pipe`, the writing end of the container's stdin. A stream
+    /// can only be read once, so when the container runs again, its stdin is empty.
+    pub(crate) fn start(&self, pipe: OwnedFd, writers: &mut Writers) {
+        let stream = self.stream.lock().unwrap_or_else(|e| e.into_inner()).take();
+        let Some(mut stream) = stream else {
+            return;
+        };
+        let fd = pipe.as_raw_fd();
+        writers.0.push(fd);
+
+        // The thread isn't joined: reading the stream may block for as long as it likes, like a
+        // terminal does
+        thread::spawn(move || {
+            let mut pipe = File::from(pipe);
+            match io::copy(&mut stream, &mut pipe) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => {}
+                Err(e) => warn!("Could not pass on the container's stdin: {}", e),
+            }
+
+            let mut writers = Writers::lock();
+            drop(pipe);
+            writers.0.retain(|writer| *writer != fd);
+        });
+    }
+}
+
+/// Returns the pipe for the container's stdin, as its reading and writing end. Both are
+/// close-on-exec.
+pub(crate) fn pipe() -> Result<(OwnedFd, OwnedFd), CartonError> {
+    let (read, write) = unistd::pipe2(OFlag::O_CLOEXEC)?;
+
+    Ok(unsafe { (OwnedFd::from_raw_fd(read), OwnedFd::from_raw_fd(write)) })
+}
+
+/// Makes `pipe` the stdin of the container process. Called right before it executes the command.
+pub(crate) fn attach(pipe: &OwnedFd) -> nix::Result<()> {
+    // The copy that dup2() makes isn't close-on-exec, so it survives execv()
+    unistd::dup2(pipe.as_raw_fd(), 0)?;
+
+    Ok(())
+}
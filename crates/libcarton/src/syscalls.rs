@@ -0,0 +1,371 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! The system calls with which the container process sets up its mount and UTS namespaces.
+//!
+//! The setup in `namespace` makes them through the `Syscalls` trait instead of calling nix
+//! directly. `Host` makes them for real, and reports each one with its arguments and result (see
+//! `Reporter::operation()`). `Recording` only writes them down, so that the order and arguments of
+//! the mounts of a configuration can be checked without root, or even without Linux. Creating the
+//! directories and files that mounts and device nodes need isn't a system call in this sense; that
+//! goes through `Rollback`, so that it's undone when the setup fails.
+//!
+//! A system call that fails comes back as a `SyscallError`, which knows what was called and which
+//! capability it needs. When it was denied, it becomes a `CartonError::PermissionDenied` with a
+//! hint about what's missing, see `capabilities::hint()`.
+
+#[cfg(test)]
+use std::cell::RefCell;
+use std::fmt;
+use std::os::fd::RawFd;
+use std::path::Path;
+#[cfg(test)]
+use std::path::PathBuf;
+
+use nix::errno::Errno;
+use nix::mount::{self, MntFlags, MsFlags};
+use nix::sys::stat::{self, Mode, SFlag};
+use nix::unistd;
+
+use crate::capabilities::{self, Capability};
+use crate::diagnostics::Reporter;
+use crate::error::CartonError;
+use crate::idmap;
+use crate::plan;
+
+/// A system call of `Syscalls` that failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SyscallError {
+    /// The system call with its arguments, like `mount(NULL, "/", NULL, MS_SLAVE|MS_REC, NULL)`.
+    pub(crate) operation: String,
+    pub(crate) errno: Errno,
+    /// The capability that the system call needs, if any.
+    pub(crate) capability: Option<Capability>,
+}
+
+impl fmt::Display for SyscallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.operation, self.errno.desc())
+    }
+}
+
+impl From<SyscallError> for CartonError {
+    fn from(error: SyscallError) -> Self {
+        let SyscallError {
+            operation,
+            errno,
+            capability,
+        } = error;
+
+        match errno {
+            Errno::EPERM | Errno::EACCES => CartonError::PermissionDenied {
+                operation,
+                error: errno.desc().into(),
+                hint: capabilities::hint(capability),
+            },
+            _ => CartonError::SysCallFailed(format!("{}: {} ({})", operation, errno.desc(), errno)),
+        }
+    }
+}
+
+pub(crate) trait Syscalls {
+    /// `mount(2)`
+    fn mount(
+        &self,
+        source: Option<&Path>,
+        target: &Path,
+        fstype: Option<&str>,
+        flags: MsFlags,
+        data: Option<&str>,
+    ) -> Result<(), SyscallError>;
+
+    /// `umount2(2)`
+    fn umount(&self, target: &Path, flags: MntFlags) -> Result<(), SyscallError>;
+
+    /// `move_mount(2)` of the detached tree `tree` (see `idmap::idmapped_tree()`) onto `target`.
+    fn move_mount(&self, tree: RawFd, target: &Path) -> Result<(), SyscallError>;
+
+    /// `mknod(2)` of a character device node that everyone can read and write.
+    fn mknod(&self, path: &Path, major: u64, minor: u64) -> Result<(), SyscallError>;
+
+    /// `symlink(2)`, which creates a symlink at `path` that points to `target`.
+    fn symlink(&self, target: &Path, path: &Path) -> Result<(), SyscallError>;
+
+    /// `pivot_root(2)`
+    fn pivot_root(&self, new_root: &Path, put_old: &Path) -> Result<(), SyscallError>;
+
+    /// `chroot(2)`
+    fn chroot(&self, new_root: &Path) -> Result<(), SyscallError>;
+
+    /// `chdir(2)`
+    fn chdir(&self, path: &Path) -> Result<(), SyscallError>;
+
+    /// `sethostname(2)`
+    fn sethostname(&self, hostname: &str) -> Result<(), SyscallError>;
+
+    /// `setdomainname(2)`, which sets the NIS domain name.
+    fn setdomainname(&self, domainname: &str) -> Result<(), SyscallError>;
+}
+
+/// Makes the system calls, and reports them to `reporter`.
+#[derive(Debug)]
+pub(crate) struct Host<'a> {
+    reporter: &'a Reporter,
+}
+
+impl<'a> Host<'a> {
+    pub(crate) fn new(reporter: &'a Reporter) -> Self {
+        Host { reporter }
+    }
+
+    /// Reports the system call `operation` and turns its error into a `SyscallError`.
+    fn call(
+        &self,
+        capability: Option<Capability>,
+        operation: fmt::Arguments,
+        result: nix::Result<()>,
+    ) -> Result<(), SyscallError> {
+        self.reporter
+            .operation(operation, result)
+            .map_err(|errno| SyscallError {
+                operation: operation.to_string(),
+                errno,
+                capability,
+            })
+    }
+}
+
+impl Syscalls for Host<'_> {
+    fn mount(
+        &self,
+        source: Option<&Path>,
+        target: &Path,
+        fstype: Option<&str>,
+        flags: MsFlags,
+        data: Option<&str>,
+    ) -> Result<(), SyscallError> {
+        let flag_names = plan::flag_names(flags);
+        self.call(
+            Some(Capability::SysAdmin),
+            format_args!(
+                "mount({}, {:?}, {}, {}, {})",
+                source.map_or_else(|| "NULL".into(), |source| format!("{:?}", source)),
+                target,
+                fstype.map_or_else(|| "NULL".into(), |fstype| format!("{:?}", fstype)),
+                if flag_names.is_empty() {
+                    "0".into()
+                } else {
+                    flag_names.join("|")
+                },
+                data.map_or_else(|| "NULL".into(), |data| format!("{:?}", data)),
+            ),
+            mount::mount(source, target, fstype, flags, data),
+        )
+    }
+
+    fn umount(&self, target: &Path, flags: MntFlags) -> Result<(), SyscallError> {
+        let flag_names: Vec<&str> = flags.iter_names().map(|(name, _)| name).collect();
+        self.call(
+            Some(Capability::SysAdmin),
+            format_args!("umount2({:?}, {})", target, flag_names.join("|")),
+            mount::umount2(target, flags),
+        )
+    }
+
+    fn move_mount(&self, tree: RawFd, target: &Path) -> Result<(), SyscallError> {
+        self.call(
+            Some(Capability::SysAdmin),
+            format_args!("move_mount({}, {:?})", tree, target),
+            idmap::attach_tree(tree, target),
+        )
+    }
+
+    fn mknod(&self, path: &Path, major: u64, minor: u64) -> Result<(), SyscallError> {
+        let mode = Mode::from_bits_truncate(0o666);
+        self.call(
+            Some(Capability::Mknod),
+            format_args!("mknod({:?}, S_IFCHR|666, {}:{})", path, major, minor),
+            // The umask takes the write bits of others off the mode of mknod(), which users other
+            // than root in the container need
+            stat::mknod(path, SFlag::S_IFCHR, mode, stat::makedev(major, minor)).and_then(|()| {
+                stat::fchmodat(None, path, mode, stat::FchmodatFlags::FollowSymlink)
+            }),
+        )
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> Result<(), SyscallError> {
+        self.call(
+            None,
+            format_args!("symlink({:?}, {:?})", target, path),
+            unistd::symlinkat(target, None, path),
+        )
+    }
+
+    fn pivot_root(&self, new_root: &Path, put_old: &Path) -> Result<(), SyscallError> {
+        self.call(
+            Some(Capability::SysAdmin),
+            format_args!("pivot_root({:?}, {:?})", new_root, put_old),
+            unistd::pivot_root(new_root, put_old),
+        )
+    }
+
+    fn chroot(&self, new_root: &Path) -> Result<(), SyscallError> {
+        self.call(
+            Some(Capability::SysChroot),
+            format_args!("chroot({:?})", new_root),
+            unistd::chroot(new_root),
+        )
+    }
+
+    fn chdir(&self, path: &Path) -> Result<(), SyscallError> {
+        self.call(None, format_args!("chdir({:?})", path), unistd::chdir(path))
+    }
+
+    fn sethostname(&self, hostname: &str) -> Result<(), SyscallError> {
+        self.call(
+            Some(Capability::SysAdmin),
+            format_args!("sethostname({:?})", hostname),
+            unistd::sethostname(hostname),
+        )
+    }
+
+    fn setdomainname(&self, domainname: &str) -> Result<(), SyscallError> {
+        // nix has no wrapper for it
+        let result = unsafe { libc::setdomainname(domainname.as_ptr().cast(), domainname.len()) };
+        self.call(
+            Some(Capability::SysAdmin),
+            format_args!("setdomainname({:?})", domainname),
+            Errno::result(result).map(drop),
+        )
+    }
+}
+
+/// A system call that `Recording` wrote down.
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Syscall {
+    Mount {
+        source: Option<PathBuf>,
+        target: PathBuf,
+        fstype: Option<String>,
+        flags: MsFlags,
+        data: Option<String>,
+    },
+    Umount(PathBuf),
+    MoveMount(PathBuf),
+    Mknod {
+        path: PathBuf,
+        major: u64,
+        minor: u64,
+    },
+    Symlink {
+        target: PathBuf,
+        path: PathBuf,
+    },
+    PivotRoot(PathBuf),
+    Chroot(PathBuf),
+    Chdir(PathBuf),
+    SetHostname(String),
+    SetDomainname(String),
+}
+
+/// Writes down the system calls instead of making them. They all succeed, except for mounts on the
+/// targets that were given with `fail_mount()`. The unit tests of the setup in `namespace` run it
+/// against a `Recording`.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct Recording {
+    calls: RefCell<Vec<Syscall>>,
+    failing_mounts: Vec<(PathBuf, Errno)>,
+}
+
+#[cfg(test)]
+impl Recording {
+    /// Makes mounts on `target` fail with `errno`, like `EPERM` for a sysfs mount in a user
+    /// namespace.
+    pub(crate) fn fail_mount(mut self, target: PathBuf, errno: Errno) -> Self {
+        self.failing_mounts.push((target, errno));
+        self
+    }
+
+    /// Returns the system calls so far, in order.
+    pub(crate) fn calls(&self) -> Vec<Syscall> {
+        self.calls.borrow().clone()
+    }
+
+    fn record(&self, call: Syscall) -> Result<(), SyscallError> {
+        self.calls.borrow_mut().push(call);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Syscalls for Recording {
+    fn mount(
+        &self,
+        source: Option<&Path>,
+        target: &Path,
+        fstype: Option<&str>,
+        flags: MsFlags,
+        data: Option<&str>,
+    ) -> Result<(), SyscallError> {
+        if let Some((_, errno)) = self.failing_mounts.iter().find(|(path, _)| path == target) {
+            return Err(SyscallError {
+                operation: format!("mount({:?})", target),
+                errno: *errno,
+                capability: Some(Capability::SysAdmin),
+            });
+        }
+
+        self.record(Syscall::Mount {
+            source: source.map(PathBuf::from),
+            target: target.into(),
+            fstype: fstype.map(String::from),
+            flags,
+            data: data.map(String::from),
+        })
+    }
+
+    fn umount(&self, target: &Path, _flags: MntFlags) -> Result<(), SyscallError> {
+        self.record(Syscall::Umount(target.into()))
+    }
+
+    fn move_mount(&self, _tree: RawFd, target: &Path) -> Result<(), SyscallError> {
+        self.record(Syscall::MoveMount(target.into()))
+    }
+
+    fn mknod(&self, path: &Path, major: u64, minor: u64) -> Result<(), SyscallError> {
+        self.record(Syscall::Mknod {
+            path: path.into(),
+            major,
+            minor,
+        })
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> Result<(), SyscallError> {
+        self.record(Syscall::Symlink {
+            target: target.into(),
+            path: path.into(),
+        })
+    }
+
+    fn pivot_root(&self, new_root: &Path, _put_old: &Path) -> Result<(), SyscallError> {
+        self.record(Syscall::PivotRoot(new_root.into()))
+    }
+
+    fn chroot(&self, new_root: &Path) -> Result<(), SyscallError> {
+        self.record(Syscall::Chroot(new_root.into()))
+    }
+
+    fn chdir(&self, path: &Path) -> Result<(), SyscallError> {
+        self.record(Syscall::Chdir(path.into()))
+    }
+
+    fn sethostname(&self, hostname: &str) -> Result<(), SyscallError> {
+        self.record(Syscall::SetHostname(hostname.into()))
+    }
+
+    fn setdomainname(&self, domainname: &str) -> Result<(), SyscallError> {
+        self.record(Syscall::SetDomainname(domainname.into()))
+    }
+}
@@ -0,0 +1,79 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Spans of the lifecycle of a container, for finding out where the time to start one goes, see
+//! `ContainerBuilder::tracer()`.
+//!
+//! A container has four spans, which are passed to the `Tracer` once they ended:
+//!
+//! * `create`: `ContainerBuilder::build()`.
+//! * `setup`: `prepare()`, until the container process is done with its own part of the setup.
+//!   Its sub-spans are `volumes`, `cgroup` and `network`, which the calling process sets up, and
+//!   `mounts`, the container process' setup of its mounts and root filesystem.
+//! * `start`: `start()`, executing the command and running the post-setup hooks.
+//! * `stop`: cleaning up after the container's process exited, with the sub-spans `network`,
+//!   `cgroup` and `volumes`.
+//!
+//! The container process measures its own span and sends it through the pipe that it reports its
+//! diagnostics through (see `diagnostics`), so `setup` is only passed on once the container is
+//! started. A container that's prepared but never started has no `setup` span.
+
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+/// A part of the lifecycle of a container, with the parts that it consists of.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    pub name: String,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub children: Vec<Span>,
+}
+
+impl Span {
+    /// Returns the span `name` from `start` until now.
+    pub(crate) fn since(name: &str, start: SystemTime, children: Vec<Span>) -> Self {
+        Span {
+            name: name.into(),
+            start,
+            end: SystemTime::now(),
+            children,
+        }
+    }
+}
+
+/// Receives the spans of containers, like an exporter to a tracing backend. Closures that take a
+/// container ID and a `&Span` implement it too.
+///
+/// ```no_run
+/// use libcarton::{ContainerBuilder, Span};
+///
+/// let builder = ContainerBuilder::new().tracer(|id: &str, span: &Span| {
+///     if let Ok(duration) = span.end.duration_since(span.start) {
+///         eprintln!("{} of {} took {:?}", span.name, id, duration);
+///     }
+/// });
+/// ```
+pub trait Tracer: Send + Sync {
+    /// Receives a span of the container with the ID `container_id`, once it ended.
+    fn span(&self, container_id: &str, span: &Span);
+}
+
+impl<F> Tracer for F
+where
+    F: Fn(&str, &Span) + Send + Sync,
+{
+    fn span(&self, container_id: &str, span: &Span) {
+        self(container_id, span)
+    }
+}
+
+/// Runs `f` and adds a span `name` of how long it took to `spans`.
+pub(crate) fn measure<T>(spans: &mut Vec<Span>, name: &str, f: impl FnOnce() -> T) -> T {
+    let start = SystemTime::now();
+    let result = f();
+    spans.push(Span::since(name, start, Vec::new()));
+
+    result
+}
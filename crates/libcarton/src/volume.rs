@@ -0,0 +1,243 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! Volumes: named storage that outlives containers, and that a `VolumeDriver` provides.
+//!
+//! A driver makes a volume available somewhere on the host when a container that uses it is
+//! prepared, and the container process bind mounts it from there like any other mount (see
+//! `ContainerBuilder::add_volume()`). When the container's process is gone, the driver is told to
+//! unmount it again. That keeps the drivers simple: they run in the calling process, with its
+//! privileges, and don't have to know anything about namespaces. `LocalVolumes`, which keeps every
+//! volume in a directory, is the driver of `carton volume` and `carton run --volume`; other drivers
+//! can mount network filesystems like NFS or CIFS, or attach cloud volumes.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::container::{self, Mount};
+use crate::diagnostics::Reporter;
+use crate::error::CartonError;
+
+/// Provides volumes to containers. Drivers are used through `Arc`s, because a container holds on
+/// to the driver of every volume it uses.
+///
+/// ```no_run
+/// # use std::collections::BTreeMap;
+/// # use std::path::PathBuf;
+/// # use std::sync::Arc;
+/// # use libcarton::{CartonError, ContainerBuilder, VolumeDriver};
+/// #[derive(Debug)]
+/// struct Nfs {
+///     server: String,
+/// }
+///
+/// impl VolumeDriver for Nfs {
+///     fn create(&self, name: &str, _options: &BTreeMap<String, String>) -> Result<(), CartonError> {
+///         // Create the export on the server
+///         # unimplemented!()
+///     }
+///
+///     fn mount(&self, name: &str, container_id: &str) -> Result<PathBuf, CartonError> {
+///         // Mount the export somewhere on the host and return where
+///         # unimplemented!()
+///     }
+///
+///     fn unmount(&self, name: &str, container_id: &str) -> Result<(), CartonError> {
+///         # unimplemented!()
+///     }
+///
+///     fn remove(&self, name: &str) -> Result<(), CartonError> {
+///         # unimplemented!()
+///     }
+///
+///     fn list(&self) -> Result<Vec<String>, CartonError> {
+///         # unimplemented!()
+///     }
+/// }
+///
+/// # fn main() -> Result<(), CartonError> {
+/// let nfs = Arc::new(Nfs {
+///     server: "192.168.1.10".into(),
+/// });
+/// let container = ContainerBuilder::new()
+///     .rootfs("/path/to/rootfs".into())
+///     .add_volume(nfs, "data".into(), "srv/data".into())
+///     .command("/usr/bin/app".into(), None)
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait VolumeDriver: fmt::Debug + Send + Sync {
+    /// Creates the volume `name`, with options that only the driver knows about, like the size of
+    /// a cloud volume. Creating a volume that exists already is an error.
+    fn create(&self, name: &str, options: &BTreeMap<String, String>) -> Result<(), CartonError>;
+
+    /// Makes the volume `name` available to the container `container_id`, and returns the path of
+    /// the file or directory on the host that the container process bind mounts. Called when the
+    /// container is prepared, before its process exists. Several containers can use the same
+    /// volume at once.
+    fn mount(&self, name: &str, container_id: &str) -> Result<PathBuf, CartonError>;
+
+    /// Tells the driver that the container `container_id` no longer uses the volume `name`, because
+    /// its process is gone or its setup failed. Errors are reported as warnings.
+    fn unmount(&self, name: &str, container_id: &str) -> Result<(), CartonError>;
+
+    /// Removes the volume `name` and everything in it.
+    fn remove(&self, name: &str) -> Result<(), CartonError>;
+
+    /// Returns the names of the volumes, sorted.
+    fn list(&self) -> Result<Vec<String>, CartonError>;
+}
+
+/// The default driver, which keeps every volume in a directory of its own.
+///
+/// Volume names consist of letters, digits, `_`, `.` and `-`, and start with a letter or digit,
+/// like container names.
+#[derive(Debug, Clone)]
+pub struct LocalVolumes {
+    dir: PathBuf,
+}
+
+impl LocalVolumes {
+    /// Keeps the volumes in `dir`, like `Paths::volume_dir()`.
+    pub fn new(dir: PathBuf) -> Self {
+        LocalVolumes { dir }
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, CartonError> {
+        container::validate_name("volume", name).map_err(|e| match e {
+            CartonError::InvalidConfiguration(message) => CartonError::VolumeError(message),
+            e => e,
+        })?;
+
+        Ok(self.dir.join(name))
+    }
+}
+
+impl VolumeDriver for LocalVolumes {
+    fn create(&self, name: &str, options: &BTreeMap<String, String>) -> Result<(), CartonError> {
+        if let Some(option) = options.keys().next() {
+            return Err(CartonError::VolumeError(format!(
+                "the local volume driver has no option `{}`",
+                option
+            )));
+        }
+
+        let path = self.path(name)?;
+        fs::create_dir_all(&self.dir)?;
+        match fs::create_dir(path) {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Err(CartonError::VolumeError(
+                format!("volume {} exists already", name),
+            )),
+            result => Ok(result?),
+        }
+    }
+
+    fn mount(&self, name: &str, _container_id: &str) -> Result<PathBuf, CartonError> {
+        let path = self.path(name)?;
+        if !path.is_dir() {
+            return Err(CartonError::VolumeError(format!(
+                "volume {} doesn't exist",
+                name
+            )));
+        }
+
+        Ok(path)
+    }
+
+    fn unmount(&self, _name: &str, _container_id: &str) -> Result<(), CartonError> {
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<(), CartonError> {
+        match fs::remove_dir_all(self.path(name)?) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(CartonError::VolumeError(format!(
+                "volume {} doesn't exist",
+                name
+            ))),
+            result => Ok(result?),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>, CartonError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        names.sort();
+
+        Ok(names)
+    }
+}
+
+/// A volume that a mount of a container mounts, see `ContainerBuilder::add_volume()`.
+#[derive(Debug, Clone)]
+pub(crate) struct Volume {
+    pub(crate) driver: Arc<dyn VolumeDriver>,
+    pub(crate) name: String,
+}
+
+/// Has the drivers mount the volumes of `mounts` for the container `container_id`, and makes the
+/// paths they return the sources of the mounts. When a driver fails, the volumes that were mounted
+/// already are unmounted again.
+pub(crate) fn mount_volumes(
+    mounts: &mut [Mount],
+    container_id: &str,
+    reporter: &Reporter,
+) -> Result<(), CartonError> {
+    for index in 0..mounts.len() {
+        let Some(volume) = mounts[index].mounted_volume().cloned() else {
+            continue;
+        };
+
+        let path = reporter.operation(
+            format_args!("mounting volume {} with {:?}", volume.name, volume.driver),
+            volume.driver.mount(&volume.name, container_id),
+        );
+        match path {
+            Ok(path) => mounts[index].set_source(Some(path)),
+            Err(e) => {
+                unmount_volumes(&mut mounts[..index], container_id, reporter);
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Has the drivers unmount the volumes of `mounts` that are mounted for the container
+/// `container_id`. Volumes that aren't mounted are left alone, so it's fine to call this more than
+/// once.
+pub(crate) fn unmount_volumes(mounts: &mut [Mount], container_id: &str, reporter: &Reporter) {
+    for mount in mounts.iter_mut() {
+        let (Some(volume), Some(_)) = (mount.mounted_volume().cloned(), &mount.source) else {
+            continue;
+        };
+
+        let result = reporter.operation(
+            format_args!("unmounting volume {} with {:?}", volume.name, volume.driver),
+            volume.driver.unmount(&volume.name, container_id),
+        );
+        if let Err(e) = result {
+            reporter.warning(format_args!(
+                "Could not unmount volume {}: {}",
+                volume.name, e
+            ));
+        }
+        mount.set_source(None);
+    }
+}
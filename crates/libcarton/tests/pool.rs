@@ -0,0 +1,43 @@
+// Copyright 2023 Arjen Verstoep
+// SPDX-License-Identifier: Apache-2.0
+
+//! End-to-end tests of `ContainerPool`, see the `carton-test` crate for how to run them.
+
+use std::time::Duration;
+
+use carton_test::{busybox_rootfs, require_root};
+use libcarton::{ContainerBuilder, ContainerExit, ContainerPool};
+
+#[test]
+#[ignore = "needs root"]
+fn ends_stdin_while_another_container_is_prepared() {
+    require_root!();
+
+    let container = ContainerBuilder::new()
+        .rootfs(busybox_rootfs())
+        .add_default_mounts()
+        .ephemeral()
+        .stdin(&b"one\ntwo\n"[..])
+        .timeout(Duration::from_secs(10))
+        .build_prepared()
+        .unwrap();
+    // Prepared after the first container, so its process was cloned while the first container's
+    // stdin was still being written to
+    let pool = ContainerPool::new(1, || {
+        ContainerBuilder::new()
+            .rootfs(busybox_rootfs())
+            .add_default_mounts()
+            .ephemeral()
+            .build_prepared()
+    })
+    .unwrap();
+
+    let mut container = container
+        .start_command("/bin/wc".into(), vec!["-l".into()])
+        .unwrap();
+    assert!(matches!(
+        container.wait_for_exit().unwrap(),
+        ContainerExit::Exited(0)
+    ));
+    assert_eq!(pool.len(), 1);
+}